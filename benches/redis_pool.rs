@@ -0,0 +1,84 @@
+//! Benchmark of the redis connection pool
+//! Sends the same concurrent load through a single connection and through pools of connections,
+//! picked round-robin like `database::Pool` does, and prints the throughput of every pool size.
+//!
+//! Needs a redis server at `REDIS_URL` (default `127.0.0.1:6379`), run it with
+//! `cargo bench --bench redis_pool`. The only key it writes is `weather_bench:counter`.
+#[macro_use]
+extern crate redis_async;
+
+use actix::Addr;
+use actix_redis::{Command, RedisActor};
+use futures::future::join_all;
+
+use std::env;
+use std::time::Instant;
+
+/// Commands sent per pool size
+const COMMANDS: usize = 50_000;
+
+/// Commands in flight at once, like concurrent requests of a worker
+const CONCURRENCY: usize = 200;
+
+/// Pool sizes to compare, the first is the single connection used before `REDIS_POOL_SIZE`
+const POOL_SIZES: [usize; 4] = [1, 2, 4, 8];
+
+/// Key the benchmark increments
+const KEY: &str = "weather_bench:counter";
+
+/// Sends `COMMANDS` commands, `CONCURRENCY` at a time spread round-robin over the connections.
+/// Returns the throughput in commands per second.
+///
+/// # Arguments
+///
+/// * `connections` - Connections of the pool
+async fn run(connections: &[Addr<RedisActor>]) -> f64 {
+    let started = Instant::now();
+
+    for batch in 0..COMMANDS / CONCURRENCY {
+        let sends = (0..CONCURRENCY).map(|i| {
+            let connection = &connections[(batch * CONCURRENCY + i) % connections.len()];
+            connection.send(Command(resp_array!["INCR", KEY]))
+        });
+
+        for res in join_all(sends).await {
+            res.expect("Redis connection stopped")
+                .expect("Redis command failed");
+        }
+    }
+
+    COMMANDS as f64 / started.elapsed().as_secs_f64()
+}
+
+fn main() {
+    let address = env::var("REDIS_URL").unwrap_or_else(|_| String::from("127.0.0.1:6379"));
+    let address = address
+        .trim_start_matches("redis://")
+        .trim_end_matches('/')
+        .to_owned();
+
+    actix_rt::System::new("redis_pool").block_on(async move {
+        let mut single = None;
+
+        for size in POOL_SIZES.iter() {
+            let connections: Vec<_> = (0..*size)
+                .map(|_| RedisActor::start(address.as_str()))
+                .collect();
+
+            // Warm up, so connecting isn't measured
+            run(&connections).await;
+            let throughput = run(&connections).await;
+
+            let baseline = *single.get_or_insert(throughput);
+            println!(
+                "{} connection(s): {:>9.0} commands/s ({:.2}x)",
+                size,
+                throughput,
+                throughput / baseline
+            );
+        }
+
+        let cleanup = RedisActor::start(address.as_str());
+        let _ = cleanup.send(Command(resp_array!["DEL", KEY])).await;
+    });
+}
@@ -0,0 +1,358 @@
+//! Documentation for alerts module
+//! Includes the alert rules of users and their evaluation on ingested readings.
+//!
+//! Every user can set rules like "temperature above 30". Rules are compared with incoming readings
+//! in the units of the user. A user is emailed when a rule starts being breached at a station, a
+//! sustained breach doesn't send more emails until the readings are back within the limit.
+//!
+//! Most functions are called from the `actix-web` framework
+use crate::haak::csrf;
+use crate::haak::database::{self, Pool};
+use crate::haak::email;
+use crate::haak::graph;
+use crate::haak::response;
+use crate::haak::sensor::Reading;
+use crate::haak::session::AuthedUser;
+use crate::haak::settings;
+use crate::haak::units;
+
+use actix_session::Session;
+use actix_web::web::{Data, Form};
+use actix_web::{HttpResponse, Result};
+
+use rand::rngs::OsRng;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+
+use std::fmt;
+
+/// Maximum number of alert rules of a user
+const MAX_RULES: usize = 20;
+
+/// Comparison of a rule
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Op {
+    Above,
+    Below,
+}
+
+impl fmt::Display for Op {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Op::Above => write!(f, "above"),
+            Op::Below => write!(f, "below"),
+        }
+    }
+}
+
+/// Alert rule of a user, the threshold is in the user's unit of the metric
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Rule {
+    pub id: String,
+    pub metric: String,
+    pub op: Op,
+    pub threshold: f64,
+}
+
+impl Rule {
+    /// Returns true if the value breaches the rule
+    ///
+    /// # Arguments
+    ///
+    /// * `value` - Value in the user's unit of the metric
+    pub fn breached(&self, value: f64) -> bool {
+        match self.op {
+            Op::Above => value > self.threshold,
+            Op::Below => value < self.threshold,
+        }
+    }
+}
+
+/// Change of the state of a rule at a station
+#[derive(Debug, PartialEq)]
+pub enum Transition {
+    /// The rule started being breached, the user is notified
+    Breached,
+    /// The readings are back within the limit
+    Recovered,
+}
+
+/// Returns the change of state of a rule, `None` if the state didn't change
+///
+/// # Arguments
+///
+/// * `was_breached` - Whether the rule was breached by the previous reading
+/// * `breached` - Whether the rule is breached by the current reading
+pub fn transition(was_breached: bool, breached: bool) -> Option<Transition> {
+    match (was_breached, breached) {
+        (false, true) => Some(Transition::Breached),
+        (true, false) => Some(Transition::Recovered),
+        _ => None,
+    }
+}
+
+/// Value of a metric in a reading, `None` for unknown metrics
+///
+/// # Arguments
+///
+/// * `reading` - Reading sent by the weather station
+/// * `metric` - Name of the metric (e.g. `temperature`)
+fn reading_value(reading: &Reading, metric: &str) -> Option<f64> {
+    match metric {
+        "temperature" => Some(reading.temperature),
+        "pressure" => Some(reading.pressure),
+        "humidity" => Some(reading.humidity),
+        _ => None,
+    }
+}
+
+/// Evaluates a reading against the rules of a single user and emails the user about new breaches
+///
+/// # Arguments
+///
+/// * `user` - Email address of the user
+/// * `reading` - Reading sent by the weather station
+/// * `redis` - Connection to database
+/// * `mailer` - Queue of the mail worker
+async fn check_user(
+    user: &str,
+    reading: &Reading,
+    redis: &Data<Pool>,
+    mailer: &email::Mailer,
+) -> Result<(), database::DbError> {
+    let rules = database::alerts_get(user, redis).await?;
+    if rules.is_empty() {
+        return Ok(());
+    }
+
+    let sett = database::settings_get(user, redis).await?;
+    let state = database::alert_state_get(user, redis).await?;
+
+    for rule in rules.iter() {
+        let value = match reading_value(reading, &rule.metric) {
+            Some(value) => units::convert(&rule.metric, value, &sett),
+            None => continue,
+        };
+
+        let key = format!("{}:{}", rule.id, reading.station);
+        match transition(state.contains(&key), rule.breached(value)) {
+            Some(Transition::Breached) => {
+                database::alert_state_set(user, &key, true, redis).await?;
+
+                let unit = units::unit(&rule.metric, &sett);
+                if let Err(e) = email::send_alert(
+                    mailer,
+                    user.to_owned(),
+                    &sett.locale,
+                    &reading.station,
+                    rule,
+                    value,
+                    unit,
+                ) {
+                    error!("Could not queue alert mail: {}", e);
+                }
+            }
+            Some(Transition::Recovered) => {
+                database::alert_state_set(user, &key, false, redis).await?;
+            }
+            None => (),
+        }
+    }
+
+    Ok(())
+}
+
+/// Evaluates a reading against the rules of every user. Errors are logged, so a failing check
+/// never rejects the reading.
+///
+/// # Arguments
+///
+/// * `reading` - Reading sent by the weather station
+/// * `redis` - Connection to database
+/// * `mailer` - Queue of the mail worker
+pub async fn check(reading: &Reading, redis: &Data<Pool>, mailer: &email::Mailer) {
+    let users = match database::alerts_users(redis).await {
+        Ok(users) => users,
+        Err(e) => {
+            error!("Could not list alert rules: {}", e);
+            return;
+        }
+    };
+
+    for user in users.iter() {
+        if let Err(e) = check_user(user, reading, redis, mailer).await {
+            error!("Could not check alert rules of {}: {}", user, e);
+        }
+    }
+}
+
+/// Creates a new 6 byte rule ID
+fn generate_id() -> String {
+    let mut id = vec![0u8; 6];
+    OsRng.fill_bytes(&mut id);
+    base64::encode_config(&id, base64::URL_SAFE)
+}
+
+/// Alert rule form data
+#[derive(Deserialize)]
+pub struct RuleForm {
+    metric: String,
+    op: Op,
+    threshold: f64,
+    csrf_token: Option<String>,
+}
+
+/// Alert rule removal form data
+#[derive(Deserialize)]
+pub struct RemoveForm {
+    id: String,
+    csrf_token: Option<String>,
+}
+
+/// Redirects to the settings page
+fn to_settings() -> HttpResponse {
+    HttpResponse::SeeOther()
+        .header(actix_web::http::header::LOCATION, "/settings")
+        .finish()
+}
+
+/// Handles POST requests to /settings/alerts. Adds an alert rule for the user.
+/// Redirects to /login if not logged in and sends 403 Forbidden if the CSRF token is invalid.
+/// Invalid rules are not saved, the settings page is shown again with 422 UnprocessableEntity.
+///
+/// # Arguments
+///
+/// * `form` - Data of the alert rule form
+/// * `user` - Logged in user
+/// * `session` - Session containing all CookieSession data
+/// * `redis` - RedisActor to access redis database
+///
+/// # Remarks
+///
+/// Should only be called from actix_web
+pub async fn alert_add(
+    form: Form<RuleForm>,
+    AuthedUser(user): AuthedUser,
+    session: Session,
+    redis: Data<Pool>,
+) -> Result<HttpResponse> {
+    let form = form.into_inner();
+
+    if !csrf::verify(&session, form.csrf_token.as_deref()) {
+        return Ok(response::invalid_csrf());
+    }
+
+    let error = if !graph::METRICS.contains(&form.metric.as_str()) || !form.threshold.is_finite() {
+        Some("Invalid alert rule, the rule was not saved")
+    } else if database::alerts_get(&user, &redis).await?.len() >= MAX_RULES {
+        Some("Too many alert rules, the rule was not saved")
+    } else {
+        None
+    };
+
+    if let Some(error) = error {
+        let view = settings::render(&user, error, &session, &redis).await?;
+
+        return Ok(HttpResponse::UnprocessableEntity()
+            .content_type("text/html")
+            .body(view));
+    }
+
+    let rule = Rule {
+        id: generate_id(),
+        metric: form.metric,
+        op: form.op,
+        threshold: form.threshold,
+    };
+    database::alerts_add(&user, &rule, &redis).await?;
+
+    Ok(to_settings())
+}
+
+/// Handles POST requests to /settings/alerts/delete. Removes an alert rule of the user.
+/// Redirects to /login if not logged in and sends 403 Forbidden if the CSRF token is invalid.
+///
+/// # Arguments
+///
+/// * `form` - Data containing the ID of the rule
+/// * `user` - Logged in user
+/// * `session` - Session containing all CookieSession data
+/// * `redis` - RedisActor to access redis database
+///
+/// # Remarks
+///
+/// Should only be called from actix_web
+pub async fn alert_delete(
+    form: Form<RemoveForm>,
+    AuthedUser(user): AuthedUser,
+    session: Session,
+    redis: Data<Pool>,
+) -> Result<HttpResponse> {
+    if !csrf::verify(&session, form.csrf_token.as_deref()) {
+        return Ok(response::invalid_csrf());
+    }
+
+    database::alerts_remove(&user, &form.id, &redis).await?;
+
+    Ok(to_settings())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn transition_only_on_state_changes() {
+        assert_eq!(transition(false, true), Some(Transition::Breached));
+        assert_eq!(transition(true, false), Some(Transition::Recovered));
+        assert_eq!(transition(false, false), None);
+        assert_eq!(transition(true, true), None);
+    }
+
+    #[test]
+    fn breach_is_reported_once_until_recovered() {
+        let rule = Rule {
+            id: String::from("rule"),
+            metric: String::from("temperature"),
+            op: Op::Above,
+            threshold: 30.0,
+        };
+
+        let mut was_breached = false;
+        let mut transitions = Vec::new();
+        for value in [25.0, 30.0, 31.0, 35.0, 32.0, 29.0, 28.0, 30.5].iter() {
+            let breached = rule.breached(*value);
+            transitions.push(transition(was_breached, breached));
+            was_breached = breached;
+        }
+
+        assert_eq!(
+            transitions,
+            vec![
+                None,
+                None,
+                Some(Transition::Breached),
+                None,
+                None,
+                Some(Transition::Recovered),
+                None,
+                Some(Transition::Breached),
+            ]
+        );
+    }
+
+    #[test]
+    fn below_rules_breach_under_the_threshold() {
+        let rule = Rule {
+            id: String::from("rule"),
+            metric: String::from("humidity"),
+            op: Op::Below,
+            threshold: 20.0,
+        };
+
+        assert!(rule.breached(19.9));
+        assert!(!rule.breached(20.0));
+        assert!(!rule.breached(60.0));
+    }
+}
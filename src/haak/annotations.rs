@@ -0,0 +1,154 @@
+//! Documentation for annotations module
+//! Includes the annotations of stations, events marked on the graph (e.g. "sensor recalibrated").
+//!
+//! Annotations are stored per station in the `annotations:<station>` sorted set, scored by their
+//! timestamp, and returned by /api/readings along with the readings of the same window.
+//!
+//! Most functions are called from the `actix-web` framework
+use crate::haak::database::{self, Pool};
+use crate::haak::graph;
+use crate::haak::response;
+use crate::haak::session::AuthedUser;
+
+use actix_web::http::StatusCode;
+use actix_web::web::{Data, Json, Path, Query};
+use actix_web::{HttpResponse, Result};
+
+use rand::rngs::OsRng;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+
+/// Maximum length of the text of an annotation in characters
+const MAX_TEXT: usize = 200;
+
+/// Annotation of a station
+#[derive(Serialize, Deserialize, Clone, Debug, Hash)]
+pub struct Annotation {
+    /// Random ID, so annotations at the same timestamp are distinct members of the sorted set
+    pub id: String,
+    pub ts: u64,
+    pub text: String,
+    /// Email address of the user who added the annotation
+    pub author: String,
+}
+
+/// JSON data of /api/annotations
+#[derive(Deserialize)]
+pub struct AnnotationData {
+    station: Option<String>,
+    ts: u64,
+    text: String,
+}
+
+/// Query data of the annotation removal
+#[derive(Deserialize)]
+pub struct StationQuery {
+    station: Option<String>,
+}
+
+/// Creates the 422 UnprocessableEntity response of an invalid station
+fn invalid_station() -> HttpResponse {
+    response::error(
+        StatusCode::UNPROCESSABLE_ENTITY,
+        "invalid_station",
+        "Invalid station",
+    )
+}
+
+/// Handles HTTP POST requests to /api/annotations
+/// Adds an annotation at `ts` to the `station` (`default` if none is given) and returns it as
+/// JSON. Sends 401 Unauthorized if not logged in and 422 UnprocessableEntity on an invalid station
+/// or an empty or too long text (more than 200 characters).
+///
+/// # Arguments
+///
+/// * `form` - JSON data containing the optional station, timestamp and text
+/// * `user` - Logged in user
+/// * `redis` - RedisActor to access redis database
+///
+/// # Remarks
+///
+/// Should only be called from actix_web
+pub async fn annotation_add(
+    form: Json<AnnotationData>,
+    AuthedUser(user): AuthedUser,
+    redis: Data<Pool>,
+) -> Result<HttpResponse> {
+    let form = form.into_inner();
+
+    let station = match graph::query_station(&form.station) {
+        Some(station) => station,
+        None => return Ok(invalid_station()),
+    };
+
+    let text = form.text.trim();
+    if text.is_empty() || text.chars().count() > MAX_TEXT {
+        return Ok(response::error(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "invalid_annotation",
+            &format!("Annotation must have 1 to {} characters", MAX_TEXT),
+        ));
+    }
+
+    let mut id = [0u8; 8];
+    OsRng.fill_bytes(&mut id);
+
+    let annotation = Annotation {
+        id: base64::encode_config(&id, base64::URL_SAFE_NO_PAD),
+        ts: form.ts,
+        text: String::from(text),
+        author: user,
+    };
+    database::annotation_add(station, &annotation, &redis).await?;
+
+    Ok(HttpResponse::Created().json(annotation))
+}
+
+/// Handles HTTP DELETE requests to /api/annotations/{ts}
+/// Removes the annotations of the logged in user at `ts` of the `station` in the query (`default`
+/// if none is given), admins remove the annotations of every user. Returns the number of removed
+/// annotations (`{"removed": 1}`). Sends 401 Unauthorized if not logged in, 404 NotFound if there
+/// is no such annotation and 422 UnprocessableEntity on an invalid station.
+///
+/// # Arguments
+///
+/// * `ts` - Timestamp of the annotation
+/// * `query` - Query containing the optional station
+/// * `user` - Logged in user
+/// * `redis` - RedisActor to access redis database
+///
+/// # Remarks
+///
+/// Should only be called from actix_web
+pub async fn annotation_delete(
+    ts: Path<u64>,
+    Query(query): Query<StationQuery>,
+    AuthedUser(user): AuthedUser,
+    redis: Data<Pool>,
+) -> Result<HttpResponse> {
+    let station = match graph::query_station(&query.station) {
+        Some(station) => station,
+        None => return Ok(invalid_station()),
+    };
+
+    let admin = database::user_is_admin(&user, &redis).await?;
+    let ts = ts.into_inner();
+
+    let mut removed = 0;
+    for annotation in database::annotations_range(station, ts, ts, &redis).await? {
+        if admin || annotation.author == user {
+            database::annotation_remove(station, &annotation, &redis).await?;
+            removed += 1;
+        }
+    }
+
+    if removed == 0 {
+        return Ok(response::error(
+            StatusCode::NOT_FOUND,
+            "unknown_annotation",
+            "No annotation of yours at this time",
+        ));
+    }
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "removed": removed })))
+}
@@ -0,0 +1,106 @@
+//! Documentation for audit module
+//! Includes the audit log of admin actions.
+//!
+//! Every successful admin action is appended to the `audit:log` list as a JSON entry, newest
+//! first. Failing to write an entry is logged but never fails the action itself.
+use crate::haak::database::{self, Pool};
+use crate::haak::response;
+use crate::haak::session::{self, AuthedUser};
+
+use actix_web::web::{Data, Query};
+use actix_web::{HttpResponse, Result};
+
+use serde::{Deserialize, Serialize};
+
+/// Default and maximum number of entries returned by /admin/audit
+const MAX_ENTRIES: usize = 1000;
+
+/// Admin action recorded in the audit log
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    Register,
+    ResendRegister,
+    DeleteUser,
+    SetRole,
+    Backup,
+    Restore,
+    TestEmail,
+}
+
+/// Entry of the audit log
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Entry {
+    /// Admin who performed the action
+    pub actor: String,
+    pub action: Action,
+    /// User the action was performed on, empty for actions on the whole database
+    pub target: String,
+    /// Details of the action, e.g. the new role
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+    pub timestamp: u64,
+}
+
+/// Appends an action to the audit log. Errors are logged, so the action succeeds regardless.
+///
+/// # Arguments
+///
+/// * `actor` - Email address of the admin
+/// * `action` - Performed action
+/// * `target` - Email address of the user the action was performed on, empty if none
+/// * `detail` - Optional details of the action
+/// * `redis` - RedisActor to access redis database
+pub async fn record(
+    actor: &str,
+    action: Action,
+    target: &str,
+    detail: Option<&str>,
+    redis: &Data<Pool>,
+) {
+    let entry = Entry {
+        actor: actor.to_owned(),
+        action,
+        target: target.to_owned(),
+        detail: detail.map(str::to_owned),
+        timestamp: session::now(),
+    };
+
+    if let Err(e) = database::audit_add(&entry, redis).await {
+        error!("Could not write audit entry {:?}: {}", entry, e);
+    }
+}
+
+/// Query data of the audit log
+#[derive(Deserialize)]
+pub struct AuditQuery {
+    limit: Option<usize>,
+}
+
+/// Handles HTTP GET requests to /admin/audit
+/// Returns the most recent `limit` (default and maximum 1000) entries of the audit log as JSON,
+/// newest first. Sends 401 Unauthorized if not logged in as admin.
+///
+/// # Arguments
+///
+/// * `query` - Query containing the optional `limit`
+/// * `user` - Logged in user
+/// * `redis` - RedisActor to access redis database
+///
+/// # Remarks
+///
+/// Should only be called from actix_web
+pub async fn audit_log(
+    Query(query): Query<AuditQuery>,
+    AuthedUser(user): AuthedUser,
+    redis: Data<Pool>,
+) -> Result<HttpResponse> {
+    // If user is not admin -> Unauthorized
+    if !database::user_is_admin(&user, &redis).await? {
+        return Ok(response::unauthorized());
+    }
+
+    let limit = query.limit.unwrap_or(MAX_ENTRIES).clamp(1, MAX_ENTRIES);
+
+    Ok(HttpResponse::Ok().json(database::audit_recent(limit, &redis).await?))
+}
@@ -2,148 +2,419 @@
 //! Includes authentication and registration.
 //!
 //! Most functions are called from the `actix-web` framework.
-use crate::haak::database;
+use crate::haak::audit;
+use crate::haak::config::Config;
+use crate::haak::csrf;
+use crate::haak::database::{self, Pool};
+use crate::haak::device;
 use crate::haak::email;
+use crate::haak::mx::MxCheck;
+use crate::haak::ratelimit;
+use crate::haak::response;
+use crate::haak::session as user_session;
+use crate::haak::session::AuthedUser;
+use crate::haak::settings::UserSettings;
+use crate::haak::totp;
+use crate::haak::webauthn;
 
-use actix::prelude::*;
-use actix_redis::RedisActor;
 use actix_session::Session;
-use actix_web::web::{Data, Json, Query};
-use actix_web::HttpResponse;
+use actix_web::error::BlockingError;
+use actix_web::http::StatusCode;
+use actix_web::web::{self, Data, Json, Query};
+use actix_web::{HttpRequest, HttpResponse, Result};
 
+use std::env;
+use std::time::{Duration, Instant};
+
+use askama::Template;
 use rand::rngs::OsRng;
 use rand::RngCore;
 use serde::{Deserialize, Serialize};
+use subtle::ConstantTimeEq;
+
+#[derive(Template)]
+#[template(path = "auth/login.html")]
+pub struct Login<'a> {
+    csrf_token: &'a str,
+    /// Whether short login codes are emailed (`LOGIN_CODE_LENGTH`)
+    login_codes: bool,
+}
 
 /// Handles HTTP GET requests to `/login`.
-/// Displays the login page, redirects to `/` if already logged in.
+/// Displays the login page with a CSRF token, redirects to `/` if already logged in.
 ///
 /// # Arguments
 ///
 /// * `session` - Session containing all CookieSession data
+/// * `config` - Server configuration, containing `LOGIN_CODE_LENGTH`
 ///
 /// # Remarks
 ///
 /// Should only be called from actix_web
-pub async fn login_get(session: Session) -> HttpResponse {
+pub async fn login_get(session: Session, config: Data<Config>) -> HttpResponse {
     match session.get::<String>("email").unwrap().is_some() {
-        true => {
-            return HttpResponse::SeeOther()
-                .header(actix_web::http::header::LOCATION, "/")
-                .finish()
+        true => HttpResponse::SeeOther()
+            .header(actix_web::http::header::LOCATION, "/")
+            .finish(),
+        false => HttpResponse::Ok().content_type("text/html").body(
+            Login {
+                csrf_token: &csrf::token(&session),
+                login_codes: config.login_code_length.is_some(),
+            }
+            .render()
+            .unwrap(),
+        ),
+    }
+}
+
+/// Role of a user, stored as the value of `user:<email>`
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    User,
+    Admin,
+}
+
+impl Role {
+    /// Value stored in the database for this role.
+    /// Regular users are stored as an empty string, as they always have been.
+    pub fn as_stored(self) -> &'static str {
+        match self {
+            Role::User => "",
+            Role::Admin => "admin",
+        }
+    }
+
+    /// Parses a role stored in the database, unknown values are regular users
+    ///
+    /// # Arguments
+    ///
+    /// * `value` - Value of `user:<email>`
+    pub fn from_stored(value: &str) -> Role {
+        match value {
+            "admin" => Role::Admin,
+            _ => Role::User,
         }
-        false => HttpResponse::Ok().body(include_str!("../../templates/auth/login.html")),
     }
 }
 
-/// Identity used in forms
+/// Identity used in forms, the password is only used for password logins
 #[derive(Deserialize)]
 pub struct Identity {
     email: String,
+    #[serde(default)]
+    password: Option<String>,
+    #[serde(default)]
+    csrf_token: Option<String>,
+    /// Whether to remember the device once the email challenge is verified
+    #[serde(default)]
+    remember: bool,
 }
 
-/// LoginChallenge to store in session (user email and challenge token)
-#[derive(Serialize, Deserialize, Debug)]
-pub struct LoginChallenge {
+/// Email change form data
+#[derive(Deserialize)]
+pub struct EmailData {
     email: String,
-    challenge: String,
+}
+
+/// Password form data
+#[derive(Deserialize)]
+pub struct PasswordData {
+    password: String,
+}
+
+/// TOTP code form data
+#[derive(Deserialize)]
+pub struct TotpCode {
+    code: String,
+}
+
+/// Short login code form data
+#[derive(Deserialize)]
+pub struct LoginCode {
+    code: String,
+    #[serde(default)]
+    csrf_token: Option<String>,
+}
+
+/// Finishes the first login step. Logs the user in, unless TOTP is enabled for the user, in which
+/// case the email is stored as `pending_totp` until the code is verified.
+/// Returns true if the user is logged in.
+///
+/// # Arguments
+///
+/// * `session` - Session containing all CookieSession data
+/// * `email` - Email address that passed the first step
+/// * `config` - Server configuration, containing the session lifetime
+/// * `redis` - RedisActor to access redis database
+async fn first_factor_passed(
+    session: &Session,
+    email: String,
+    config: &Config,
+    redis: &Data<Pool>,
+) -> Result<bool, database::DbError> {
+    if database::totp_enabled(&email, redis).await? {
+        let _ = session.set("pending_totp", email);
+        return Ok(false);
+    }
+
+    user_session::start(session, email, &config.session, redis).await?;
+    Ok(true)
+}
+
+/// Minimum time of the steps that depend on whether a user exists, so known and unknown users
+/// can't be told apart by response time
+const MIN_RESPONSE_TIME: Duration = Duration::from_millis(250);
+
+/// Waits until `MIN_RESPONSE_TIME` has passed since `started`
+///
+/// # Arguments
+///
+/// * `started` - Start of the steps that depend on whether the user exists
+async fn pad_response(started: Instant) {
+    if let Some(rest) = MIN_RESPONSE_TIME.checked_sub(started.elapsed()) {
+        actix_rt::time::delay_for(rest).await;
+    }
+}
+
+/// Returns true if password logins are enabled with `AUTH_MODE=password`
+fn password_mode() -> bool {
+    env::var("AUTH_MODE")
+        .map(|m| m == "password")
+        .unwrap_or(false)
 }
 
 /// Handles HTTP POST requests to /login.
 /// Validates email (sends 422 UnprocessableEntity if invalid), generates a challenge, stores that
-/// challenge in the CookieSession and emails the challenge to the user.
+/// challenge in the database (valid for `LOGIN_TTL_SECS`) and queues the challenge email to the
+/// user. With `LOGIN_CODE_LENGTH` set the email also has a short code for /verify_login_code.
+/// The session is marked as having a pending login for as long as the challenge is valid.
+/// Unknown users get the same response, after the same minimum time, so the response doesn't
+/// reveal whether the user exists.
+/// Sends 429 TooManyRequests if the client IP or email is rate limited.
+/// With `VALIDATE_MX=true`, also sends 422 UnprocessableEntity if the domain has no MX record.
+///
+/// When `AUTH_MODE=password` is set and a password is supplied, the password is verified instead
+/// and the user is logged in directly (sends 401 Unauthorized if invalid, or 202 Accepted if a
+/// TOTP code is required next).
+///
+/// Clients with the cookie of a remembered device of the user (see `device`) are logged in
+/// directly as well. With `remember` set, the device is remembered once the challenge is verified.
 ///
 /// # Arguments
 ///
-/// * `form` - JSON data of the login form, containing user's email
+/// * `req` - Request of the client, used for rate limiting
+/// * `form` - JSON data of the login form, containing user's email, optional password and whether
+///   to remember the device
 /// * `session` - Session containing all CookieSession data
+/// * `config` - Server configuration, containing the login expiry and cookie secret
 /// * `redis` - RedisActor to access redis database
+/// * `mailer` - Queue of the mail worker
+/// * `mx_check` - MX record check of the email domain
 ///
 /// # Remarks
 ///
 /// Should only be called from actix_web
 pub async fn login_submit(
+    req: HttpRequest,
     form: Json<Identity>,
     session: Session,
-    redis: Data<Addr<RedisActor>>,
-) -> HttpResponse {
+    config: Data<Config>,
+    redis: Data<Pool>,
+    mailer: Data<email::Mailer>,
+    mx_check: Data<MxCheck>,
+) -> Result<HttpResponse> {
     let email = form.email.clone();
 
     // If logged in -> redirect to /
     if session.get::<String>("email").unwrap().is_some() {
-        return HttpResponse::SeeOther()
+        return Ok(HttpResponse::SeeOther()
             .header(actix_web::http::header::LOCATION, "/")
-            .finish();
+            .finish());
+    }
+
+    // If CSRF token missing or mismatched -> Respond
+    if !csrf::verify(&session, form.csrf_token.as_deref()) {
+        return Ok(response::invalid_csrf());
     }
 
     // If invalid email -> Respond
     if !validator::validate_email(email.as_str()) {
-        return HttpResponse::UnprocessableEntity().body("Invalid email");
+        return Ok(response::error(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "invalid_email",
+            "Invalid email",
+        ));
     }
 
-    // If not in database (user doesnt exist) -> send check email (to prevent getting data)
-    if !database::user_exists(&email, &redis).await {
-        return HttpResponse::Ok().body("Check your mail for login code");
+    // If too many attempts -> Respond
+    if ratelimit::exceeded("login", &req, &email, &redis).await? {
+        return Ok(response::rate_limited());
     }
 
-    let challenge = generate_challenge();
+    // If remembered device -> log in without email challenge
+    if device::remembered(&req, &email, &config, &redis).await? {
+        if !first_factor_passed(&session, email, &config, &redis).await? {
+            return Ok(HttpResponse::Accepted().body("TOTP code required"));
+        }
+        return Ok(HttpResponse::Ok().body("Logged in"));
+    }
 
-    let _ = session.set(
-        "pending_login",
-        LoginChallenge {
-            email: email.clone(),
-            challenge: challenge.clone(),
-        },
-    );
+    // Unknown users skip the password hash and the challenge, pad the response to hide that
+    let started = Instant::now();
 
-    match email::send_challenge(email, challenge) {
-        Ok(_) => HttpResponse::Ok().body("Check your mail for login code"),
-        Err(_) => HttpResponse::InternalServerError().body("Could not send authentication mail"),
+    // If password login -> log in without email challenge
+    if let (true, Some(password)) = (password_mode(), &form.password) {
+        let response = if !database::verify_password(&email, password, &redis).await? {
+            response::error(
+                StatusCode::UNAUTHORIZED,
+                "invalid_credentials",
+                "Invalid email or password",
+            )
+        } else if !first_factor_passed(&session, email, &config, &redis).await? {
+            HttpResponse::Accepted().body("TOTP code required")
+        } else {
+            HttpResponse::Ok().body("Logged in")
+        };
+
+        pad_response(started).await;
+        return Ok(response);
+    }
+
+    // If the domain has no MX record -> Respond
+    if !mx_check.accepts(&email).await {
+        return Ok(response::error(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "invalid_email_domain",
+            "Email domain does not accept email",
+        ));
+    }
+
+    // Marked for unknown users as well, so /poll_login doesn't reveal whether the user exists
+    let _ = session.set("pending_login", user_session::now() + config.login_ttl_secs);
+    let _ = session.set("pending_login_email", &email);
+    let _ = session.set("remember_device", form.remember);
+
+    // If in database -> send challenge, unknown users get the same response (to prevent leaks)
+    if database::user_exists(&email, &redis).await? {
+        let challenge = generate_challenge();
+
+        database::login_add(&email, &challenge, config.login_ttl_secs, &redis).await?;
+
+        let short_code = match config.login_code_length {
+            Some(length) => {
+                let code = generate_code(length);
+                database::login_code_add(&email, &code, &challenge, config.login_ttl_secs, &redis)
+                    .await?;
+                code
+            }
+            None => String::new(),
+        };
+
+        // Respond the same as for unknown users, so failures don't reveal the user exists
+        let locale = database::settings_get(&email, &redis).await?.locale;
+        if let Err(e) = email::send_challenge(&mailer, email, &locale, challenge, &short_code) {
+            error!("Could not queue login mail: {}", e);
+        }
     }
+
+    pad_response(started).await;
+    Ok(HttpResponse::Ok().body("Check your mail for login code"))
 }
 
 /// Handles HTTP POST request to /register
-/// Sends a registration email to a new user, with verification link
+/// Sends a registration email to a new user, with verification link.
+/// Sends 422 UnprocessableEntity if the email is already registered. Unlike /login this reveals
+/// whether the user exists, on purpose: only admins can register users and they need to know.
+/// The response time doesn't reveal it, both outcomes take the same minimum time.
+/// Sends 429 TooManyRequests if the client IP or email is rate limited.
+/// With `VALIDATE_MX=true`, also sends 422 UnprocessableEntity if the domain has no MX record.
 ///
 /// # Arguments
 ///
+/// * `req` - Request of the client, used for rate limiting
 /// * `form` - JSON data of the login form, containing user's email
-/// * `session` - Session containing all CookieSession data
+/// * `user` - Logged in user
+/// * `config` - Server configuration, containing the registration expiry
 /// * `redis` - RedisActor to access redis database
+/// * `mailer` - Queue of the mail worker
+/// * `mx_check` - MX record check of the email domain
 ///
 /// # Remarks
 ///
 /// Should only be called from actix_web
 pub async fn register(
+    req: HttpRequest,
     form: Json<Identity>,
-    session: Session,
-    redis: Data<Addr<RedisActor>>,
-) -> HttpResponse {
-    let user = session.get::<String>("email").unwrap();
+    AuthedUser(user): AuthedUser,
+    config: Data<Config>,
+    redis: Data<Pool>,
+    mailer: Data<email::Mailer>,
+    mx_check: Data<MxCheck>,
+) -> Result<HttpResponse> {
     let email = form.email.clone();
 
-    // If user is not logged in or not admin -> Unauthorized
-    if user.is_none() || !database::user_is_admin(&user.unwrap(), &redis).await {
-        return HttpResponse::Unauthorized().finish();
+    // If user is not admin -> Unauthorized
+    if !database::user_is_admin(&user, &redis).await? {
+        return Ok(response::unauthorized());
     }
 
     // If invalid email -> Respond
     if !validator::validate_email(email.as_str()) {
-        return HttpResponse::UnprocessableEntity().body("Invalid email");
+        return Ok(response::error(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "invalid_email",
+            "Invalid email",
+        ));
     }
 
-    if database::user_exists(&email, &redis).await {
-        return HttpResponse::UnprocessableEntity().body("Email already registered");
+    // If too many attempts -> Respond
+    if ratelimit::exceeded("register", &req, &email, &redis).await? {
+        return Ok(response::rate_limited());
     }
 
-    let challenge = generate_challenge();
+    // If the domain has no MX record -> Respond
+    if !mx_check.accepts(&email).await {
+        return Ok(response::error(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "invalid_email_domain",
+            "Email domain does not accept email",
+        ));
+    }
 
-    database::register_email(&email, &challenge, &redis).await;
+    // Only new users get the challenge, pad the response to hide that
+    let started = Instant::now();
 
-    match email::send_register(email, challenge) {
-        Ok(_) => HttpResponse::Ok().body("Check your mail for login code"),
-        Err(_) => HttpResponse::InternalServerError().body("Could not send authentication mail"),
+    if database::user_exists(&email, &redis).await? {
+        pad_response(started).await;
+        return Ok(response::error(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "email_registered",
+            "Email already registered",
+        ));
+    }
+
+    // A new registration replaces the pending one, only the latest link works
+    let mut challenge = generate_challenge();
+    while !database::register_email(&email, &challenge, config.register_ttl_secs, &redis).await? {
+        challenge = generate_challenge();
     }
+
+    audit::record(&user, audit::Action::Register, &email, None, &redis).await;
+
+    // The user has no settings yet, so the email is in the default locale
+    let locale = UserSettings::from_env().locale;
+    let sent = email::send_register(&mailer, email, &locale, challenge);
+
+    pad_response(started).await;
+    Ok(match sent {
+        Ok(_) => HttpResponse::Ok().body("Check your mail for login code"),
+        Err(_) => response::error(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "mail_failed",
+            "Could not send authentication mail",
+        ),
+    })
 }
 
 /// Handles HTTP GET request to /logout
@@ -152,23 +423,74 @@ pub async fn register(
 /// # Arguments
 ///
 /// * `session` - Session containing all CookieSession data
+/// * `redis` - RedisActor to access redis database
 ///
 /// # Remarks
 ///
 /// Should only be called from actix_web
-pub async fn logout(session: Session) -> HttpResponse {
+pub async fn logout(session: Session, redis: Data<Pool>) -> Result<HttpResponse> {
     let user = session.get::<String>("email").unwrap();
 
-    if user.is_some() {
+    if let Some(user) = user {
+        if let Some(sid) = session.get::<String>("sid").unwrap() {
+            database::session_remove(&user, &sid, &redis).await?;
+        }
         session.purge();
     }
 
-    HttpResponse::SeeOther()
+    Ok(HttpResponse::SeeOther()
+        .header(actix_web::http::header::LOCATION, "/login")
+        .finish())
+}
+
+/// Handles HTTP POST requests to /logout_all
+/// Logs the user out of all sessions on every device, forgets all remembered devices and redirects
+/// them to /login. Sends 401 Unauthorized if not logged in.
+///
+/// # Arguments
+///
+/// * `user` - Logged in user
+/// * `session` - Session containing all CookieSession data
+/// * `redis` - RedisActor to access redis database
+///
+/// # Remarks
+///
+/// Should only be called from actix_web
+pub async fn logout_all(
+    AuthedUser(user): AuthedUser,
+    session: Session,
+    redis: Data<Pool>,
+) -> Result<HttpResponse> {
+    database::sessions_clear(&user, &redis).await?;
+    database::devices_clear(&user, &redis).await?;
+    session.purge();
+
+    Ok(HttpResponse::SeeOther()
         .header(actix_web::http::header::LOCATION, "/login")
-        .finish()
+        .cookie(device::forget())
+        .finish())
+}
+
+/// Login state of a session as reported by /poll_login
+#[derive(Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum LoginState {
+    /// The session is logged in
+    LoggedIn,
+    /// A challenge was sent and hasn't expired yet
+    Pending,
+    /// No login in progress
+    None,
+}
+
+/// Response of /poll_login
+#[derive(Serialize)]
+pub struct LoginStatus {
+    state: LoginState,
 }
 
-/// Handles HTTP GET requests to /poll_login. Returns 200 OK if logged in and otherwise 406
+/// Handles HTTP GET requests to /poll_login. Returns the login state of the session as JSON
+/// (`{"state":"logged_in|pending|none"}`), with 200 OK if logged in and otherwise 406
 /// NotAcceptable
 ///
 /// # Arguments
@@ -179,9 +501,22 @@ pub async fn logout(session: Session) -> HttpResponse {
 ///
 /// Should only be called from actix_web
 pub async fn poll_login(session: Session) -> HttpResponse {
-    match session.get::<String>("email").unwrap().is_some() {
-        true => HttpResponse::Ok().body(""),
-        false => HttpResponse::NotAcceptable().body(""),
+    let pending = session.get::<u64>("pending_login").unwrap_or(None);
+
+    let state = if session.get::<String>("email").unwrap().is_some() {
+        LoginState::LoggedIn
+    } else if pending.is_some_and(|expires| expires > user_session::now()) {
+        LoginState::Pending
+    } else {
+        if pending.is_some() {
+            session.remove("pending_login");
+        }
+        LoginState::None
+    };
+
+    match state == LoginState::LoggedIn {
+        true => HttpResponse::Ok().json(LoginStatus { state }),
+        false => HttpResponse::NotAcceptable().json(LoginStatus { state }),
     }
 }
 
@@ -192,6 +527,33 @@ fn generate_challenge() -> String {
     base64::encode_config(&challenge, base64::URL_SAFE)
 }
 
+/// Characters of short login codes, without the easily confused 0, 1, I and O
+const CODE_ALPHABET: &[u8] = b"23456789ABCDEFGHJKLMNPQRSTUVWXYZ";
+
+/// Creates a new short login code, 5 bits of entropy per character
+///
+/// # Arguments
+///
+/// * `length` - Number of characters
+fn generate_code(length: usize) -> String {
+    let mut code = vec![0u8; length];
+    OsRng.fill_bytes(&mut code);
+    code.iter()
+        .map(|byte| CODE_ALPHABET[*byte as usize % CODE_ALPHABET.len()] as char)
+        .collect()
+}
+
+/// Compares two tokens in constant time, so the comparison doesn't leak how many leading bytes
+/// match. Returns true if both tokens are equal.
+///
+/// # Arguments
+///
+/// * `a` - Token to compare
+/// * `b` - Token to compare against
+pub fn tokens_equal(a: &str, b: &str) -> bool {
+    a.as_bytes().ct_eq(b.as_bytes()).into()
+}
+
 /// Query data of verify_login call (remaps ?c -> challenge)
 #[derive(Deserialize)]
 pub struct VerifyQuery {
@@ -200,36 +562,177 @@ pub struct VerifyQuery {
 }
 
 /// Handles HTTP GET requests to /verify_login
+/// Logs the user in if the challenge is pending in the database, or redirects to /verify_totp if
+/// the user has TOTP enabled. The challenge is removed afterwards so it can only be used once.
+/// If the login asked to remember the device, the device token cookie is set as well.
+/// The challenge is looked up by key, so no comparison against the submitted token happens in this
+/// handler.
+///
+/// Failed verifications are counted per client IP. Once the limit is reached the pending login of
+/// the session (its links and short code) is removed, the session is purged and 429
+/// TooManyRequests is sent, so a fresh login has to be requested.
 ///
 /// # Arguments
 ///
+/// * `req` - Request of the client, used for counting failed verifications
 /// * `query` - Query containing the challenge token
 /// * `session` - Session containing all CookieSession data
+/// * `config` - Server configuration, containing the cookie secret and `REMEMBER_DEVICE_DAYS`
+/// * `redis` - RedisActor to access redis database
 ///
 /// # Remarks
 ///
 /// Should only be called from actix_web
-pub async fn verify_login(Query(query): Query<VerifyQuery>, session: Session) -> HttpResponse {
-    let pending_login: Option<LoginChallenge> = session
-        .get::<LoginChallenge>("pending_login")
-        .unwrap_or(None);
-
-    let login_challenge = match pending_login {
-        Some(lc) => lc,
-        None => {
-            return HttpResponse::SeeOther()
-                .header(actix_web::http::header::LOCATION, "/login")
-                .finish()
+pub async fn verify_login(
+    req: HttpRequest,
+    Query(query): Query<VerifyQuery>,
+    session: Session,
+    config: Data<Config>,
+    redis: Data<Pool>,
+) -> Result<HttpResponse> {
+    // If too many failed verifications -> force a fresh login
+    if ratelimit::failures_exceeded("verify", &req, &redis).await? {
+        if let Some(email) = session.get::<String>("pending_login_email").unwrap_or(None) {
+            database::login_purge(&email, &redis).await?;
         }
+        session.purge();
+        return Ok(HttpResponse::TooManyRequests()
+            .body("Too many attempts, request a new login at /login"));
+    }
+
+    Ok(
+        match database::login_exists(&query.challenge, &redis).await? {
+            Some(email) => {
+                database::login_remove(&query.challenge, &redis).await?;
+                database::login_code_remove(&email, &redis).await?;
+                session.remove("pending_login_email");
+
+                let remember = session.get::<bool>("remember_device").unwrap_or(None);
+                session.remove("remember_device");
+                let cookie = match remember {
+                    Some(true) => Some(device::remember(&email, &config, &redis).await?),
+                    _ => None,
+                };
+
+                let logged_in = first_factor_passed(&session, email, &config, &redis).await?;
+
+                let mut response = match logged_in {
+                    true => HttpResponse::Ok(),
+                    false => HttpResponse::SeeOther(),
+                };
+                if let Some(cookie) = cookie {
+                    response.cookie(cookie);
+                }
+
+                match logged_in {
+                    true => response.body(include_str!("../../templates/auth/verified.html")),
+                    false => response
+                        .header(actix_web::http::header::LOCATION, "/verify_totp")
+                        .finish(),
+                }
+            }
+            None => {
+                ratelimit::record_failure("verify", &req, &redis).await?;
+
+                HttpResponse::Unauthorized()
+                    .body(include_str!("../../templates/auth/invalid_token.html"))
+            }
+        },
+    )
+}
+
+/// Handles HTTP POST requests to /verify_login_code
+/// Logs the user in with the short login code of the pending login of the session, like
+/// /verify_login does with the link (sends 202 Accepted if a TOTP code is required next). The code
+/// and the link are removed afterwards so they can only be used once.
+///
+/// Short codes are easier to guess than the link, so every attempt counts for the client IP and the
+/// email of the pending login, not just failures. Once either is rate limited the code and the
+/// links are removed and 429 TooManyRequests is sent, so a fresh login has to be requested.
+/// Sends 404 NotFound if short codes are disabled and 401 Unauthorized on a wrong code or without a
+/// pending login.
+///
+/// # Arguments
+///
+/// * `req` - Request of the client, used for rate limiting
+/// * `form` - JSON data containing the code and the CSRF token
+/// * `session` - Session containing all CookieSession data
+/// * `config` - Server configuration, containing the cookie secret and `LOGIN_CODE_LENGTH`
+/// * `redis` - RedisActor to access redis database
+///
+/// # Remarks
+///
+/// Should only be called from actix_web
+pub async fn verify_login_code(
+    req: HttpRequest,
+    form: Json<LoginCode>,
+    session: Session,
+    config: Data<Config>,
+    redis: Data<Pool>,
+) -> Result<HttpResponse> {
+    if config.login_code_length.is_none() {
+        return Ok(response::error(
+            StatusCode::NOT_FOUND,
+            "login_codes_disabled",
+            "Login codes are disabled",
+        ));
+    }
+
+    // If CSRF token missing or mismatched -> Respond
+    if !csrf::verify(&session, form.csrf_token.as_deref()) {
+        return Ok(response::invalid_csrf());
+    }
+
+    let invalid = || {
+        response::error(
+            StatusCode::UNAUTHORIZED,
+            "invalid_code",
+            "Invalid or expired code",
+        )
     };
 
-    if login_challenge.challenge == query.challenge {
-        let _ = session.set("email", login_challenge.email);
+    let email = match session.get::<String>("pending_login_email").unwrap_or(None) {
+        Some(email) => email,
+        None => return Ok(invalid()),
+    };
 
-        HttpResponse::Ok().body(include_str!("../../templates/auth/verified.html"))
-    } else {
-        HttpResponse::Unauthorized().body(include_str!("../../templates/auth/invalid_token.html"))
+    // If too many attempts -> invalidate the code and links, forcing a fresh login
+    if ratelimit::exceeded("login_code", &req, &email, &redis).await? {
+        database::login_purge(&email, &redis).await?;
+        return Ok(response::rate_limited());
+    }
+
+    let code = form.code.trim().to_uppercase();
+    let challenge = match database::login_code_get(&email, &redis).await? {
+        Some((stored, challenge)) if tokens_equal(&code, &stored) => challenge,
+        _ => return Ok(invalid()),
+    };
+
+    database::login_code_remove(&email, &redis).await?;
+    database::login_remove(&challenge, &redis).await?;
+    session.remove("pending_login_email");
+
+    let remember = session.get::<bool>("remember_device").unwrap_or(None);
+    session.remove("remember_device");
+    let cookie = match remember {
+        Some(true) => Some(device::remember(&email, &config, &redis).await?),
+        _ => None,
+    };
+
+    let logged_in = first_factor_passed(&session, email, &config, &redis).await?;
+
+    let mut response = match logged_in {
+        true => HttpResponse::Ok(),
+        false => HttpResponse::Accepted(),
+    };
+    if let Some(cookie) = cookie {
+        response.cookie(cookie);
     }
+
+    Ok(match logged_in {
+        true => response.body("Logged in"),
+        false => response.body("TOTP code required"),
+    })
 }
 
 /// Handles HTTP GET requests to /verify_register
@@ -245,22 +748,1002 @@ pub async fn verify_login(Query(query): Query<VerifyQuery>, session: Session) ->
 /// Should only be called from actix_web
 pub async fn verify_register(
     Query(query): Query<VerifyQuery>,
-    redis: Data<Addr<RedisActor>>,
-) -> HttpResponse {
-    let email = database::register_exists(&query.challenge, &redis).await;
+    redis: Data<Pool>,
+) -> Result<HttpResponse> {
+    Ok(
+        match database::register_exists(&query.challenge, &redis).await? {
+            Some(e) => {
+                database::user_add(&e, &redis).await?;
+                database::register_remove(&e, &query.challenge, &redis).await?;
+                HttpResponse::Ok().body(include_str!("../../templates/auth/registered.html"))
+            }
+            None => HttpResponse::Unauthorized()
+                .body(include_str!("../../templates/auth/invalid_token.html")),
+        },
+    )
+}
 
-    if email.is_none() {
-        return HttpResponse::Unauthorized()
-            .body(include_str!("../../templates/auth/invalid_token.html"));
+/// Handles HTTP POST requests to /admin/resend_register
+/// Sends the registration email of a pending registration again and resets its expiry. Sends 401
+/// Unauthorized if not logged in as admin and 404 NotFound if there is no pending registration for
+/// the email.
+/// Sends 429 TooManyRequests if the admin is rate limited.
+///
+/// # Arguments
+///
+/// * `req` - Request of the client, used for rate limiting
+/// * `form` - JSON data containing the email of the pending registration
+/// * `user` - Logged in user
+/// * `config` - Server configuration, containing the registration expiry
+/// * `redis` - RedisActor to access redis database
+/// * `mailer` - Queue of the mail worker
+///
+/// # Remarks
+///
+/// Should only be called from actix_web
+pub async fn resend_register(
+    req: HttpRequest,
+    form: Json<Identity>,
+    AuthedUser(user): AuthedUser,
+    config: Data<Config>,
+    redis: Data<Pool>,
+    mailer: Data<email::Mailer>,
+) -> Result<HttpResponse> {
+    // If user is not admin -> Unauthorized
+    if !database::user_is_admin(&user, &redis).await? {
+        return Ok(response::unauthorized());
+    }
+
+    // If too many admin actions -> Respond
+    if ratelimit::exceeded("admin", &req, &user, &redis).await? {
+        return Ok(response::rate_limited());
     }
 
-    match email {
-        Some(e) => {
-            database::user_add(&e, &redis).await;
-            database::register_remove(&query.challenge, &redis).await;
-            HttpResponse::Ok().body(include_str!("../../templates/auth/registered.html"))
+    let email = form.email.clone();
+
+    let challenge = match database::register_pending(&email, &redis).await? {
+        Some(challenge) => challenge,
+        None => {
+            return Ok(response::error(
+                StatusCode::NOT_FOUND,
+                "no_pending_registration",
+                "No pending registration",
+            ))
         }
-        None => HttpResponse::Unauthorized()
-            .body(include_str!("../../templates/auth/invalid_token.html")),
+    };
+
+    database::register_refresh(&email, &challenge, config.register_ttl_secs, &redis).await?;
+
+    audit::record(&user, audit::Action::ResendRegister, &email, None, &redis).await;
+
+    // The user has no settings yet, so the email is in the default locale
+    let locale = UserSettings::from_env().locale;
+    Ok(
+        match email::send_register(&mailer, email, &locale, challenge) {
+            Ok(_) => HttpResponse::Ok().body("Registration email sent"),
+            Err(_) => response::error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "mail_failed",
+                "Could not send registration mail",
+            ),
+        },
+    )
+}
+
+/// Handles HTTP POST requests to /admin/test_email
+/// Sends a test email to the given address through the configured mail transport, to check mail
+/// delivery. Sends 401 Unauthorized if not logged in as admin, 422 UnprocessableEntity on an
+/// invalid email and 502 BadGateway with the error of the transport if sending failed.
+/// Sends 429 TooManyRequests if the admin is rate limited.
+///
+/// # Arguments
+///
+/// * `req` - Request of the client, used for rate limiting
+/// * `form` - JSON data containing the email of the recipient
+/// * `user` - Logged in user
+/// * `redis` - RedisActor to access redis database
+/// * `mailer` - Queue of the mail worker
+///
+/// # Remarks
+///
+/// Should only be called from actix_web
+pub async fn test_email(
+    req: HttpRequest,
+    form: Json<Identity>,
+    AuthedUser(user): AuthedUser,
+    redis: Data<Pool>,
+    mailer: Data<email::Mailer>,
+) -> Result<HttpResponse> {
+    // If user is not admin -> Unauthorized
+    if !database::user_is_admin(&user, &redis).await? {
+        return Ok(response::unauthorized());
+    }
+
+    // If too many admin actions -> Respond
+    if ratelimit::exceeded("admin", &req, &user, &redis).await? {
+        return Ok(response::rate_limited());
+    }
+
+    let email = form.email.clone();
+
+    // If invalid email -> Respond
+    if !validator::validate_email(email.as_str()) {
+        return Ok(response::error(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "invalid_email",
+            "Invalid email",
+        ));
+    }
+
+    audit::record(&user, audit::Action::TestEmail, &email, None, &redis).await;
+
+    let mailer = mailer.get_ref().clone();
+    Ok(
+        match web::block(move || email::send_test(&mailer, email)).await {
+            Ok(_) => HttpResponse::Ok().body("Test email sent"),
+            Err(BlockingError::Error(e)) => {
+                response::error(StatusCode::BAD_GATEWAY, "mail_failed", &e.to_string())
+            }
+            Err(BlockingError::Canceled) => response::error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "mail_failed",
+                "Sending the test email was canceled",
+            ),
+        },
+    )
+}
+
+/// Handles HTTP POST requests to /admin/delete_user
+/// Removes a user and all of their settings. Sends 401 Unauthorized if not logged in as admin and
+/// 404 NotFound if the user doesn't exist.
+/// Sends 429 TooManyRequests if the admin is rate limited.
+///
+/// # Arguments
+///
+/// * `req` - Request of the client, used for rate limiting
+/// * `form` - JSON data containing the email of the user to delete
+/// * `user` - Logged in user
+/// * `redis` - RedisActor to access redis database
+///
+/// # Remarks
+///
+/// Should only be called from actix_web
+pub async fn delete_user(
+    req: HttpRequest,
+    form: Json<Identity>,
+    AuthedUser(user): AuthedUser,
+    redis: Data<Pool>,
+) -> Result<HttpResponse> {
+    // If user is not admin -> Unauthorized
+    if !database::user_is_admin(&user, &redis).await? {
+        return Ok(response::unauthorized());
+    }
+
+    // If too many admin actions -> Respond
+    if ratelimit::exceeded("admin", &req, &user, &redis).await? {
+        return Ok(response::rate_limited());
+    }
+
+    if !database::user_exists(&form.email, &redis).await? {
+        return Ok(response::error(
+            StatusCode::NOT_FOUND,
+            "user_not_found",
+            "User does not exist",
+        ));
+    }
+
+    database::user_delete(&form.email, &redis).await?;
+    audit::record(&user, audit::Action::DeleteUser, &form.email, None, &redis).await;
+
+    Ok(HttpResponse::Ok().body("User deleted"))
+}
+
+/// Profile of the logged in user, as returned by /api/me
+#[derive(Serialize)]
+pub struct Profile {
+    email: String,
+    is_admin: bool,
+}
+
+/// Handles HTTP GET requests to /api/me
+/// Returns the email address of the logged in user and whether they are admin as JSON
+/// (`{"email", "is_admin"}`). Sends 401 Unauthorized if not logged in.
+///
+/// # Arguments
+///
+/// * `user` - Logged in user
+/// * `redis` - RedisActor to access redis database
+///
+/// # Remarks
+///
+/// Should only be called from actix_web
+pub async fn me(AuthedUser(user): AuthedUser, redis: Data<Pool>) -> Result<HttpResponse> {
+    let is_admin = database::user_is_admin(&user, &redis).await?;
+
+    Ok(HttpResponse::Ok().json(Profile {
+        email: user,
+        is_admin,
+    }))
+}
+
+/// User as listed on the admin pages
+#[derive(Serialize)]
+pub struct UserInfo {
+    email: String,
+    admin: bool,
+}
+
+/// Handles HTTP GET requests to /admin/users
+/// Returns all users and whether they are admin as JSON. Sends 401 Unauthorized if not logged in
+/// as admin.
+///
+/// # Arguments
+///
+/// * `user` - Logged in user
+/// * `redis` - RedisActor to access redis database
+///
+/// # Remarks
+///
+/// Should only be called from actix_web
+pub async fn list_users(AuthedUser(user): AuthedUser, redis: Data<Pool>) -> Result<HttpResponse> {
+    // If user is not admin -> Unauthorized
+    if !database::user_is_admin(&user, &redis).await? {
+        return Ok(response::unauthorized());
+    }
+
+    let mut users = Vec::new();
+    for email in database::list_users(&redis).await? {
+        let admin = database::user_is_admin(&email, &redis).await?;
+        users.push(UserInfo { email, admin });
+    }
+
+    Ok(HttpResponse::Ok().json(users))
+}
+
+/// Role form data
+#[derive(Deserialize)]
+pub struct RoleData {
+    email: String,
+    role: Role,
+}
+
+/// Handles HTTP POST requests to /admin/set_role
+/// Promotes a user to admin or demotes them to user. Sends 401 Unauthorized if not logged in as
+/// admin, 404 NotFound if the user doesn't exist and 409 Conflict when demoting the last admin.
+/// Sends 429 TooManyRequests if the admin is rate limited.
+///
+/// # Arguments
+///
+/// * `req` - Request of the client, used for rate limiting
+/// * `form` - JSON data containing the email of the user and the new role (`user` or `admin`)
+/// * `user` - Logged in user
+/// * `redis` - RedisActor to access redis database
+///
+/// # Remarks
+///
+/// Should only be called from actix_web
+pub async fn set_role(
+    req: HttpRequest,
+    form: Json<RoleData>,
+    AuthedUser(user): AuthedUser,
+    redis: Data<Pool>,
+) -> Result<HttpResponse> {
+    // If user is not admin -> Unauthorized
+    if !database::user_is_admin(&user, &redis).await? {
+        return Ok(response::unauthorized());
+    }
+
+    // If too many admin actions -> Respond
+    if ratelimit::exceeded("admin", &req, &user, &redis).await? {
+        return Ok(response::rate_limited());
+    }
+
+    let current = match database::user_role(&form.email, &redis).await? {
+        Some(role) => role,
+        None => {
+            return Ok(response::error(
+                StatusCode::NOT_FOUND,
+                "user_not_found",
+                "User does not exist",
+            ))
+        }
+    };
+
+    // Demoting the last admin would lock everyone out of the admin pages
+    if current == Role::Admin && form.role == Role::User {
+        let mut admins = 0;
+        for email in database::list_users(&redis).await? {
+            if database::user_is_admin(&email, &redis).await? {
+                admins += 1;
+            }
+        }
+
+        if admins <= 1 {
+            return Ok(response::error(
+                StatusCode::CONFLICT,
+                "last_admin",
+                "Cannot demote the last admin",
+            ));
+        }
+    }
+
+    database::set_role(&form.email, form.role, &redis).await?;
+    let role = match form.role {
+        Role::User => "user",
+        Role::Admin => "admin",
+    };
+    audit::record(
+        &user,
+        audit::Action::SetRole,
+        &form.email,
+        Some(role),
+        &redis,
+    )
+    .await;
+
+    Ok(HttpResponse::Ok().body("Role set"))
+}
+
+/// Handles HTTP POST requests to /settings/password
+/// Sets the password of the logged in user, used for password logins (`AUTH_MODE=password`).
+/// Sends 401 Unauthorized if not logged in and 422 UnprocessableEntity if the password is shorter
+/// than 8 characters.
+///
+/// # Arguments
+///
+/// * `form` - JSON data containing the new password
+/// * `user` - Logged in user
+/// * `redis` - RedisActor to access redis database
+///
+/// # Remarks
+///
+/// Should only be called from actix_web
+pub async fn set_password(
+    form: Json<PasswordData>,
+    AuthedUser(user): AuthedUser,
+    redis: Data<Pool>,
+) -> Result<HttpResponse> {
+    if form.password.chars().count() < 8 {
+        return Ok(response::error(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "password_too_short",
+            "Password too short",
+        ));
+    }
+
+    database::set_password(&user, &form.password, &redis).await?;
+
+    Ok(HttpResponse::Ok().body("Password set"))
+}
+
+/// Handles HTTP POST requests to /settings/change_email
+/// Sends a challenge to the new email address of the logged in user, the current address stays
+/// active until the change is verified. Sends 401 Unauthorized if not logged in, 422
+/// UnprocessableEntity if the email is invalid, 409 Conflict if the email is already in use and
+/// 429 TooManyRequests if rate limited.
+///
+/// # Arguments
+///
+/// * `req` - Request of the client, used for rate limiting
+/// * `form` - JSON data containing the new email
+/// * `user` - Logged in user
+/// * `config` - Server configuration, containing `EMAIL_CHANGE_TTL_SECS`
+/// * `redis` - RedisActor to access redis database
+/// * `mailer` - Queue of the mail worker
+///
+/// # Remarks
+///
+/// Should only be called from actix_web
+pub async fn change_email(
+    req: HttpRequest,
+    form: Json<EmailData>,
+    AuthedUser(user): AuthedUser,
+    config: Data<Config>,
+    redis: Data<Pool>,
+    mailer: Data<email::Mailer>,
+) -> Result<HttpResponse> {
+    let email = form.email.clone();
+
+    if !validator::validate_email(email.as_str()) {
+        return Ok(response::error(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "invalid_email",
+            "Invalid email",
+        ));
+    }
+
+    if ratelimit::exceeded("change_email", &req, &user, &redis).await? {
+        return Ok(response::rate_limited());
+    }
+
+    if database::user_exists(&email, &redis).await? {
+        return Ok(response::error(
+            StatusCode::CONFLICT,
+            "email_in_use",
+            "Email already in use",
+        ));
+    }
+
+    let challenge = generate_challenge();
+    database::email_change_add(
+        &user,
+        &email,
+        &challenge,
+        config.email_change_ttl_secs,
+        &redis,
+    )
+    .await?;
+
+    let locale = database::settings_get(&user, &redis).await?.locale;
+    Ok(
+        match email::send_email_change(&mailer, email, &locale, challenge) {
+            Ok(_) => HttpResponse::Ok().body("Check your mail to confirm the new address"),
+            Err(_) => response::error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "mail_failed",
+                "Could not send authentication mail",
+            ),
+        },
+    )
+}
+
+/// Handles HTTP GET requests to /verify_email_change
+/// Moves the user to the new email address if the challenge is pending in the database and
+/// updates the session if it belongs to the user. Sends 409 Conflict if the new address was taken
+/// in the meantime.
+///
+/// # Arguments
+///
+/// * `query` - Query containing the challenge token
+/// * `session` - Session containing all CookieSession data
+/// * `redis` - RedisActor to access redis database
+///
+/// # Remarks
+///
+/// Should only be called from actix_web
+pub async fn verify_email_change(
+    Query(query): Query<VerifyQuery>,
+    session: Session,
+    redis: Data<Pool>,
+) -> Result<HttpResponse> {
+    let (old, new) = match database::email_change_get(&query.challenge, &redis).await? {
+        Some(change) => change,
+        None => {
+            return Ok(HttpResponse::Unauthorized()
+                .body(include_str!("../../templates/auth/invalid_token.html")))
+        }
+    };
+
+    database::email_change_remove(&query.challenge, &redis).await?;
+
+    if database::user_exists(&new, &redis).await? {
+        return Ok(HttpResponse::Conflict().body("Email already in use"));
+    }
+
+    database::user_rename(&old, &new, &redis).await?;
+
+    if session.get::<String>("email").unwrap() == Some(old) {
+        let _ = session.set("email", new);
+    }
+
+    Ok(HttpResponse::Ok().body(include_str!("../../templates/auth/email_changed.html")))
+}
+
+/// Handles HTTP GET requests to /verify_totp
+/// Displays the TOTP code page, redirects to /login if no login is waiting for a code.
+///
+/// # Arguments
+///
+/// * `session` - Session containing all CookieSession data
+///
+/// # Remarks
+///
+/// Should only be called from actix_web
+pub async fn totp_get(session: Session) -> HttpResponse {
+    match session.get::<String>("pending_totp").unwrap_or(None) {
+        Some(_) => HttpResponse::Ok().body(include_str!("../../templates/auth/totp.html")),
+        None => HttpResponse::SeeOther()
+            .header(actix_web::http::header::LOCATION, "/login")
+            .finish(),
+    }
+}
+
+/// Handles HTTP POST requests to /verify_totp
+/// Verifies the TOTP code of a pending login and logs the user in. Sends 401 Unauthorized if the
+/// code is invalid or no login is pending and 429 TooManyRequests if rate limited.
+///
+/// # Arguments
+///
+/// * `req` - Request of the client, used for rate limiting
+/// * `form` - JSON data containing the TOTP code
+/// * `session` - Session containing all CookieSession data
+/// * `config` - Server configuration, containing the session lifetime
+/// * `redis` - RedisActor to access redis database
+///
+/// # Remarks
+///
+/// Should only be called from actix_web
+pub async fn totp_submit(
+    req: HttpRequest,
+    form: Json<TotpCode>,
+    session: Session,
+    config: Data<Config>,
+    redis: Data<Pool>,
+) -> Result<HttpResponse> {
+    let email = match session.get::<String>("pending_totp").unwrap_or(None) {
+        Some(email) => email,
+        None => return Ok(response::unauthorized()),
+    };
+
+    if ratelimit::exceeded("totp", &req, &email, &redis).await? {
+        return Ok(response::rate_limited());
+    }
+
+    let valid = match database::get_totp_secret(&email, &redis).await? {
+        Some(secret) => totp::verify(&secret, &form.code),
+        None => false,
+    };
+
+    if !valid {
+        return Ok(response::error(
+            StatusCode::UNAUTHORIZED,
+            "invalid_code",
+            "Invalid code",
+        ));
+    }
+
+    session.remove("pending_totp");
+    user_session::start(&session, email, &config.session, &redis).await?;
+
+    Ok(HttpResponse::Ok().body("Logged in"))
+}
+
+/// Handles HTTP POST requests to /totp/enroll
+/// Generates a new TOTP secret for the logged in user and returns the `otpauth://` URI as JSON.
+/// TOTP is only enabled after a code is confirmed at /totp/enable. Sends 401 Unauthorized if not
+/// logged in and 409 Conflict if TOTP is already enabled.
+///
+/// # Arguments
+///
+/// * `user` - Logged in user
+/// * `redis` - RedisActor to access redis database
+///
+/// # Remarks
+///
+/// Should only be called from actix_web
+pub async fn totp_enroll(AuthedUser(user): AuthedUser, redis: Data<Pool>) -> Result<HttpResponse> {
+    if database::totp_enabled(&user, &redis).await? {
+        return Ok(response::error(
+            StatusCode::CONFLICT,
+            "totp_enabled",
+            "TOTP already enabled",
+        ));
+    }
+
+    let secret = totp::generate_secret();
+    database::set_totp_secret(&user, &secret, &redis).await?;
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "uri": totp::uri(&user, &secret),
+        "secret": secret,
+    })))
+}
+
+/// Handles HTTP POST requests to /totp/enable
+/// Enables TOTP for the logged in user once a code for the enrolled secret is confirmed. Sends 401
+/// Unauthorized if not logged in and 422 UnprocessableEntity if the code is invalid.
+///
+/// # Arguments
+///
+/// * `form` - JSON data containing the TOTP code
+/// * `user` - Logged in user
+/// * `redis` - RedisActor to access redis database
+///
+/// # Remarks
+///
+/// Should only be called from actix_web
+pub async fn totp_enable(
+    form: Json<TotpCode>,
+    AuthedUser(user): AuthedUser,
+    redis: Data<Pool>,
+) -> Result<HttpResponse> {
+    let valid = match database::get_totp_secret(&user, &redis).await? {
+        Some(secret) => totp::verify(&secret, &form.code),
+        None => false,
+    };
+
+    if !valid {
+        return Ok(response::error(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "invalid_code",
+            "Invalid code",
+        ));
+    }
+
+    database::set_totp_enabled(&user, &redis).await?;
+
+    Ok(HttpResponse::Ok().body("TOTP enabled"))
+}
+
+/// Handles HTTP POST requests to /webauthn/register/start
+/// Starts the registration of a security key or passkey for the logged in user. Returns the
+/// options for `navigator.credentials.create` as JSON, the response is sent to
+/// /webauthn/register/finish. Sends 401 Unauthorized if not logged in.
+///
+/// # Arguments
+///
+/// * `user` - Logged in user
+/// * `session` - Session containing all CookieSession data
+/// * `config` - Server configuration, containing the host name credentials are bound to
+/// * `redis` - RedisActor to access redis database
+///
+/// # Remarks
+///
+/// Should only be called from actix_web
+pub async fn webauthn_register_start(
+    AuthedUser(user): AuthedUser,
+    session: Session,
+    config: Data<Config>,
+    redis: Data<Pool>,
+) -> Result<HttpResponse> {
+    let existing = database::webauthn_credentials(&user, &redis).await?;
+    let challenge = webauthn::generate_challenge();
+    let options = webauthn::creation_options(
+        &webauthn::RelyingParty::new(&config),
+        &user,
+        &challenge,
+        &existing,
+    );
+
+    let _ = session.set(
+        "webauthn_register",
+        webauthn::Pending {
+            email: user,
+            challenge,
+            expires: user_session::now() + webauthn::TIMEOUT_SECS,
+        },
+    );
+
+    Ok(HttpResponse::Ok().json(options))
+}
+
+/// Handles HTTP POST requests to /webauthn/register/finish
+/// Verifies the response of the authenticator and stores the credential, after which the user can
+/// log in with it at /webauthn/login/start. Sends 401 Unauthorized if not logged in or no
+/// registration is pending, 409 Conflict if the credential is already registered and 422
+/// UnprocessableEntity if the response is invalid.
+///
+/// # Arguments
+///
+/// * `form` - JSON data containing the response of the authenticator
+/// * `user` - Logged in user
+/// * `session` - Session containing all CookieSession data
+/// * `config` - Server configuration, containing the host name credentials are bound to
+/// * `redis` - RedisActor to access redis database
+///
+/// # Remarks
+///
+/// Should only be called from actix_web
+pub async fn webauthn_register_finish(
+    form: Json<webauthn::Attestation>,
+    AuthedUser(user): AuthedUser,
+    session: Session,
+    config: Data<Config>,
+    redis: Data<Pool>,
+) -> Result<HttpResponse> {
+    // If no registration of this user is pending -> Unauthorized
+    let pending = session
+        .get::<webauthn::Pending>("webauthn_register")
+        .unwrap_or(None)
+        .filter(|pending| pending.email == user && pending.expires > user_session::now());
+    session.remove("webauthn_register");
+    let pending = match pending {
+        Some(pending) => pending,
+        None => return Ok(response::unauthorized()),
+    };
+
+    let rp = webauthn::RelyingParty::new(&config);
+    let credential = match webauthn::register(&rp, &pending.challenge, &form) {
+        Ok(credential) => credential,
+        Err(e) => {
+            return Ok(response::error(
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "invalid_credential",
+                &e,
+            ))
+        }
+    };
+
+    let existing = database::webauthn_credentials(&user, &redis).await?;
+    if existing.iter().any(|stored| stored.id == credential.id) {
+        return Ok(response::error(
+            StatusCode::CONFLICT,
+            "credential_registered",
+            "Security key already registered",
+        ));
+    }
+
+    database::webauthn_set(&user, &credential, &redis).await?;
+
+    Ok(HttpResponse::Created().body("Security key registered"))
+}
+
+/// Handles HTTP POST requests to /webauthn/login/start
+/// Starts a login with a security key or passkey instead of the email challenge. Returns the
+/// options for `navigator.credentials.get` as JSON, the response is sent to
+/// /webauthn/login/finish. Unknown users and users without a security key get options with a
+/// made-up credential, so the response doesn't reveal either.
+/// Sends 403 Forbidden if the CSRF token is invalid, 422 UnprocessableEntity if the email is
+/// invalid and 429 TooManyRequests if the client IP or email is rate limited.
+///
+/// # Arguments
+///
+/// * `req` - Request of the client, used for rate limiting
+/// * `form` - JSON data of the login form, containing user's email
+/// * `session` - Session containing all CookieSession data
+/// * `config` - Server configuration, containing the host name credentials are bound to
+/// * `redis` - RedisActor to access redis database
+///
+/// # Remarks
+///
+/// Should only be called from actix_web
+pub async fn webauthn_login_start(
+    req: HttpRequest,
+    form: Json<Identity>,
+    session: Session,
+    config: Data<Config>,
+    redis: Data<Pool>,
+) -> Result<HttpResponse> {
+    let email = form.email.clone();
+
+    // If CSRF token missing or mismatched -> Respond
+    if !csrf::verify(&session, form.csrf_token.as_deref()) {
+        return Ok(response::invalid_csrf());
+    }
+
+    // If invalid email -> Respond
+    if !validator::validate_email(email.as_str()) {
+        return Ok(response::error(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "invalid_email",
+            "Invalid email",
+        ));
+    }
+
+    // If too many attempts -> Respond
+    if ratelimit::exceeded("login", &req, &email, &redis).await? {
+        return Ok(response::rate_limited());
+    }
+
+    let mut ids: Vec<String> = database::webauthn_credentials(&email, &redis)
+        .await?
+        .into_iter()
+        .map(|credential| credential.id)
+        .collect();
+    if ids.is_empty() {
+        ids.push(webauthn::decoy_id(&email, &config.cookie_secret));
+    }
+
+    let challenge = webauthn::generate_challenge();
+    let options =
+        webauthn::request_options(&webauthn::RelyingParty::new(&config), &challenge, &ids);
+
+    let _ = session.set(
+        "webauthn_login",
+        webauthn::Pending {
+            email,
+            challenge,
+            expires: user_session::now() + webauthn::TIMEOUT_SECS,
+        },
+    );
+
+    Ok(HttpResponse::Ok().json(options))
+}
+
+/// Handles HTTP POST requests to /webauthn/login/finish
+/// Verifies the signature of the authenticator and logs the user in. Like after the email
+/// challenge, users with TOTP enabled still need to enter a code (sends 202 Accepted then).
+/// Sends 401 Unauthorized if the signature is invalid or no login is pending and 429
+/// TooManyRequests if rate limited.
+///
+/// # Arguments
+///
+/// * `req` - Request of the client, used for rate limiting
+/// * `form` - JSON data containing the response of the authenticator
+/// * `session` - Session containing all CookieSession data
+/// * `config` - Server configuration, containing the host name credentials are bound to
+/// * `redis` - RedisActor to access redis database
+///
+/// # Remarks
+///
+/// Should only be called from actix_web
+pub async fn webauthn_login_finish(
+    req: HttpRequest,
+    form: Json<webauthn::Assertion>,
+    session: Session,
+    config: Data<Config>,
+    redis: Data<Pool>,
+) -> Result<HttpResponse> {
+    // A challenge is only signed once, whatever the outcome
+    let pending = session
+        .get::<webauthn::Pending>("webauthn_login")
+        .unwrap_or(None)
+        .filter(|pending| pending.expires > user_session::now());
+    session.remove("webauthn_login");
+    let pending = match pending {
+        Some(pending) => pending,
+        None => return Ok(response::unauthorized()),
+    };
+
+    if ratelimit::exceeded("webauthn", &req, &pending.email, &redis).await? {
+        return Ok(response::rate_limited());
+    }
+
+    let rp = webauthn::RelyingParty::new(&config);
+    let credential = database::webauthn_credentials(&pending.email, &redis)
+        .await?
+        .into_iter()
+        .find(|credential| credential.id == form.id.trim_end_matches('='));
+    let verified = credential.and_then(|mut credential| {
+        match webauthn::authenticate(&rp, &pending.challenge, &credential, &form) {
+            Ok(sign_count) => {
+                credential.sign_count = sign_count;
+                Some(credential)
+            }
+            Err(e) => {
+                warn!("WebAuthn login of {} failed: {}", pending.email, e);
+                None
+            }
+        }
+    });
+
+    let credential = match verified {
+        Some(credential) => credential,
+        None => {
+            return Ok(response::error(
+                StatusCode::UNAUTHORIZED,
+                "invalid_credential",
+                "Invalid security key",
+            ))
+        }
+    };
+
+    database::webauthn_set(&pending.email, &credential, &redis).await?;
+
+    if !first_factor_passed(&session, pending.email, &config, &redis).await? {
+        return Ok(HttpResponse::Accepted().body("TOTP code required"));
+    }
+
+    Ok(HttpResponse::Ok().body("Logged in"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::haak::testing;
+
+    use actix_session::CookieSession;
+    use actix_web::{test, App};
+
+    #[test]
+    fn tokens_equal_compares_whole_tokens() {
+        assert!(tokens_equal("", ""));
+        assert!(tokens_equal("c2VjcmV0LXRva2Vu", "c2VjcmV0LXRva2Vu"));
+
+        assert!(!tokens_equal("c2VjcmV0LXRva2Vu", "c2VjcmV0LXRva2Vv"));
+        assert!(!tokens_equal("c2VjcmV0LXRva2Vu", "C2VjcmV0LXRva2Vu"));
+
+        assert!(!tokens_equal("c2VjcmV0LXRva2Vu", "c2VjcmV0"));
+        assert!(!tokens_equal("c2VjcmV0", "c2VjcmV0LXRva2Vu"));
+        assert!(!tokens_equal("", "c2VjcmV0"));
+    }
+
+    /// Scheduling slack allowed on top of the padded response time
+    const TOLERANCE: Duration = Duration::from_millis(100);
+
+    #[actix_rt::test]
+    async fn pad_response_waits_for_min_response_time() {
+        for already in [0, 100, 240].iter() {
+            let started = Instant::now() - Duration::from_millis(*already);
+            pad_response(started).await;
+
+            let elapsed = started.elapsed();
+            assert!(
+                elapsed >= MIN_RESPONSE_TIME,
+                "{:?} after {}ms",
+                elapsed,
+                already
+            );
+            assert!(
+                elapsed < MIN_RESPONSE_TIME + TOLERANCE,
+                "{:?} after {}ms",
+                elapsed,
+                already
+            );
+        }
+    }
+
+    #[actix_rt::test]
+    async fn pad_response_does_not_delay_slow_steps() {
+        let started = Instant::now() - MIN_RESPONSE_TIME - Duration::from_millis(50);
+
+        let padding = Instant::now();
+        pad_response(started).await;
+
+        assert!(padding.elapsed() < TOLERANCE, "{:?}", padding.elapsed());
+    }
+
+    /// Posts a login for an email from a new session and returns how long /login took
+    ///
+    /// # Arguments
+    ///
+    /// * `email` - Email address to log in with
+    /// * `redis` - Connection to database
+    async fn timed_login(email: &str, redis: &Data<Pool>) -> Duration {
+        let mut app = test::init_service(
+            App::new()
+                .wrap(CookieSession::signed(&[0; 32]).secure(false))
+                .data(testing::config())
+                .app_data(redis.clone())
+                .data(email::Mailer::start(String::from("localhost")))
+                .data(MxCheck::start(false))
+                .route(
+                    "/csrf",
+                    web::get().to(|session: Session| async move { csrf::token(&session) }),
+                )
+                .route("/login", web::post().to(login_submit)),
+        )
+        .await;
+
+        let res =
+            test::call_service(&mut app, test::TestRequest::get().uri("/csrf").to_request()).await;
+        let cookie = res.response().cookies().next().unwrap().into_owned();
+        let token = String::from_utf8(test::read_body(res).await.to_vec()).unwrap();
+
+        // A new client address every run, so reruns don't hit the rate limit
+        let ip = OsRng.next_u32().to_be_bytes();
+        let req = test::TestRequest::post()
+            .uri("/login")
+            .cookie(cookie)
+            .peer_addr(
+                format!("10.{}.{}.{}:443", ip[0], ip[1], ip[2])
+                    .parse()
+                    .unwrap(),
+            )
+            .set_json(&serde_json::json!({ "email": email, "csrf_token": token }))
+            .to_request();
+
+        let started = Instant::now();
+        let res = test::call_service(&mut app, req).await;
+        let elapsed = started.elapsed();
+
+        assert_eq!(res.status(), StatusCode::OK);
+        elapsed
+    }
+
+    #[actix_rt::test]
+    #[ignore = "needs redis, run with cargo test -- --ignored"]
+    async fn login_takes_as_long_for_unknown_users() {
+        let redis = testing::pool();
+        let (known, unknown) = (testing::email(), testing::email());
+        database::user_add(&known, &redis).await.unwrap();
+
+        let known_took = timed_login(&known, &redis).await;
+        let unknown_took = timed_login(&unknown, &redis).await;
+
+        assert!(known_took >= MIN_RESPONSE_TIME, "{:?}", known_took);
+        assert!(unknown_took >= MIN_RESPONSE_TIME, "{:?}", unknown_took);
+        let difference = match known_took > unknown_took {
+            true => known_took - unknown_took,
+            false => unknown_took - known_took,
+        };
+        assert!(
+            difference < TOLERANCE,
+            "{:?} for known, {:?} for unknown",
+            known_took,
+            unknown_took
+        );
+
+        database::user_delete(&known, &redis).await.unwrap();
     }
 }
@@ -0,0 +1,475 @@
+//! Documentation for backup module
+//! Includes the backup and restore of the database.
+//!
+//! /admin/backup streams all users and stations, and optionally all readings, as one JSON
+//! document. Users and pages of readings are fetched one at a time, so the dataset is never held
+//! in memory. /admin/restore reads such a document back. Every document carries `version`, so
+//! documents of older versions can be migrated on restore.
+use crate::haak::alerts::Rule;
+use crate::haak::audit;
+use crate::haak::auth::Role;
+use crate::haak::config::Config;
+use crate::haak::database::{self, DbError, Pool};
+use crate::haak::graph;
+use crate::haak::limits;
+use crate::haak::ratelimit;
+use crate::haak::response;
+use crate::haak::sensor;
+use crate::haak::session::AuthedUser;
+use crate::haak::settings::{self, UserSettings};
+use crate::haak::webauthn::Credential;
+
+use actix_web::http::{header, StatusCode};
+use actix_web::web::{Bytes, Data, Payload, Query};
+use actix_web::{HttpRequest, HttpResponse, Result};
+
+use futures::stream;
+
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use std::collections::{BTreeMap, VecDeque};
+
+/// Version of the backup document, increased on every incompatible change
+pub const VERSION: u32 = 1;
+
+/// Number of readings fetched from or written to the database at once
+const PAGE: usize = 1000;
+
+/// User in the backup, with everything needed to log in again except their sessions
+#[derive(Serialize, Deserialize)]
+pub struct UserBackup {
+    pub email: String,
+    pub role: Role,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password_hash: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub totp_secret: Option<String>,
+    #[serde(default)]
+    pub totp_enabled: bool,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub webauthn: Vec<Credential>,
+    pub settings: UserSettings,
+    #[serde(default)]
+    pub alerts: Vec<Rule>,
+}
+
+/// Station in the backup
+#[derive(Serialize, Deserialize)]
+pub struct StationBackup {
+    pub id: String,
+    /// Altitude in meters, if set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub altitude: Option<f64>,
+}
+
+/// Reading of a single metric in the backup
+#[derive(Serialize, Deserialize)]
+pub struct ReadingBackup {
+    pub station: String,
+    pub metric: String,
+    pub timestamp: u64,
+    pub value: f64,
+}
+
+/// Backup document as read by /admin/restore
+#[derive(Deserialize)]
+pub struct Backup {
+    version: u32,
+    #[serde(default)]
+    users: Vec<UserBackup>,
+    #[serde(default)]
+    stations: Vec<StationBackup>,
+    #[serde(default)]
+    readings: Vec<ReadingBackup>,
+}
+
+/// Query data of the backup
+#[derive(Deserialize)]
+pub struct BackupQuery {
+    readings: Option<bool>,
+}
+
+/// Piece of the backup document, fetched when it is its turn to be sent
+enum Part {
+    /// Fixed JSON text
+    Text(String),
+    /// User, and whether it is the first in the array
+    User(String, bool),
+    /// Station, and whether it is the first in the array
+    Station(String, bool),
+    /// Page of readings of a metric, starting at the cursor of `database::readings_slice`
+    Readings {
+        station: String,
+        metric: &'static str,
+        cursor: u64,
+    },
+}
+
+/// State of a streaming backup
+struct Dump {
+    parts: VecDeque<Part>,
+    /// Whether no reading was sent yet, so the next one needs no separator
+    first_reading: bool,
+    redis: Data<Pool>,
+}
+
+/// Prefixes a JSON value with the array separator unless it is the first
+///
+/// # Arguments
+///
+/// * `value` - Value to serialize
+/// * `first` - Whether the value is the first in the array
+fn element<T: Serialize>(value: &T, first: bool) -> Vec<u8> {
+    let mut out = if first { vec![] } else { vec![b','] };
+    out.extend(serde_json::to_vec(value).unwrap());
+    out
+}
+
+impl Dump {
+    /// Fetches and serializes a part of the document
+    ///
+    /// # Arguments
+    ///
+    /// * `part` - Part to fetch
+    async fn chunk(&mut self, part: Part) -> Result<Vec<u8>, DbError> {
+        let redis = &self.redis;
+
+        match part {
+            Part::Text(text) => Ok(text.into_bytes()),
+            Part::User(email, first) => {
+                let user = UserBackup {
+                    role: database::user_role(&email, redis)
+                        .await?
+                        .unwrap_or(Role::User),
+                    password_hash: database::password_hash(&email, redis).await?,
+                    totp_secret: database::get_totp_secret(&email, redis).await?,
+                    totp_enabled: database::totp_enabled(&email, redis).await?,
+                    webauthn: database::webauthn_credentials(&email, redis).await?,
+                    settings: database::settings_get(&email, redis).await?,
+                    alerts: database::alerts_get(&email, redis).await?,
+                    email,
+                };
+
+                Ok(element(&user, first))
+            }
+            Part::Station(id, first) => {
+                let station = StationBackup {
+                    altitude: database::station_altitude(&id, redis).await?,
+                    id,
+                };
+
+                Ok(element(&station, first))
+            }
+            Part::Readings {
+                station,
+                metric,
+                cursor,
+            } => {
+                let (page, next) =
+                    database::readings_slice(&station, metric, cursor, PAGE, redis).await?;
+
+                let mut out = vec![];
+                for (timestamp, value) in page.iter() {
+                    let reading = ReadingBackup {
+                        station: station.clone(),
+                        metric: metric.to_owned(),
+                        timestamp: *timestamp,
+                        value: *value,
+                    };
+                    out.extend(element(&reading, self.first_reading));
+                    self.first_reading = false;
+                }
+
+                if let Some(cursor) = next {
+                    self.parts.push_front(Part::Readings {
+                        station,
+                        metric,
+                        cursor,
+                    });
+                }
+
+                Ok(out)
+            }
+        }
+    }
+}
+
+/// Handles HTTP GET requests to /admin/backup
+/// Streams all users, their settings, alert rules, password hashes and TOTP secrets, and all
+/// stations as downloadable `backup.json`. With `?readings=true` all readings are included as
+/// well. Sends 401 Unauthorized if not logged in as admin.
+/// Sends 429 TooManyRequests if the admin is rate limited.
+///
+/// # Arguments
+///
+/// * `req` - Request of the client, used for rate limiting
+/// * `query` - Query containing the optional `readings`
+/// * `user` - Logged in user
+/// * `redis` - RedisActor to access redis database
+///
+/// # Remarks
+///
+/// Should only be called from actix_web.
+/// A database error halfway aborts the response, leaving an incomplete document.
+pub async fn backup(
+    req: HttpRequest,
+    Query(query): Query<BackupQuery>,
+    AuthedUser(user): AuthedUser,
+    redis: Data<Pool>,
+) -> Result<HttpResponse> {
+    // If user is not admin -> Unauthorized
+    if !database::user_is_admin(&user, &redis).await? {
+        return Ok(response::unauthorized());
+    }
+
+    // If too many admin actions -> Respond
+    if ratelimit::exceeded("admin", &req, &user, &redis).await? {
+        return Ok(response::rate_limited());
+    }
+
+    let users = database::list_users(&redis).await?;
+    let stations = database::stations(&redis).await?;
+    let readings = query.readings.unwrap_or(false);
+
+    let mut parts = VecDeque::new();
+    parts.push_back(Part::Text(format!("{{\"version\":{},\"users\":[", VERSION)));
+    for (i, email) in users.into_iter().enumerate() {
+        parts.push_back(Part::User(email, i == 0));
+    }
+    parts.push_back(Part::Text(String::from("],\"stations\":[")));
+    for (i, id) in stations.iter().enumerate() {
+        parts.push_back(Part::Station(id.clone(), i == 0));
+    }
+    if readings {
+        parts.push_back(Part::Text(String::from("],\"readings\":[")));
+        for station in stations.iter() {
+            for metric in graph::METRICS.iter() {
+                parts.push_back(Part::Readings {
+                    station: station.clone(),
+                    metric,
+                    cursor: 0,
+                });
+            }
+        }
+    }
+    parts.push_back(Part::Text(String::from("]}")));
+
+    audit::record(
+        &user,
+        audit::Action::Backup,
+        "",
+        readings.then_some("readings"),
+        &redis,
+    )
+    .await;
+
+    let dump = Dump {
+        parts,
+        first_reading: true,
+        redis,
+    };
+
+    let body = stream::unfold(dump, |mut dump| async move {
+        let part = dump.parts.pop_front()?;
+        let chunk = dump.chunk(part).await;
+
+        // Stop after an error, the document can't be completed
+        if let Err(e) = &chunk {
+            error!("Backup aborted: {}", e);
+            dump.parts.clear();
+        }
+
+        Some((chunk.map(Bytes::from), dump))
+    });
+
+    Ok(HttpResponse::Ok()
+        .content_type("application/json")
+        .header(
+            header::CONTENT_DISPOSITION,
+            "attachment; filename=\"backup.json\"",
+        )
+        .streaming(body))
+}
+
+/// Returns a description of the first invalid entry of the backup, `None` if it is valid
+///
+/// # Arguments
+///
+/// * `backup` - Backup to check
+fn invalid_entry(backup: &Backup) -> Option<String> {
+    if backup.version > VERSION {
+        return Some(format!(
+            "Unsupported version {}, the latest is {}",
+            backup.version, VERSION
+        ));
+    }
+
+    for user in backup.users.iter() {
+        if !validator::validate_email(user.email.as_str()) {
+            return Some(format!("Invalid email {}", user.email));
+        }
+        if let Err(field) = settings::validate_settings(&user.settings) {
+            return Some(format!("Invalid {} of {}", field, user.email));
+        }
+        if let Some(rule) = user
+            .alerts
+            .iter()
+            .find(|rule| !graph::METRICS.contains(&rule.metric.as_str()))
+        {
+            return Some(format!("Invalid alert {} of {}", rule.id, user.email));
+        }
+    }
+
+    for station in backup.stations.iter() {
+        if !sensor::valid_station(&station.id) {
+            return Some(format!("Invalid station {}", station.id));
+        }
+    }
+
+    for reading in backup.readings.iter() {
+        if !sensor::valid_station(&reading.station) {
+            return Some(format!("Invalid station {}", reading.station));
+        }
+        if !graph::METRICS.contains(&reading.metric.as_str()) || !reading.value.is_finite() {
+            return Some(format!(
+                "Invalid {} reading of {} at {}",
+                reading.metric, reading.station, reading.timestamp
+            ));
+        }
+    }
+
+    None
+}
+
+/// Restores a user, replacing the user with the same email along with their sessions
+///
+/// # Arguments
+///
+/// * `user` - User to restore
+/// * `redis` - RedisActor to access redis database
+async fn restore_user(user: &UserBackup, redis: &Data<Pool>) -> Result<(), DbError> {
+    let email = &user.email;
+
+    database::user_delete(email, redis).await?;
+    database::set_role(email, user.role, redis).await?;
+    database::settings_set(email, &user.settings, redis).await?;
+
+    if let Some(hash) = &user.password_hash {
+        database::password_hash_set(email, hash, redis).await?;
+    }
+    if let Some(secret) = &user.totp_secret {
+        database::set_totp_secret(email, secret, redis).await?;
+        if user.totp_enabled {
+            database::set_totp_enabled(email, redis).await?;
+        }
+    }
+    for credential in user.webauthn.iter() {
+        database::webauthn_set(email, credential, redis).await?;
+    }
+    for rule in user.alerts.iter() {
+        database::alerts_add(email, rule, redis).await?;
+    }
+
+    Ok(())
+}
+
+/// Handles HTTP POST requests to /admin/restore
+/// Restores a document of /admin/backup. Users in the backup replace the users with the same
+/// email, who are logged out. Other users are kept and readings are merged with the stored
+/// readings. Returns the number of restored entries
+/// (`{"users": 2, "stations": 1, "readings": 120}`).
+/// Sends 401 Unauthorized if not logged in as admin, 413 PayloadTooLarge if the document is
+/// larger than `RESTORE_LIMIT` and 422 UnprocessableEntity if the document is malformed, of a newer
+/// version or contains an invalid entry, in which case nothing is restored.
+/// Sends 429 TooManyRequests if the admin is rate limited.
+///
+/// # Arguments
+///
+/// * `req` - Request of the client, used for rate limiting
+/// * `body` - Raw JSON body containing the backup
+/// * `user` - Logged in user
+/// * `config` - Configuration containing the restore limit
+/// * `redis` - RedisActor to access redis database
+///
+/// # Remarks
+///
+/// Should only be called from actix_web
+pub async fn restore(
+    req: HttpRequest,
+    body: Payload,
+    AuthedUser(user): AuthedUser,
+    config: Data<Config>,
+    redis: Data<Pool>,
+) -> Result<HttpResponse> {
+    // If user is not admin -> Unauthorized
+    if !database::user_is_admin(&user, &redis).await? {
+        return Ok(response::unauthorized());
+    }
+
+    // If too many admin actions -> Respond
+    if ratelimit::exceeded("admin", &req, &user, &redis).await? {
+        return Ok(response::rate_limited());
+    }
+
+    let body = limits::read(body, config.restore_limit).await?;
+
+    let backup: Backup = match serde_json::from_slice(&body) {
+        Ok(backup) => backup,
+        Err(e) => {
+            return Ok(response::error(
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "invalid_backup",
+                &format!("Invalid backup: {}", e),
+            ))
+        }
+    };
+
+    if let Some(problem) = invalid_entry(&backup) {
+        return Ok(response::error(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "invalid_backup",
+            &problem,
+        ));
+    }
+
+    for restored in backup.users.iter() {
+        restore_user(restored, &redis).await?;
+    }
+
+    for station in backup.stations.iter() {
+        if let Some(altitude) = station.altitude {
+            database::station_altitude_set(&station.id, altitude, &redis).await?;
+        }
+    }
+
+    let mut series: BTreeMap<(&str, &str), Vec<(u64, f64)>> = BTreeMap::new();
+    for reading in backup.readings.iter() {
+        series
+            .entry((&reading.station, &reading.metric))
+            .or_default()
+            .push((reading.timestamp, reading.value));
+    }
+    for ((station, metric), readings) in series.iter() {
+        for page in readings.chunks(PAGE) {
+            database::readings_restore(station, metric, page, &redis).await?;
+        }
+    }
+
+    let counts = json!({
+        "users": backup.users.len(),
+        "stations": backup.stations.len(),
+        "readings": backup.readings.len(),
+    });
+
+    audit::record(
+        &user,
+        audit::Action::Restore,
+        "",
+        Some(&counts.to_string()),
+        &redis,
+    )
+    .await;
+
+    Ok(HttpResponse::Ok().json(counts))
+}
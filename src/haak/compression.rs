@@ -0,0 +1,121 @@
+//! Documentation for compression module
+//! Includes the compact encoding of readings stored in windows (`READINGS_COMPRESSION_SECS`).
+//!
+//! A window is a hash field holding a blob of chunks, every write appends a chunk. A chunk is the
+//! number of readings followed by the readings ordered by timestamp, each as the timestamp delta
+//! (to the window start for the first reading, to the previous reading for the others) as a
+//! LEB128 varint and the value as a little endian `f64`. A reading usually takes 9 bytes.
+
+/// Returns the start of the window containing a timestamp
+///
+/// # Arguments
+///
+/// * `timestamp` - Unix timestamp
+/// * `window` - Length of the windows in seconds
+pub fn window_start(timestamp: u64, window: u64) -> u64 {
+    timestamp - timestamp % window
+}
+
+/// Appends a LEB128 varint
+///
+/// # Arguments
+///
+/// * `blob` - Blob to append to
+/// * `value` - Value to append
+fn put_varint(blob: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        blob.push((value as u8 & 0x7f) | 0x80);
+        value >>= 7;
+    }
+    blob.push(value as u8);
+}
+
+/// Reads a LEB128 varint, `None` if the blob ends early
+///
+/// # Arguments
+///
+/// * `blob` - Blob to read from, advanced past the varint
+fn take_varint(blob: &mut &[u8]) -> Option<u64> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let (byte, rest) = blob.split_first()?;
+        *blob = rest;
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+
+    None
+}
+
+/// Encodes readings of a single window as a chunk
+///
+/// # Arguments
+///
+/// * `start` - Start of the window
+/// * `readings` - `(timestamp, value)` pairs within the window, later pairs win over earlier ones
+pub fn encode(start: u64, readings: &[(u64, f64)]) -> Vec<u8> {
+    // Stable, so of readings with the same timestamp the last one stays last
+    let mut readings = readings.to_vec();
+    readings.sort_by_key(|(timestamp, _)| *timestamp);
+
+    let mut blob = Vec::with_capacity(1 + readings.len() * 9);
+    put_varint(&mut blob, readings.len() as u64);
+
+    let mut previous = start;
+    for (timestamp, value) in readings {
+        put_varint(&mut blob, timestamp - previous);
+        blob.extend_from_slice(&value.to_le_bytes());
+        previous = timestamp;
+    }
+
+    blob
+}
+
+/// Decodes the chunks of a window into `(timestamp, value)` pairs ordered by timestamp, of
+/// readings with the same timestamp the one written last wins. A truncated chunk ends decoding.
+///
+/// # Arguments
+///
+/// * `start` - Start of the window
+/// * `blob` - Chunks as stored
+pub fn decode(start: u64, mut blob: &[u8]) -> Vec<(u64, f64)> {
+    let mut readings = Vec::new();
+
+    'chunks: while !blob.is_empty() {
+        let count = match take_varint(&mut blob) {
+            Some(count) => count,
+            None => break,
+        };
+
+        let mut previous = start;
+        for _ in 0..count {
+            let delta = match take_varint(&mut blob) {
+                Some(delta) if blob.len() >= 8 => delta,
+                _ => break 'chunks,
+            };
+            let (value, rest) = blob.split_at(8);
+            blob = rest;
+
+            let mut bytes = [0u8; 8];
+            bytes.copy_from_slice(value);
+
+            previous = previous.saturating_add(delta);
+            readings.push((previous, f64::from_le_bytes(bytes)));
+        }
+    }
+
+    // Stable, so readings of later chunks stay behind earlier ones at the same timestamp
+    readings.sort_by_key(|(timestamp, _)| *timestamp);
+
+    let mut latest: Vec<(u64, f64)> = Vec::with_capacity(readings.len());
+    for reading in readings {
+        match latest.last_mut() {
+            Some(last) if last.0 == reading.0 => *last = reading,
+            _ => latest.push(reading),
+        }
+    }
+
+    latest
+}
@@ -0,0 +1,736 @@
+//! Documentation for config module
+//! Includes the startup configuration.
+//!
+//! Settings are read from the environment. With `WEATHER_CONFIG` pointing to a TOML file, the
+//! file supplies values for every variable that isn't set, keys are the lowercase variable names:
+//!
+//! ```toml
+//! weather_ip = "0.0.0.0"
+//! weather_port = 443
+//! weather_url = "weather.example.com"
+//! smtp_host = "mail.example.com"
+//! ```
+//!
+//! The file is applied to the environment, so variables read later (e.g. `SMTP_HOST` when sending
+//! mail) see its values as well.
+use crate::haak::limits;
+use crate::haak::session::SessionConfig;
+use crate::haak::tls;
+
+use std::env;
+use std::fmt;
+use std::fs;
+use std::net::IpAddr;
+
+use actix_web::cookie::SameSite;
+
+/// Configuration required to start the server
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// Key used to sign the session cookie, base64 decoded from `COOKIE_SECRET_KEY`
+    pub cookie_secret: Vec<u8>,
+    /// Addresses the HTTPS listener binds to, comma separated (`WEATHER_IP`)
+    pub ips: Vec<IpAddr>,
+    /// Port of the HTTPS listener (`WEATHER_PORT`)
+    pub port: u16,
+    /// Certificate and private key of the HTTPS listener (`TLS_CERT_PATH` and `TLS_KEY_PATH`, or
+    /// `TLS_PKCS12_PATH` and `TLS_PKCS12_PASSWORD`)
+    pub tls: tls::Identity,
+    /// Public host name of the server, used in emails and redirects (`WEATHER_URL`)
+    pub url: String,
+    /// Origin browsers report for WebAuthn ceremonies (`WEBAUTHN_ORIGIN`, default
+    /// `https://<WEATHER_URL>`), set it when a proxy serves the site on another port
+    pub webauthn_origin: String,
+    /// Key the weather station authenticates with (`WEATHER_API_KEY`)
+    pub api_key: String,
+    /// Redis address as `host:port` (`REDIS_URL`, default `127.0.0.1:6379`)
+    pub redis_address: String,
+    /// Connections to redis of every worker (`REDIS_POOL_SIZE`, default 1)
+    pub redis_pool_size: usize,
+    /// Prefix of every redis key, to share redis between instances (`REDIS_KEY_PREFIX`, default
+    /// empty)
+    pub redis_key_prefix: String,
+    /// Directory of the static files (`TEMPLATES_DIR`, default `./templates`)
+    pub templates_dir: String,
+    /// Port of the plain HTTP listener redirecting to HTTPS (`WEATHER_HTTP_PORT`)
+    pub http_port: Option<u16>,
+    /// Port of the internal metrics listener (`METRICS_PORT`)
+    pub metrics_port: Option<u16>,
+    /// Address the metrics listener binds to (`METRICS_IP`, default `127.0.0.1`)
+    pub metrics_ip: String,
+    /// Days readings are kept, forever when unset (`READINGS_RETENTION_DAYS`)
+    pub retention_days: Option<u64>,
+    /// Seconds of readings compressed together, stored in sorted sets when unset
+    /// (`READINGS_COMPRESSION_SECS`)
+    pub compression_secs: Option<u64>,
+    /// Maximum size of JSON bodies in bytes (`JSON_LIMIT`, default 8 KiB)
+    pub json_limit: usize,
+    /// Maximum size of form bodies in bytes (`FORM_LIMIT`, default 8 KiB)
+    pub form_limit: usize,
+    /// Maximum size of sensor readings in bytes (`INGEST_LIMIT`, default 8 KiB)
+    pub ingest_limit: usize,
+    /// Maximum size of restored backups in bytes (`RESTORE_LIMIT`, default 64 MiB)
+    pub restore_limit: usize,
+    /// Maximum number of readings in a batch (`INGEST_BATCH_MAX`, default 1000)
+    pub ingest_batch_max: usize,
+    /// Minimum seconds between two readings of a station on /ingest, unlimited when unset
+    /// (`INGEST_MIN_INTERVAL_SECS`)
+    pub ingest_min_interval_secs: Option<u64>,
+    /// Days a device is remembered after logging in with "remember this device"
+    /// (`REMEMBER_DEVICE_DAYS`, default 30)
+    pub remember_device_days: usize,
+    /// Whether readings may omit their units, for old firmware (`INGEST_LEGACY_FORMAT`, default
+    /// `false`)
+    pub ingest_legacy_format: bool,
+    /// Origins allowed to call the API cross-origin, comma separated (`CORS_ALLOWED_ORIGINS`)
+    pub cors_origins: Vec<String>,
+    /// Whether to refuse to start when redis is unreachable (`REQUIRE_REDIS`, default `true`)
+    pub require_redis: bool,
+    /// Seconds without readings after which a station is stale (`STATION_STALE_SECS`, default 900)
+    pub station_stale_secs: u64,
+    /// Seconds a registration link stays valid (`REGISTER_TTL_SECS`, default 3600)
+    pub register_ttl_secs: u64,
+    /// Seconds an email change link stays valid (`EMAIL_CHANGE_TTL_SECS`, default 3600)
+    pub email_change_ttl_secs: u64,
+    /// Seconds a login link stays valid (`LOGIN_TTL_SECS`, default 600)
+    pub login_ttl_secs: u64,
+    /// Length of the short login code emailed along with the login link, no code when unset
+    /// (`LOGIN_CODE_LENGTH`, 6 to 16 characters)
+    pub login_code_length: Option<usize>,
+    /// Whether to reject emails whose domain has no MX record (`VALIDATE_MX`, default `false`)
+    pub validate_mx: bool,
+    /// Session lifetime and cookie settings (`SESSION_TTL_SECS`, `SESSION_IDLE_SECS`,
+    /// `MAX_SESSIONS_PER_USER`, `SESSION_COOKIE_NAME`, `SESSION_COOKIE_SECURE` and
+    /// `SESSION_COOKIE_SAMESITE`)
+    pub session: SessionConfig,
+}
+
+/// Problems found while loading the configuration, all reported at once
+#[derive(Debug)]
+pub struct ConfigError(Vec<String>);
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Invalid configuration:")?;
+        for problem in &self.0 {
+            writeln!(f, "  - {}", problem)?;
+        }
+        Ok(())
+    }
+}
+
+/// Applies the TOML file of `WEATHER_CONFIG` to the environment, without overriding variables that
+/// are already set.
+fn apply_file(problems: &mut Vec<String>) {
+    let path = match env::var("WEATHER_CONFIG") {
+        Ok(path) => path,
+        Err(_) => return,
+    };
+
+    let table = match fs::read_to_string(&path)
+        .map_err(|e| e.to_string())
+        .and_then(|file| file.parse::<toml::Value>().map_err(|e| e.to_string()))
+    {
+        Ok(toml::Value::Table(table)) => table,
+        Ok(_) => {
+            problems.push(format!("WEATHER_CONFIG '{}' is not a TOML table", path));
+            return;
+        }
+        Err(e) => {
+            problems.push(format!("Could not read WEATHER_CONFIG '{}': {}", path, e));
+            return;
+        }
+    };
+
+    for (key, value) in table {
+        let value = match value {
+            toml::Value::String(s) => s,
+            toml::Value::Integer(i) => i.to_string(),
+            toml::Value::Float(f) => f.to_string(),
+            toml::Value::Boolean(b) => b.to_string(),
+            _ => {
+                problems.push(format!("Unsupported value for '{}' in '{}'", key, path));
+                continue;
+            }
+        };
+
+        let name = key.to_uppercase();
+        if env::var_os(&name).is_none() {
+            env::set_var(name, value);
+        }
+    }
+}
+
+/// Reads a required variable
+///
+/// # Arguments
+///
+/// * `name` - Name of the variable
+/// * `hint` - How to set the variable, shown when it is missing
+/// * `problems` - Problems found so far
+fn required(name: &str, hint: &str, problems: &mut Vec<String>) -> String {
+    env::var(name).unwrap_or_else(|_| {
+        problems.push(format!("{} not set, {}", name, hint));
+        String::new()
+    })
+}
+
+/// Reads the addresses the listeners bind to from `WEATHER_IP`, a comma separated list of IPv4
+/// and IPv6 addresses (e.g. `192.168.1.10,2001:db8::10`).
+/// `::` alone listens on IPv4 as well on most systems, so it can't be combined with `0.0.0.0`.
+///
+/// # Arguments
+///
+/// * `problems` - Problems found so far
+fn bind_ips(problems: &mut Vec<String>) -> Vec<IpAddr> {
+    let hint = "set it with export WEATHER_IP=<ip>[,<ip>...]";
+    let ips = match env::var("WEATHER_IP") {
+        Ok(ips) => ips,
+        Err(_) => {
+            problems.push(format!("WEATHER_IP not set, {}", hint));
+            return Vec::new();
+        }
+    };
+
+    let ips: Vec<&str> = ips
+        .split(',')
+        .map(str::trim)
+        .filter(|ip| !ip.is_empty())
+        .collect();
+    if ips.is_empty() {
+        problems.push(format!("WEATHER_IP contains no address, {}", hint));
+    }
+
+    ips.into_iter()
+        .filter_map(|ip| match ip.parse() {
+            Ok(ip) => Some(ip),
+            Err(_) => {
+                problems.push(format!(
+                    "Invalid WEATHER_IP address '{}', expected e.g. 0.0.0.0 or ::",
+                    ip
+                ));
+                None
+            }
+        })
+        .collect()
+}
+
+/// Reads the location of the TLS certificate and key. A PKCS#12 bundle (`TLS_PKCS12_PATH`) takes
+/// precedence over the PEM files (`TLS_CERT_PATH`, default `cert.pem`, and `TLS_KEY_PATH`, default
+/// `key.pem`). Every file must be readable.
+///
+/// # Arguments
+///
+/// * `problems` - Problems found so far
+fn tls_identity(problems: &mut Vec<String>) -> tls::Identity {
+    let mut readable = |name: &str, path: &str| {
+        if let Err(e) = fs::File::open(path) {
+            problems.push(format!("Can't read {} '{}': {}", name, path, e));
+        }
+    };
+
+    match env::var("TLS_PKCS12_PATH") {
+        Ok(path) => {
+            readable("TLS_PKCS12_PATH", &path);
+            tls::Identity::Pkcs12 {
+                path,
+                password: env::var("TLS_PKCS12_PASSWORD").unwrap_or_default(),
+            }
+        }
+        Err(_) => {
+            let cert = env::var("TLS_CERT_PATH").unwrap_or_else(|_| String::from("cert.pem"));
+            let key = env::var("TLS_KEY_PATH").unwrap_or_else(|_| String::from("key.pem"));
+            readable("TLS_CERT_PATH", &cert);
+            readable("TLS_KEY_PATH", &key);
+            tls::Identity::Pem { cert, key }
+        }
+    }
+}
+
+/// Reads an optional port
+///
+/// # Arguments
+///
+/// * `name` - Name of the variable
+/// * `problems` - Problems found so far
+fn optional_port(name: &str, problems: &mut Vec<String>) -> Option<u16> {
+    let port = env::var(name).ok()?;
+
+    match port.parse() {
+        Ok(port) => Some(port),
+        Err(_) => {
+            problems.push(format!(
+                "Invalid {} '{}', expected a port number",
+                name, port
+            ));
+            None
+        }
+    }
+}
+
+/// Reads a request body limit in bytes, `default` if unset
+///
+/// # Arguments
+///
+/// * `name` - Name of the variable
+/// * `default` - Value used when unset
+/// * `problems` - Problems found so far
+fn body_limit(name: &str, default: usize, problems: &mut Vec<String>) -> usize {
+    let limit = match env::var(name) {
+        Ok(limit) => limit,
+        Err(_) => return default,
+    };
+
+    match limit.parse() {
+        Ok(limit) if limit > 0 => limit,
+        _ => {
+            problems.push(format!(
+                "Invalid {} '{}', expected a positive number of bytes",
+                name, limit
+            ));
+            default
+        }
+    }
+}
+
+/// Reads an optional boolean (`true`/`false`), `default` if unset
+///
+/// # Arguments
+///
+/// * `name` - Name of the variable
+/// * `default` - Value used when unset
+/// * `problems` - Problems found so far
+fn flag(name: &str, default: bool, problems: &mut Vec<String>) -> bool {
+    match env::var(name).as_ref().map(String::as_str) {
+        Ok("true") => true,
+        Ok("false") => false,
+        Ok(other) => {
+            problems.push(format!(
+                "Invalid {} '{}', expected true or false",
+                name, other
+            ));
+            default
+        }
+        Err(_) => default,
+    }
+}
+
+/// Reads a positive count, `default` if unset
+///
+/// # Arguments
+///
+/// * `name` - Name of the variable
+/// * `what` - What is counted, for the error message
+/// * `default` - Value used when unset
+/// * `problems` - Problems found so far
+fn count(name: &str, what: &str, default: usize, problems: &mut Vec<String>) -> usize {
+    let count = match env::var(name) {
+        Ok(count) => count,
+        Err(_) => return default,
+    };
+
+    match count.parse() {
+        Ok(count) if count > 0 => count,
+        _ => {
+            problems.push(format!(
+                "Invalid {} '{}', expected a positive number of {}",
+                name, count, what
+            ));
+            default
+        }
+    }
+}
+
+/// Reads a positive number of seconds, `default` if unset
+///
+/// # Arguments
+///
+/// * `name` - Name of the variable
+/// * `default` - Value used when unset
+/// * `problems` - Problems found so far
+fn seconds(name: &str, default: u64, problems: &mut Vec<String>) -> u64 {
+    let secs = match env::var(name) {
+        Ok(secs) => secs,
+        Err(_) => return default,
+    };
+
+    match secs.parse() {
+        Ok(secs) if secs > 0 => secs,
+        _ => {
+            problems.push(format!(
+                "Invalid {} '{}', expected a positive number of seconds",
+                name, secs
+            ));
+            default
+        }
+    }
+}
+
+/// Reads the absolute lifetime of a session, at most 65535 seconds as it is the TTL of the redis
+/// session key
+///
+/// # Arguments
+///
+/// * `problems` - Problems found so far
+fn session_ttl(problems: &mut Vec<String>) -> u16 {
+    let ttl = match env::var("SESSION_TTL_SECS") {
+        Ok(ttl) => ttl,
+        Err(_) => return 7200,
+    };
+
+    match ttl.parse() {
+        Ok(ttl) if ttl > 0 => ttl,
+        _ => {
+            problems.push(format!(
+                "Invalid SESSION_TTL_SECS '{}', expected seconds between 1 and 65535",
+                ttl
+            ));
+            7200
+        }
+    }
+}
+
+/// Reads the session lifetime and cookie settings
+///
+/// # Arguments
+///
+/// * `problems` - Problems found so far
+fn session_config(problems: &mut Vec<String>) -> SessionConfig {
+    let cookie_name =
+        env::var("SESSION_COOKIE_NAME").unwrap_or_else(|_| String::from("actix-session"));
+    if cookie_name.is_empty() || !cookie_name.chars().all(|c| c.is_ascii_graphic()) {
+        problems.push(format!(
+            "Invalid SESSION_COOKIE_NAME '{}', expected printable characters without spaces",
+            cookie_name
+        ));
+    }
+
+    let cookie_same_site = match env::var("SESSION_COOKIE_SAMESITE")
+        .as_ref()
+        .map(String::as_str)
+    {
+        Ok("Lax") | Err(_) => SameSite::Lax,
+        Ok("Strict") => SameSite::Strict,
+        Ok(same_site) => {
+            problems.push(format!(
+                "Invalid SESSION_COOKIE_SAMESITE '{}', expected Strict or Lax",
+                same_site
+            ));
+            SameSite::Lax
+        }
+    };
+
+    SessionConfig {
+        ttl: session_ttl(problems),
+        idle: seconds("SESSION_IDLE_SECS", 1800, problems),
+        max: env::var("MAX_SESSIONS_PER_USER")
+            .ok()
+            .map(|_| count("MAX_SESSIONS_PER_USER", "sessions", 1, problems)),
+        cookie_name,
+        cookie_secure: flag("SESSION_COOKIE_SECURE", true, problems),
+        cookie_same_site,
+    }
+}
+
+/// Reads the origins allowed to call the API cross-origin. A wildcard is rejected, as the session
+/// cookie is sent along with cross-origin requests.
+///
+/// # Arguments
+///
+/// * `problems` - Problems found so far
+fn cors_origins(problems: &mut Vec<String>) -> Vec<String> {
+    let origins: Vec<String> = env::var("CORS_ALLOWED_ORIGINS")
+        .unwrap_or_default()
+        .split(',')
+        .map(|origin| origin.trim().trim_end_matches('/').to_owned())
+        .filter(|origin| !origin.is_empty())
+        .collect();
+
+    for origin in origins.iter() {
+        if origin == "*" {
+            problems.push(String::from(
+                "CORS_ALLOWED_ORIGINS can't contain '*', list the allowed origins",
+            ));
+        } else if !origin.starts_with("https://") && !origin.starts_with("http://") {
+            problems.push(format!(
+                "Invalid CORS_ALLOWED_ORIGINS origin '{}', expected e.g. https://example.com",
+                origin
+            ));
+        }
+    }
+
+    origins
+}
+
+/// Lengths the decoded cookie secret may have: `RedisSession` needs at least 32 bytes and derives
+/// its keys from the secret, so longer secrets add nothing beyond a full 64 byte key
+const COOKIE_SECRET_LENS: [usize; 2] = [32, 64];
+
+/// How to generate a valid cookie secret
+const COOKIE_SECRET_HINT: &str =
+    "generate a new one with export COOKIE_SECRET_KEY=`cat /dev/urandom | head -c 32 | base64`";
+
+/// Decodes and validates the cookie secret, which must be base64 and exactly 32 or 64 bytes long
+///
+/// # Arguments
+///
+/// * `key` - Base64 encoded key
+fn cookie_secret(key: &str) -> Result<Vec<u8>, String> {
+    let secret = base64::decode(key.trim()).map_err(|e| {
+        format!(
+            "COOKIE_SECRET_KEY is not valid base64 ({}), {}",
+            e, COOKIE_SECRET_HINT
+        )
+    })?;
+
+    if !COOKIE_SECRET_LENS.contains(&secret.len()) {
+        return Err(format!(
+            "COOKIE_SECRET_KEY decodes to {} bytes, {} or {} are required, {}",
+            secret.len(),
+            COOKIE_SECRET_LENS[0],
+            COOKIE_SECRET_LENS[1],
+            COOKIE_SECRET_HINT
+        ));
+    }
+
+    Ok(secret)
+}
+
+/// Reads the origin of WebAuthn ceremonies, which is the public address of the site and not the
+/// address the server binds to, as a TLS proxy may be in front of it
+///
+/// # Arguments
+///
+/// * `url` - Public host name of the server
+/// * `problems` - Problems found so far
+fn webauthn_origin(url: &str, problems: &mut Vec<String>) -> String {
+    let origin = match env::var("WEBAUTHN_ORIGIN") {
+        Ok(origin) => origin.trim().trim_end_matches('/').to_owned(),
+        Err(_) => return format!("https://{}", url),
+    };
+
+    let host = origin.trim_start_matches("https://");
+    if !origin.starts_with("https://") || host.is_empty() || host.contains('/') {
+        problems.push(format!(
+            "Invalid WEBAUTHN_ORIGIN '{}', expected e.g. https://weather.example.com",
+            origin
+        ));
+    }
+
+    origin
+}
+
+/// Reads the optional retention window of readings in days
+///
+/// # Arguments
+///
+/// * `problems` - Problems found so far
+fn retention_days(problems: &mut Vec<String>) -> Option<u64> {
+    let days = env::var("READINGS_RETENTION_DAYS").ok()?;
+
+    match days.parse() {
+        Ok(days) if days > 0 => Some(days),
+        _ => {
+            problems.push(format!(
+                "Invalid READINGS_RETENTION_DAYS '{}', expected a positive number of days",
+                days
+            ));
+            None
+        }
+    }
+}
+
+/// Reads the optional length of the windows readings are compressed in, in seconds
+///
+/// # Arguments
+///
+/// * `problems` - Problems found so far
+fn compression_secs(problems: &mut Vec<String>) -> Option<u64> {
+    env::var("READINGS_COMPRESSION_SECS").ok()?;
+
+    Some(seconds("READINGS_COMPRESSION_SECS", 3600, problems))
+}
+
+/// Reads the prefix of every redis key, empty if unset. Glob characters are rejected, as the
+/// prefix is part of `SCAN` patterns.
+///
+/// # Arguments
+///
+/// * `problems` - Problems found so far
+fn redis_key_prefix(problems: &mut Vec<String>) -> String {
+    let prefix = env::var("REDIS_KEY_PREFIX").unwrap_or_default();
+
+    if prefix
+        .chars()
+        .any(|c| c.is_whitespace() || "*?[]\\".contains(c))
+    {
+        problems.push(format!(
+            "Invalid REDIS_KEY_PREFIX '{}', expected no whitespace or *?[]\\ characters",
+            prefix
+        ));
+        return String::new();
+    }
+
+    prefix
+}
+
+/// Reads the optional minimum interval between two readings of a station, in seconds
+///
+/// # Arguments
+///
+/// * `problems` - Problems found so far
+fn ingest_min_interval_secs(problems: &mut Vec<String>) -> Option<u64> {
+    env::var("INGEST_MIN_INTERVAL_SECS").ok()?;
+
+    Some(seconds("INGEST_MIN_INTERVAL_SECS", 1, problems))
+}
+
+/// Reads the optional length of short login codes
+///
+/// # Arguments
+///
+/// * `problems` - Problems found so far
+fn login_code_length(problems: &mut Vec<String>) -> Option<usize> {
+    let length = env::var("LOGIN_CODE_LENGTH").ok()?;
+
+    match length.parse() {
+        Ok(length) if (6..=16).contains(&length) => Some(length),
+        _ => {
+            problems.push(format!(
+                "Invalid LOGIN_CODE_LENGTH '{}', expected 6 to 16 characters",
+                length
+            ));
+            None
+        }
+    }
+}
+
+/// Reads the redis address from `REDIS_URL` (`host:port` or `redis://host:port`)
+///
+/// # Arguments
+///
+/// * `problems` - Problems found so far
+fn redis_address(problems: &mut Vec<String>) -> String {
+    let url = match env::var("REDIS_URL") {
+        Ok(url) => url,
+        Err(_) => return String::from("127.0.0.1:6379"),
+    };
+
+    let address = url.trim_start_matches("redis://").trim_end_matches('/');
+
+    match address.rsplitn(2, ':').collect::<Vec<_>>()[..] {
+        [port, host] if !host.is_empty() && port.parse::<u16>().is_ok() => address.to_owned(),
+        _ => {
+            problems.push(format!(
+                "Invalid REDIS_URL '{}', expected host:port (e.g. export REDIS_URL=127.0.0.1:6379)",
+                url
+            ));
+            String::new()
+        }
+    }
+}
+
+impl Config {
+    /// Loads the configuration from the `WEATHER_CONFIG` file and the environment.
+    /// Returns every missing or invalid value in a single error.
+    pub fn load() -> Result<Config, ConfigError> {
+        let mut problems = Vec::new();
+
+        apply_file(&mut problems);
+
+        let cookie_secret = match env::var("COOKIE_SECRET_KEY") {
+            Ok(key) => cookie_secret(&key).unwrap_or_else(|e| {
+                problems.push(e);
+                Vec::new()
+            }),
+            Err(_) => {
+                problems.push(format!("COOKIE_SECRET_KEY not set, {}", COOKIE_SECRET_HINT));
+                Vec::new()
+            }
+        };
+        let ips = bind_ips(&mut problems);
+        if env::var_os("WEATHER_PORT").is_none() {
+            problems.push(String::from(
+                "WEATHER_PORT not set, set it with export WEATHER_PORT=443",
+            ));
+        }
+        let port = optional_port("WEATHER_PORT", &mut problems).unwrap_or(0);
+        let url = required(
+            "WEATHER_URL",
+            "set it with export WEATHER_URL=<url>",
+            &mut problems,
+        );
+        let api_key = required(
+            "WEATHER_API_KEY",
+            "generate a new one with export WEATHER_API_KEY=`cat /dev/urandom | head -c 32 | base64`",
+            &mut problems,
+        );
+
+        let config = Config {
+            cookie_secret,
+            ips,
+            port,
+            tls: tls_identity(&mut problems),
+            webauthn_origin: webauthn_origin(&url, &mut problems),
+            url,
+            api_key,
+            redis_address: redis_address(&mut problems),
+            redis_pool_size: count("REDIS_POOL_SIZE", "connections", 1, &mut problems),
+            redis_key_prefix: redis_key_prefix(&mut problems),
+            templates_dir: env::var("TEMPLATES_DIR")
+                .map(|dir| dir.trim_end_matches('/').to_owned())
+                .unwrap_or_else(|_| String::from("./templates")),
+            http_port: optional_port("WEATHER_HTTP_PORT", &mut problems),
+            metrics_port: optional_port("METRICS_PORT", &mut problems),
+            metrics_ip: env::var("METRICS_IP").unwrap_or_else(|_| String::from("127.0.0.1")),
+            retention_days: retention_days(&mut problems),
+            compression_secs: compression_secs(&mut problems),
+            json_limit: body_limit("JSON_LIMIT", limits::DEFAULT_LIMIT, &mut problems),
+            form_limit: body_limit("FORM_LIMIT", limits::DEFAULT_LIMIT, &mut problems),
+            ingest_limit: body_limit("INGEST_LIMIT", limits::DEFAULT_LIMIT, &mut problems),
+            restore_limit: body_limit("RESTORE_LIMIT", 64 * 1024 * 1024, &mut problems),
+            ingest_batch_max: count("INGEST_BATCH_MAX", "readings", 1000, &mut problems),
+            ingest_min_interval_secs: ingest_min_interval_secs(&mut problems),
+            remember_device_days: count("REMEMBER_DEVICE_DAYS", "days", 30, &mut problems),
+            ingest_legacy_format: flag("INGEST_LEGACY_FORMAT", false, &mut problems),
+            cors_origins: cors_origins(&mut problems),
+            require_redis: flag("REQUIRE_REDIS", true, &mut problems),
+            station_stale_secs: seconds("STATION_STALE_SECS", 900, &mut problems),
+            register_ttl_secs: seconds("REGISTER_TTL_SECS", 3600, &mut problems),
+            email_change_ttl_secs: seconds("EMAIL_CHANGE_TTL_SECS", 3600, &mut problems),
+            login_ttl_secs: seconds("LOGIN_TTL_SECS", 600, &mut problems),
+            login_code_length: login_code_length(&mut problems),
+            validate_mx: flag("VALIDATE_MX", false, &mut problems),
+            session: session_config(&mut problems),
+        };
+
+        match problems.is_empty() {
+            true => Ok(config),
+            false => Err(ConfigError(problems)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cookie_secret_lengths() {
+        for len in COOKIE_SECRET_LENS.iter() {
+            let key = base64::encode(&vec![7u8; *len]);
+            assert_eq!(cookie_secret(&key), Ok(vec![7u8; *len]));
+        }
+
+        for len in [0, 16, 31, 33, 48, 63, 65, 128].iter() {
+            let key = base64::encode(&vec![7u8; *len]);
+            assert!(cookie_secret(&key).is_err(), "{} bytes accepted", len);
+        }
+    }
+
+    #[test]
+    fn cookie_secret_must_be_base64() {
+        assert!(cookie_secret("not base64!").is_err());
+        assert!(cookie_secret(&format!(" {}\n", base64::encode(&[1u8; 32]))).is_ok());
+    }
+}
@@ -0,0 +1,40 @@
+//! Documentation for cors module
+//! Includes the CORS policy of the API.
+//!
+//! With `CORS_ALLOWED_ORIGINS` set, the listed origins may call /api/* from the browser with the
+//! session cookie. The HTML routes stay same-origin. /api/annotations also accepts POST and
+//! DELETE.
+//!
+//! The session cookie is `SameSite=Lax` or `Strict` (actix-web 2 can't emit `None`), so browsers
+//! only send it when the frontend is on the same site, e.g. another subdomain.
+use crate::haak::request_id;
+
+use actix_cors::{Cors, CorsFactory};
+use actix_web::http::{header, Method};
+
+/// Creates the CORS middleware allowing credentialed requests from the given origins only
+///
+/// # Arguments
+///
+/// * `origins` - Allowed origins (e.g. `https://dashboard.example.com`)
+///
+/// # Remarks
+/// Without origins every origin is allowed, only wrap it when origins are configured
+pub fn cors(origins: &[String]) -> CorsFactory {
+    origins
+        .iter()
+        .fold(Cors::new(), |cors, origin| cors.allowed_origin(origin))
+        .allowed_methods(vec![Method::GET, Method::POST, Method::DELETE])
+        .allowed_headers(vec![
+            header::ACCEPT,
+            header::CONTENT_TYPE,
+            header::IF_NONE_MATCH,
+        ])
+        .expose_headers(vec![
+            header::ETAG,
+            header::HeaderName::from_static(request_id::HEADER),
+        ])
+        .supports_credentials()
+        .max_age(3600)
+        .finish()
+}
@@ -0,0 +1,49 @@
+//! Documentation for csrf module
+//! Includes CSRF tokens for POST forms.
+//!
+//! A token is stored in the session and rendered into the form as a hidden `csrf_token` field.
+//! POST handlers reject the request with 403 Forbidden if the submitted token doesn't match.
+use crate::haak::auth;
+
+use actix_session::Session;
+
+use rand::rngs::OsRng;
+use rand::RngCore;
+
+/// Session key of the CSRF token
+const SESSION_KEY: &str = "csrf_token";
+
+/// Returns the CSRF token of the session, a new 32 byte token is created if there is none yet.
+///
+/// # Arguments
+///
+/// * `session` - Session containing all CookieSession data
+pub fn token(session: &Session) -> String {
+    if let Some(token) = session.get::<String>(SESSION_KEY).unwrap_or(None) {
+        return token;
+    }
+
+    let mut token = vec![0u8; 32];
+    OsRng.fill_bytes(&mut token);
+    let token = base64::encode_config(&token, base64::URL_SAFE);
+
+    let _ = session.set(SESSION_KEY, &token);
+    token
+}
+
+/// Checks a submitted CSRF token against the token of the session.
+/// Returns false if either token is missing or they don't match.
+///
+/// # Arguments
+///
+/// * `session` - Session containing all CookieSession data
+/// * `submitted` - Token submitted with the form
+pub fn verify(session: &Session, submitted: Option<&str>) -> bool {
+    match (
+        session.get::<String>(SESSION_KEY).unwrap_or(None),
+        submitted,
+    ) {
+        (Some(token), Some(submitted)) => auth::tokens_equal(&token, submitted),
+        _ => false,
+    }
+}
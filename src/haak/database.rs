@@ -1,11 +1,231 @@
 //! Documentation for database module
 //!
 //! Most functions are called from the `actix-web` framework
+use crate::haak::alerts;
+use crate::haak::annotations::Annotation;
+use crate::haak::audit;
+use crate::haak::auth;
+use crate::haak::compression;
+use crate::haak::keys;
+use crate::haak::redis_util::{
+    resp_to_blobs, resp_to_i64, resp_to_json, resp_to_string, resp_to_strings, resp_to_vec,
+};
+use crate::haak::response;
+use crate::haak::sensor;
 use crate::haak::settings;
+use crate::haak::webauthn;
 
-use actix::Addr;
+use actix::{Addr, MailboxError};
 use actix_redis::{Command, RedisActor, RespValue};
+use actix_web::http::StatusCode;
 use actix_web::web::Data;
+use actix_web::{HttpResponse, ResponseError};
+
+use futures::future::try_join_all;
+
+use rand::rngs::OsRng;
+use rand::RngCore;
+
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Errors that can occur while talking to the database
+#[derive(Debug)]
+pub enum DbError {
+    /// The RedisActor could not be reached (mailbox closed or timed out)
+    Mailbox(MailboxError),
+    /// The connection to redis failed
+    Connection(actix_redis::Error),
+    /// Redis replied with an error or a RESP type we did not expect
+    UnexpectedResponse(RespValue),
+}
+
+impl fmt::Display for DbError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DbError::Mailbox(e) => write!(f, "Database mailbox error: {}", e),
+            DbError::Connection(e) => write!(f, "Database connection error: {}", e),
+            DbError::UnexpectedResponse(v) => write!(f, "Unexpected database response: {:?}", v),
+        }
+    }
+}
+
+impl From<MailboxError> for DbError {
+    fn from(e: MailboxError) -> Self {
+        DbError::Mailbox(e)
+    }
+}
+
+impl From<RespValue> for DbError {
+    fn from(res: RespValue) -> Self {
+        DbError::UnexpectedResponse(res)
+    }
+}
+
+impl From<actix_redis::Error> for DbError {
+    fn from(e: actix_redis::Error) -> Self {
+        DbError::Connection(e)
+    }
+}
+
+/// Database errors are logged and rendered as 500 InternalServerError, without leaking details
+impl ResponseError for DbError {
+    fn error_response(&self) -> HttpResponse {
+        error!("{}", self);
+        response::error(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "database_error",
+            "Database error",
+        )
+    }
+}
+
+/// Connections to the database. Commands are spread over the connections round-robin, so a slow
+/// reply (e.g. a long range of readings) only holds up the commands queued on its own connection.
+pub struct Pool {
+    connections: Vec<Addr<RedisActor>>,
+    next: AtomicUsize,
+    /// Length of the windows readings are compressed in, sorted sets when `None`
+    compression: Option<u64>,
+}
+
+impl Pool {
+    /// Starts the connections of the pool on the current arbiter
+    ///
+    /// # Arguments
+    ///
+    /// * `address` - Redis address as `host:port`
+    /// * `size` - Number of connections, at least one is started
+    /// * `compression` - Length of the windows readings are compressed in (seconds), readings are
+    ///   stored in sorted sets when `None`
+    pub fn start(address: &str, size: usize, compression: Option<u64>) -> Pool {
+        Pool {
+            connections: (0..size.max(1))
+                .map(|_| RedisActor::start(address))
+                .collect(),
+            next: AtomicUsize::new(0),
+            compression,
+        }
+    }
+
+    /// Returns the connection the next command is sent on
+    fn connection(&self) -> &Addr<RedisActor> {
+        let next = self.next.fetch_add(1, Ordering::Relaxed);
+        &self.connections[next % self.connections.len()]
+    }
+}
+
+/// Sends a command on a connection and returns the reply.
+/// Redis error replies are turned into `DbError::UnexpectedResponse`.
+///
+/// # Arguments
+///
+/// * `command` - Command to send
+/// * `connection` - Connection to database
+async fn send(command: RespValue, connection: &Addr<RedisActor>) -> Result<RespValue, DbError> {
+    match connection.send(Command(command)).await?? {
+        RespValue::Error(e) => Err(DbError::UnexpectedResponse(RespValue::Error(e))),
+        res => Ok(res),
+    }
+}
+
+/// Sends a command to the database and returns the reply.
+/// Redis error replies are turned into `DbError::UnexpectedResponse`.
+///
+/// # Arguments
+///
+/// * `command` - Command to send
+/// * `redis` - Connection to database
+async fn query(command: RespValue, redis: &Data<Pool>) -> Result<RespValue, DbError> {
+    send(command, redis.connection()).await
+}
+
+/// Sends several commands to the database at once and returns their replies in order.
+/// The commands are queued on the same connection together, so they cost a single round trip
+/// and redis runs them in order.
+///
+/// # Arguments
+///
+/// * `commands` - Commands to send
+/// * `redis` - Connection to database
+async fn pipeline(commands: Vec<RespValue>, redis: &Data<Pool>) -> Result<Vec<RespValue>, DbError> {
+    let connection = redis.connection();
+
+    try_join_all(
+        commands
+            .into_iter()
+            .map(|command| send(command, connection)),
+    )
+    .await
+}
+
+/// Pings the database, fails if redis does not reply with `PONG` within the timeout.
+///
+/// # Arguments
+///
+/// * `timeout` - Time to wait for the reply
+/// * `redis` - Connection to database
+pub async fn ping(timeout: Duration, redis: &Data<Pool>) -> Result<(), DbError> {
+    match redis
+        .connection()
+        .send(Command(resp_array!["PING"]))
+        .timeout(timeout)
+        .await??
+    {
+        RespValue::SimpleString(ref s) if s == "PONG" => Ok(()),
+        res => Err(DbError::UnexpectedResponse(res)),
+    }
+}
+
+/// Finds all keys matching a pattern, using `SCAN` so redis isn't blocked on large datasets.
+/// The pattern is prefixed like every key, the keys found include the prefix.
+///
+/// # Arguments
+///
+/// * `pattern` - Glob-style pattern (e.g. `settings:*`)
+/// * `redis` - Connection to database
+async fn scan_keys(pattern: &str, redis: &Data<Pool>) -> Result<Vec<String>, DbError> {
+    let pattern = keys::key(pattern);
+    let mut keys = Vec::new();
+    let mut cursor = String::from("0");
+
+    loop {
+        let res = query(
+            resp_array![
+                "SCAN",
+                cursor.as_str(),
+                "MATCH",
+                pattern.as_str(),
+                "COUNT",
+                "100"
+            ],
+            redis,
+        )
+        .await?;
+
+        // Reply is the next cursor and the keys found
+        let mut res = resp_to_vec(res)?.into_iter();
+        let (next, found) = match (res.next(), res.next()) {
+            (Some(next), Some(found)) => (next, found),
+            _ => return Err(DbError::UnexpectedResponse(RespValue::Nil)),
+        };
+
+        for key in resp_to_vec(found)? {
+            keys.extend(resp_to_string(key)?);
+        }
+
+        cursor = match resp_to_string(next)? {
+            Some(cursor) => cursor,
+            None => return Err(DbError::UnexpectedResponse(RespValue::Nil)),
+        };
+
+        if cursor == "0" {
+            return Ok(keys);
+        }
+    }
+}
 
 /// Checks if a user exists in the database.
 ///
@@ -13,197 +233,2614 @@ use actix_web::web::Data;
 ///
 /// * `email` - Email address to check
 /// * `redis` - Connection to database
-pub async fn user_exists(email: &String, redis: &Data<Addr<RedisActor>>) -> bool {
-    let res = redis
-        .send(Command(resp_array!["EXISTS", "user:".to_owned() + &email]))
-        .await
-        .expect("Database error")
-        .unwrap();
+pub async fn user_exists(email: &str, redis: &Data<Pool>) -> Result<bool, DbError> {
+    let res = query(
+        resp_array!["EXISTS", keys::key(&format!("user:{}", email))],
+        redis,
+    )
+    .await?;
 
-    res == RespValue::Integer(1)
+    Ok(resp_to_i64(res)? == 1)
 }
 
-/// Checks if a user exists in the database.
+/// Retrieves the role of a user, `None` if the user doesn't exist.
 ///
 /// # Arguments
 ///
 /// * `email` - Email address to check
 /// * `redis` - Connection to database
-pub async fn user_is_admin(email: &String, redis: &Data<Addr<RedisActor>>) -> bool {
-    let res = redis
-        .send(Command(resp_array!["GET", "user:".to_owned() + &email]))
-        .await
-        .expect("Database error")
-        .unwrap();
+pub async fn user_role(email: &str, redis: &Data<Pool>) -> Result<Option<auth::Role>, DbError> {
+    let res = query(
+        resp_array!["GET", keys::key(&format!("user:{}", email))],
+        redis,
+    )
+    .await?;
+
+    Ok(resp_to_string(res)?.map(|role| auth::Role::from_stored(&role)))
+}
+
+/// Checks if a user is an admin.
+///
+/// # Arguments
+///
+/// * `email` - Email address to check
+/// * `redis` - Connection to database
+pub async fn user_is_admin(email: &str, redis: &Data<Pool>) -> Result<bool, DbError> {
+    Ok(user_role(email, redis).await? == Some(auth::Role::Admin))
+}
+
+/// Sets the role of a user
+///
+/// # Arguments
+///
+/// * `email` - Email address of the user
+/// * `role` - New role
+/// * `redis` - Connection to database
+pub async fn set_role(email: &str, role: auth::Role, redis: &Data<Pool>) -> Result<(), DbError> {
+    query(
+        resp_array![
+            "SET",
+            keys::key(&format!("user:{}", email)),
+            role.as_stored()
+        ],
+        redis,
+    )
+    .await?;
 
-    res == RespValue::BulkString(vec![97, 100, 109, 105, 110])
+    Ok(())
 }
 
-/// Registers a new user in the system, adds the email and token to the database.
+/// Stores a pending registration, replacing the previous one of the email. Fails without
+/// changes if the token is taken by another registration. `KEYS[1]` is the token key,
+/// `KEYS[2]` the email index, `ARGV` holds the email, token, expiry and the prefix of token keys.
+const REGISTER_SCRIPT: &str = r#"
+if not redis.call('SET', KEYS[1], ARGV[1], 'EX', ARGV[3], 'NX') then
+    return 0
+end
+local previous = redis.call('GET', KEYS[2])
+if previous and previous ~= ARGV[2] then
+    redis.call('DEL', ARGV[4] .. previous)
+end
+redis.call('SET', KEYS[2], ARGV[2], 'EX', ARGV[3])
+return 1
+"#;
+
+/// Registers a new user in the system, adds the email and token to the database. The token is
+/// also indexed by email as `register_email:<email>` so the registration can be resent.
+/// Returns false if the token is already taken, the caller should retry with a new token.
 ///
 /// # Arguments
 ///
 /// * `email` - Email address to register
 /// * `token` - Challenge token
+/// * `ttl` - Seconds until the registration expires
 /// * `redis` - Connection to database
-pub async fn register_email(email: &String, token: &String, redis: &Data<Addr<RedisActor>>) {
-    redis
-        .send(Command(resp_array![
-            "SET",
-            "register:".to_owned() + &token,
-            email.clone()
-        ]))
-        .await
-        .expect("Database error")
-        .unwrap();
+///
+/// # Remarks
+/// Only the latest registration of an email is pending, the token of an earlier registration is
+/// removed in the same script, so concurrent registrations leave exactly one valid link
+pub async fn register_email(
+    email: &str,
+    token: &str,
+    ttl: u64,
+    redis: &Data<Pool>,
+) -> Result<bool, DbError> {
+    let res = query(
+        resp_array![
+            "EVAL",
+            REGISTER_SCRIPT,
+            "2",
+            keys::key(&format!("register:{}", token)),
+            keys::key(&format!("register_email:{}", email)),
+            email,
+            token,
+            ttl.to_string(),
+            keys::key("register:")
+        ],
+        redis,
+    )
+    .await?;
 
-    // Set the key to expire in 1 hour
-    redis
-        .send(Command(resp_array![
-            "EXPIRE",
-            "register:".to_owned() + &token,
-            3600
-        ]))
-        .await
-        .expect("Database error")
-        .unwrap();
+    Ok(resp_to_i64(res)? == 1)
 }
 
-/// Check if token is in pending registrations in database
+/// Resets the expiry of a pending registration
 ///
 /// # Arguments
 ///
+/// * `email` - Email address of the pending registration
 /// * `token` - Challenge token
+/// * `ttl` - Seconds until the registration expires
 /// * `redis` - Connection to database
-pub async fn register_exists(token: &String, redis: &Data<Addr<RedisActor>>) -> Option<String> {
-    let res = redis
-        .send(Command(resp_array!["GET", "register:".to_owned() + &token]))
-        .await
-        .expect("Database error")
-        .unwrap();
+pub async fn register_refresh(
+    email: &str,
+    token: &str,
+    ttl: u64,
+    redis: &Data<Pool>,
+) -> Result<(), DbError> {
+    pipeline(
+        vec![
+            resp_array![
+                "EXPIRE",
+                keys::key(&format!("register:{}", token)),
+                ttl.to_string()
+            ],
+            resp_array![
+                "EXPIRE",
+                keys::key(&format!("register_email:{}", email)),
+                ttl.to_string()
+            ],
+        ],
+        redis,
+    )
+    .await?;
 
-    match res {
-        RespValue::BulkString(val) => Some(String::from_utf8(val).unwrap()),
+    Ok(())
+}
+
+/// Retrieves the token of the pending registration of an email, `None` if there is none
+///
+/// # Arguments
+///
+/// * `email` - Email address of the pending registration
+/// * `redis` - Connection to database
+pub async fn register_pending(email: &str, redis: &Data<Pool>) -> Result<Option<String>, DbError> {
+    let token = match resp_to_string(
+        query(
+            resp_array!["GET", keys::key(&format!("register_email:{}", email))],
+            redis,
+        )
+        .await?,
+    )? {
+        Some(token) => token,
+        None => return Ok(None),
+    };
+
+    // The index may outlive a registration that was replaced or completed
+    Ok(match register_exists(&token, redis).await? {
+        Some(pending) if pending == email => Some(token),
         _ => None,
-    }
+    })
+}
+
+/// Check if token is in pending registrations in database
+///
+/// # Arguments
+///
+/// * `token` - Challenge token
+/// * `redis` - Connection to database
+pub async fn register_exists(token: &str, redis: &Data<Pool>) -> Result<Option<String>, DbError> {
+    let res = query(
+        resp_array!["GET", keys::key(&format!("register:{}", token))],
+        redis,
+    )
+    .await?;
+
+    Ok(resp_to_string(res)?)
 }
 
 /// Remove a pending registration from the database
 ///
 /// # Arguments
 ///
+/// * `email` - Email address of the pending registration
 /// * `token` - Challenge token
 /// * `redis` - Connection to database
-pub async fn register_remove(token: &String, redis: &Data<Addr<RedisActor>>) {
-    redis
-        .send(Command(resp_array!["DEL", "register:".to_owned() + &token]))
-        .await
-        .expect("Database error")
-        .unwrap();
+pub async fn register_remove(email: &str, token: &str, redis: &Data<Pool>) -> Result<(), DbError> {
+    query(
+        resp_array!["DEL", keys::key(&format!("register:{}", token))],
+        redis,
+    )
+    .await?;
+    query(
+        resp_array!["DEL", keys::key(&format!("register_email:{}", email))],
+        redis,
+    )
+    .await?;
+
+    Ok(())
 }
 
-/// Adds an user to the database and adds the default settings to the database.
+/// Stores a pending login and indexes its token in the `login_tokens:<email>` set of the user,
+/// which expires along with the newest login
+const LOGIN_ADD_SCRIPT: &str = r#"
+redis.call('SET', KEYS[1], ARGV[1], 'EX', ARGV[3])
+redis.call('SADD', KEYS[2], ARGV[2])
+redis.call('EXPIRE', KEYS[2], ARGV[3])
+return 1
+"#;
+
+/// Stores a pending login in the database, adds the token and email to the database. The token is
+/// also indexed by email, so `login_purge` can find it.
 ///
 /// # Arguments
 ///
-/// * `email` - Email address
+/// * `email` - Email address trying to log in
+/// * `token` - Challenge token
+/// * `ttl` - Seconds until the login expires
 /// * `redis` - Connection to database
-pub async fn user_add(email: &String, redis: &Data<Addr<RedisActor>>) {
-    redis
-        .send(Command(resp_array![
-            "MSET",
-            // User
-            format!("user:{}", email),
-            "",
-            // Temperature
-            format!("settings:{}:units:temperature", email),
-            "Celsius",
-            // Pressure
-            format!("settings:{}:units:pressure", email),
-            "Bar",
-            // Theme
-            format!("settings:{}:theme", email),
-            "Light",
-            // Timeframe
-            format!("settings:{}:timeframe", email),
-            "Week"
-        ]))
-        .await
-        .expect("Database error")
-        .unwrap();
+pub async fn login_add(
+    email: &str,
+    token: &str,
+    ttl: u64,
+    redis: &Data<Pool>,
+) -> Result<(), DbError> {
+    query(
+        resp_array![
+            "EVAL",
+            LOGIN_ADD_SCRIPT,
+            "2",
+            keys::key(&format!("login:{}", token)),
+            keys::key(&format!("login_tokens:{}", email)),
+            email,
+            token,
+            ttl.to_string()
+        ],
+        redis,
+    )
+    .await?;
+
+    Ok(())
 }
 
-/// Retrieves settings from the database for the corresponding user
+/// Removes every pending login token of a user, its index and the short login code
+const LOGIN_PURGE_SCRIPT: &str = r#"
+for _, token in ipairs(redis.call('SMEMBERS', KEYS[1])) do
+    redis.call('DEL', ARGV[1] .. token)
+end
+redis.call('DEL', KEYS[1], KEYS[2])
+return 1
+"#;
+
+/// Removes every pending login of a user: the links and the short login code. Used once too many
+/// attempts failed, so a fresh login has to be requested.
 ///
 /// # Arguments
 ///
-/// * `email` - Email address
+/// * `email` - Email address of the user
 /// * `redis` - Connection to database
+pub async fn login_purge(email: &str, redis: &Data<Pool>) -> Result<(), DbError> {
+    query(
+        resp_array![
+            "EVAL",
+            LOGIN_PURGE_SCRIPT,
+            "2",
+            keys::key(&format!("login_tokens:{}", email)),
+            keys::key(&format!("login_code:{}", email)),
+            keys::key("login:")
+        ],
+        redis,
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// Check if token is in pending logins in database, returns the corresponding email
 ///
-/// # Remarks
-/// Returns an array containing [temperature, pressure, theme, timeframe]
-///
-/// TODO: Potentially return a struct instead of array.
-pub async fn settings_get(email: &String, redis: &Data<Addr<RedisActor>>) -> Vec<String> {
-    let res = redis
-        .send(Command(resp_array![
-            "MGET",
-            format!("settings:{}:units:temperature", email),
-            format!("settings:{}:units:pressure", email),
-            format!("settings:{}:theme", email),
-            format!("settings:{}:timeframe", email)
-        ]))
-        .await
-        .expect("Database error")
-        .unwrap();
+/// # Arguments
+///
+/// * `token` - Challenge token
+/// * `redis` - Connection to database
+pub async fn login_exists(token: &str, redis: &Data<Pool>) -> Result<Option<String>, DbError> {
+    let res = query(
+        resp_array!["GET", keys::key(&format!("login:{}", token))],
+        redis,
+    )
+    .await?;
 
-    let res = match res {
-        RespValue::Array(val) => Some(val),
-        _ => None,
+    Ok(resp_to_string(res)?)
+}
+
+/// Remove a pending login from the database
+///
+/// # Arguments
+///
+/// * `token` - Challenge token
+/// * `redis` - Connection to database
+pub async fn login_remove(token: &str, redis: &Data<Pool>) -> Result<(), DbError> {
+    query(
+        resp_array!["DEL", keys::key(&format!("login:{}", token))],
+        redis,
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// Stores the short login code of a pending login, along with the token of its link so logging in
+/// with either removes both. A new code replaces the previous one of the user.
+///
+/// # Arguments
+///
+/// * `email` - Email address of the user
+/// * `code` - Short login code
+/// * `token` - Challenge token of the login link
+/// * `ttl` - Seconds until the code expires
+/// * `redis` - Connection to database
+pub async fn login_code_add(
+    email: &str,
+    code: &str,
+    token: &str,
+    ttl: u64,
+    redis: &Data<Pool>,
+) -> Result<(), DbError> {
+    query(
+        resp_array![
+            "SET",
+            keys::key(&format!("login_code:{}", email)),
+            format!("{}:{}", code, token),
+            "EX",
+            ttl.to_string()
+        ],
+        redis,
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// Retrieves the short login code of a user and the token of its link, `None` if there is no
+/// pending code
+///
+/// # Arguments
+///
+/// * `email` - Email address of the user
+/// * `redis` - Connection to database
+pub async fn login_code_get(
+    email: &str,
+    redis: &Data<Pool>,
+) -> Result<Option<(String, String)>, DbError> {
+    let res = query(
+        resp_array!["GET", keys::key(&format!("login_code:{}", email))],
+        redis,
+    )
+    .await?;
+
+    Ok(resp_to_string(res)?.and_then(|value| {
+        let mut parts = value.splitn(2, ':');
+        Some((parts.next()?.to_owned(), parts.next()?.to_owned()))
+    }))
+}
+
+/// Removes the short login code of a user
+///
+/// # Arguments
+///
+/// * `email` - Email address of the user
+/// * `redis` - Connection to database
+pub async fn login_code_remove(email: &str, redis: &Data<Pool>) -> Result<(), DbError> {
+    query(
+        resp_array!["DEL", keys::key(&format!("login_code:{}", email))],
+        redis,
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// Stores a pending email change in the database
+///
+/// # Arguments
+///
+/// * `old` - Current email address of the user
+/// * `new` - New email address, which the challenge is sent to
+/// * `token` - Challenge token
+/// * `ttl` - Seconds until the change expires
+/// * `redis` - Connection to database
+pub async fn email_change_add(
+    old: &str,
+    new: &str,
+    token: &str,
+    ttl: u64,
+    redis: &Data<Pool>,
+) -> Result<(), DbError> {
+    pipeline(
+        vec![
+            resp_array![
+                "HMSET",
+                keys::key(&format!("email_change:{}", token)),
+                "old",
+                old,
+                "new",
+                new
+            ],
+            resp_array![
+                "EXPIRE",
+                keys::key(&format!("email_change:{}", token)),
+                ttl.to_string()
+            ],
+        ],
+        redis,
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// Retrieves a pending email change as `(old, new)`, `None` if the token is unknown
+///
+/// # Arguments
+///
+/// * `token` - Challenge token
+/// * `redis` - Connection to database
+pub async fn email_change_get(
+    token: &str,
+    redis: &Data<Pool>,
+) -> Result<Option<(String, String)>, DbError> {
+    let res = query(
+        resp_array![
+            "HMGET",
+            keys::key(&format!("email_change:{}", token)),
+            "old",
+            "new"
+        ],
+        redis,
+    )
+    .await?;
+
+    let mut values = resp_to_vec(res)?.into_iter().map(resp_to_string);
+
+    match (values.next().transpose()?, values.next().transpose()?) {
+        (Some(Some(old)), Some(Some(new))) => Ok(Some((old, new))),
+        _ => Ok(None),
+    }
+}
+
+/// Removes a pending email change from the database
+///
+/// # Arguments
+///
+/// * `token` - Challenge token
+/// * `redis` - Connection to database
+pub async fn email_change_remove(token: &str, redis: &Data<Pool>) -> Result<(), DbError> {
+    query(
+        resp_array!["DEL", keys::key(&format!("email_change:{}", token))],
+        redis,
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// Adds a session ID to the active sessions of a user, scored by its creation time. Expired
+/// sessions are dropped and, with a maximum, the oldest sessions beyond it are evicted. The set
+/// expires with the newest session.
+///
+/// # Arguments
+///
+/// * `email` - Email address of the user
+/// * `sid` - Session ID
+/// * `created` - Unix timestamp of the login
+/// * `ttl` - Lifetime of the session in seconds
+/// * `max` - Maximum number of active sessions, unlimited if `None`
+/// * `redis` - Connection to database
+pub async fn session_add(
+    email: &str,
+    sid: &str,
+    created: u64,
+    ttl: u16,
+    max: Option<usize>,
+    redis: &Data<Pool>,
+) -> Result<(), DbError> {
+    let key = keys::key(&format!("sessions:{}", email));
+    let expired = created.saturating_sub(u64::from(ttl));
+
+    let mut commands = vec![
+        resp_array!["ZREMRANGEBYSCORE", &key, "-inf", format!("({}", expired)],
+        resp_array!["ZADD", &key, created.to_string(), sid],
+    ];
+    if let Some(max) = max {
+        // Keeps the newest `max` sessions, including the new one
+        commands.push(resp_array![
+            "ZREMRANGEBYRANK",
+            &key,
+            "0",
+            format!("-{}", max + 1)
+        ]);
     }
-    .unwrap();
+    commands.push(resp_array!["EXPIRE", &key, ttl.to_string()]);
+
+    pipeline(commands, redis).await?;
 
-    res.iter()
-        .filter(|_s| match _s {
-            RespValue::BulkString(_s) => true,
-            _ => false,
-        })
-        .map(|s| match s {
-            RespValue::BulkString(s) => String::from_utf8(s.to_vec()).unwrap(),
-            _ => String::from(""),
-        })
-        .collect()
+    Ok(())
 }
 
-/// Saves settings for the corresponding user in the database
+/// Checks if a session ID is one of the active sessions of a user
 ///
 /// # Arguments
 ///
-/// * `email` - Email address
-/// * `data` - Data containing settings
+/// * `email` - Email address of the user
+/// * `sid` - Session ID
 /// * `redis` - Connection to database
-pub async fn settings_set(
-    email: &String,
-    data: &settings::SettingsData,
-    redis: &Data<Addr<RedisActor>>,
-) {
-    redis
-        .send(Command(resp_array![
-            "MSET",
-            // Temperature
-            format!("settings:{}:units:temperature", email),
-            data.temperature.clone(),
-            // Pressure
-            format!("settings:{}:units:pressure", email),
-            data.pressure.clone(),
-            // Theme
-            format!("settings:{}:theme", email),
-            data.theme.clone(),
-            // Timeframe
-            format!("settings:{}:timeframe", email),
-            data.timeframe.clone()
-        ]))
-        .await
-        .expect("Database error")
-        .unwrap();
+pub async fn session_exists(email: &str, sid: &str, redis: &Data<Pool>) -> Result<bool, DbError> {
+    let res = query(
+        resp_array!["ZSCORE", keys::key(&format!("sessions:{}", email)), sid],
+        redis,
+    )
+    .await?;
+
+    Ok(res != RespValue::Nil)
+}
+
+/// Removes a session ID from the active sessions of a user
+///
+/// # Arguments
+///
+/// * `email` - Email address of the user
+/// * `sid` - Session ID
+/// * `redis` - Connection to database
+pub async fn session_remove(email: &str, sid: &str, redis: &Data<Pool>) -> Result<(), DbError> {
+    query(
+        resp_array!["ZREM", keys::key(&format!("sessions:{}", email)), sid],
+        redis,
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// Removes all active sessions of a user
+///
+/// # Arguments
+///
+/// * `email` - Email address of the user
+/// * `redis` - Connection to database
+pub async fn sessions_clear(email: &str, redis: &Data<Pool>) -> Result<(), DbError> {
+    query(
+        resp_array!["DEL", keys::key(&format!("sessions:{}", email))],
+        redis,
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// Remembers a device of a user, which may log in without email challenge until the key expires
+///
+/// # Arguments
+///
+/// * `email` - Email address of the user
+/// * `id` - ID of the device
+/// * `ttl` - Seconds the device is remembered
+/// * `redis` - Connection to database
+pub async fn device_add(
+    email: &str,
+    id: &str,
+    ttl: u64,
+    redis: &Data<Pool>,
+) -> Result<(), DbError> {
+    query(
+        resp_array![
+            "SET",
+            keys::key(&format!("device:{}:{}", email, id)),
+            "1",
+            "EX",
+            ttl.to_string()
+        ],
+        redis,
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// Returns true if the device of the user is remembered
+///
+/// # Arguments
+///
+/// * `email` - Email address of the user
+/// * `id` - ID of the device
+/// * `redis` - Connection to database
+pub async fn device_exists(email: &str, id: &str, redis: &Data<Pool>) -> Result<bool, DbError> {
+    let res = query(
+        resp_array!["EXISTS", keys::key(&format!("device:{}:{}", email, id))],
+        redis,
+    )
+    .await?;
+
+    Ok(resp_to_i64(res)? == 1)
+}
+
+/// Forgets all remembered devices of a user
+///
+/// # Arguments
+///
+/// * `email` - Email address of the user
+/// * `redis` - Connection to database
+pub async fn devices_clear(email: &str, redis: &Data<Pool>) -> Result<(), DbError> {
+    let keys = scan_keys(&format!("device:{}:*", keys::escape(email)), redis).await?;
+    if keys.is_empty() {
+        return Ok(());
+    }
+
+    let mut command = vec![RespValue::from("DEL")];
+    command.extend(keys.into_iter().map(RespValue::from));
+
+    query(RespValue::Array(command), redis).await?;
+
+    Ok(())
+}
+
+/// Counts the active sessions of all users
+///
+/// # Arguments
+///
+/// * `redis` - Connection to database
+pub async fn sessions_count(redis: &Data<Pool>) -> Result<i64, DbError> {
+    let mut count = 0;
+
+    for key in scan_keys("sessions:*", redis).await? {
+        count += resp_to_i64(query(resp_array!["ZCARD", key], redis).await?)?;
+    }
+
+    Ok(count)
+}
+
+/// Counts an attempt and starts the window of a rate limit key without one, in a single script
+/// so the counter can't be left without expiry
+const RATE_LIMIT_SCRIPT: &str = r#"
+local count = redis.call('INCR', KEYS[1])
+if redis.call('TTL', KEYS[1]) < 0 then
+    redis.call('EXPIRE', KEYS[1], ARGV[1])
+end
+return count
+"#;
+
+/// Counts an attempt for a rate limit key, returns the number of attempts in the current window.
+/// The first attempt starts the window, after which the counter expires.
+///
+/// # Arguments
+///
+/// * `key` - Rate limit key (e.g. `login:ip:127.0.0.1`)
+/// * `window` - Length of the window in seconds
+/// * `redis` - Connection to database
+pub async fn rate_limit_incr(key: &str, window: u64, redis: &Data<Pool>) -> Result<i64, DbError> {
+    Ok(resp_to_i64(
+        query(
+            resp_array![
+                "EVAL",
+                RATE_LIMIT_SCRIPT,
+                "1",
+                keys::key(&format!("ratelimit:{}", key)),
+                window.to_string()
+            ],
+            redis,
+        )
+        .await?,
+    )?)
+}
+
+/// Retrieves the number of attempts for a rate limit key in the current window
+///
+/// # Arguments
+///
+/// * `key` - Rate limit key (e.g. `verify:failures:ip:127.0.0.1`)
+/// * `redis` - Connection to database
+pub async fn rate_limit_get(key: &str, redis: &Data<Pool>) -> Result<i64, DbError> {
+    let res = query(
+        resp_array!["GET", keys::key(&format!("ratelimit:{}", key))],
+        redis,
+    )
+    .await?;
+
+    Ok(resp_to_string(res)?
+        .and_then(|count| count.parse().ok())
+        .unwrap_or(0))
+}
+
+/// Adds an user to the database and adds the default settings to the database.
+///
+/// # Arguments
+///
+/// * `email` - Email address
+/// * `redis` - Connection to database
+pub async fn user_add(email: &str, redis: &Data<Pool>) -> Result<(), DbError> {
+    let defaults = settings::UserSettings::from_env();
+
+    query(
+        resp_array![
+            "MSET",
+            // User
+            keys::key(&format!("user:{}", email)),
+            auth::Role::User.as_stored(),
+            // Temperature
+            keys::key(&format!("settings:{}:units:temperature", email)),
+            defaults.temperature,
+            // Pressure
+            keys::key(&format!("settings:{}:units:pressure", email)),
+            defaults.pressure,
+            // Humidity
+            keys::key(&format!("settings:{}:units:humidity", email)),
+            defaults.humidity,
+            // Theme
+            keys::key(&format!("settings:{}:theme", email)),
+            defaults.theme,
+            // Timeframe
+            keys::key(&format!("settings:{}:timeframe", email)),
+            defaults.timeframe,
+            // Timezone
+            keys::key(&format!("settings:{}:timezone", email)),
+            defaults.timezone,
+            // Locale
+            keys::key(&format!("settings:{}:locale", email)),
+            defaults.locale,
+            // Chart type
+            keys::key(&format!("settings:{}:chart_type", email)),
+            defaults.chart_type,
+            // Smoothing
+            keys::key(&format!("settings:{}:smoothing", email)),
+            defaults.smoothing.to_string(),
+            // Visible metrics
+            keys::key(&format!("settings:{}:show:temperature", email)),
+            defaults.show_temperature.to_string(),
+            keys::key(&format!("settings:{}:show:pressure", email)),
+            defaults.show_pressure.to_string(),
+            keys::key(&format!("settings:{}:show:humidity", email)),
+            defaults.show_humidity.to_string()
+        ],
+        redis,
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// Lists the email addresses of all users in the database
+///
+/// # Arguments
+///
+/// * `redis` - Connection to database
+pub async fn list_users(redis: &Data<Pool>) -> Result<Vec<String>, DbError> {
+    let keys = scan_keys("user:*", redis).await?;
+
+    Ok(keys
+        .into_iter()
+        .filter(|key| !key.ends_with(":pwhash"))
+        .map(|key| keys::strip(&key).trim_start_matches("user:").to_owned())
+        .collect())
+}
+
+/// Stores the password of a user as a salted Argon2 hash.
+///
+/// # Arguments
+///
+/// * `email` - Email address
+/// * `password` - Plain text password
+/// * `redis` - Connection to database
+pub async fn set_password(email: &str, password: &str, redis: &Data<Pool>) -> Result<(), DbError> {
+    let mut salt = [0u8; 16];
+    OsRng.fill_bytes(&mut salt);
+
+    let config = argon2::Config {
+        variant: argon2::Variant::Argon2id,
+        ..argon2::Config::default()
+    };
+    let hash = argon2::hash_encoded(password.as_bytes(), &salt, &config)
+        .expect("Invalid argon2 configuration");
+
+    query(
+        resp_array!["SET", keys::key(&format!("user:{}:pwhash", email)), hash],
+        redis,
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// Verifies the password of a user against the stored hash.
+/// Returns false if the user has no password.
+///
+/// # Arguments
+///
+/// * `email` - Email address
+/// * `password` - Plain text password
+/// * `redis` - Connection to database
+pub async fn verify_password(
+    email: &str,
+    password: &str,
+    redis: &Data<Pool>,
+) -> Result<bool, DbError> {
+    let res = query(
+        resp_array!["GET", keys::key(&format!("user:{}:pwhash", email))],
+        redis,
+    )
+    .await?;
+
+    Ok(match resp_to_string(res)? {
+        Some(hash) => argon2::verify_encoded(&hash, password.as_bytes()).unwrap_or(false),
+        None => false,
+    })
+}
+
+/// Retrieves the stored password hash of a user, `None` if the user has no password
+///
+/// # Arguments
+///
+/// * `email` - Email address
+/// * `redis` - Connection to database
+pub async fn password_hash(email: &str, redis: &Data<Pool>) -> Result<Option<String>, DbError> {
+    let res = query(
+        resp_array!["GET", keys::key(&format!("user:{}:pwhash", email))],
+        redis,
+    )
+    .await?;
+
+    Ok(resp_to_string(res)?)
+}
+
+/// Stores an already hashed password of a user, as retrieved with `password_hash`
+///
+/// # Arguments
+///
+/// * `email` - Email address
+/// * `hash` - Encoded Argon2 hash
+/// * `redis` - Connection to database
+pub async fn password_hash_set(email: &str, hash: &str, redis: &Data<Pool>) -> Result<(), DbError> {
+    query(
+        resp_array!["SET", keys::key(&format!("user:{}:pwhash", email)), hash],
+        redis,
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// Stores the TOTP secret of a user. TOTP is not enabled until `set_totp_enabled` is called.
+///
+/// # Arguments
+///
+/// * `email` - Email address
+/// * `secret` - Base32 encoded secret
+/// * `redis` - Connection to database
+pub async fn set_totp_secret(email: &str, secret: &str, redis: &Data<Pool>) -> Result<(), DbError> {
+    query(
+        resp_array!["SET", keys::key(&format!("totp:{}:secret", email)), secret],
+        redis,
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// Retrieves the TOTP secret of a user, `None` if the user never enrolled.
+///
+/// # Arguments
+///
+/// * `email` - Email address
+/// * `redis` - Connection to database
+pub async fn get_totp_secret(email: &str, redis: &Data<Pool>) -> Result<Option<String>, DbError> {
+    let res = query(
+        resp_array!["GET", keys::key(&format!("totp:{}:secret", email))],
+        redis,
+    )
+    .await?;
+
+    Ok(resp_to_string(res)?)
+}
+
+/// Enables TOTP as second factor for a user.
+///
+/// # Arguments
+///
+/// * `email` - Email address
+/// * `redis` - Connection to database
+pub async fn set_totp_enabled(email: &str, redis: &Data<Pool>) -> Result<(), DbError> {
+    query(
+        resp_array!["SET", keys::key(&format!("totp:{}:enabled", email)), "1"],
+        redis,
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// Checks if TOTP is enabled as second factor for a user.
+///
+/// # Arguments
+///
+/// * `email` - Email address
+/// * `redis` - Connection to database
+pub async fn totp_enabled(email: &str, redis: &Data<Pool>) -> Result<bool, DbError> {
+    let res = query(
+        resp_array!["EXISTS", keys::key(&format!("totp:{}:enabled", email))],
+        redis,
+    )
+    .await?;
+
+    Ok(resp_to_i64(res)? == 1)
+}
+
+/// Retrieves the WebAuthn credentials of a user, empty if the user has none
+///
+/// # Arguments
+///
+/// * `email` - Email address
+/// * `redis` - Connection to database
+pub async fn webauthn_credentials(
+    email: &str,
+    redis: &Data<Pool>,
+) -> Result<Vec<webauthn::Credential>, DbError> {
+    let res = query(
+        resp_array!["HVALS", keys::key(&format!("webauthn:{}", email))],
+        redis,
+    )
+    .await?;
+
+    Ok(resp_to_json(res)?)
+}
+
+/// Stores a WebAuthn credential of a user, replacing the stored credential with the same ID
+///
+/// # Arguments
+///
+/// * `email` - Email address
+/// * `credential` - Credential to store
+/// * `redis` - Connection to database
+pub async fn webauthn_set(
+    email: &str,
+    credential: &webauthn::Credential,
+    redis: &Data<Pool>,
+) -> Result<(), DbError> {
+    query(
+        resp_array![
+            "HSET",
+            keys::key(&format!("webauthn:{}", email)),
+            credential.id.clone(),
+            serde_json::to_string(credential).unwrap()
+        ],
+        redis,
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// Removes an user and all of their settings, TOTP and WebAuthn data and remembered devices from
+/// the database.
+///
+/// # Arguments
+///
+/// * `email` - Email address
+/// * `redis` - Connection to database
+pub async fn user_delete(email: &str, redis: &Data<Pool>) -> Result<(), DbError> {
+    let mut keys = user_keys(email);
+    keys.extend(scan_keys(&format!("device:{}:*", keys::escape(email)), redis).await?);
+    keys.push(keys::key(&format!("login_code:{}", email)));
+    keys.push(keys::key(&format!("login_tokens:{}", email)));
+
+    let mut command = vec![RespValue::from("DEL")];
+    command.extend(keys.into_iter().map(RespValue::from));
+
+    query(RespValue::Array(command), redis).await?;
+
+    Ok(())
+}
+
+/// Renames every source key that exists to its target key and deletes the other keys, atomically.
+/// Fails without changing anything if a target key already exists. `KEYS` holds source/target
+/// pairs followed by the keys to delete, `ARGV[1]` is the number of keys in pairs.
+const RENAME_SCRIPT: &str = r#"
+local renamed = tonumber(ARGV[1])
+for i = 1, renamed, 2 do
+    if redis.call('EXISTS', KEYS[i + 1]) == 1 then
+        return redis.error_reply('target key exists: ' .. KEYS[i + 1])
+    end
+end
+for i = 1, renamed, 2 do
+    if redis.call('EXISTS', KEYS[i]) == 1 then
+        redis.call('RENAME', KEYS[i], KEYS[i + 1])
+    end
+end
+for i = renamed + 1, #KEYS do
+    redis.call('DEL', KEYS[i])
+end
+return 1
+"#;
+
+/// Moves a user, their settings, password, TOTP secret, WebAuthn credentials, sessions and alert
+/// rules to a new email address. Remembered devices are forgotten, as their cookies are bound to
+/// the old address.
+/// The keys are renamed and the devices forgotten in a single script, which redis runs atomically
+/// like MULTI/EXEC, so a failed change never leaves the user split over both addresses or without
+/// their devices. A transaction isn't used as
+/// the RedisActor connection is shared, so other requests could end up inside MULTI/EXEC.
+///
+/// # Arguments
+///
+/// * `old` - Current email address
+/// * `new` - New email address, must not be in use
+/// * `redis` - Connection to database
+pub async fn user_rename(old: &str, new: &str, redis: &Data<Pool>) -> Result<(), DbError> {
+    let devices = scan_keys(&format!("device:{}:*", keys::escape(old)), redis).await?;
+
+    let keys = user_keys(old);
+
+    let old_prefix = format!(":{}", old);
+    let renamed = keys.len() * 2;
+    let mut command = vec![
+        RespValue::from("EVAL"),
+        RespValue::from(RENAME_SCRIPT),
+        RespValue::from((renamed + devices.len()).to_string()),
+    ];
+    for key in keys {
+        // Keys are `<prefix>:<email>[:<rest>]`, only the first email is replaced
+        let target = keys::key(&keys::strip(&key).replacen(&old_prefix, &format!(":{}", new), 1));
+        command.push(RespValue::from(key));
+        command.push(RespValue::from(target));
+    }
+    command.extend(devices.into_iter().map(RespValue::from));
+    command.push(RespValue::from(renamed.to_string()));
+
+    query(RespValue::Array(command), redis).await?;
+
+    Ok(())
+}
+
+/// Names of the settings keys of a user (`settings:<email>:<name>`), in the order `settings_get`
+/// reads them
+const SETTINGS: [&str; 12] = [
+    "units:temperature",
+    "units:pressure",
+    "units:humidity",
+    "theme",
+    "timeframe",
+    "timezone",
+    "locale",
+    "chart_type",
+    "smoothing",
+    "show:temperature",
+    "show:pressure",
+    "show:humidity",
+];
+
+/// Keys of a user that move along with an email change: the user, their settings, password, TOTP
+/// secret, WebAuthn credentials, sessions and alert rules. Listed exactly instead of scanned, as
+/// email addresses may contain glob characters.
+///
+/// # Arguments
+///
+/// * `email` - Email address
+fn user_keys(email: &str) -> Vec<String> {
+    let mut keys: Vec<String> = SETTINGS
+        .iter()
+        .map(|name| keys::key(&format!("settings:{}:{}", email, name)))
+        .collect();
+    keys.push(keys::key(&format!("totp:{}:secret", email)));
+    keys.push(keys::key(&format!("totp:{}:enabled", email)));
+    keys.push(keys::key(&format!("user:{}", email)));
+    keys.push(keys::key(&format!("user:{}:pwhash", email)));
+    keys.push(keys::key(&format!("sessions:{}", email)));
+    keys.push(keys::key(&format!("alerts:{}", email)));
+    keys.push(keys::key(&format!("alert_state:{}", email)));
+    keys.push(keys::key(&format!("webauthn:{}", email)));
+    keys
+}
+
+/// Retrieves settings from the database for the corresponding user.
+/// Settings missing from the database are substituted with their default value.
+///
+/// # Arguments
+///
+/// * `email` - Email address
+/// * `redis` - Connection to database
+pub async fn settings_get(
+    email: &str,
+    redis: &Data<Pool>,
+) -> Result<settings::UserSettings, DbError> {
+    let mut command = vec![RespValue::from("MGET")];
+    command.extend(
+        SETTINGS
+            .iter()
+            .map(|name| RespValue::from(keys::key(&format!("settings:{}:{}", email, name)))),
+    );
+
+    let res = query(RespValue::Array(command), redis).await?;
+
+    let mut values = resp_to_vec(res)?.into_iter().map(resp_to_string);
+
+    let defaults = settings::UserSettings::from_env();
+    let mut next = |default: String| -> Result<String, DbError> {
+        Ok(values.next().transpose()?.flatten().unwrap_or(default))
+    };
+
+    Ok(settings::UserSettings {
+        temperature: next(defaults.temperature)?,
+        pressure: next(defaults.pressure)?,
+        humidity: next(defaults.humidity)?,
+        theme: next(defaults.theme)?,
+        timeframe: next(defaults.timeframe)?,
+        timezone: next(defaults.timezone)?,
+        locale: next(defaults.locale)?,
+        chart_type: next(defaults.chart_type)?,
+        smoothing: next(defaults.smoothing.to_string())? == "true",
+        show_temperature: next(defaults.show_temperature.to_string())? == "true",
+        show_pressure: next(defaults.show_pressure.to_string())? == "true",
+        show_humidity: next(defaults.show_humidity.to_string())? == "true",
+    })
+}
+
+/// Saves settings for the corresponding user in the database
+///
+/// # Arguments
+///
+/// * `email` - Email address
+/// * `data` - Data containing settings
+/// * `redis` - Connection to database
+pub async fn settings_set(
+    email: &str,
+    data: &settings::UserSettings,
+    redis: &Data<Pool>,
+) -> Result<(), DbError> {
+    query(
+        resp_array![
+            "MSET",
+            // Temperature
+            keys::key(&format!("settings:{}:units:temperature", email)),
+            data.temperature.clone(),
+            // Pressure
+            keys::key(&format!("settings:{}:units:pressure", email)),
+            data.pressure.clone(),
+            // Humidity
+            keys::key(&format!("settings:{}:units:humidity", email)),
+            data.humidity.clone(),
+            // Theme
+            keys::key(&format!("settings:{}:theme", email)),
+            data.theme.clone(),
+            // Timeframe
+            keys::key(&format!("settings:{}:timeframe", email)),
+            data.timeframe.clone(),
+            // Timezone
+            keys::key(&format!("settings:{}:timezone", email)),
+            data.timezone.clone(),
+            // Locale
+            keys::key(&format!("settings:{}:locale", email)),
+            data.locale.clone(),
+            // Chart type
+            keys::key(&format!("settings:{}:chart_type", email)),
+            data.chart_type.clone(),
+            // Smoothing
+            keys::key(&format!("settings:{}:smoothing", email)),
+            data.smoothing.to_string(),
+            // Visible metrics
+            keys::key(&format!("settings:{}:show:temperature", email)),
+            data.show_temperature.to_string(),
+            keys::key(&format!("settings:{}:show:pressure", email)),
+            data.show_pressure.to_string(),
+            keys::key(&format!("settings:{}:show:humidity", email)),
+            data.show_humidity.to_string()
+        ],
+        redis,
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// Retrieves the alert rules of a user (`alerts:<email>`), rules that can't be decoded are skipped
+///
+/// # Arguments
+///
+/// * `email` - Email address
+/// * `redis` - Connection to database
+pub async fn alerts_get(email: &str, redis: &Data<Pool>) -> Result<Vec<alerts::Rule>, DbError> {
+    let res = query(
+        resp_array!["LRANGE", keys::key(&format!("alerts:{}", email)), "0", "-1"],
+        redis,
+    )
+    .await?;
+
+    Ok(resp_to_json(res)?)
+}
+
+/// Prepends an entry to the audit log
+///
+/// # Arguments
+///
+/// * `entry` - Entry to add
+/// * `redis` - Connection to database
+pub async fn audit_add(entry: &audit::Entry, redis: &Data<Pool>) -> Result<(), DbError> {
+    let entry = serde_json::to_string(entry).unwrap();
+
+    query(resp_array!["LPUSH", keys::key("audit:log"), entry], redis).await?;
+
+    Ok(())
+}
+
+/// Retrieves the most recent entries of the audit log, newest first
+///
+/// # Arguments
+///
+/// * `count` - Maximum number of entries
+/// * `redis` - Connection to database
+pub async fn audit_recent(count: usize, redis: &Data<Pool>) -> Result<Vec<audit::Entry>, DbError> {
+    let res = query(
+        resp_array![
+            "LRANGE",
+            keys::key("audit:log"),
+            "0",
+            (count - 1).to_string()
+        ],
+        redis,
+    )
+    .await?;
+
+    Ok(resp_to_json(res)?)
+}
+
+/// Adds an alert rule for a user
+///
+/// # Arguments
+///
+/// * `email` - Email address
+/// * `rule` - Rule to add
+/// * `redis` - Connection to database
+pub async fn alerts_add(
+    email: &str,
+    rule: &alerts::Rule,
+    redis: &Data<Pool>,
+) -> Result<(), DbError> {
+    let rule = serde_json::to_string(rule).unwrap();
+
+    query(
+        resp_array!["RPUSH", keys::key(&format!("alerts:{}", email)), rule],
+        redis,
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// Removes an alert rule of a user, along with its state
+///
+/// # Arguments
+///
+/// * `email` - Email address
+/// * `id` - ID of the rule
+/// * `redis` - Connection to database
+pub async fn alerts_remove(email: &str, id: &str, redis: &Data<Pool>) -> Result<(), DbError> {
+    let rule = match alerts_get(email, redis)
+        .await?
+        .into_iter()
+        .find(|rule| rule.id == id)
+    {
+        Some(rule) => rule,
+        None => return Ok(()),
+    };
+
+    let state: Vec<String> = alert_state_get(email, redis)
+        .await?
+        .into_iter()
+        .filter(|key| key.starts_with(&format!("{}:", id)))
+        .collect();
+
+    let mut commands = vec![resp_array![
+        "LREM",
+        keys::key(&format!("alerts:{}", email)),
+        "1",
+        serde_json::to_string(&rule).unwrap()
+    ]];
+    if !state.is_empty() {
+        let mut command = vec![
+            RespValue::from("SREM"),
+            RespValue::from(keys::key(&format!("alert_state:{}", email))),
+        ];
+        command.extend(state.into_iter().map(RespValue::from));
+        commands.push(RespValue::Array(command));
+    }
+
+    pipeline(commands, redis).await?;
+
+    Ok(())
+}
+
+/// Retrieves the email addresses of all users with alert rules
+///
+/// # Arguments
+///
+/// * `redis` - Connection to database
+pub async fn alerts_users(redis: &Data<Pool>) -> Result<Vec<String>, DbError> {
+    Ok(scan_keys("alerts:*", redis)
+        .await?
+        .into_iter()
+        .map(|key| keys::strip(&key).trim_start_matches("alerts:").to_owned())
+        .collect())
+}
+
+/// Retrieves the rules of a user that are currently breached, as `<rule id>:<station>`
+///
+/// # Arguments
+///
+/// * `email` - Email address
+/// * `redis` - Connection to database
+pub async fn alert_state_get(email: &str, redis: &Data<Pool>) -> Result<HashSet<String>, DbError> {
+    let res = query(
+        resp_array!["SMEMBERS", keys::key(&format!("alert_state:{}", email))],
+        redis,
+    )
+    .await?;
+
+    Ok(resp_to_strings(res)?.into_iter().collect())
+}
+
+/// Marks a rule of a user at a station as breached or recovered
+///
+/// # Arguments
+///
+/// * `email` - Email address
+/// * `key` - Rule at a station, as `<rule id>:<station>`
+/// * `breached` - Whether the rule is breached
+/// * `redis` - Connection to database
+pub async fn alert_state_set(
+    email: &str,
+    key: &str,
+    breached: bool,
+    redis: &Data<Pool>,
+) -> Result<(), DbError> {
+    let command = if breached { "SADD" } else { "SREM" };
+
+    query(
+        resp_array![command, keys::key(&format!("alert_state:{}", email)), key],
+        redis,
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// Stores readings of a single metric, last write wins. Every timestamp keeps exactly one member:
+/// the members scored by the timestamp are removed before the new one is added, in one script so
+/// concurrent ingestion can't leave two values at the same timestamp. `KEYS[1]` is the sorted
+/// set, `ARGV` holds timestamp/member pairs.
+const STORE_SCRIPT: &str = r#"
+for i = 1, #ARGV, 2 do
+    redis.call('ZREMRANGEBYSCORE', KEYS[1], ARGV[i], ARGV[i])
+    redis.call('ZADD', KEYS[1], ARGV[i], ARGV[i + 1])
+end
+return 1
+"#;
+
+/// Appends chunks of compressed readings to their windows. `KEYS[1]` is the hash of the metric,
+/// `ARGV` holds window/chunk pairs.
+const APPEND_SCRIPT: &str = r#"
+for i = 1, #ARGV, 2 do
+    local blob = redis.call('HGET', KEYS[1], ARGV[i]) or ''
+    redis.call('HSET', KEYS[1], ARGV[i], blob .. ARGV[i + 1])
+end
+return 1
+"#;
+
+/// Removes compressed readings older than `ARGV[1]` from the windows in hash `KEYS[1]`, returns the
+/// number of removed readings. Runs as a single command, so readings appended meanwhile aren't
+/// lost. Readings within a chunk are sorted, so the kept readings are the end of every chunk, only
+/// the first delta of the kept readings is encoded again (see `compression`).
+const TRIM_SCRIPT: &str = r#"
+local function take_varint(blob, pos)
+    local value, scale = 0, 1
+    while true do
+        local byte = string.byte(blob, pos)
+        if not byte then
+            return nil, pos
+        end
+        pos = pos + 1
+        value = value + (byte % 128) * scale
+        if byte < 128 then
+            return value, pos
+        end
+        scale = scale * 128
+    end
+end
+
+local function put_varint(value)
+    local bytes = {}
+    while value >= 128 do
+        bytes[#bytes + 1] = string.char(value % 128 + 128)
+        value = math.floor(value / 128)
+    end
+    bytes[#bytes + 1] = string.char(value)
+    return table.concat(bytes)
+end
+
+local cutoff = tonumber(ARGV[1])
+local removed = 0
+for _, field in ipairs(redis.call('HKEYS', KEYS[1])) do
+    local start = tonumber(field)
+    if start and start < cutoff then
+        local blob = redis.call('HGET', KEYS[1], field)
+        local chunks, trimmed = {}, 0
+        local pos = 1
+        while pos <= #blob do
+            local count
+            count, pos = take_varint(blob, pos)
+            if not count then
+                break
+            end
+
+            local timestamp, kept, truncated = start, {}, false
+            for _ = 1, count do
+                local delta, value = take_varint(blob, pos)
+                if not delta or value + 7 > #blob then
+                    truncated = true
+                    break
+                end
+
+                timestamp = timestamp + delta
+                if timestamp < cutoff then
+                    trimmed = trimmed + 1
+                elseif #kept == 0 then
+                    kept[1] = put_varint(timestamp - start) .. string.sub(blob, value, value + 7)
+                else
+                    kept[#kept + 1] = string.sub(blob, pos, value + 7)
+                end
+                pos = value + 8
+            end
+
+            if #kept > 0 then
+                chunks[#chunks + 1] = put_varint(#kept) .. table.concat(kept)
+            end
+            if truncated then
+                break
+            end
+        end
+
+        if #chunks == 0 then
+            redis.call('HDEL', KEYS[1], field)
+        elseif trimmed > 0 then
+            redis.call('HSET', KEYS[1], field, table.concat(chunks))
+        end
+        removed = removed + trimmed
+    end
+end
+return removed
+"#;
+
+/// Returns the hash holding the compressed readings of a metric
+///
+/// # Arguments
+///
+/// * `station` - Station ID
+/// * `metric` - Name of the metric (e.g. `temperature`)
+fn windows_key(station: &str, metric: &str) -> String {
+    keys::key(&format!("readings_windows:{}:{}", station, metric))
+}
+
+/// Returns the key the readings of a metric are stored in, the hash of its windows when readings
+/// are compressed
+///
+/// # Arguments
+///
+/// * `station` - Station ID
+/// * `metric` - Name of the metric (e.g. `temperature`)
+/// * `redis` - Connection to database
+pub fn readings_key(station: &str, metric: &str, redis: &Data<Pool>) -> String {
+    match redis.compression {
+        Some(_) => windows_key(station, metric),
+        None => keys::key(&format!("readings:{}:{}", station, metric)),
+    }
+}
+
+/// Creates the command storing readings of a single metric, with `STORE_SCRIPT` or with
+/// `APPEND_SCRIPT` when readings are compressed
+///
+/// # Arguments
+///
+/// * `station` - Station ID
+/// * `metric` - Name of the metric (e.g. `temperature`)
+/// * `readings` - `(timestamp, value)` pairs, later pairs win over earlier ones
+/// * `compression` - Length of the windows readings are compressed in
+fn store_command(
+    station: &str,
+    metric: &str,
+    readings: &[(u64, f64)],
+    compression: Option<u64>,
+) -> RespValue {
+    let window = match compression {
+        Some(window) => window,
+        None => {
+            let mut command = vec![
+                RespValue::from("EVAL"),
+                RespValue::from(STORE_SCRIPT),
+                RespValue::from("1"),
+                RespValue::from(keys::key(&format!("readings:{}:{}", station, metric))),
+            ];
+            for (timestamp, value) in readings {
+                command.push(RespValue::from(timestamp.to_string()));
+                command.push(RespValue::from(format!("{}:{}", timestamp, value)));
+            }
+
+            return RespValue::Array(command);
+        }
+    };
+
+    let mut windows: BTreeMap<u64, Vec<(u64, f64)>> = BTreeMap::new();
+    for (timestamp, value) in readings {
+        windows
+            .entry(compression::window_start(*timestamp, window))
+            .or_default()
+            .push((*timestamp, *value));
+    }
+
+    let mut command = vec![
+        RespValue::from("EVAL"),
+        RespValue::from(APPEND_SCRIPT),
+        RespValue::from("1"),
+        RespValue::from(windows_key(station, metric)),
+    ];
+    for (start, readings) in windows {
+        command.push(RespValue::from(start.to_string()));
+        command.push(RespValue::BulkString(compression::encode(start, &readings)));
+    }
+
+    RespValue::Array(command)
+}
+
+/// Retrieves the start of every window of compressed readings of a metric, sorted
+///
+/// # Arguments
+///
+/// * `station` - Station ID
+/// * `metric` - Name of the metric (e.g. `temperature`)
+/// * `redis` - Connection to database
+async fn windows(station: &str, metric: &str, redis: &Data<Pool>) -> Result<Vec<u64>, DbError> {
+    let res = query(resp_array!["HKEYS", windows_key(station, metric)], redis).await?;
+
+    let mut windows: Vec<u64> = resp_to_strings(res)?
+        .iter()
+        .filter_map(|start| start.parse().ok())
+        .collect();
+    windows.sort();
+
+    Ok(windows)
+}
+
+/// Retrieves and decodes windows of compressed readings of a metric, ordered by timestamp
+///
+/// # Arguments
+///
+/// * `station` - Station ID
+/// * `metric` - Name of the metric (e.g. `temperature`)
+/// * `starts` - Sorted starts of the windows
+/// * `redis` - Connection to database
+async fn windows_decode(
+    station: &str,
+    metric: &str,
+    starts: &[u64],
+    redis: &Data<Pool>,
+) -> Result<Vec<(u64, f64)>, DbError> {
+    if starts.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let mut command = vec![
+        RespValue::from("HMGET"),
+        RespValue::from(windows_key(station, metric)),
+    ];
+    command.extend(
+        starts
+            .iter()
+            .map(|start| RespValue::from(start.to_string())),
+    );
+
+    let blobs = resp_to_blobs(query(RespValue::Array(command), redis).await?)?;
+
+    Ok(starts
+        .iter()
+        .zip(blobs.iter())
+        .flat_map(|(start, blob)| compression::decode(*start, blob))
+        .collect())
+}
+
+/// Retrieves the start of every window of compressed readings of a metric overlapping a time
+/// range, sorted
+///
+/// # Arguments
+///
+/// * `station` - Station ID
+/// * `metric` - Name of the metric (e.g. `temperature`)
+/// * `from` - Start of the range (unix timestamp, inclusive)
+/// * `to` - End of the range (unix timestamp, inclusive)
+/// * `window` - Length of the windows
+/// * `redis` - Connection to database
+async fn windows_within(
+    station: &str,
+    metric: &str,
+    from: u64,
+    to: u64,
+    window: u64,
+    redis: &Data<Pool>,
+) -> Result<Vec<u64>, DbError> {
+    Ok(windows(station, metric, redis)
+        .await?
+        .into_iter()
+        .filter(|start| *start <= to && start.saturating_add(window) > from)
+        .collect())
+}
+
+/// Retrieves the compressed readings of a metric within a time range, ordered by timestamp
+///
+/// # Arguments
+///
+/// * `station` - Station ID
+/// * `metric` - Name of the metric (e.g. `temperature`)
+/// * `from` - Start of the range (unix timestamp, inclusive)
+/// * `to` - End of the range (unix timestamp, inclusive)
+/// * `window` - Length of the windows
+/// * `redis` - Connection to database
+async fn windows_range(
+    station: &str,
+    metric: &str,
+    from: u64,
+    to: u64,
+    window: u64,
+    redis: &Data<Pool>,
+) -> Result<Vec<(u64, f64)>, DbError> {
+    let starts = windows_within(station, metric, from, to, window, redis).await?;
+
+    Ok(windows_decode(station, metric, &starts, redis)
+        .await?
+        .into_iter()
+        .filter(|(timestamp, _)| *timestamp >= from && *timestamp <= to)
+        .collect())
+}
+
+/// Stores a reading from the weather station in the database.
+/// Every metric of every station has its own sorted set (`readings:<station>:<metric>`) scored by
+/// timestamp, the station is added to the set of known stations (`stations`).
+/// A reading at a timestamp that is already stored replaces the stored values.
+///
+/// With `READINGS_COMPRESSION_SECS` set readings are stored in a hash per metric instead
+/// (`readings_windows:<station>:<metric>`), with the readings of every window encoded by the
+/// compression module in the field of the window start. Readings stored in the other format are
+/// not read, switch formats by restoring a backup.
+///
+/// # Arguments
+///
+/// * `reading` - Reading sent by the weather station
+/// * `redis` - Connection to database
+///
+/// # Remarks
+/// Members are stored as `<timestamp>:<value>` so equal values at different times don't collapse
+/// into a single member. Readings stored before last-write-wins may still hold several members
+/// at one timestamp.
+pub async fn readings_add(reading: &sensor::Reading, redis: &Data<Pool>) -> Result<(), DbError> {
+    readings_add_batch(std::slice::from_ref(reading), redis).await
+}
+
+/// Stores a batch of readings in the database with a single script per metric of every station,
+/// all sent in one pipeline. Stored like `readings_add`, so storing a reading again is a no-op
+/// and of readings with the same timestamp the last one wins.
+///
+/// # Arguments
+///
+/// * `readings` - Readings sent by the weather station
+/// * `redis` - Connection to database
+pub async fn readings_add_batch(
+    readings: &[sensor::Reading],
+    redis: &Data<Pool>,
+) -> Result<(), DbError> {
+    if readings.is_empty() {
+        return Ok(());
+    }
+
+    let mut stations = vec![
+        RespValue::from("SADD"),
+        RespValue::from(keys::key("stations")),
+    ];
+    let mut sets: BTreeMap<(&str, &str), Vec<(u64, f64)>> = BTreeMap::new();
+
+    for reading in readings {
+        stations.push(RespValue::from(reading.station.as_str()));
+
+        let metrics = [
+            ("temperature", reading.temperature),
+            ("pressure", reading.pressure),
+            ("humidity", reading.humidity),
+        ];
+
+        for (metric, value) in metrics.iter() {
+            sets.entry((&reading.station, metric))
+                .or_default()
+                .push((reading.timestamp, *value));
+        }
+    }
+
+    let mut commands = vec![RespValue::Array(stations)];
+    commands.extend(sets.iter().map(|((station, metric), readings)| {
+        store_command(station, metric, readings, redis.compression)
+    }));
+
+    pipeline(commands, redis).await?;
+
+    Ok(())
+}
+
+/// Marks a reading of a station as accepted, unless one was accepted less than `interval` seconds
+/// ago. Returns `None` if accepted, otherwise the seconds until the next reading is accepted.
+///
+/// # Arguments
+///
+/// * `station` - Station ID
+/// * `interval` - Minimum seconds between two readings
+/// * `redis` - Connection to database
+pub async fn ingest_accept(
+    station: &str,
+    interval: u64,
+    redis: &Data<Pool>,
+) -> Result<Option<u64>, DbError> {
+    let key = keys::key(&format!("ingest_last:{}", station));
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+
+    // The key expires once the interval has passed, so setting it only succeeds after that
+    let res = query(
+        resp_array![
+            "SET",
+            &key,
+            now.to_string(),
+            "EX",
+            interval.to_string(),
+            "NX"
+        ],
+        redis,
+    )
+    .await?;
+    if res != RespValue::Nil {
+        return Ok(None);
+    }
+
+    let ttl = resp_to_i64(query(resp_array!["TTL", &key], redis).await?)?;
+
+    Ok(Some(ttl.max(1) as u64))
+}
+
+/// Retrieves the IDs of all stations that sent readings, sorted
+///
+/// # Arguments
+///
+/// * `redis` - Connection to database
+pub async fn stations(redis: &Data<Pool>) -> Result<Vec<String>, DbError> {
+    let res = query(resp_array!["SMEMBERS", keys::key("stations")], redis).await?;
+
+    let mut stations = resp_to_strings(res)?;
+    stations.sort();
+
+    Ok(stations)
+}
+
+/// Retrieves the altitude of a station in meters, `None` if it is not set
+///
+/// # Arguments
+///
+/// * `station` - Station ID
+/// * `redis` - Connection to database
+pub async fn station_altitude(station: &str, redis: &Data<Pool>) -> Result<Option<f64>, DbError> {
+    let res = query(
+        resp_array!["GET", keys::key(&format!("station:{}:altitude", station))],
+        redis,
+    )
+    .await?;
+
+    Ok(resp_to_string(res)?.and_then(|altitude| altitude.parse().ok()))
+}
+
+/// Sets the altitude of a station
+///
+/// # Arguments
+///
+/// * `station` - Station ID
+/// * `altitude` - Altitude in meters above sea level
+/// * `redis` - Connection to database
+pub async fn station_altitude_set(
+    station: &str,
+    altitude: f64,
+    redis: &Data<Pool>,
+) -> Result<(), DbError> {
+    query(
+        resp_array![
+            "SET",
+            keys::key(&format!("station:{}:altitude", station)),
+            altitude.to_string()
+        ],
+        redis,
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// Removes readings of a metric older than the cutoff, returns the number of removed readings
+///
+/// # Arguments
+///
+/// * `station` - Station ID
+/// * `metric` - Name of the metric (e.g. `temperature`)
+/// * `cutoff` - Readings before this unix timestamp are removed
+/// * `redis` - Connection to database
+pub async fn readings_trim(
+    station: &str,
+    metric: &str,
+    cutoff: u64,
+    redis: &Data<Pool>,
+) -> Result<i64, DbError> {
+    if redis.compression.is_some() {
+        return windows_trim(station, metric, cutoff, redis).await;
+    }
+
+    let res = query(
+        resp_array![
+            "ZREMRANGEBYSCORE",
+            keys::key(&format!("readings:{}:{}", station, metric)),
+            "-inf",
+            format!("({}", cutoff)
+        ],
+        redis,
+    )
+    .await?;
+
+    Ok(resp_to_i64(res)?)
+}
+
+/// Removes compressed readings of a metric older than the cutoff with `TRIM_SCRIPT`, returns the
+/// number of removed readings. Windows ending before the cutoff are removed, the window containing
+/// the cutoff is rewritten without its older readings.
+///
+/// # Arguments
+///
+/// * `station` - Station ID
+/// * `metric` - Name of the metric (e.g. `temperature`)
+/// * `cutoff` - Readings before this unix timestamp are removed
+/// * `redis` - Connection to database
+async fn windows_trim(
+    station: &str,
+    metric: &str,
+    cutoff: u64,
+    redis: &Data<Pool>,
+) -> Result<i64, DbError> {
+    let res = query(
+        resp_array![
+            "EVAL",
+            TRIM_SCRIPT,
+            "1",
+            windows_key(station, metric),
+            cutoff.to_string()
+        ],
+        redis,
+    )
+    .await?;
+
+    Ok(resp_to_i64(res)?)
+}
+
+/// Retrieves the most recent reading of a metric, `None` if there are no readings yet
+///
+/// # Arguments
+///
+/// * `station` - Station ID
+/// * `metric` - Name of the metric (e.g. `temperature`)
+/// * `redis` - Connection to database
+pub async fn readings_latest(
+    station: &str,
+    metric: &str,
+    redis: &Data<Pool>,
+) -> Result<Option<(u64, f64)>, DbError> {
+    if redis.compression.is_some() {
+        for start in windows(station, metric, redis).await?.iter().rev() {
+            let readings = windows_decode(station, metric, std::slice::from_ref(start), redis);
+            if let Some(latest) = readings.await?.pop() {
+                return Ok(Some(latest));
+            }
+        }
+
+        return Ok(None);
+    }
+
+    let res = query(
+        resp_array![
+            "ZREVRANGE",
+            keys::key(&format!("readings:{}:{}", station, metric)),
+            "0",
+            "0"
+        ],
+        redis,
+    )
+    .await?;
+
+    Ok(resp_to_strings(res)?
+        .iter()
+        .find_map(|member| parse_reading(member)))
+}
+
+/// Retrieves the readings of a single metric within a time range, ordered by timestamp
+///
+/// # Arguments
+///
+/// * `station` - Station ID
+/// * `metric` - Name of the metric (e.g. `temperature`)
+/// * `from` - Start of the range (unix timestamp, inclusive)
+/// * `to` - End of the range (unix timestamp, inclusive)
+/// * `redis` - Connection to database
+///
+/// # Remarks
+/// Returns an array of `(timestamp, value)` pairs, empty if there is no data in the range
+pub async fn readings_range(
+    station: &str,
+    metric: &str,
+    from: u64,
+    to: u64,
+    redis: &Data<Pool>,
+) -> Result<Vec<(u64, f64)>, DbError> {
+    if let Some(window) = redis.compression {
+        return windows_range(station, metric, from, to, window, redis).await;
+    }
+
+    let res = query(
+        resp_array![
+            "ZRANGEBYSCORE",
+            keys::key(&format!("readings:{}:{}", station, metric)),
+            from.to_string(),
+            to.to_string()
+        ],
+        redis,
+    )
+    .await?;
+
+    parse_readings(res)
+}
+
+/// Adds an annotation to a station
+///
+/// # Arguments
+///
+/// * `station` - Station ID
+/// * `annotation` - Annotation to add
+/// * `redis` - Connection to database
+pub async fn annotation_add(
+    station: &str,
+    annotation: &Annotation,
+    redis: &Data<Pool>,
+) -> Result<(), DbError> {
+    query(
+        resp_array![
+            "ZADD",
+            keys::key(&format!("annotations:{}", station)),
+            annotation.ts.to_string(),
+            serde_json::to_string(annotation).unwrap()
+        ],
+        redis,
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// Retrieves the annotations of a station within a time range, ordered by timestamp
+///
+/// # Arguments
+///
+/// * `station` - Station ID
+/// * `from` - Start of the range (unix timestamp, inclusive)
+/// * `to` - End of the range (unix timestamp, inclusive)
+/// * `redis` - Connection to database
+pub async fn annotations_range(
+    station: &str,
+    from: u64,
+    to: u64,
+    redis: &Data<Pool>,
+) -> Result<Vec<Annotation>, DbError> {
+    let res = query(
+        resp_array![
+            "ZRANGEBYSCORE",
+            keys::key(&format!("annotations:{}", station)),
+            from.to_string(),
+            to.to_string()
+        ],
+        redis,
+    )
+    .await?;
+
+    Ok(resp_to_json(res)?)
+}
+
+/// Removes an annotation of a station, as retrieved by `annotations_range`
+///
+/// # Arguments
+///
+/// * `station` - Station ID
+/// * `annotation` - Annotation to remove
+/// * `redis` - Connection to database
+pub async fn annotation_remove(
+    station: &str,
+    annotation: &Annotation,
+    redis: &Data<Pool>,
+) -> Result<(), DbError> {
+    query(
+        resp_array![
+            "ZREM",
+            keys::key(&format!("annotations:{}", station)),
+            serde_json::to_string(annotation).unwrap()
+        ],
+        redis,
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// Retrieves at most `limit` readings of a single metric within a time range, ordered by timestamp
+///
+/// # Arguments
+///
+/// * `station` - Station ID
+/// * `metric` - Name of the metric (e.g. `temperature`)
+/// * `from` - Start of the range (unix timestamp, inclusive)
+/// * `to` - End of the range (unix timestamp, inclusive)
+/// * `limit` - Maximum number of readings
+/// * `redis` - Connection to database
+pub async fn readings_page(
+    station: &str,
+    metric: &str,
+    from: u64,
+    to: u64,
+    limit: usize,
+    redis: &Data<Pool>,
+) -> Result<Vec<(u64, f64)>, DbError> {
+    if let Some(window) = redis.compression {
+        let starts = windows_within(station, metric, from, to, window, redis).await?;
+
+        // Decode a few windows at a time, up to the window holding the last reading of the page
+        let mut readings = Vec::new();
+        for chunk in starts.chunks(SLICE_WINDOWS) {
+            readings.extend(
+                windows_decode(station, metric, chunk, redis)
+                    .await?
+                    .into_iter()
+                    .filter(|(timestamp, _)| *timestamp >= from && *timestamp <= to),
+            );
+
+            if readings.len() >= limit {
+                break;
+            }
+        }
+        readings.truncate(limit);
+
+        return Ok(readings);
+    }
+
+    let res = query(
+        resp_array![
+            "ZRANGEBYSCORE",
+            keys::key(&format!("readings:{}:{}", station, metric)),
+            from.to_string(),
+            to.to_string(),
+            "LIMIT",
+            "0",
+            limit.to_string()
+        ],
+        redis,
+    )
+    .await?;
+
+    parse_readings(res)
+}
+
+/// Number of compressed windows decoded at once by `readings_slice`
+const SLICE_WINDOWS: usize = 16;
+
+/// Retrieves a page of about `count` readings of a single metric starting at `cursor`, ordered by
+/// timestamp, along with the cursor of the next page (`None` after the last page). Used to walk
+/// over all readings page by page, starting with cursor 0.
+///
+/// The cursor is the index of the first reading in the sorted set, or the first window start when
+/// readings are compressed. Compressed pages hold whole windows, so they may hold more than `count`
+/// readings, and every window is decoded once over the whole walk.
+///
+/// # Arguments
+///
+/// * `station` - Station ID
+/// * `metric` - Name of the metric (e.g. `temperature`)
+/// * `cursor` - Cursor of the page, 0 for the first page
+/// * `count` - Number of readings of a page
+/// * `redis` - Connection to database
+pub async fn readings_slice(
+    station: &str,
+    metric: &str,
+    cursor: u64,
+    count: usize,
+    redis: &Data<Pool>,
+) -> Result<(Vec<(u64, f64)>, Option<u64>), DbError> {
+    if count == 0 {
+        return Ok((Vec::new(), None));
+    }
+
+    if redis.compression.is_some() {
+        let starts: Vec<u64> = windows(station, metric, redis)
+            .await?
+            .into_iter()
+            .filter(|start| *start >= cursor)
+            .collect();
+
+        let mut page = Vec::new();
+        for (i, chunk) in starts.chunks(SLICE_WINDOWS).enumerate() {
+            page.extend(windows_decode(station, metric, chunk, redis).await?);
+
+            if page.len() >= count {
+                let next = starts.get((i + 1) * SLICE_WINDOWS).copied();
+                return Ok((page, next));
+            }
+        }
+
+        return Ok((page, None));
+    }
+
+    let offset = cursor as usize;
+    let res = query(
+        resp_array![
+            "ZRANGE",
+            keys::key(&format!("readings:{}:{}", station, metric)),
+            offset.to_string(),
+            (offset + count - 1).to_string()
+        ],
+        redis,
+    )
+    .await?;
+
+    let page = parse_readings(res)?;
+    let next = match page.len() == count {
+        // A full page may be followed by more readings
+        true => Some((offset + count) as u64),
+        false => None,
+    };
+
+    Ok((page, next))
+}
+
+/// Stores readings of a single metric of a station, as retrieved with `readings_slice`.
+/// Stored like `readings_add`, so a restored reading replaces a stored one at the same timestamp.
+///
+/// # Arguments
+///
+/// * `station` - Station ID
+/// * `metric` - Name of the metric (e.g. `temperature`)
+/// * `readings` - `(timestamp, value)` pairs
+/// * `redis` - Connection to database
+pub async fn readings_restore(
+    station: &str,
+    metric: &str,
+    readings: &[(u64, f64)],
+    redis: &Data<Pool>,
+) -> Result<(), DbError> {
+    pipeline(
+        vec![
+            resp_array!["SADD", keys::key("stations"), station],
+            store_command(station, metric, readings, redis.compression),
+        ],
+        redis,
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// Decodes the reply of a range query into `(timestamp, value)` pairs
+///
+/// # Arguments
+///
+/// * `res` - Reply from the database
+fn parse_readings(res: RespValue) -> Result<Vec<(u64, f64)>, DbError> {
+    Ok(resp_to_strings(res)?
+        .iter()
+        .filter_map(|member| parse_reading(member))
+        .collect())
+}
+
+/// Parses a sorted set member of the form `<timestamp>:<value>`
+///
+/// # Arguments
+///
+/// * `member` - Member as stored by `readings_add`
+fn parse_reading(member: &str) -> Option<(u64, f64)> {
+    let mut parts = member.splitn(2, ':');
+    let ts = parts.next()?.parse().ok()?;
+    let value = parts.next()?.parse().ok()?;
+
+    Some((ts, value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::haak::testing;
+
+    #[actix_rt::test]
+    #[ignore = "needs redis, run with cargo test -- --ignored"]
+    async fn rate_limit_window_expires() {
+        let redis = testing::pool();
+        let key = testing::unique("login:ip");
+
+        assert_eq!(rate_limit_incr(&key, 60, &redis).await.unwrap(), 1);
+        assert_eq!(rate_limit_incr(&key, 60, &redis).await.unwrap(), 2);
+        assert_eq!(rate_limit_get(&key, &redis).await.unwrap(), 2);
+
+        let ttl = resp_to_i64(
+            query(
+                resp_array!["TTL", keys::key(&format!("ratelimit:{}", key))],
+                &redis,
+            )
+            .await
+            .unwrap(),
+        )
+        .unwrap();
+        assert!(ttl > 0 && ttl <= 60, "ttl {}", ttl);
+    }
+
+    #[actix_rt::test]
+    #[ignore = "needs redis, run with cargo test -- --ignored"]
+    async fn login_purge_removes_pending_logins() {
+        let redis = testing::pool();
+        let email = testing::email();
+        let (first, second) = (testing::unique("token"), testing::unique("token"));
+
+        login_add(&email, &first, 600, &redis).await.unwrap();
+        login_add(&email, &second, 600, &redis).await.unwrap();
+        login_code_add(&email, "ABC123", &second, 600, &redis)
+            .await
+            .unwrap();
+        assert_eq!(
+            login_exists(&first, &redis).await.unwrap(),
+            Some(email.clone())
+        );
+
+        login_purge(&email, &redis).await.unwrap();
+
+        assert_eq!(login_exists(&first, &redis).await.unwrap(), None);
+        assert_eq!(login_exists(&second, &redis).await.unwrap(), None);
+        assert_eq!(login_code_get(&email, &redis).await.unwrap(), None);
+    }
+
+    /// Walks over every reading with `readings_slice` in pages of `count`
+    async fn walk(station: &str, count: usize, redis: &Data<Pool>) -> Vec<(u64, f64)> {
+        let (mut all, mut cursor) = (Vec::new(), Some(0));
+        while let Some(current) = cursor {
+            let (page, next) = readings_slice(station, "temperature", current, count, redis)
+                .await
+                .unwrap();
+            all.extend(page);
+            cursor = next;
+        }
+        all
+    }
+
+    #[actix_rt::test]
+    #[ignore = "needs redis, run with cargo test -- --ignored"]
+    async fn readings_slice_walks_every_reading() {
+        let readings: Vec<(u64, f64)> = (0..250)
+            .map(|i| (1_600_000_000 + i * 60, i as f64))
+            .collect();
+
+        for compression in [None, Some(3600)].iter() {
+            let redis = testing::pool_compressed(*compression);
+            let station = testing::unique("station");
+            readings_restore(&station, "temperature", &readings, &redis)
+                .await
+                .unwrap();
+
+            assert_eq!(walk(&station, 7, &redis).await, readings);
+            assert_eq!(walk(&station, 1000, &redis).await, readings);
+            assert_eq!(
+                readings_slice(&station, "temperature", 0, 0, &redis)
+                    .await
+                    .unwrap(),
+                (Vec::new(), None)
+            );
+        }
+    }
+
+    #[actix_rt::test]
+    #[ignore = "needs redis, run with cargo test -- --ignored"]
+    async fn readings_trim_keeps_readings_after_cutoff() {
+        let readings: Vec<(u64, f64)> = (0..250)
+            .map(|i| (1_600_000_000 + i * 60, i as f64))
+            .collect();
+        let cutoff = readings[130].0;
+
+        for compression in [None, Some(3600)].iter() {
+            let redis = testing::pool_compressed(*compression);
+            let station = testing::unique("station");
+            // The window of the cutoff gets two chunks, the first one is trimmed completely
+            for half in readings.chunks(125) {
+                readings_restore(&station, "temperature", half, &redis)
+                    .await
+                    .unwrap();
+            }
+
+            assert_eq!(
+                readings_trim(&station, "temperature", cutoff, &redis)
+                    .await
+                    .unwrap(),
+                130
+            );
+            assert_eq!(walk(&station, 1000, &redis).await, &readings[130..]);
+
+            let page = readings_page(&station, "temperature", cutoff + 1, u64::MAX, 3, &redis);
+            assert_eq!(page.await.unwrap(), &readings[131..134]);
+        }
+    }
+
+    #[actix_rt::test]
+    #[ignore = "needs redis, run with cargo test -- --ignored"]
+    async fn reading_at_same_timestamp_replaces_stored_one() {
+        for compression in [None, Some(3600)].iter() {
+            let redis = testing::pool_compressed(*compression);
+            let station = testing::unique("station");
+            let reading = |temperature| sensor::Reading {
+                station: station.clone(),
+                temperature,
+                pressure: 1.013,
+                humidity: 50.0,
+                timestamp: 1_600_000_000,
+            };
+
+            readings_add(&reading(21.5), &redis).await.unwrap();
+            readings_add(&reading(23.0), &redis).await.unwrap();
+
+            let stored = readings_range(&station, "temperature", 0, u64::MAX, &redis)
+                .await
+                .unwrap();
+            assert_eq!(stored, vec![(1_600_000_000, 23.0)]);
+        }
+    }
+
+    #[actix_rt::test]
+    #[ignore = "needs redis, run with cargo test -- --ignored"]
+    async fn user_delete_removes_every_key() {
+        let redis = testing::pool();
+        let email = testing::email();
+        let rule = alerts::Rule {
+            id: String::from("rule"),
+            metric: String::from("temperature"),
+            op: alerts::Op::Above,
+            threshold: 30.0,
+        };
+        let credential = webauthn::Credential {
+            id: String::from("credential"),
+            alg: -7,
+            public_key: String::new(),
+            sign_count: 0,
+        };
+
+        user_add(&email, &redis).await.unwrap();
+        set_password(&email, "correct horse battery staple", &redis)
+            .await
+            .unwrap();
+        set_totp_secret(&email, "SECRET", &redis).await.unwrap();
+        set_totp_enabled(&email, &redis).await.unwrap();
+        session_add(&email, "sid", 1_600_000_000, 600, None, &redis)
+            .await
+            .unwrap();
+        device_add(&email, "device", 600, &redis).await.unwrap();
+        alerts_add(&email, &rule, &redis).await.unwrap();
+        alert_state_set(&email, "rule:default", true, &redis)
+            .await
+            .unwrap();
+        webauthn_set(&email, &credential, &redis).await.unwrap();
+        login_add(&email, &testing::unique("token"), 600, &redis)
+            .await
+            .unwrap();
+        login_code_add(&email, "ABC123", "token", 600, &redis)
+            .await
+            .unwrap();
+        assert!(!scan_keys(&format!("*{}*", email), &redis)
+            .await
+            .unwrap()
+            .is_empty());
+
+        user_delete(&email, &redis).await.unwrap();
+
+        assert_eq!(
+            scan_keys(&format!("*{}*", email), &redis).await.unwrap(),
+            Vec::<String>::new()
+        );
+        assert!(!user_exists(&email, &redis).await.unwrap());
+    }
+
+    #[actix_rt::test]
+    #[ignore = "needs redis, run with cargo test -- --ignored"]
+    async fn missing_settings_fall_back_to_defaults() {
+        let redis = testing::pool();
+        let email = testing::email();
+        let defaults = settings::UserSettings::from_env();
+
+        user_add(&email, &redis).await.unwrap();
+        let mut custom = settings_get(&email, &redis).await.unwrap();
+        custom.theme = String::from("not the default");
+        custom.timezone = String::from("Europe/Amsterdam");
+        settings_set(&email, &custom, &redis).await.unwrap();
+
+        query(
+            resp_array![
+                "DEL",
+                keys::key(&format!("settings:{}:theme", email)),
+                keys::key(&format!("settings:{}:units:temperature", email))
+            ],
+            &redis,
+        )
+        .await
+        .unwrap();
+
+        let sett = settings_get(&email, &redis).await.unwrap();
+        assert_eq!(sett.theme, defaults.theme);
+        assert_eq!(sett.temperature, defaults.temperature);
+        assert_eq!(sett.timezone, "Europe/Amsterdam");
+
+        let page = crate::haak::graph::graph_index(
+            crate::haak::session::AuthedUser(email.clone()),
+            redis.clone(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(page.status(), StatusCode::OK);
+
+        user_delete(&email, &redis).await.unwrap();
+    }
+
+    #[actix_rt::test]
+    #[ignore = "needs redis, run with cargo test -- --ignored"]
+    async fn user_rename_forgets_devices_only_when_renamed() {
+        let redis = testing::pool();
+        let (old, taken, new) = (testing::email(), testing::email(), testing::email());
+
+        user_add(&old, &redis).await.unwrap();
+        user_add(&taken, &redis).await.unwrap();
+        device_add(&old, "device", 600, &redis).await.unwrap();
+
+        assert!(user_rename(&old, &taken, &redis).await.is_err());
+        assert!(device_exists(&old, "device", &redis).await.unwrap());
+
+        user_rename(&old, &new, &redis).await.unwrap();
+        assert!(!device_exists(&old, "device", &redis).await.unwrap());
+        assert!(!device_exists(&new, "device", &redis).await.unwrap());
+        assert!(!user_exists(&old, &redis).await.unwrap());
+        assert!(user_exists(&new, &redis).await.unwrap());
+
+        user_delete(&new, &redis).await.unwrap();
+        user_delete(&taken, &redis).await.unwrap();
+    }
+
+    #[actix_rt::test]
+    #[ignore = "needs redis, run with cargo test -- --ignored"]
+    async fn devices_clear_only_forgets_that_users_devices() {
+        let redis = testing::pool();
+        let domain = format!("{}.com", testing::unique("x"));
+        let (star, bob) = (format!("*@{}", domain), format!("bob@{}", domain));
+
+        device_add(&star, "device", 600, &redis).await.unwrap();
+        device_add(&bob, "device", 600, &redis).await.unwrap();
+
+        devices_clear(&star, &redis).await.unwrap();
+        assert!(!device_exists(&star, "device", &redis).await.unwrap());
+        assert!(device_exists(&bob, "device", &redis).await.unwrap());
+
+        devices_clear(&bob, &redis).await.unwrap();
+    }
+
+    #[actix_rt::test]
+    #[ignore = "needs redis, run with cargo test -- --ignored"]
+    async fn registering_again_keeps_only_the_latest_token() {
+        let redis = testing::pool();
+        let email = testing::email();
+        let (first, second) = (testing::unique("token"), testing::unique("token"));
+
+        assert!(register_email(&email, &first, 600, &redis).await.unwrap());
+        assert!(register_email(&email, &second, 600, &redis).await.unwrap());
+
+        assert_eq!(register_exists(&first, &redis).await.unwrap(), None);
+        assert_eq!(
+            register_exists(&second, &redis).await.unwrap(),
+            Some(email.clone())
+        );
+        assert_eq!(
+            register_pending(&email, &redis).await.unwrap(),
+            Some(second.clone())
+        );
+
+        for key in [
+            format!("register:{}", second),
+            format!("register_email:{}", email),
+        ]
+        .iter()
+        {
+            let ttl = resp_to_i64(
+                query(resp_array!["TTL", keys::key(key)], &redis)
+                    .await
+                    .unwrap(),
+            )
+            .unwrap();
+            assert!(ttl > 0 && ttl <= 600, "{} ttl {}", key, ttl);
+        }
+
+        // A token that is already taken isn't stored again
+        let other = testing::email();
+        assert!(!register_email(&other, &second, 600, &redis).await.unwrap());
+        assert_eq!(
+            register_exists(&second, &redis).await.unwrap(),
+            Some(email.clone())
+        );
+
+        register_remove(&email, &second, &redis).await.unwrap();
+    }
+
+    #[actix_rt::test]
+    #[ignore = "needs redis, run with cargo test -- --ignored"]
+    async fn user_delete_only_removes_that_user() {
+        let redis = testing::pool();
+        let domain = format!("{}.com", testing::unique("x"));
+        let (star, bob) = (format!("*@{}", domain), format!("bob@{}", domain));
+
+        for email in [&star, &bob].iter() {
+            user_add(email, &redis).await.unwrap();
+            set_totp_secret(email, "SECRET", &redis).await.unwrap();
+            set_totp_enabled(email, &redis).await.unwrap();
+            device_add(email, "device", 600, &redis).await.unwrap();
+        }
+        let mut custom = settings_get(&bob, &redis).await.unwrap();
+        custom.theme = String::from("not the default");
+        settings_set(&bob, &custom, &redis).await.unwrap();
+
+        user_delete(&star, &redis).await.unwrap();
+
+        assert!(!user_exists(&star, &redis).await.unwrap());
+        assert!(!totp_enabled(&star, &redis).await.unwrap());
+        assert!(!device_exists(&star, "device", &redis).await.unwrap());
+
+        assert!(user_exists(&bob, &redis).await.unwrap());
+        assert!(totp_enabled(&bob, &redis).await.unwrap());
+        assert_eq!(
+            get_totp_secret(&bob, &redis).await.unwrap(),
+            Some(String::from("SECRET"))
+        );
+        assert!(device_exists(&bob, "device", &redis).await.unwrap());
+        assert_eq!(
+            settings_get(&bob, &redis).await.unwrap().theme,
+            "not the default"
+        );
+
+        user_delete(&bob, &redis).await.unwrap();
+    }
+
+    #[actix_rt::test]
+    #[ignore = "needs redis, run with cargo test -- --ignored"]
+    async fn verify_password_checks_the_hash() {
+        let redis = testing::pool();
+        let email = testing::email();
+
+        assert!(!verify_password(&email, "", &redis).await.unwrap());
+
+        set_password(&email, "correct horse battery staple", &redis)
+            .await
+            .unwrap();
+        assert!(
+            verify_password(&email, "correct horse battery staple", &redis)
+                .await
+                .unwrap()
+        );
+        assert!(
+            !verify_password(&email, "correct horse battery stable", &redis)
+                .await
+                .unwrap()
+        );
+        assert!(!verify_password(&email, "", &redis).await.unwrap());
+
+        for malformed in ["", "not a hash", "$argon2id$v=19$m=4096,t=3,p=1$c2FsdA$"].iter() {
+            password_hash_set(&email, malformed, &redis).await.unwrap();
+            assert!(
+                !verify_password(&email, "correct horse battery staple", &redis)
+                    .await
+                    .unwrap()
+            );
+        }
+
+        user_delete(&email, &redis).await.unwrap();
+    }
 }
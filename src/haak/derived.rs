@@ -0,0 +1,174 @@
+//! Documentation for derived module
+//! Includes metrics computed from the stored readings.
+//!
+//! Derived metrics are computed on demand from the temperature and humidity readings taken at the
+//! same timestamp, timestamps missing either reading are skipped.
+//!
+//! Most functions are called from the `actix-web` framework
+use crate::haak::database::{self, Pool};
+use crate::haak::graph;
+use crate::haak::response;
+use crate::haak::session::AuthedUser;
+use crate::haak::units;
+
+use actix_web::http::StatusCode;
+use actix_web::web::{Data, Query};
+use actix_web::{HttpResponse, Result};
+
+use serde::Deserialize;
+
+use std::collections::{BTreeMap, HashMap};
+
+/// Metrics served by the derived API
+pub const DERIVED: [&str; 2] = ["dewpoint", "heat_index"];
+
+/// Query data of the derived API, only `metric` is required
+#[derive(Deserialize)]
+pub struct DerivedQuery {
+    metric: String,
+    station: Option<String>,
+    from: Option<u64>,
+    to: Option<u64>,
+}
+
+/// Computes the dewpoint with the Magnus formula, `None` if the humidity is not above 0
+///
+/// # Arguments
+///
+/// * `temperature` - Temperature in Celsius
+/// * `humidity` - Relative humidity in percent
+pub fn dewpoint(temperature: f64, humidity: f64) -> Option<f64> {
+    const A: f64 = 17.62;
+    const B: f64 = 243.12;
+
+    if humidity <= 0.0 {
+        return None;
+    }
+
+    let gamma = (humidity / 100.0).ln() + A * temperature / (B + temperature);
+
+    Some(B * gamma / (A - gamma))
+}
+
+/// Computes the heat index with the regression of the US National Weather Service
+///
+/// # Arguments
+///
+/// * `temperature` - Temperature in Celsius
+/// * `humidity` - Relative humidity in percent
+///
+/// # Remarks
+/// Returns a temperature in Celsius. Below about 27 °C the heat index is close to the temperature
+/// and the simple formula is used.
+pub fn heat_index(temperature: f64, humidity: f64) -> f64 {
+    let t = temperature * 9.0 / 5.0 + 32.0;
+    let rh = humidity;
+
+    let simple = 0.5 * (t + 61.0 + (t - 68.0) * 1.2 + rh * 0.094);
+
+    let hi = if (simple + t) / 2.0 < 80.0 {
+        simple
+    } else {
+        let mut hi = -42.379 + 2.049_015_23 * t + 10.143_331_27 * rh
+            - 0.224_755_41 * t * rh
+            - 0.006_837_83 * t * t
+            - 0.054_817_17 * rh * rh
+            + 0.001_228_74 * t * t * rh
+            + 0.000_852_82 * t * rh * rh
+            - 0.000_001_99 * t * t * rh * rh;
+
+        if rh < 13.0 && (80.0..=112.0).contains(&t) {
+            hi -= (13.0 - rh) / 4.0 * ((17.0 - (t - 95.0).abs()) / 17.0).sqrt();
+        } else if rh > 85.0 && (80.0..=87.0).contains(&t) {
+            hi += (rh - 85.0) / 10.0 * (87.0 - t) / 5.0;
+        }
+
+        hi
+    };
+
+    (hi - 32.0) * 5.0 / 9.0
+}
+
+/// Computes a derived metric, `None` if it can't be computed from the readings
+///
+/// # Arguments
+///
+/// * `metric` - Name of the derived metric (`dewpoint` or `heat_index`)
+/// * `temperature` - Temperature in Celsius
+/// * `humidity` - Relative humidity in percent
+fn compute(metric: &str, temperature: f64, humidity: f64) -> Option<f64> {
+    match metric {
+        "dewpoint" => dewpoint(temperature, humidity),
+        "heat_index" => Some(heat_index(temperature, humidity)),
+        _ => None,
+    }
+}
+
+/// Handles HTTP GET requests to /api/derived.
+/// Returns a derived metric (`dewpoint` or `heat_index`) in the user's temperature unit as JSON
+/// (`{"dewpoint": [[ts, val], ...]}`), computed from the temperature and humidity readings of the
+/// `station` in the query (`default` if none is given). When no range is given the user's
+/// timeframe setting is used as window, ending now.
+/// Sends 401 Unauthorized if not logged in and 422 UnprocessableEntity on an invalid station or an
+/// unknown metric.
+///
+/// # Arguments
+///
+/// * `query` - Query containing `metric` and optional `station`, `from` and `to`
+/// * `user` - Logged in user
+/// * `redis` - RedisActor to access redis database
+///
+/// # Remarks
+///
+/// Should only be called from actix_web
+pub async fn derived(
+    Query(query): Query<DerivedQuery>,
+    AuthedUser(user): AuthedUser,
+    redis: Data<Pool>,
+) -> Result<HttpResponse> {
+    let metric = match DERIVED.iter().find(|m| **m == query.metric) {
+        Some(metric) => *metric,
+        None => {
+            return Ok(response::error(
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "invalid_metric",
+                "Invalid metric",
+            ))
+        }
+    };
+
+    let station = match graph::query_station(&query.station) {
+        Some(station) => station,
+        None => {
+            return Ok(response::error(
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "invalid_station",
+                "Invalid station",
+            ))
+        }
+    };
+
+    let (from, to) = graph::query_range(query.from, query.to, &user, &redis).await?;
+    let sett = database::settings_get(&user, &redis).await?;
+
+    let humidity: HashMap<u64, f64> =
+        database::readings_range(station, "humidity", from, to, &redis)
+            .await?
+            .into_iter()
+            .collect();
+
+    let series: Vec<(u64, f64)> =
+        database::readings_range(station, "temperature", from, to, &redis)
+            .await?
+            .into_iter()
+            .filter_map(|(ts, temperature)| {
+                let value = compute(metric, temperature, *humidity.get(&ts)?)?;
+                Some((ts, units::convert_temperature(value, &sett.temperature)))
+            })
+            .collect();
+
+    let mut data = BTreeMap::new();
+    data.insert(metric, series);
+
+    Ok(HttpResponse::Ok().json(data))
+}
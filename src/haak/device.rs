@@ -0,0 +1,123 @@
+//! Documentation for device module
+//! Includes the remembered devices of users, which log in without the email challenge.
+//!
+//! Checking "remember this device" on /login issues a device token once the email challenge is
+//! verified. The token is the `device:<email>:<id>` key, which expires after
+//! `REMEMBER_DEVICE_DAYS`, and the matching `weather_device` cookie holding the email, the ID and
+//! an HMAC of both under the cookie secret. A login for the same email with a valid cookie skips
+//! the challenge, other factors (TOTP) are still required. /logout_all forgets all devices.
+use crate::haak::auth;
+use crate::haak::config::Config;
+use crate::haak::database::{self, DbError, Pool};
+
+use actix_web::cookie::{Cookie, SameSite};
+use actix_web::web::Data;
+use actix_web::{HttpMessage, HttpRequest};
+
+use openssl::hash::MessageDigest;
+use openssl::pkey::PKey;
+use openssl::sign::Signer;
+use rand::rngs::OsRng;
+use rand::RngCore;
+
+/// Name of the cookie holding the device token
+pub const COOKIE: &str = "weather_device";
+
+/// Signs the email and device ID with the cookie secret, base64url encoded
+///
+/// # Arguments
+///
+/// * `email` - Email address of the user
+/// * `id` - ID of the device
+/// * `secret` - Cookie secret
+fn sign(email: &str, id: &str, secret: &[u8]) -> String {
+    let key = PKey::hmac(secret).unwrap();
+    let mut signer = Signer::new(MessageDigest::sha256(), &key).unwrap();
+    signer.update(email.as_bytes()).unwrap();
+    signer.update(b"\n").unwrap();
+    signer.update(id.as_bytes()).unwrap();
+
+    base64::encode_config(&signer.sign_to_vec().unwrap(), base64::URL_SAFE_NO_PAD)
+}
+
+/// Remembers the device for the user and creates the cookie holding its token
+///
+/// # Arguments
+///
+/// * `email` - Email address of the user
+/// * `config` - Server configuration, containing the cookie secret and `REMEMBER_DEVICE_DAYS`
+/// * `redis` - RedisActor to access redis database
+pub async fn remember(
+    email: &str,
+    config: &Config,
+    redis: &Data<Pool>,
+) -> Result<Cookie<'static>, DbError> {
+    let mut id = [0u8; 16];
+    OsRng.fill_bytes(&mut id);
+    let id = base64::encode_config(&id, base64::URL_SAFE_NO_PAD);
+
+    let ttl = config.remember_device_days as u64 * 24 * 60 * 60;
+    database::device_add(email, &id, ttl, redis).await?;
+
+    let token = format!(
+        "{}.{}.{}",
+        base64::encode_config(email, base64::URL_SAFE_NO_PAD),
+        id,
+        sign(email, &id, &config.cookie_secret)
+    );
+
+    Ok(Cookie::build(COOKIE, token)
+        .path("/")
+        .secure(true)
+        .http_only(true)
+        .same_site(SameSite::Strict)
+        .max_age(ttl as i64)
+        .finish())
+}
+
+/// Returns true if the request comes from a remembered device of the user, i.e. the cookie is
+/// signed, bound to the email and its device is still remembered
+///
+/// # Arguments
+///
+/// * `req` - Request of the client, containing the cookie
+/// * `email` - Email address the client logs in as
+/// * `config` - Server configuration, containing the cookie secret
+/// * `redis` - RedisActor to access redis database
+pub async fn remembered(
+    req: &HttpRequest,
+    email: &str,
+    config: &Config,
+    redis: &Data<Pool>,
+) -> Result<bool, DbError> {
+    let cookie = match req.cookie(COOKIE) {
+        Some(cookie) => cookie,
+        None => return Ok(false),
+    };
+
+    let mut parts = cookie.value().splitn(3, '.');
+    let (owner, id, signature) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(owner), Some(id), Some(signature)) => (owner, id, signature),
+        _ => return Ok(false),
+    };
+
+    let owner = base64::decode_config(owner, base64::URL_SAFE_NO_PAD).unwrap_or_default();
+    if owner != email.as_bytes()
+        || !auth::tokens_equal(signature, &sign(email, id, &config.cookie_secret))
+    {
+        return Ok(false);
+    }
+
+    database::device_exists(email, id, redis).await
+}
+
+/// Creates an expired cookie, which removes the device token from the browser
+pub fn forget() -> Cookie<'static> {
+    Cookie::build(COOKIE, "")
+        .path("/")
+        .secure(true)
+        .http_only(true)
+        .same_site(SameSite::Strict)
+        .max_age(0)
+        .finish()
+}
@@ -1,8 +1,15 @@
 //! Documentation for email sending
 //!
+//! Email bodies are askama templates in `templates/email/<locale>/`, picked by the locale setting
+//! of the recipient (see `i18n::LOCALES`). Unknown locales get the English emails. Every email has
+//! an HTML body with a plain text alternative (`.txt`) for text-only mail clients.
+//!
+//! Handlers never wait on the mail transport: `send_*` queue the email for the mail worker thread
+//! (see `Mailer`), only the admin test email is sent directly, on the blocking threadpool.
+//!
 //! # Examples
 //! ```
-//! match send_challenge("test@test.com", "generated_challenge") {
+//! match send_challenge(&mailer, "test@test.com", "en", "generated_challenge") {
 //!     Ok() => {
 //!         // Handle success
 //!     },
@@ -12,24 +19,318 @@
 //! }
 //! ```
 
-use lettre::sendmail::error::Error;
-use lettre::sendmail::SendmailTransport;
-use lettre::Transport;
-use lettre_email::EmailBuilder;
+use crate::haak::alerts;
+
+use askama::Template;
+use lettre::smtp::authentication::Credentials;
+use lettre::smtp::SUBMISSION_PORT;
+use lettre::{
+    sendmail, smtp, ClientSecurity, ClientTlsParameters, SendableEmail, SendmailTransport,
+    SmtpClient, SmtpTransport, Transport,
+};
+use lettre_email::{Email, EmailBuilder, Mailbox};
+use native_tls::TlsConnector;
 
 use std::env;
+use std::fmt;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+/// Errors that can occur while sending an email
+#[derive(Debug)]
+pub enum Error {
+    /// The transport could not be configured from the environment
+    Config(String),
+    /// The email could not be built (e.g. malformed recipient or sender)
+    Build(lettre_email::error::Error),
+    /// Sending through sendmail failed
+    Sendmail(sendmail::error::Error),
+    /// Sending through SMTP failed
+    Smtp(smtp::error::Error),
+    /// The mail worker stopped, so the email could not be queued
+    WorkerStopped,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Config(e) => write!(f, "Mail configuration error: {}", e),
+            Error::Build(e) => write!(f, "Could not build email: {}", e),
+            Error::Sendmail(e) => write!(f, "Sendmail error: {}", e),
+            Error::Smtp(e) => write!(f, "SMTP error: {}", e),
+            Error::WorkerStopped => write!(f, "Mail worker stopped"),
+        }
+    }
+}
+
+#[derive(Template)]
+#[template(path = "email/register.html")]
+struct RegisterEmail<'a> {
+    locale: &'a str,
+    url: &'a str,
+    code: &'a str,
+}
+
+#[derive(Template)]
+#[template(path = "email/register.txt")]
+struct RegisterText<'a> {
+    locale: &'a str,
+    url: &'a str,
+    code: &'a str,
+}
+
+#[derive(Template)]
+#[template(path = "email/email_change.html")]
+struct EmailChangeEmail<'a> {
+    locale: &'a str,
+    url: &'a str,
+    code: &'a str,
+}
+
+#[derive(Template)]
+#[template(path = "email/email_change.txt")]
+struct EmailChangeText<'a> {
+    locale: &'a str,
+    url: &'a str,
+    code: &'a str,
+}
+
+#[derive(Template)]
+#[template(path = "email/login.html")]
+struct LoginEmail<'a> {
+    locale: &'a str,
+    url: &'a str,
+    code: &'a str,
+    short_code: &'a str,
+}
+
+#[derive(Template)]
+#[template(path = "email/login.txt")]
+struct LoginText<'a> {
+    locale: &'a str,
+    url: &'a str,
+    code: &'a str,
+    short_code: &'a str,
+}
+
+#[derive(Template)]
+#[template(path = "email/alert.html")]
+struct AlertEmail<'a> {
+    locale: &'a str,
+    url: &'a str,
+    station: &'a str,
+    metric: &'a str,
+    op: &'a str,
+    threshold: f64,
+    value: f64,
+    unit: &'a str,
+}
+
+#[derive(Template)]
+#[template(path = "email/alert.txt")]
+struct AlertText<'a> {
+    locale: &'a str,
+    url: &'a str,
+    station: &'a str,
+    metric: &'a str,
+    op: &'a str,
+    threshold: f64,
+    value: f64,
+    unit: &'a str,
+}
+
+/// Transport used to deliver emails, selected with `MAIL_TRANSPORT`
+pub enum MailTransport {
+    Sendmail(SendmailTransport),
+    Smtp(Box<SmtpTransport>),
+}
+
+impl MailTransport {
+    /// Sends an email through the transport
+    ///
+    /// # Arguments
+    ///
+    /// * `email` - Email to send
+    pub fn send(&mut self, email: SendableEmail) -> Result<(), Error> {
+        match self {
+            MailTransport::Sendmail(sender) => sender.send(email).map_err(Error::Sendmail),
+            MailTransport::Smtp(sender) => sender.send(email).map(|_| ()).map_err(Error::Smtp),
+        }
+    }
+}
+
+/// Creates the mail transport configured by the environment.
+///
+/// `MAIL_TRANSPORT` selects `sendmail` (default) or `smtp`. The SMTP transport is configured with
+/// `SMTP_HOST`, `SMTP_PORT` (default 587), `SMTP_USER`, `SMTP_PASS` and `SMTP_TLS` (`starttls`
+/// (default), `tls` or `none`).
+pub fn transport() -> Result<MailTransport, Error> {
+    match env::var("MAIL_TRANSPORT").as_ref().map(String::as_str) {
+        Ok("smtp") => Ok(MailTransport::Smtp(Box::new(smtp_transport()?))),
+        Ok("sendmail") | Err(_) => Ok(MailTransport::Sendmail(SendmailTransport::new())),
+        Ok(other) => Err(Error::Config(format!(
+            "Unknown MAIL_TRANSPORT '{}', expected smtp or sendmail",
+            other
+        ))),
+    }
+}
+
+/// Reads a number from the environment, `default` if unset or invalid
+///
+/// # Arguments
+///
+/// * `name` - Name of the environment variable
+/// * `default` - Value used when unset or invalid
+fn env_or(name: &str, default: u64) -> u64 {
+    env::var(name)
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(default)
+}
+
+/// Sends an email, retrying failed deliveries with exponential backoff.
+/// Retries up to `MAIL_MAX_RETRIES` (default 3) times, waiting `MAIL_RETRY_BASE_MS` (default 500)
+/// milliseconds before the first retry and doubling the wait for every next retry.
+///
+/// # Arguments
+///
+/// * `email` - Email to send
+///
+/// # Remarks
+///
+/// Blocks while sending and waiting, should only be called from the mail worker
+fn send_with_retry(email: Email) -> Result<(), Error> {
+    let retries = env_or("MAIL_MAX_RETRIES", 3);
+    let base = env_or("MAIL_RETRY_BASE_MS", 500);
+
+    let mut attempt = 0;
+    loop {
+        let res = transport()?.send(email.clone().into());
+
+        match res {
+            Err(e) if attempt < retries => {
+                let wait = base.saturating_mul(1 << attempt.min(16));
+                warn!("Sending email failed ({}), retrying in {} ms", e, wait);
+                thread::sleep(Duration::from_millis(wait));
+                attempt += 1;
+            }
+            res => return res,
+        }
+    }
+}
+
+/// Queue of outgoing emails, delivered by a dedicated worker thread so handlers don't wait on the
+/// mail transport. Delivery failures are logged by the worker.
+#[derive(Clone)]
+pub struct Mailer {
+    sender: mpsc::Sender<Email>,
+    /// Public host name of the server, used in links
+    url: String,
+    /// Sender of all emails
+    from: Mailbox,
+}
+
+/// Sender of the emails, `MAIL_FROM` with the optional display name `MAIL_FROM_NAME`.
+/// Falls back to `weather@<url>` when `MAIL_FROM` is unset.
+///
+/// # Arguments
+///
+/// * `url` - Public host name of the server (`WEATHER_URL`)
+fn from_address(url: &str) -> Mailbox {
+    let address = env::var("MAIL_FROM").unwrap_or_else(|_| format!("weather@{}", url));
+
+    match env::var("MAIL_FROM_NAME") {
+        Ok(name) => Mailbox::new_with_name(name, address),
+        Err(_) => Mailbox::new(address),
+    }
+}
+
+impl Mailer {
+    /// Starts the mail worker thread and returns the queue feeding it
+    ///
+    /// # Arguments
+    ///
+    /// * `url` - Public host name of the server (`WEATHER_URL`)
+    pub fn start(url: String) -> Mailer {
+        let (sender, receiver) = mpsc::channel::<Email>();
+
+        thread::Builder::new()
+            .name(String::from("mailer"))
+            .spawn(move || {
+                for email in receiver {
+                    if let Err(e) = send_with_retry(email) {
+                        error!("Could not deliver email: {}", e);
+                    }
+                }
+            })
+            .expect("Could not start mail worker");
+
+        let from = from_address(&url);
+
+        Mailer { sender, url, from }
+    }
+
+    /// Queues an email for delivery
+    ///
+    /// # Arguments
+    ///
+    /// * `email` - Email to send
+    fn queue(&self, email: Email) -> Result<(), Error> {
+        self.sender.send(email).map_err(|_| Error::WorkerStopped)
+    }
+}
+
+/// Creates an SMTP transport from the `SMTP_*` environment variables
+fn smtp_transport() -> Result<SmtpTransport, Error> {
+    let host =
+        env::var("SMTP_HOST").map_err(|_| Error::Config(String::from("SMTP_HOST not set")))?;
+    let port = match env::var("SMTP_PORT") {
+        Ok(port) => port
+            .parse::<u16>()
+            .map_err(|_| Error::Config(format!("Invalid SMTP_PORT '{}'", port)))?,
+        Err(_) => SUBMISSION_PORT,
+    };
+
+    let tls = || -> Result<ClientTlsParameters, Error> {
+        let connector = TlsConnector::new().map_err(|e| Error::Config(e.to_string()))?;
+        Ok(ClientTlsParameters::new(host.clone(), connector))
+    };
+
+    let security = match env::var("SMTP_TLS").as_ref().map(String::as_str) {
+        Ok("starttls") | Err(_) => ClientSecurity::Required(tls()?),
+        Ok("tls") => ClientSecurity::Wrapper(tls()?),
+        Ok("none") => ClientSecurity::None,
+        Ok(other) => {
+            return Err(Error::Config(format!(
+                "Unknown SMTP_TLS '{}', expected starttls, tls or none",
+                other
+            )))
+        }
+    };
+
+    let mut client = SmtpClient::new((host.as_str(), port), security).map_err(Error::Smtp)?;
+
+    if let (Ok(user), Ok(pass)) = (env::var("SMTP_USER"), env::var("SMTP_PASS")) {
+        client = client.credentials(Credentials::new(user, pass));
+    }
+
+    Ok(client.transport())
+}
 
 /// Sends a register email to an user
-/// Returns `Ok` on success or `Err` on failure
+/// Returns `Ok` once queued or `Err` if the email could not be built or queued
 ///
 /// # Arguments
 ///
+/// * `mailer` - Queue of the mail worker
 /// * `recipient` - Email address of user
+/// * `locale` - Locale of the user (e.g. `en`)
 /// * `code` - Challenge token
 ///
 /// # Examples
 /// ```
-/// match send_register("test@test.com", "generated_challenge") {
+/// match send_register(&mailer, "test@test.com", "en", "generated_challenge") {
 ///     Ok() => {
 ///         // Handle success
 ///     },
@@ -40,32 +341,99 @@ use std::env;
 /// ```
 ///
 /// # Remarks
-/// Email should be validated. This function **does not** validate the email input
-pub fn send_register(recipient: String, code: String) -> Result<(), Error> {
-    let weather_url = &env::var("WEATHER_URL").unwrap();
+/// Email should be validated. This function **does not** validate the email input, an address
+/// rejected by the email builder results in `Err`
+pub fn send_register(
+    mailer: &Mailer,
+    recipient: String,
+    locale: &str,
+    code: String,
+) -> Result<(), Error> {
+    let html = RegisterEmail {
+        locale,
+        url: &mailer.url,
+        code: &code,
+    };
+    let text = RegisterText {
+        locale,
+        url: &mailer.url,
+        code: &code,
+    };
+    let subject = match locale {
+        "nl" => "Registratie Weerstation",
+        _ => "Weather Station Registration",
+    };
+
     let email = EmailBuilder::new()
         .to(recipient)
-        .from(format!("weather@{}", weather_url))
-        .subject("Weather Station Registration")
-        .html(format!("Hello,<br /><br />Your Weather Station Admin has generated a registration request for you.<br />Press the following link to register for the web interface. <a href=\"https://{}/verify_register?c={}\">Register.</a><br /><br />HAAK Weather Station", weather_url, code))
+        .from(mailer.from.clone())
+        .subject(subject)
+        .alternative(html.render().unwrap(), text.render().unwrap())
         .build()
-        .unwrap();
+        .map_err(Error::Build)?;
 
-    let mut sender = SendmailTransport::new();
-    sender.send(email.into())
+    mailer.queue(email)
+}
+
+/// Sends an email change challenge to the new address of a user
+/// Returns `Ok` once queued or `Err` if the email could not be built or queued
+///
+/// # Arguments
+///
+/// * `mailer` - Queue of the mail worker
+/// * `recipient` - New email address of user
+/// * `locale` - Locale of the user (e.g. `en`)
+/// * `code` - Challenge token
+///
+/// # Remarks
+/// Email should be validated. This function **does not** validate the email input, an address
+/// rejected by the email builder results in `Err`
+pub fn send_email_change(
+    mailer: &Mailer,
+    recipient: String,
+    locale: &str,
+    code: String,
+) -> Result<(), Error> {
+    let html = EmailChangeEmail {
+        locale,
+        url: &mailer.url,
+        code: &code,
+    };
+    let text = EmailChangeText {
+        locale,
+        url: &mailer.url,
+        code: &code,
+    };
+    let subject = match locale {
+        "nl" => "Wijziging e-mailadres Weerstation",
+        _ => "Weather Station Email Change",
+    };
+
+    let email = EmailBuilder::new()
+        .to(recipient)
+        .from(mailer.from.clone())
+        .subject(subject)
+        .alternative(html.render().unwrap(), text.render().unwrap())
+        .build()
+        .map_err(Error::Build)?;
+
+    mailer.queue(email)
 }
 
 /// Sends login challenge email to user
-/// Returns `Ok` on success or `Err` on failure
+/// Returns `Ok` once queued or `Err` if the email could not be built or queued
 ///
 /// # Arguments
 ///
+/// * `mailer` - Queue of the mail worker
 /// * `recipient` - Email address of user
+/// * `locale` - Locale of the user (e.g. `en`)
 /// * `code` - Challenge token
+/// * `short_code` - Short login code to type in instead of opening the link, empty if none
 ///
 /// # Examples
 /// ```
-/// match send_challenge("test@test.com", "generated_challenge") {
+/// match send_challenge(&mailer, "test@test.com", "en", "generated_challenge", "") {
 ///     Ok() => {
 ///         // Handle success
 ///     },
@@ -76,17 +444,194 @@ pub fn send_register(recipient: String, code: String) -> Result<(), Error> {
 /// ```
 ///
 /// # Remarks
-/// Email should be validated. This function **does not** validate the email input
-pub fn send_challenge(recipient: String, code: String) -> Result<(), Error> {
-    let weather_url = &env::var("WEATHER_URL").unwrap();
+/// Email should be validated. This function **does not** validate the email input, an address
+/// rejected by the email builder results in `Err`
+pub fn send_challenge(
+    mailer: &Mailer,
+    recipient: String,
+    locale: &str,
+    code: String,
+    short_code: &str,
+) -> Result<(), Error> {
+    let html = LoginEmail {
+        locale,
+        url: &mailer.url,
+        code: &code,
+        short_code,
+    };
+    let text = LoginText {
+        locale,
+        url: &mailer.url,
+        code: &code,
+        short_code,
+    };
+    let subject = match locale {
+        "nl" => "Inlogpoging Weerstation",
+        _ => "Weather Station Login Attempt",
+    };
+
+    let email = EmailBuilder::new()
+        .to(recipient)
+        .from(mailer.from.clone())
+        .subject(subject)
+        .alternative(html.render().unwrap(), text.render().unwrap())
+        .build()
+        .map_err(Error::Build)?;
+
+    mailer.queue(email)
+}
+
+/// Sends an alert email to a user when one of their alert rules is breached
+/// Returns `Ok` once queued or `Err` if the email could not be built or queued
+///
+/// # Arguments
+///
+/// * `mailer` - Queue of the mail worker
+/// * `recipient` - Email address of user
+/// * `locale` - Locale of the user (e.g. `en`)
+/// * `station` - Station whose reading breached the rule
+/// * `rule` - Breached rule
+/// * `value` - Value of the reading in the user's unit
+/// * `unit` - User's unit of the metric
+pub fn send_alert(
+    mailer: &Mailer,
+    recipient: String,
+    locale: &str,
+    station: &str,
+    rule: &alerts::Rule,
+    value: f64,
+    unit: &str,
+) -> Result<(), Error> {
+    let op = rule.op.to_string();
+    let html = AlertEmail {
+        locale,
+        url: &mailer.url,
+        station,
+        metric: &rule.metric,
+        op: &op,
+        threshold: rule.threshold,
+        value,
+        unit,
+    };
+    let text = AlertText {
+        locale,
+        url: &mailer.url,
+        station,
+        metric: &rule.metric,
+        op: &op,
+        threshold: rule.threshold,
+        value,
+        unit,
+    };
+    let subject = match locale {
+        "nl" => format!(
+            "Alarm Weerstation: {} {} {}",
+            rule.metric, rule.op, rule.threshold
+        ),
+        _ => format!(
+            "Weather Station Alert: {} {} {}",
+            rule.metric, rule.op, rule.threshold
+        ),
+    };
+
+    let email = EmailBuilder::new()
+        .to(recipient)
+        .from(mailer.from.clone())
+        .subject(subject)
+        .alternative(html.render().unwrap(), text.render().unwrap())
+        .build()
+        .map_err(Error::Build)?;
+
+    mailer.queue(email)
+}
+
+/// Sends a plain text test email through the configured transport right away, without the queue
+/// and without retrying, so the result of the transport is returned.
+/// Returns `Ok` once delivered to the transport or the `Err` of building or sending the email
+///
+/// # Arguments
+///
+/// * `mailer` - Queue of the mail worker, for the sender address
+/// * `recipient` - Email address to send the test email to
+///
+/// # Remarks
+///
+/// Blocks while sending, should be run with `web::block`
+pub fn send_test(mailer: &Mailer, recipient: String) -> Result<(), Error> {
     let email = EmailBuilder::new()
         .to(recipient)
-        .from(format!("weather@{}", weather_url))
-        .subject("Weather Station Login Attempt")
-        .html(format!("Hello,<br /><br />You are receiving this email because a login has been requested for the Weather Station.<br />Press the following link to authorize the request. <a href=\"https://{}/verify_login?c={}\">Authorize Request.</a><br /><br />HAAK Weather Station", weather_url, code))
+        .from(mailer.from.clone())
+        .subject("Weather Station Test Email")
+        .text(format!(
+            "This is a test email of the weather station at {}, mail delivery works.",
+            mailer.url
+        ))
         .build()
+        .map_err(Error::Build)?;
+
+    transport()?.send(email.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Creates a mailer without worker, whose queued emails arrive at the returned receiver
+    fn mailer() -> (Mailer, mpsc::Receiver<Email>) {
+        let (sender, receiver) = mpsc::channel();
+        let mailer = Mailer {
+            sender,
+            url: String::from("weather.example.com"),
+            from: Mailbox::new(String::from("weather@weather.example.com")),
+        };
+
+        (mailer, receiver)
+    }
+
+    #[test]
+    fn build_failure_is_an_error() {
+        let (mailer, receiver) = mailer();
+
+        let result = send_register(
+            &mailer,
+            String::from("not an email"),
+            "en",
+            String::from("challenge"),
+        );
+        assert!(matches!(result, Err(Error::Build(_))), "{:?}", result);
+
+        let result = send_challenge(
+            &mailer,
+            String::from("user@"),
+            "en",
+            String::from("challenge"),
+            "",
+        );
+        assert!(matches!(result, Err(Error::Build(_))), "{:?}", result);
+
+        assert!(receiver.try_recv().is_err());
+    }
+
+    #[test]
+    fn emails_have_text_and_html_parts() {
+        let (mailer, receiver) = mailer();
+
+        send_challenge(
+            &mailer,
+            String::from("user@example.com"),
+            "en",
+            String::from("challenge"),
+            "",
+        )
         .unwrap();
 
-    let mut sender = SendmailTransport::new();
-    sender.send(email.into())
+        let email: SendableEmail = receiver.try_recv().unwrap().into();
+        let message = email.message_to_string().unwrap();
+
+        assert!(message.contains("multipart/alternative"), "{}", message);
+        assert!(message.contains("text/plain"), "{}", message);
+        assert!(message.contains("text/html"), "{}", message);
+        // The link is in both parts
+        assert!(message.matches("challenge").count() >= 2, "{}", message);
+    }
 }
@@ -1,22 +1,63 @@
 //! Documentation for graph module
 //!
 //! Most functions are called from the `actix-web` framework
-use crate::haak::database;
+use crate::haak::annotations::Annotation;
+use crate::haak::database::{self, Pool};
+use crate::haak::i18n;
+use crate::haak::response;
+use crate::haak::sensor;
+use crate::haak::session::AuthedUser;
+use crate::haak::settings::{self, UserSettings};
+use crate::haak::units;
 
-use actix::Addr;
-use actix_redis::RedisActor;
-use actix_session::Session;
-use actix_web::{web::Data, HttpResponse, Result};
+use actix_web::http::{header, StatusCode};
+use actix_web::web::{Bytes, Data, Query};
+use actix_web::{HttpRequest, HttpResponse, Result};
 
 use askama::Template;
+use chrono::{Datelike, Duration, TimeZone, Utc};
+use futures::{stream, StreamExt, TryStreamExt};
+use serde::{Deserialize, Serialize};
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::hash::{Hash, Hasher};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Metrics served by the readings API
+pub const METRICS: [&str; 3] = ["temperature", "pressure", "humidity"];
+
+/// Pressure reduced to sea level with the altitude of the station, only served on request
+pub const PRESSURE_MSL: &str = "pressure_msl";
+
+/// Latest reading of a metric
+#[derive(Serialize, Debug)]
+pub struct Latest {
+    value: f64,
+    ts: u64,
+}
+
+/// Seconds of readings fetched from the database per chunk of the CSV export
+const CSV_CHUNK_SECS: u64 = 24 * 60 * 60;
 
 #[derive(Template)]
 #[template(path = "index.html")]
 pub struct GraphSettings<'a> {
     temperature: &'a str,
     pressure: &'a str,
+    humidity: &'a str,
     theme: &'a str,
+    theme_class: &'static str,
     timeframe: &'a str,
+    timezone: &'a str,
+    locale: &'a str,
+    chart_type: &'a str,
+    smoothing: bool,
+    show_temperature: bool,
+    show_pressure: bool,
+    show_humidity: bool,
+    t: i18n::Messages<'a>,
 }
 
 /// Index of the graph, if not logged in redirect user to /login
@@ -24,24 +65,24 @@ pub struct GraphSettings<'a> {
 /// # Remarks
 ///
 /// Should only be called from actix_web
-pub async fn graph_index(session: Session, redis: Data<Addr<RedisActor>>) -> Result<HttpResponse> {
-    let user = session.get::<String>("email").unwrap();
-
-    // If not logged in -> redirect to /login
-    if user.is_none() {
-        return Ok(HttpResponse::SeeOther()
-            .header(actix_web::http::header::LOCATION, "/login")
-            .body(""));
-    }
-
-    let sett =
-        database::settings_get(&session.get::<String>("email").unwrap().unwrap(), &redis).await;
+pub async fn graph_index(AuthedUser(user): AuthedUser, redis: Data<Pool>) -> Result<HttpResponse> {
+    let sett = database::settings_get(&user, &redis).await?;
 
     let view = GraphSettings {
-        temperature: &sett[0],
-        pressure: &sett[1],
-        theme: &sett[2],
-        timeframe: &sett[3],
+        temperature: &sett.temperature,
+        pressure: &sett.pressure,
+        humidity: &sett.humidity,
+        theme: &sett.theme,
+        theme_class: settings::theme_class(&sett.theme),
+        timeframe: &sett.timeframe,
+        timezone: &sett.timezone,
+        locale: &sett.locale,
+        chart_type: &sett.chart_type,
+        smoothing: sett.smoothing,
+        show_temperature: sett.show_temperature,
+        show_pressure: sett.show_pressure,
+        show_humidity: sett.show_humidity,
+        t: i18n::Messages::new(&sett.locale),
     }
     .render()
     .unwrap();
@@ -49,4 +90,900 @@ pub async fn graph_index(session: Session, redis: Data<Addr<RedisActor>>) -> Res
     Ok(HttpResponse::Ok().content_type("text/html").body(view))
 }
 
-// TODO: Implement more routes based on what GUI wants.
+/// Query data of the readings API, all fields are optional
+#[derive(Deserialize)]
+pub struct ReadingsQuery {
+    station: Option<String>,
+    from: Option<u64>,
+    to: Option<u64>,
+    metric: Option<String>,
+    interval: Option<String>,
+    limit: Option<usize>,
+    cursor: Option<u64>,
+}
+
+/// Default and maximum number of readings per metric in one page of /api/readings
+const PAGE_LIMIT: usize = 10_000;
+
+/// Page of the readings API, `next_cursor` is only set when more readings remain
+#[derive(Serialize, Debug)]
+pub struct ReadingsPage {
+    #[serde(flatten)]
+    data: BTreeMap<&'static str, Series>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    next_cursor: Option<u64>,
+    /// Annotations of the station within the window, only on the first page
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    annotations: Vec<Annotation>,
+}
+
+/// Aggregate of the readings in one bucket, `ts` is the start of the bucket
+#[derive(Serialize, Debug)]
+pub struct Bucket {
+    ts: u64,
+    min: f64,
+    max: f64,
+    avg: f64,
+}
+
+/// Readings of a metric, either every raw point or aggregated per bucket
+#[derive(Serialize, Debug)]
+#[serde(untagged)]
+pub enum Series {
+    Raw(Vec<(u64, f64)>),
+    Aggregated(Vec<Bucket>),
+}
+
+/// Length of a bucket in seconds for an `interval` query parameter, 0 means raw readings.
+/// Returns `None` on an unknown interval.
+///
+/// # Arguments
+///
+/// * `interval` - Interval (`raw`, `hour` or `day`)
+fn interval_seconds(interval: &str) -> Option<u64> {
+    match interval {
+        "raw" => Some(0),
+        "hour" => Some(60 * 60),
+        "day" => Some(24 * 60 * 60),
+        _ => None,
+    }
+}
+
+/// Default bucket length in seconds for a range, so a week stays raw, a month is aggregated per
+/// hour and longer ranges per day.
+///
+/// # Arguments
+///
+/// * `window` - Length of the range in seconds
+fn default_interval(window: u64) -> u64 {
+    const DAY: u64 = 24 * 60 * 60;
+
+    match window {
+        w if w <= 7 * DAY => 0,
+        w if w <= 30 * DAY => 60 * 60,
+        _ => DAY,
+    }
+}
+
+/// Buckets readings sorted by timestamp and returns min/max/avg per bucket
+///
+/// # Arguments
+///
+/// * `readings` - Readings sorted by timestamp
+/// * `interval` - Length of a bucket in seconds, non-zero
+fn aggregate(readings: &[(u64, f64)], interval: u64) -> Vec<Bucket> {
+    let mut buckets: Vec<Bucket> = Vec::new();
+    let mut count = 0.0;
+
+    for &(ts, value) in readings {
+        let start = ts - ts % interval;
+
+        match buckets.last_mut() {
+            Some(bucket) if bucket.ts == start => {
+                bucket.min = bucket.min.min(value);
+                bucket.max = bucket.max.max(value);
+                bucket.avg += (value - bucket.avg) / (count + 1.0);
+                count += 1.0;
+            }
+            _ => {
+                buckets.push(Bucket {
+                    ts: start,
+                    min: value,
+                    max: value,
+                    avg: value,
+                });
+                count = 1.0;
+            }
+        }
+    }
+
+    buckets
+}
+
+/// Start of the next page when a page is cut off, `None` if every reading fits in the page.
+/// Pages end on a bucket boundary when aggregated, so no bucket is split over two pages.
+///
+/// # Arguments
+///
+/// * `pages` - Readings fetched per metric, sorted by timestamp
+/// * `start` - Start of the current page
+/// * `limit` - Maximum number of readings per metric
+/// * `interval` - Length of a bucket in seconds, 0 for raw readings
+fn next_cursor(pages: &[Vec<(u64, f64)>], start: u64, limit: usize, interval: u64) -> Option<u64> {
+    // Every reading up to the earliest last reading of a full page is complete
+    let last = pages
+        .iter()
+        .filter(|page| page.len() >= limit)
+        .filter_map(|page| page.last().map(|(ts, _)| *ts))
+        .min()?;
+
+    Some(match interval {
+        0 => last + 1,
+        interval if last - last % interval > start => last - last % interval,
+        _ => last + 1,
+    })
+}
+
+/// Computes the ETag of a readings response from every timestamp and value of the readings of every
+/// metric, along with everything else that shapes the response, so a reading replaced at the same
+/// timestamp changes the ETag. The range itself is left out, so a window ending now keeps its ETag
+/// until readings are added, replaced or drop out.
+///
+/// # Arguments
+///
+/// * `metrics` - Metrics of the response
+/// * `pages` - Readings fetched per metric, sorted by timestamp
+/// * `interval` - Length of a bucket in seconds, 0 for raw readings
+/// * `next_cursor` - Start of the next page
+/// * `annotations` - Annotations of the response
+/// * `settings` - Settings of the user, for the units
+fn etag(
+    metrics: &[&str],
+    pages: &[Vec<(u64, f64)>],
+    interval: u64,
+    next_cursor: Option<u64>,
+    annotations: &[Annotation],
+    settings: &UserSettings,
+) -> String {
+    let mut hasher = DefaultHasher::new();
+
+    for (metric, page) in metrics.iter().zip(pages) {
+        metric.hash(&mut hasher);
+        page.len().hash(&mut hasher);
+        for (ts, value) in page.iter() {
+            ts.hash(&mut hasher);
+            value.to_bits().hash(&mut hasher);
+        }
+    }
+    interval.hash(&mut hasher);
+    next_cursor.hash(&mut hasher);
+    annotations.hash(&mut hasher);
+    settings.temperature.hash(&mut hasher);
+    settings.pressure.hash(&mut hasher);
+    settings.humidity.hash(&mut hasher);
+
+    format!("\"{:016x}\"", hasher.finish())
+}
+
+/// Returns true if the `If-None-Match` header of the request matches the ETag
+///
+/// # Arguments
+///
+/// * `req` - HTTP request
+/// * `etag` - ETag of the response
+fn not_modified(req: &HttpRequest, etag: &str) -> bool {
+    req.headers()
+        .get(header::IF_NONE_MATCH)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| {
+            value
+                .split(',')
+                .map(str::trim)
+                .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+        })
+}
+
+/// Length of a timeframe setting in seconds
+///
+/// # Arguments
+///
+/// * `timeframe` - Timeframe setting (`Week`, `Month` or `QuarterYear`)
+fn timeframe_seconds(timeframe: &str) -> u64 {
+    const DAY: u64 = 24 * 60 * 60;
+
+    match timeframe {
+        "Month" => 30 * DAY,
+        "QuarterYear" => 91 * DAY,
+        _ => 7 * DAY,
+    }
+}
+
+/// Metrics selected by the query, all metrics if none is given.
+/// Returns `None` on an unknown metric.
+///
+/// # Arguments
+///
+/// * `query` - Query containing the optional metric
+fn query_metrics(query: &ReadingsQuery) -> Option<Vec<&'static str>> {
+    match &query.metric {
+        Some(metric) => METRICS.iter().find(|m| *m == metric).map(|m| vec![*m]),
+        None => Some(METRICS.to_vec()),
+    }
+}
+
+/// Query data of the latest readings API
+#[derive(Deserialize)]
+pub struct StationQuery {
+    station: Option<String>,
+}
+
+/// Station selected by the query, `default` if none is given. Returns `None` on an invalid station.
+///
+/// # Arguments
+///
+/// * `station` - Optional station of the query
+pub fn query_station(station: &Option<String>) -> Option<&str> {
+    match station {
+        Some(station) if sensor::valid_station(station) => Some(station),
+        Some(_) => None,
+        None => Some(sensor::DEFAULT_STATION),
+    }
+}
+
+/// Range selected by the query. When no range is given the user's timeframe setting is used as
+/// window, ending now.
+///
+/// # Arguments
+///
+/// * `from` - Optional start of the range
+/// * `to` - Optional end of the range
+/// * `user` - Email address of the logged in user
+/// * `redis` - RedisActor to access redis database
+pub async fn query_range(
+    from: Option<u64>,
+    to: Option<u64>,
+    user: &str,
+    redis: &Data<Pool>,
+) -> Result<(u64, u64), database::DbError> {
+    Ok(match (from, to) {
+        (Some(from), Some(to)) => (from, to),
+        (from, to) => {
+            let sett = database::settings_get(user, redis).await?;
+            let window = timeframe_seconds(&sett.timeframe);
+            let to = to.unwrap_or_else(|| {
+                SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap()
+                    .as_secs()
+            });
+
+            (from.unwrap_or_else(|| to.saturating_sub(window)), to)
+        }
+    })
+}
+
+/// Handles HTTP GET requests to /api/readings.
+/// Returns the readings per metric in the user's units as JSON
+/// (`{"temperature": [[ts, val], ...], ...}`), or with `interval=hour|day` the aggregates per
+/// bucket (`{"temperature": [{"ts", "min", "max", "avg"}, ...], ...}`). Without an interval,
+/// ranges longer than a week are aggregated.
+/// When no range is given the user's timeframe setting is used as window, ending now.
+/// Readings are of the `station` in the query, `default` if none is given.
+/// `metric=pressure_msl` returns the pressure reduced to sea level with the altitude of the station
+/// (see `units::sea_level_pressure`), it is not part of the response without a metric. Without a
+/// metric, metrics the user hid in the settings are left out as well.
+///
+/// At most `limit` readings per metric (default and maximum 10000) are returned. When more remain
+/// the response has a `next_cursor`, passed as `cursor` with the same query to get the next page.
+///
+/// The first page also has the `annotations` of the station within the range, if any
+/// (`{"annotations": [{"id", "ts", "text", "author"}, ...]}`).
+///
+/// The response has an ETag, 304 NotModified is sent when it matches `If-None-Match`.
+///
+/// Sends 401 Unauthorized if not logged in and 422 UnprocessableEntity on an invalid station, an
+/// unknown metric or interval, or `pressure_msl` for a station without altitude.
+///
+/// # Arguments
+///
+/// * `req` - HTTP request, containing the optional `If-None-Match` header
+/// * `query` - Query containing optional `station`, `from`, `to`, `metric`, `interval`, `limit`
+///   and `cursor`
+/// * `user` - Logged in user
+/// * `redis` - RedisActor to access redis database
+///
+/// # Remarks
+///
+/// Should only be called from actix_web
+pub async fn readings(
+    req: HttpRequest,
+    Query(query): Query<ReadingsQuery>,
+    AuthedUser(user): AuthedUser,
+    redis: Data<Pool>,
+) -> Result<HttpResponse> {
+    let sea_level = query.metric.as_deref() == Some(PRESSURE_MSL);
+    let metrics = match query_metrics(&query) {
+        Some(metrics) => metrics,
+        None if sea_level => vec![PRESSURE_MSL],
+        None => {
+            return Ok(response::error(
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "invalid_metric",
+                "Invalid metric",
+            ))
+        }
+    };
+
+    let station = match query_station(&query.station) {
+        Some(station) => station,
+        None => {
+            return Ok(response::error(
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "invalid_station",
+                "Invalid station",
+            ))
+        }
+    };
+
+    let altitude = match sea_level {
+        true => match database::station_altitude(station, &redis).await? {
+            Some(altitude) => altitude,
+            None => {
+                return Ok(response::error(
+                    StatusCode::UNPROCESSABLE_ENTITY,
+                    "unknown_altitude",
+                    "Altitude of the station is not set",
+                ))
+            }
+        },
+        false => 0.0,
+    };
+
+    let interval = match &query.interval {
+        Some(interval) => match interval_seconds(interval) {
+            Some(interval) => Some(interval),
+            None => {
+                return Ok(response::error(
+                    StatusCode::UNPROCESSABLE_ENTITY,
+                    "invalid_interval",
+                    "Invalid interval",
+                ))
+            }
+        },
+        None => None,
+    };
+
+    let (from, to) = query_range(query.from, query.to, &user, &redis).await?;
+    let interval = interval.unwrap_or_else(|| default_interval(to.saturating_sub(from)));
+    let sett = database::settings_get(&user, &redis).await?;
+
+    // Hidden metrics aren't fetched, unless asked for explicitly
+    let metrics: Vec<&str> = match query.metric {
+        Some(_) => metrics,
+        None => metrics
+            .into_iter()
+            .filter(|metric| sett.shows(metric))
+            .collect(),
+    };
+
+    let limit = query.limit.unwrap_or(PAGE_LIMIT).clamp(1, PAGE_LIMIT);
+    let start = query.cursor.map_or(from, |cursor| cursor.max(from));
+
+    let mut pages = Vec::new();
+    for metric in metrics.iter() {
+        let page = match *metric {
+            PRESSURE_MSL => database::readings_page(station, "pressure", start, to, limit, &redis)
+                .await?
+                .into_iter()
+                .map(|(ts, value)| (ts, units::sea_level_pressure(value, altitude)))
+                .collect(),
+            metric => database::readings_page(station, metric, start, to, limit, &redis).await?,
+        };
+
+        pages.push(page);
+    }
+
+    let next_cursor = next_cursor(&pages, start, limit, interval).filter(|next| *next <= to);
+
+    let annotations = match query.cursor {
+        Some(_) => Vec::new(),
+        None => database::annotations_range(station, from, to, &redis).await?,
+    };
+
+    let etag = etag(&metrics, &pages, interval, next_cursor, &annotations, &sett);
+    if not_modified(&req, &etag) {
+        return Ok(HttpResponse::NotModified()
+            .header(header::ETAG, etag)
+            .finish());
+    }
+
+    let mut data = BTreeMap::new();
+    for (metric, page) in metrics.into_iter().zip(pages) {
+        let readings: Vec<(u64, f64)> = page
+            .into_iter()
+            .filter(|(ts, _)| next_cursor.is_none_or(|next| *ts < next))
+            .map(|(ts, value)| (ts, units::convert(metric, value, &sett)))
+            .collect();
+        let series = match interval {
+            0 => Series::Raw(readings),
+            interval => Series::Aggregated(aggregate(&readings, interval)),
+        };
+
+        data.insert(metric, series);
+    }
+
+    Ok(HttpResponse::Ok()
+        .header(header::ETAG, etag)
+        .header(header::CACHE_CONTROL, "private, no-cache")
+        .json(ReadingsPage {
+            data,
+            next_cursor,
+            annotations,
+        }))
+}
+
+/// Fetches the readings of one chunk of the CSV export and formats them as CSV rows, one row per
+/// timestamp. Metrics without a reading at that timestamp are left empty.
+///
+/// # Arguments
+///
+/// * `station` - Station ID
+/// * `metrics` - Metrics to export, in column order
+/// * `from` - Start of the chunk (inclusive)
+/// * `to` - End of the chunk (inclusive)
+/// * `redis` - RedisActor to access redis database
+async fn csv_chunk(
+    station: &str,
+    metrics: &[&str],
+    from: u64,
+    to: u64,
+    redis: &Data<Pool>,
+) -> Result<Bytes, database::DbError> {
+    let mut rows: BTreeMap<u64, Vec<Option<f64>>> = BTreeMap::new();
+
+    for (i, metric) in metrics.iter().enumerate() {
+        for (ts, value) in database::readings_range(station, metric, from, to, redis).await? {
+            rows.entry(ts).or_insert_with(|| vec![None; metrics.len()])[i] = Some(value);
+        }
+    }
+
+    let mut csv = String::new();
+    for (ts, values) in rows {
+        let _ = write!(csv, "{}", ts);
+        for value in values {
+            match value {
+                Some(value) => {
+                    let _ = write!(csv, ",{}", value);
+                }
+                None => csv.push(','),
+            }
+        }
+        csv.push('\n');
+    }
+
+    Ok(Bytes::from(csv))
+}
+
+/// Handles HTTP GET requests to /api/readings.csv.
+/// Streams the readings as CSV (`timestamp,temperature,pressure,humidity`) attachment, fetching
+/// one day of readings at a time. Accepts the same query as /api/readings, the CSV always contains
+/// raw readings in the stored units (Celsius, Bar and percent) so `interval` is ignored.
+/// Sends 401 Unauthorized if not logged in and 422 UnprocessableEntity on an invalid station or an
+/// unknown metric.
+///
+/// # Arguments
+///
+/// * `query` - Query containing optional `station`, `from`, `to` and `metric`
+/// * `user` - Logged in user
+/// * `redis` - RedisActor to access redis database
+///
+/// # Remarks
+///
+/// Should only be called from actix_web
+pub async fn readings_csv(
+    Query(query): Query<ReadingsQuery>,
+    AuthedUser(user): AuthedUser,
+    redis: Data<Pool>,
+) -> Result<HttpResponse> {
+    let metrics = match query_metrics(&query) {
+        Some(metrics) => metrics,
+        None => {
+            return Ok(response::error(
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "invalid_metric",
+                "Invalid metric",
+            ))
+        }
+    };
+
+    let station = match query_station(&query.station) {
+        Some(station) => station.to_owned(),
+        None => {
+            return Ok(response::error(
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "invalid_station",
+                "Invalid station",
+            ))
+        }
+    };
+
+    let (from, to) = query_range(query.from, query.to, &user, &redis).await?;
+
+    let header = Bytes::from(format!("timestamp,{}\n", metrics.join(",")));
+
+    // Cursor is the start of the next chunk, `None` once the range is exported
+    let rows = stream::try_unfold(Some(from), move |cursor| {
+        let station = station.clone();
+        let metrics = metrics.clone();
+        let redis = redis.clone();
+
+        async move {
+            let start = match cursor {
+                Some(start) if start <= to => start,
+                _ => return Ok(None),
+            };
+            let end = start.saturating_add(CSV_CHUNK_SECS - 1).min(to);
+            let chunk = csv_chunk(&station, &metrics, start, end, &redis).await?;
+
+            let next = if end < to { Some(end + 1) } else { None };
+            Ok::<_, database::DbError>(Some((chunk, next)))
+        }
+    });
+
+    let body = stream::once(async { Ok(header) })
+        .chain(rows)
+        .map_err(actix_web::Error::from);
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/csv")
+        .header(
+            actix_web::http::header::CONTENT_DISPOSITION,
+            "attachment; filename=\"readings.csv\"",
+        )
+        .streaming(body))
+}
+
+/// Handles HTTP GET requests to /api/latest.
+/// Returns the most recent reading per metric in the user's units as JSON
+/// (`{"temperature": {"value": 21.3, "ts": 1578000000}, ...}`), `null` for metrics without
+/// readings. Readings are of the `station` in the query, `default` if none is given.
+/// Sends 401 Unauthorized if not logged in and 422 UnprocessableEntity on an invalid station.
+///
+/// # Arguments
+///
+/// * `query` - Query containing the optional `station`
+/// * `user` - Logged in user
+/// * `redis` - RedisActor to access redis database
+///
+/// # Remarks
+///
+/// Should only be called from actix_web
+pub async fn latest(
+    Query(query): Query<StationQuery>,
+    AuthedUser(user): AuthedUser,
+    redis: Data<Pool>,
+) -> Result<HttpResponse> {
+    let station = match query_station(&query.station) {
+        Some(station) => station,
+        None => {
+            return Ok(response::error(
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "invalid_station",
+                "Invalid station",
+            ))
+        }
+    };
+
+    let sett = database::settings_get(&user, &redis).await?;
+
+    let mut data = BTreeMap::new();
+    for metric in METRICS.iter() {
+        let latest = database::readings_latest(station, metric, &redis)
+            .await?
+            .map(|(ts, value)| Latest {
+                value: units::convert(metric, value, &sett),
+                ts,
+            });
+
+        data.insert(*metric, latest);
+    }
+
+    Ok(HttpResponse::Ok().json(data))
+}
+
+/// Query data of the summary API
+#[derive(Deserialize)]
+pub struct SummaryQuery {
+    station: Option<String>,
+    metric: String,
+    period: String,
+}
+
+/// Statistics of a metric over a period, the values are `None` when the period has no readings
+#[derive(Serialize, Debug)]
+pub struct Summary {
+    count: usize,
+    min: Option<f64>,
+    max: Option<f64>,
+    mean: Option<f64>,
+}
+
+/// Computes count, min, max and mean of the values
+///
+/// # Arguments
+///
+/// * `values` - Values to summarize
+fn summarize(values: &[f64]) -> Summary {
+    let count = values.len();
+    let min = values.iter().cloned().fold(None, |min: Option<f64>, v| {
+        Some(min.map_or(v, |min| min.min(v)))
+    });
+    let max = values.iter().cloned().fold(None, |max: Option<f64>, v| {
+        Some(max.map_or(v, |max| max.max(v)))
+    });
+    let mean = match count {
+        0 => None,
+        count => Some(values.iter().sum::<f64>() / count as f64),
+    };
+
+    Summary {
+        count,
+        min,
+        max,
+        mean,
+    }
+}
+
+/// Start of the current day, week (starting Monday) or month in the timezone, as UNIX timestamp.
+/// Returns `None` on an unknown period.
+///
+/// # Arguments
+///
+/// * `period` - Period (`day`, `week` or `month`)
+/// * `timezone` - Timezone of the user, UTC if invalid
+/// * `now` - Current UNIX timestamp
+fn period_start(period: &str, timezone: &str, now: u64) -> Option<u64> {
+    let tz: chrono_tz::Tz = timezone.parse().unwrap_or(chrono_tz::UTC);
+    let today = Utc
+        .timestamp(now as i64, 0)
+        .with_timezone(&tz)
+        .date()
+        .naive_local();
+
+    let first = match period {
+        "day" => today,
+        "week" => today - Duration::days(i64::from(today.weekday().num_days_from_monday())),
+        "month" => today.with_day(1)?,
+        _ => return None,
+    };
+
+    // Midnight may not exist when a DST change happens at midnight, the day starts an hour later
+    let midnight = first.and_hms(0, 0, 0);
+    let start = tz.from_local_datetime(&midnight).earliest().or_else(|| {
+        tz.from_local_datetime(&(midnight + Duration::hours(1)))
+            .earliest()
+    })?;
+
+    Some(start.timestamp().max(0) as u64)
+}
+
+/// Handles HTTP GET requests to /api/summary.
+/// Returns the count, min, max and mean of a metric in the user's units as JSON
+/// (`{"count", "min", "max", "mean"}`) over the current `day`, `week` or `month` in the user's
+/// timezone, up to now. Min, max and mean are null when the period has no readings.
+/// Readings are of the `station` in the query, `default` if none is given.
+///
+/// Sends 401 Unauthorized if not logged in and 422 UnprocessableEntity on an invalid station or an
+/// unknown metric or period.
+///
+/// # Arguments
+///
+/// * `query` - Query containing `metric`, `period` and the optional `station`
+/// * `user` - Logged in user
+/// * `redis` - RedisActor to access redis database
+///
+/// # Remarks
+///
+/// Should only be called from actix_web
+pub async fn summary(
+    Query(query): Query<SummaryQuery>,
+    AuthedUser(user): AuthedUser,
+    redis: Data<Pool>,
+) -> Result<HttpResponse> {
+    let metric = match METRICS.iter().find(|m| **m == query.metric) {
+        Some(metric) => metric,
+        None => {
+            return Ok(response::error(
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "invalid_metric",
+                "Invalid metric",
+            ))
+        }
+    };
+
+    let station = match query_station(&query.station) {
+        Some(station) => station,
+        None => {
+            return Ok(response::error(
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "invalid_station",
+                "Invalid station",
+            ))
+        }
+    };
+
+    let sett = database::settings_get(&user, &redis).await?;
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+
+    let from = match period_start(&query.period, &sett.timezone, now) {
+        Some(from) => from,
+        None => {
+            return Ok(response::error(
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "invalid_period",
+                "Invalid period",
+            ))
+        }
+    };
+
+    let values: Vec<f64> = database::readings_range(station, metric, from, now, &redis)
+        .await?
+        .into_iter()
+        .map(|(_, value)| units::convert(metric, value, &sett))
+        .collect();
+
+    Ok(HttpResponse::Ok().json(summarize(&values)))
+}
+
+/// Query data of the gaps API
+#[derive(Deserialize)]
+pub struct GapsQuery {
+    station: Option<String>,
+    from: Option<u64>,
+    to: Option<u64>,
+    metric: String,
+    expected_interval: u64,
+}
+
+/// Spacing between two readings, as a multiple of the expected interval, above which it is a gap
+const GAP_FACTOR: u64 = 2;
+
+/// Gap between two consecutive readings, `start` and `end` are the timestamps of the readings
+#[derive(Serialize, Debug)]
+pub struct Gap {
+    start: u64,
+    end: u64,
+    duration: u64,
+}
+
+/// Finds the gaps between consecutive readings spaced more than `threshold` seconds apart
+///
+/// # Arguments
+///
+/// * `readings` - `(timestamp, value)` pairs ordered by timestamp
+/// * `threshold` - Largest spacing in seconds that isn't a gap
+fn find_gaps(readings: &[(u64, f64)], threshold: u64) -> Vec<Gap> {
+    readings
+        .windows(2)
+        .map(|pair| (pair[0].0, pair[1].0))
+        .filter(|(start, end)| end - start > threshold)
+        .map(|(start, end)| Gap {
+            start,
+            end,
+            duration: end - start,
+        })
+        .collect()
+}
+
+/// Handles HTTP GET requests to /api/gaps.
+/// Returns the gaps in the readings of a metric as JSON (`[{"start", "end", "duration"}, ...]`),
+/// where consecutive readings are more than twice `expected_interval` seconds apart. When no range
+/// is given the user's timeframe setting is used as window, ending now.
+/// Readings are of the `station` in the query, `default` if none is given.
+///
+/// Sends 401 Unauthorized if not logged in and 422 UnprocessableEntity on an invalid station, an
+/// unknown metric or an `expected_interval` of 0.
+///
+/// # Arguments
+///
+/// * `query` - Query containing `metric`, `expected_interval` (seconds) and the optional
+///   `station`, `from` and `to`
+/// * `user` - Logged in user
+/// * `redis` - RedisActor to access redis database
+///
+/// # Remarks
+///
+/// Should only be called from actix_web
+pub async fn gaps(
+    Query(query): Query<GapsQuery>,
+    AuthedUser(user): AuthedUser,
+    redis: Data<Pool>,
+) -> Result<HttpResponse> {
+    let metric = match METRICS.iter().find(|m| **m == query.metric) {
+        Some(metric) => metric,
+        None => {
+            return Ok(response::error(
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "invalid_metric",
+                "Invalid metric",
+            ))
+        }
+    };
+
+    let station = match query_station(&query.station) {
+        Some(station) => station,
+        None => {
+            return Ok(response::error(
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "invalid_station",
+                "Invalid station",
+            ))
+        }
+    };
+
+    if query.expected_interval == 0 {
+        return Ok(response::error(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "invalid_interval",
+            "Expected interval must be at least 1 second",
+        ));
+    }
+
+    let (from, to) = query_range(query.from, query.to, &user, &redis).await?;
+    let readings = database::readings_range(station, metric, from, to, &redis).await?;
+
+    Ok(HttpResponse::Ok().json(find_gaps(
+        &readings,
+        query.expected_interval.saturating_mul(GAP_FACTOR),
+    )))
+}
+
+/// Handles HTTP GET requests to /api/stations.
+/// Returns the IDs of all stations that sent readings as JSON (`["default", ...]`). Sends 401
+/// Unauthorized if not logged in.
+///
+/// # Arguments
+///
+/// * `_user` - Logged in user
+/// * `redis` - RedisActor to access redis database
+///
+/// # Remarks
+///
+/// Should only be called from actix_web
+pub async fn stations(_user: AuthedUser, redis: Data<Pool>) -> Result<HttpResponse> {
+    Ok(HttpResponse::Ok().json(database::stations(&redis).await?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn etag_changes_with_replaced_values() {
+        let settings = UserSettings::default();
+        let page = vec![(100, 21.5), (160, 21.7), (220, 21.6)];
+        let tag = |page: &Vec<(u64, f64)>| {
+            etag(
+                &["temperature"],
+                std::slice::from_ref(page),
+                0,
+                None,
+                &[],
+                &settings,
+            )
+        };
+
+        assert_eq!(tag(&page), tag(&page));
+
+        let mut replaced = page.clone();
+        replaced[1].1 = 25.0;
+        assert_ne!(tag(&page), tag(&replaced));
+
+        let mut added = page.clone();
+        added.push((280, 21.4));
+        assert_ne!(tag(&page), tag(&added));
+    }
+}
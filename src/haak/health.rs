@@ -0,0 +1,122 @@
+//! Documentation for health module
+//! Includes the health check used by load balancers and orchestrators, the startup diagnostics
+//! and the build info.
+//!
+//! Most functions are called from the `actix-web` framework.
+use crate::haak::config::Config;
+use crate::haak::database::{self, Pool};
+use crate::haak::email;
+use crate::haak::tls;
+
+use actix_web::web::Data;
+use actix_web::HttpResponse;
+
+use serde_json::json;
+
+use std::time::Duration;
+
+/// Number of pings of the startup probe
+const PROBE_ATTEMPTS: u32 = 5;
+
+/// Checks at startup that redis is reachable, pinging it a few times as the RedisActor connects in
+/// the background. Returns the last error if redis never replied.
+///
+/// # Arguments
+///
+/// * `redis` - RedisActor to access redis database
+pub async fn probe(redis: &Data<Pool>) -> Result<(), database::DbError> {
+    let mut attempt = 1;
+
+    loop {
+        match database::ping(Duration::from_secs(1), redis).await {
+            Ok(_) => return Ok(()),
+            Err(e) if attempt >= PROBE_ATTEMPTS => return Err(e),
+            Err(_) => {
+                actix_rt::time::delay_for(Duration::from_secs(1)).await;
+                attempt += 1;
+            }
+        }
+    }
+}
+
+/// Results of the startup checks of the dependencies of the server
+pub struct Diagnostics {
+    /// Whether redis replies to a `PING`
+    pub redis: Result<(), String>,
+    /// Whether the certificate and private key load
+    pub tls: Result<(), String>,
+    /// Whether the mail transport can be created, no email is sent
+    pub mail: Result<(), String>,
+}
+
+impl Diagnostics {
+    /// Returns true if every check passed
+    pub fn passed(&self) -> bool {
+        self.redis.is_ok() && self.tls.is_ok() && self.mail.is_ok()
+    }
+}
+
+/// Checks the dependencies of the server at startup and logs a line per dependency, `✓` if it
+/// works and `✗` with the error if it doesn't.
+///
+/// # Arguments
+///
+/// * `config` - Server configuration
+/// * `redis` - RedisActor to access redis database
+pub async fn diagnose(config: &Config, redis: &Data<Pool>) -> Diagnostics {
+    let diagnostics = Diagnostics {
+        redis: probe(redis).await.map_err(|e| e.to_string()),
+        tls: tls::Reloadable::load(config.tls.clone()).map(|_| ()),
+        mail: email::transport().map(|_| ()).map_err(|e| e.to_string()),
+    };
+
+    let checks = [
+        ("redis", &diagnostics.redis),
+        ("tls", &diagnostics.tls),
+        ("mail", &diagnostics.mail),
+    ];
+    for (name, result) in checks.iter() {
+        match result {
+            Ok(_) => info!("\u{2713} {}", name),
+            Err(e) => error!("\u{2717} {}: {}", name, e),
+        }
+    }
+
+    diagnostics
+}
+
+/// Handles HTTP GET requests to /healthz.
+/// Returns 200 OK if redis replies to a `PING` and 503 ServiceUnavailable otherwise.
+/// Does not require authentication.
+///
+/// # Arguments
+///
+/// * `redis` - RedisActor to access redis database
+///
+/// # Remarks
+///
+/// Should only be called from actix_web
+pub async fn healthz(redis: Data<Pool>) -> HttpResponse {
+    match database::ping(Duration::from_secs(2), &redis).await {
+        Ok(_) => HttpResponse::Ok().json(json!({"redis": "ok"})),
+        Err(e) => {
+            warn!("Health check failed: {}", e);
+            HttpResponse::ServiceUnavailable().json(json!({"redis": "down"}))
+        }
+    }
+}
+
+/// Handles HTTP GET requests to /version.
+/// Returns the crate version, git commit and build time (unix timestamp) of the running binary as
+/// JSON (`{"version", "commit", "built_at"}`). Does not require authentication.
+///
+/// # Remarks
+///
+/// Should only be called from actix_web
+pub async fn version() -> HttpResponse {
+    HttpResponse::Ok().json(json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "commit": env!("GIT_HASH"),
+        "built_at": env!("BUILD_TIMESTAMP").parse::<u64>().unwrap_or(0),
+    }))
+}
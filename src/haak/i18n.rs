@@ -0,0 +1,70 @@
+//! Documentation for i18n module
+//! Includes the message catalogs of the web pages.
+//!
+//! Catalogs are TOML files in `templates/i18n/<locale>.toml` mapping message keys to strings,
+//! built into the binary. Templates look messages up with `{{ t.get("key") }}`. Keys missing from
+//! a catalog fall back to English, keys missing from English render as the key itself.
+use std::collections::HashMap;
+use std::sync::OnceLock;
+
+/// Locales the web pages and emails are available in, the first one is the fallback
+pub const LOCALES: [&str; 2] = ["en", "nl"];
+
+/// Sources of the catalogs, in the order of `LOCALES`
+const SOURCES: [&str; 2] = [
+    include_str!("../../templates/i18n/en.toml"),
+    include_str!("../../templates/i18n/nl.toml"),
+];
+
+/// Parsed catalogs per locale
+static CATALOGS: OnceLock<HashMap<&'static str, HashMap<String, String>>> = OnceLock::new();
+
+/// Parses the catalogs on first use
+///
+/// # Panics
+///
+/// Panics if a built-in catalog is not a TOML table of strings
+fn catalogs() -> &'static HashMap<&'static str, HashMap<String, String>> {
+    CATALOGS.get_or_init(|| {
+        LOCALES
+            .iter()
+            .zip(SOURCES.iter())
+            .map(|(locale, source)| {
+                let catalog = toml::from_str(source)
+                    .unwrap_or_else(|e| panic!("Invalid catalog {}: {}", locale, e));
+                (*locale, catalog)
+            })
+            .collect()
+    })
+}
+
+/// Messages of one locale, passed to templates as `t`
+pub struct Messages<'a> {
+    locale: &'a str,
+}
+
+impl<'a> Messages<'a> {
+    /// Messages in the locale, unknown locales get English
+    ///
+    /// # Arguments
+    ///
+    /// * `locale` - Locale of the user (e.g. `en`)
+    pub fn new(locale: &'a str) -> Messages<'a> {
+        Messages { locale }
+    }
+
+    /// Looks up a message by key, falling back to English and then to the key
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - Message key (e.g. `nav.settings`)
+    pub fn get<'k>(&self, key: &'k str) -> &'k str {
+        let catalogs = catalogs();
+
+        [self.locale, LOCALES[0]]
+            .iter()
+            .filter_map(|locale| catalogs.get(*locale))
+            .find_map(|catalog| catalog.get(key))
+            .map_or(key, String::as_str)
+    }
+}
@@ -0,0 +1,71 @@
+//! Documentation for keys module
+//! Includes the construction of redis keys.
+//!
+//! Every key the server reads or writes goes through `key`, which prepends `REDIS_KEY_PREFIX`
+//! (empty by default), so several instances can share one redis without seeing each other's
+//! data, e.g. `tenant1:user:<email>` and `tenant2:user:<email>`.
+use std::sync::OnceLock;
+
+/// Prefix of every key, set once at startup
+static PREFIX: OnceLock<String> = OnceLock::new();
+
+/// Sets the prefix of every key, later calls are ignored
+///
+/// # Arguments
+///
+/// * `prefix` - Prefix of every key (e.g. `tenant1:`)
+pub fn init(prefix: &str) {
+    let _ = PREFIX.set(prefix.to_owned());
+}
+
+/// Prefix of every key, empty if `init` wasn't called
+fn prefix() -> &'static str {
+    PREFIX.get().map(String::as_str).unwrap_or("")
+}
+
+/// Returns the key of a name, with the prefix prepended
+///
+/// # Arguments
+///
+/// * `name` - Name of the key (e.g. `user:<email>`), or a `SCAN` pattern
+pub fn key(name: &str) -> String {
+    format!("{}{}", prefix(), name)
+}
+
+/// Escapes the glob characters of a part of a `SCAN` pattern, so e.g. the email address
+/// `*@example.com` in `device:<email>:*` only matches that user's keys
+///
+/// # Arguments
+///
+/// * `part` - Literal part of the pattern (e.g. an email address)
+pub fn escape(part: &str) -> String {
+    let mut escaped = String::with_capacity(part.len());
+    for c in part.chars() {
+        if "*?[]\\".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Returns the name of a key, without the prefix. Keys found with `SCAN` have the prefix.
+///
+/// # Arguments
+///
+/// * `key` - Key including the prefix
+pub fn strip(key: &str) -> &str {
+    key.strip_prefix(prefix()).unwrap_or(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escape_glob_characters() {
+        assert_eq!(escape("bob@example.com"), "bob@example.com");
+        assert_eq!(escape("*@example.com"), "\\*@example.com");
+        assert_eq!(escape("a?b[c]d\\e@x.com"), "a\\?b\\[c\\]d\\\\e@x.com");
+    }
+}
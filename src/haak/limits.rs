@@ -0,0 +1,153 @@
+//! Documentation for limits module
+//! Includes the request body size limits.
+//!
+//! Every extractor gets its own limit, so the JSON API (authentication and administration), the
+//! settings form and the sensor ingestion can be limited separately. Oversized bodies are rejected
+//! with 413 Payload Too Large, malformed bodies with 400 Bad Request, both in the JSON error
+//! envelope of `response`.
+use crate::haak::response;
+
+use actix_web::error::{InternalError, JsonPayloadError, PayloadError, UrlencodedError};
+use actix_web::http::StatusCode;
+use actix_web::web::{BytesMut, FormConfig, JsonConfig, Payload, PayloadConfig};
+
+use futures::StreamExt;
+
+/// Default limit of every request body, 8 KiB
+pub const DEFAULT_LIMIT: usize = 8 * 1024;
+
+/// Creates the configuration of the JSON extractor
+///
+/// # Arguments
+///
+/// * `limit` - Maximum size of a JSON body in bytes
+pub fn json(limit: usize) -> JsonConfig {
+    json_expecting(limit, "")
+}
+
+/// Creates the configuration of the JSON extractor of a route, naming the expected body in the
+/// error of a malformed body. Set as data of the route's resource, which replaces the app's.
+///
+/// # Arguments
+///
+/// * `limit` - Maximum size of a JSON body in bytes
+/// * `expected` - Shape of the body (e.g. `{"email": ...}`), empty to leave it out
+pub fn json_expecting(limit: usize, expected: &'static str) -> JsonConfig {
+    JsonConfig::default()
+        .limit(limit)
+        .error_handler(move |err, _| match err {
+            JsonPayloadError::Overflow => too_large(err, limit),
+            JsonPayloadError::ContentType => malformed(
+                err,
+                "invalid_json",
+                "Expected a body with Content-Type: application/json",
+                expected,
+            ),
+            err => {
+                let message = format!("Invalid JSON body: {}", err);
+                malformed(err, "invalid_json", &message, expected)
+            }
+        })
+}
+
+/// Creates the configuration of the form extractor
+///
+/// # Arguments
+///
+/// * `limit` - Maximum size of a form body in bytes
+pub fn form(limit: usize) -> FormConfig {
+    form_expecting(limit, "")
+}
+
+/// Creates the configuration of the form extractor of a route, naming the expected fields in the
+/// error of a malformed form. Set as data of the route's resource, which replaces the app's.
+///
+/// # Arguments
+///
+/// * `limit` - Maximum size of a form body in bytes
+/// * `expected` - Fields of the form (e.g. `temperature, pressure`), empty to leave them out
+pub fn form_expecting(limit: usize, expected: &'static str) -> FormConfig {
+    FormConfig::default()
+        .limit(limit)
+        .error_handler(move |err, _| match err {
+            UrlencodedError::Overflow { .. } => too_large(err, limit),
+            err => {
+                let message = format!("Invalid form: {}", err);
+                malformed(err, "invalid_form", &message, expected)
+            }
+        })
+}
+
+/// Creates the configuration of the raw body extractor
+///
+/// # Arguments
+///
+/// * `limit` - Maximum size of a raw body in bytes
+pub fn payload(limit: usize) -> PayloadConfig {
+    PayloadConfig::default().limit(limit)
+}
+
+/// Reads a raw body with its own limit, for routes needing a different limit than the extractors
+///
+/// # Arguments
+///
+/// * `payload` - Body of the request
+/// * `limit` - Maximum size of the body in bytes
+pub async fn read(mut payload: Payload, limit: usize) -> Result<BytesMut, actix_web::Error> {
+    let mut body = BytesMut::new();
+
+    while let Some(chunk) = payload.next().await {
+        let chunk = chunk?;
+        if body.len() + chunk.len() > limit {
+            return Err(too_large(PayloadError::Overflow, limit));
+        }
+        body.extend_from_slice(&chunk);
+    }
+
+    Ok(body)
+}
+
+/// Creates the 400 Bad Request error of a body the extractor couldn't parse
+///
+/// # Arguments
+///
+/// * `err` - Error of the extractor
+/// * `code` - Machine readable code (e.g. `invalid_json`)
+/// * `message` - Human readable message
+/// * `expected` - Shape of the body, appended to the message unless empty
+fn malformed<E>(err: E, code: &str, message: &str, expected: &str) -> actix_web::Error
+where
+    E: std::fmt::Debug + std::fmt::Display + 'static,
+{
+    let message = match expected {
+        "" => String::from(message),
+        expected => format!("{}, expected {}", message, expected),
+    };
+
+    InternalError::from_response(
+        err,
+        response::error(StatusCode::BAD_REQUEST, code, &message),
+    )
+    .into()
+}
+
+/// Creates the 413 Payload Too Large error of an oversized body
+///
+/// # Arguments
+///
+/// * `err` - Error of the extractor
+/// * `limit` - Maximum size of the body in bytes
+fn too_large<E>(err: E, limit: usize) -> actix_web::Error
+where
+    E: std::fmt::Debug + std::fmt::Display + 'static,
+{
+    InternalError::from_response(
+        err,
+        response::error(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            "payload_too_large",
+            &format!("Request body too large, the limit is {} bytes", limit),
+        ),
+    )
+    .into()
+}
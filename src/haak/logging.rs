@@ -0,0 +1,139 @@
+//! Documentation for logging module
+//! Includes logger setup and the JSON access log middleware.
+//!
+//! With `LOG_FORMAT=json` every log line is a single JSON object, access log lines contain
+//! `method`, `path`, `status`, `duration_ms`, `remote_ip` and `request_id`.
+use crate::haak::request_id::RequestId;
+
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::{Error, HttpMessage};
+
+use futures::future::{ok, Ready};
+use log::LevelFilter;
+use serde_json::json;
+
+use std::env;
+use std::future::Future;
+use std::io::Write;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Instant;
+
+/// Log target of access log lines
+const ACCESS_TARGET: &str = "access";
+
+/// Format of the text access log, the default format of `actix_web::middleware::Logger` followed
+/// by the request ID
+pub const ACCESS_FORMAT: &str =
+    "%a \"%r\" %s %b \"%{Referer}i\" \"%{User-Agent}i\" %T %{X-Request-Id}o";
+
+/// Returns true if `LOG_FORMAT=json` is set
+pub fn json_enabled() -> bool {
+    env::var("LOG_FORMAT").map(|f| f == "json").unwrap_or(false)
+}
+
+/// Initializes the logger from `RUST_LOG`.
+/// In JSON mode access log lines are written as is and other lines are wrapped in a JSON object.
+/// The text access log of `actix_web::middleware::Logger` is silenced in JSON mode.
+///
+/// # Arguments
+///
+/// * `json` - Whether to write JSON lines
+pub fn init(json: bool) {
+    let mut builder = env_logger::Builder::from_default_env();
+
+    if json {
+        builder.filter_module("actix_web::middleware::logger", LevelFilter::Off);
+        builder.format(|buf, record| {
+            if record.target() == ACCESS_TARGET {
+                writeln!(buf, "{}", record.args())
+            } else {
+                let line = json!({
+                    "time": buf.timestamp().to_string(),
+                    "level": record.level().to_string(),
+                    "target": record.target(),
+                    "message": record.args().to_string(),
+                });
+                writeln!(buf, "{}", line)
+            }
+        });
+    }
+
+    builder.init();
+}
+
+/// Middleware logging one JSON object per request
+pub struct JsonLogger;
+
+impl<S, B> Transform<S> for JsonLogger
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = JsonLoggerMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(JsonLoggerMiddleware { service })
+    }
+}
+
+/// Service created by `JsonLogger`
+pub struct JsonLoggerMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service for JsonLoggerMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    #[allow(clippy::type_complexity)]
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let start = Instant::now();
+        let method = req.method().to_string();
+        let path = req.path().to_owned();
+        let remote_ip = req.peer_addr().map(|addr| addr.ip().to_string());
+        let request_id = req.extensions().get::<RequestId>().map(|id| id.0.clone());
+
+        let fut = self.service.call(req);
+
+        Box::pin(async move {
+            let res = fut.await;
+            let status = match &res {
+                Ok(res) => res.status(),
+                Err(e) => e.as_response_error().status_code(),
+            };
+
+            info!(
+                target: ACCESS_TARGET,
+                "{}",
+                json!({
+                    "method": method,
+                    "path": path,
+                    "status": status.as_u16(),
+                    "duration_ms": start.elapsed().as_secs_f64() * 1000.0,
+                    "remote_ip": remote_ip,
+                    "request_id": request_id,
+                })
+            );
+
+            res
+        })
+    }
+}
@@ -0,0 +1,206 @@
+//! Documentation for metrics module
+//! Includes the Prometheus metrics, the request metrics middleware and the /metrics handler.
+//!
+//! Exports `http_requests_total` by route and status, `http_request_duration_seconds` by route and
+//! `active_sessions`. The session gauge is only counted from redis when /metrics is scraped.
+use crate::haak::database::{self, Pool};
+
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::StatusCode;
+use actix_web::web::Data;
+use actix_web::{Error, HttpRequest, HttpResponse, Result};
+
+use futures::future::{ok, Ready};
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounterVec, IntGauge, Opts, Registry, TextEncoder,
+};
+
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Instant;
+
+/// Registry and metrics, cloning shares the underlying metrics
+#[derive(Clone)]
+pub struct Metrics {
+    registry: Registry,
+    requests: IntCounterVec,
+    duration: HistogramVec,
+    sessions: IntGauge,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        let requests = IntCounterVec::new(
+            Opts::new("http_requests_total", "Total number of HTTP requests"),
+            &["route", "status"],
+        )
+        .unwrap();
+        let duration = HistogramVec::new(
+            HistogramOpts::new(
+                "http_request_duration_seconds",
+                "HTTP request duration in seconds",
+            ),
+            &["route"],
+        )
+        .unwrap();
+        let sessions = IntGauge::new("active_sessions", "Number of logged in sessions").unwrap();
+
+        let registry = Registry::new();
+        registry.register(Box::new(requests.clone())).unwrap();
+        registry.register(Box::new(duration.clone())).unwrap();
+        registry.register(Box::new(sessions.clone())).unwrap();
+
+        Metrics {
+            registry,
+            requests,
+            duration,
+            sessions,
+        }
+    }
+}
+
+/// Handles HTTP GET requests to /metrics.
+/// Counts the logged in sessions and returns all metrics in the Prometheus text format.
+///
+/// # Arguments
+///
+/// * `metrics` - Metrics to export
+/// * `redis` - RedisActor to access redis database
+///
+/// # Remarks
+///
+/// Should only be called from actix_web
+pub async fn metrics(metrics: Data<Metrics>, redis: Data<Pool>) -> Result<HttpResponse> {
+    metrics
+        .sessions
+        .set(database::sessions_count(&redis).await?);
+
+    let encoder = TextEncoder::new();
+    let mut buffer = Vec::new();
+    encoder
+        .encode(&metrics.registry.gather(), &mut buffer)
+        .unwrap();
+
+    Ok(HttpResponse::Ok()
+        .content_type(encoder.format_type())
+        .body(buffer))
+}
+
+/// Middleware counting requests and measuring their duration
+pub struct RequestMetrics(pub Metrics);
+
+impl<S, B> Transform<S> for RequestMetrics
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = RequestMetricsMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(RequestMetricsMiddleware {
+            service,
+            metrics: self.0.clone(),
+        })
+    }
+}
+
+/// Service created by `RequestMetrics`
+pub struct RequestMetricsMiddleware<S> {
+    service: S,
+    metrics: Metrics,
+}
+
+/// Returns the path of a request with its path parameters replaced by their names, so e.g. every
+/// `/api/annotations/<timestamp>` is counted as `/api/annotations/{ts}`
+///
+/// # Arguments
+///
+/// * `req` - Request matched to a route
+fn route(req: &HttpRequest) -> String {
+    let params = req.match_info();
+
+    req.path()
+        .split('/')
+        .map(
+            |segment| match params.iter().find(|(_, value)| *value == segment) {
+                Some((name, _)) => format!("{{{}}}", name),
+                None => segment.to_owned(),
+            },
+        )
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+impl<S, B> Service for RequestMetricsMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    #[allow(clippy::type_complexity)]
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let start = Instant::now();
+        let metrics = self.metrics.clone();
+
+        let fut = self.service.call(req);
+
+        Box::pin(async move {
+            let res = fut.await;
+            let status = match &res {
+                Ok(res) => res.status(),
+                Err(e) => e.as_response_error().status_code(),
+            };
+
+            // Unknown paths and failed requests share one series
+            let route = match (&res, status) {
+                (_, StatusCode::NOT_FOUND) | (Err(_), _) => String::from("unmatched"),
+                (Ok(res), _) => route(res.request()),
+            };
+
+            metrics
+                .requests
+                .with_label_values(&[&route, status.as_str()])
+                .inc();
+            metrics
+                .duration
+                .with_label_values(&[&route])
+                .observe(start.elapsed().as_secs_f64());
+
+            res
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use actix_web::test::TestRequest;
+
+    #[test]
+    fn route_names_path_parameters() {
+        let req = TestRequest::with_uri("/api/annotations/1577836800")
+            .param("ts", "1577836800")
+            .to_http_request();
+        assert_eq!(route(&req), "/api/annotations/{ts}");
+
+        let req = TestRequest::with_uri("/api/annotations").to_http_request();
+        assert_eq!(route(&req), "/api/annotations");
+    }
+}
@@ -1,6 +1,37 @@
 //! Module containing all of our logic
+pub mod alerts;
+pub mod annotations;
+pub mod audit;
 pub mod auth;
+pub mod backup;
+pub mod compression;
+pub mod config;
+pub mod cors;
+pub mod csrf;
 pub mod database;
+pub mod derived;
+pub mod device;
 pub mod email;
 pub mod graph;
+pub mod health;
+pub mod i18n;
+pub mod keys;
+pub mod limits;
+pub mod logging;
+pub mod metrics;
+pub mod mx;
+pub mod ratelimit;
+pub mod redirect;
+pub mod redis_util;
+pub mod request_id;
+pub mod response;
+pub mod retention;
+pub mod sensor;
+pub mod session;
 pub mod settings;
+#[cfg(test)]
+pub mod testing;
+pub mod tls;
+pub mod totp;
+pub mod units;
+pub mod webauthn;
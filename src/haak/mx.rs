@@ -0,0 +1,67 @@
+//! Documentation for mx module
+//! Includes the optional MX record check of email addresses.
+//!
+//! With `VALIDATE_MX=true` addresses whose domain has no MX record are rejected before a challenge
+//! email is sent, so a typo'd domain is noticed instead of the email bouncing silently.
+use actix_rt::spawn;
+use trust_dns_resolver::error::ResolveErrorKind;
+use trust_dns_resolver::AsyncResolver;
+
+/// Checks the MX records of email domains, every address passes when disabled
+#[derive(Clone)]
+pub struct MxCheck {
+    resolver: Option<AsyncResolver>,
+}
+
+impl MxCheck {
+    /// Creates the check, starting a resolver from the system configuration when enabled.
+    /// Must be called from within the actix runtime.
+    ///
+    /// # Arguments
+    ///
+    /// * `enabled` - Whether to look up MX records (`VALIDATE_MX`)
+    pub fn start(enabled: bool) -> MxCheck {
+        if !enabled {
+            return MxCheck { resolver: None };
+        }
+
+        let (resolver, background) = AsyncResolver::from_system_conf()
+            .unwrap_or_else(|e| panic!("Could not read the DNS configuration: {}", e));
+        spawn(background);
+
+        MxCheck {
+            resolver: Some(resolver),
+        }
+    }
+
+    /// Returns false if the domain of the email has no MX record.
+    /// Lookup failures other than a missing record are logged and let the email pass, so a DNS
+    /// outage doesn't block logins.
+    ///
+    /// # Arguments
+    ///
+    /// * `email` - Syntactically valid email address
+    pub async fn accepts(&self, email: &str) -> bool {
+        let resolver = match &self.resolver {
+            Some(resolver) => resolver,
+            None => return true,
+        };
+
+        let domain = match email.rsplit('@').next() {
+            Some(domain) => domain,
+            None => return false,
+        };
+
+        // Trailing dot, so the search domains of the system aren't appended
+        match resolver.mx_lookup(format!("{}.", domain)).await {
+            Ok(records) => records.iter().next().is_some(),
+            Err(e) => match e.kind() {
+                ResolveErrorKind::NoRecordsFound { .. } => false,
+                _ => {
+                    warn!("MX lookup of {} failed: {}", domain, e);
+                    true
+                }
+            },
+        }
+    }
+}
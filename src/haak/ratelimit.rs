@@ -0,0 +1,103 @@
+//! Documentation for rate limiting
+//!
+//! Attempts are counted per key in the database, a key is limited once it exceeds
+//! `RATE_LIMIT_MAX` (default 5) attempts within `RATE_LIMIT_WINDOW_SECS` (default 900) seconds.
+//! Actions without a target email only count failed attempts per client IP.
+use crate::haak::database::{self, DbError, Pool};
+
+use actix_web::web::Data;
+use actix_web::HttpRequest;
+
+use std::env;
+
+/// Reads a positive number from the environment, falling back to a default
+///
+/// # Arguments
+///
+/// * `key` - Environment variable
+/// * `default` - Value used when unset or invalid
+fn env_or(key: &str, default: u64) -> u64 {
+    env::var(key)
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .filter(|v| *v > 0)
+        .unwrap_or(default)
+}
+
+/// IP address of the client, `unknown` if not available
+///
+/// # Arguments
+///
+/// * `req` - Request of the client
+fn client_ip(req: &HttpRequest) -> String {
+    match req.peer_addr() {
+        Some(addr) => addr.ip().to_string(),
+        None => String::from("unknown"),
+    }
+}
+
+/// Counts an attempt for the client IP and the target email within a scope.
+/// Returns true if either of them exceeded the limit.
+///
+/// # Arguments
+///
+/// * `scope` - Name of the rate limited action (e.g. `login`)
+/// * `req` - Request of the client, used for its IP address
+/// * `email` - Target email of the action
+/// * `redis` - Connection to database
+pub async fn exceeded(
+    scope: &str,
+    req: &HttpRequest,
+    email: &str,
+    redis: &Data<Pool>,
+) -> Result<bool, DbError> {
+    let max = env_or("RATE_LIMIT_MAX", 5) as i64;
+    let window = env_or("RATE_LIMIT_WINDOW_SECS", 900);
+
+    let ip = client_ip(req);
+
+    let ip_count =
+        database::rate_limit_incr(&format!("{}:ip:{}", scope, ip), window, redis).await?;
+    let email_count =
+        database::rate_limit_incr(&format!("{}:email:{}", scope, email), window, redis).await?;
+
+    Ok(ip_count > max || email_count > max)
+}
+
+/// Counts a failed attempt for the client IP within a scope
+///
+/// # Arguments
+///
+/// * `scope` - Name of the rate limited action (e.g. `verify`)
+/// * `req` - Request of the client, used for its IP address
+/// * `redis` - Connection to database
+pub async fn record_failure(
+    scope: &str,
+    req: &HttpRequest,
+    redis: &Data<Pool>,
+) -> Result<(), DbError> {
+    let window = env_or("RATE_LIMIT_WINDOW_SECS", 900);
+    let key = format!("{}:failures:ip:{}", scope, client_ip(req));
+
+    database::rate_limit_incr(&key, window, redis).await?;
+
+    Ok(())
+}
+
+/// Returns true if the client IP has reached the limit of failed attempts within a scope
+///
+/// # Arguments
+///
+/// * `scope` - Name of the rate limited action (e.g. `verify`)
+/// * `req` - Request of the client, used for its IP address
+/// * `redis` - Connection to database
+pub async fn failures_exceeded(
+    scope: &str,
+    req: &HttpRequest,
+    redis: &Data<Pool>,
+) -> Result<bool, DbError> {
+    let max = env_or("RATE_LIMIT_MAX", 5) as i64;
+    let key = format!("{}:failures:ip:{}", scope, client_ip(req));
+
+    Ok(database::rate_limit_get(&key, redis).await? >= max)
+}
@@ -0,0 +1,40 @@
+//! Documentation for redirect module
+//! Includes the plain HTTP listener that redirects everything to HTTPS.
+//!
+//! Most functions are called from the `actix-web` framework.
+use crate::haak::config::Config;
+
+use actix_web::web::Data;
+use actix_web::{HttpRequest, HttpResponse};
+
+/// Handles every HTTP request on the plain HTTP listener.
+/// Sends 301 MovedPermanently to the `https://` equivalent on `WEATHER_URL`, preserving path and
+/// query. The configured URL is used instead of the Host header, so the redirect can't be pointed
+/// at another site.
+///
+/// # Arguments
+///
+/// * `req` - Request to redirect
+/// * `config` - Configuration containing the URL and port of the HTTPS listener
+///
+/// # Remarks
+///
+/// Should only be called from actix_web
+pub async fn https_redirect(req: HttpRequest, config: Data<Config>) -> HttpResponse {
+    let host = match config.port {
+        443 => config.url.clone(),
+        port => format!("{}:{}", config.url, port),
+    };
+
+    let path = match req.uri().path_and_query() {
+        Some(path) => path.as_str(),
+        None => "/",
+    };
+
+    HttpResponse::MovedPermanently()
+        .header(
+            actix_web::http::header::LOCATION,
+            format!("https://{}{}", host, path),
+        )
+        .finish()
+}
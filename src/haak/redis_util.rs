@@ -0,0 +1,90 @@
+//! Documentation for redis_util module
+//! Includes the conversions of redis replies into Rust types.
+//!
+//! Every conversion fails with the reply it couldn't convert, which the database module turns
+//! into `DbError::UnexpectedResponse`.
+use actix_redis::RespValue;
+
+use serde::de::DeserializeOwned;
+
+/// Decodes a string reply (`GET`), `Nil` becomes `None`
+///
+/// # Arguments
+///
+/// * `res` - Reply from the database
+pub fn resp_to_string(res: RespValue) -> Result<Option<String>, RespValue> {
+    match res {
+        RespValue::Nil => Ok(None),
+        RespValue::SimpleString(val) => Ok(Some(val)),
+        RespValue::BulkString(val) => String::from_utf8(val)
+            .map(Some)
+            .map_err(|e| RespValue::BulkString(e.into_bytes())),
+        res => Err(res),
+    }
+}
+
+/// Decodes an integer reply (`INCR`, `EXISTS`)
+///
+/// # Arguments
+///
+/// * `res` - Reply from the database
+pub fn resp_to_i64(res: RespValue) -> Result<i64, RespValue> {
+    match res {
+        RespValue::Integer(val) => Ok(val),
+        res => Err(res),
+    }
+}
+
+/// Decodes an array reply (`MGET`, `LRANGE`) into its elements
+///
+/// # Arguments
+///
+/// * `res` - Reply from the database
+pub fn resp_to_vec(res: RespValue) -> Result<Vec<RespValue>, RespValue> {
+    match res {
+        RespValue::Array(val) => Ok(val),
+        res => Err(res),
+    }
+}
+
+/// Decodes an array reply of binary strings (`HMGET`), elements that aren't strings are empty
+///
+/// # Arguments
+///
+/// * `res` - Reply from the database
+pub fn resp_to_blobs(res: RespValue) -> Result<Vec<Vec<u8>>, RespValue> {
+    Ok(resp_to_vec(res)?
+        .into_iter()
+        .map(|val| match val {
+            RespValue::BulkString(val) => val,
+            _ => Vec::new(),
+        })
+        .collect())
+}
+
+/// Decodes an array reply of strings (`SMEMBERS`), elements that aren't strings are skipped
+///
+/// # Arguments
+///
+/// * `res` - Reply from the database
+pub fn resp_to_strings(res: RespValue) -> Result<Vec<String>, RespValue> {
+    Ok(resp_to_vec(res)?
+        .into_iter()
+        .filter_map(|val| resp_to_string(val).ok().flatten())
+        .collect())
+}
+
+/// Decodes an array reply of JSON encoded values, elements that can't be decoded are skipped
+///
+/// # Arguments
+///
+/// * `res` - Reply from the database
+pub fn resp_to_json<T: DeserializeOwned>(res: RespValue) -> Result<Vec<T>, RespValue> {
+    Ok(resp_to_vec(res)?
+        .into_iter()
+        .filter_map(|val| match val {
+            RespValue::BulkString(val) => serde_json::from_slice(&val).ok(),
+            _ => None,
+        })
+        .collect())
+}
@@ -0,0 +1,114 @@
+//! Documentation for request_id module
+//! Includes the request ID middleware.
+//!
+//! Every request gets an ID, taken from the `X-Request-Id` header of the client or generated as a
+//! UUID. The ID is echoed in the `X-Request-Id` response header, written to the access log and
+//! added to JSON error bodies, so a failed request can be found in the logs.
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::{Error, HttpMessage};
+
+use futures::future::{ok, Ready};
+
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+/// Header carrying the request ID
+pub const HEADER: &str = "x-request-id";
+
+tokio::task_local! {
+    /// ID of the request handled by the current task
+    static CURRENT: String;
+}
+
+/// ID of a request, stored in the request extensions
+#[derive(Clone, Debug)]
+pub struct RequestId(pub String);
+
+/// Returns the ID of the request being handled, `None` outside of a request
+pub fn current() -> Option<String> {
+    CURRENT.try_with(|id| id.clone()).ok()
+}
+
+/// Returns true if a client supplied ID is usable: 1 to 64 ASCII letters, digits, `-` or `_`
+///
+/// # Arguments
+///
+/// * `id` - Request ID sent by the client
+fn valid(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= 64
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Middleware assigning an ID to every request
+pub struct RequestIds;
+
+impl<S, B> Transform<S> for RequestIds
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = RequestIdsMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(RequestIdsMiddleware { service })
+    }
+}
+
+/// Service created by `RequestIds`
+pub struct RequestIdsMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service for RequestIdsMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    #[allow(clippy::type_complexity)]
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let id = req
+            .headers()
+            .get(HEADER)
+            .and_then(|id| id.to_str().ok())
+            .filter(|id| valid(id))
+            .map(str::to_owned)
+            .unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+
+        req.extensions_mut().insert(RequestId(id.clone()));
+
+        let fut = CURRENT.scope(id.clone(), self.service.call(req));
+
+        Box::pin(async move {
+            let mut res = fut.await?;
+
+            // The ID only contains header safe characters
+            if let Ok(value) = HeaderValue::from_str(&id) {
+                res.headers_mut()
+                    .insert(HeaderName::from_static(HEADER), value);
+            }
+
+            Ok(res)
+        })
+    }
+}
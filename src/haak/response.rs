@@ -0,0 +1,58 @@
+//! Documentation for response module
+//! Includes the JSON error envelope of the API.
+//!
+//! Errors of the API and of POST handlers are sent as `{"error":"<message>","code":"<code>"}`,
+//! where `code` is a stable snake_case identifier clients can match on, along with the
+//! `request_id` of the request. HTML pages keep HTML error bodies.
+use crate::haak::request_id;
+
+use actix_web::http::StatusCode;
+use actix_web::HttpResponse;
+
+use serde::Serialize;
+
+/// Body of an error response
+#[derive(Serialize, Debug)]
+pub struct ErrorBody<'a> {
+    /// Human readable message
+    pub error: &'a str,
+    /// Machine readable code (e.g. `invalid_email`)
+    pub code: &'a str,
+    /// ID of the request, as in the `X-Request-Id` header and the access log
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+}
+
+/// Creates an error response with a JSON error body
+///
+/// # Arguments
+///
+/// * `status` - Status code of the response
+/// * `code` - Machine readable code (e.g. `invalid_email`)
+/// * `message` - Human readable message
+pub fn error(status: StatusCode, code: &str, message: &str) -> HttpResponse {
+    HttpResponse::build(status).json(ErrorBody {
+        error: message,
+        code,
+        request_id: request_id::current(),
+    })
+}
+
+/// 401 Unauthorized, sent when the client is not logged in or lacks the required role
+pub fn unauthorized() -> HttpResponse {
+    error(StatusCode::UNAUTHORIZED, "unauthorized", "Unauthorized")
+}
+
+/// 403 Forbidden, sent when the CSRF token is missing or invalid
+pub fn invalid_csrf() -> HttpResponse {
+    error(StatusCode::FORBIDDEN, "invalid_csrf", "Invalid CSRF token")
+}
+
+/// 429 TooManyRequests, sent when the client is rate limited
+pub fn rate_limited() -> HttpResponse {
+    error(
+        StatusCode::TOO_MANY_REQUESTS,
+        "rate_limited",
+        "Too many attempts, try again later",
+    )
+}
@@ -0,0 +1,69 @@
+//! Documentation for retention module
+//! Includes the background task trimming old readings.
+//!
+//! With `READINGS_RETENTION_DAYS` set, readings older than the retention window are removed
+//! every hour.
+use crate::haak::database::{self, Pool};
+use crate::haak::graph;
+
+use actix_web::web::Data;
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Time between two trim runs
+const INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Removes readings older than the retention window from every metric of every station and logs
+/// how many readings were removed.
+///
+/// # Arguments
+///
+/// * `days` - Retention window in days
+/// * `redis` - Connection to database
+async fn trim(days: u64, redis: &Data<Pool>) {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let cutoff = now.saturating_sub(days * 24 * 60 * 60);
+
+    let stations = match database::stations(redis).await {
+        Ok(stations) => stations,
+        Err(e) => {
+            error!("Could not list stations to trim: {}", e);
+            return;
+        }
+    };
+
+    for station in stations.iter() {
+        for metric in graph::METRICS.iter() {
+            match database::readings_trim(station, metric, cutoff, redis).await {
+                Ok(removed) => info!(
+                    "Trimmed {} {} readings of station {} older than {} days",
+                    removed, metric, station, days
+                ),
+                Err(e) => error!(
+                    "Could not trim {} readings of station {}: {}",
+                    metric, station, e
+                ),
+            }
+        }
+    }
+}
+
+/// Starts the hourly trim task on the current arbiter
+///
+/// # Arguments
+///
+/// * `days` - Retention window in days
+/// * `redis` - Connection to database
+pub fn start(days: u64, redis: Data<Pool>) {
+    actix_rt::spawn(async move {
+        let mut interval = actix_rt::time::interval(INTERVAL);
+
+        loop {
+            interval.tick().await;
+            trim(days, &redis).await;
+        }
+    });
+}
@@ -0,0 +1,635 @@
+//! Documentation for sensor module
+//! Includes ingestion of readings sent by the weather station hardware.
+//!
+//! Most functions are called from the `actix-web` framework.
+use crate::haak::alerts;
+use crate::haak::auth;
+use crate::haak::config::Config;
+use crate::haak::database::{self, Pool};
+use crate::haak::email;
+use crate::haak::graph;
+use crate::haak::limits;
+use crate::haak::response;
+use crate::haak::session::{self, AuthedUser};
+use crate::haak::units;
+
+use actix_web::http::{header, HeaderValue, StatusCode};
+use actix_web::web::{Bytes, Data, Json, Payload, Query};
+use actix_web::{HttpRequest, HttpResponse, Result};
+
+use askama::Template;
+use chrono::{TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+
+use std::collections::HashMap;
+use std::convert::TryFrom;
+
+/// Header the weather station uses to send its API key
+const API_KEY_HEADER: &str = "X-Api-Key";
+
+/// Header requesting a dry run, like `?dry_run=true`
+const DRY_RUN_HEADER: &str = "X-Dry-Run";
+
+/// Station readings are stored under when the station doesn't identify itself
+pub const DEFAULT_STATION: &str = "default";
+
+/// Station of readings without a `station` field
+fn default_station() -> String {
+    String::from(DEFAULT_STATION)
+}
+
+/// Returns true if the station ID is valid: 1 to 64 ASCII letters, digits, `-` or `_`
+///
+/// # Arguments
+///
+/// * `station` - Station ID
+pub fn valid_station(station: &str) -> bool {
+    !station.is_empty()
+        && station.len() <= 64
+        && station
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Last second of the year 9999, the newest timestamp a reading may have
+const MAX_TIMESTAMP: u64 = 253_402_300_799;
+
+/// Returns true if the Unix timestamp of a reading can be shown as a date, up to the year 9999
+///
+/// # Arguments
+///
+/// * `timestamp` - Unix timestamp in seconds
+pub fn valid_timestamp(timestamp: u64) -> bool {
+    timestamp <= MAX_TIMESTAMP
+}
+
+/// Creates the 400 BadRequest response of a reading with an out of range timestamp
+fn invalid_timestamp() -> HttpResponse {
+    response::error(
+        StatusCode::BAD_REQUEST,
+        "invalid_timestamp",
+        "Timestamp out of range",
+    )
+}
+
+/// A single reading as stored, temperature in Celsius, pressure in Bar and humidity in percent
+#[derive(Deserialize, Debug)]
+pub struct Reading {
+    #[serde(default = "default_station")]
+    pub station: String,
+    pub temperature: f64,
+    pub pressure: f64,
+    pub humidity: f64,
+    pub timestamp: u64,
+}
+
+/// Value of a metric as sent by the weather station
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+pub enum Measurement {
+    /// Value with its unit, e.g. `{"value": 1013.2, "unit": "hPa"}`
+    WithUnit { value: f64, unit: String },
+    /// Bare value in the stored unit, only accepted with `INGEST_LEGACY_FORMAT=true`
+    Legacy(f64),
+}
+
+impl Measurement {
+    /// Converts the value into the unit the metric is stored in.
+    /// Returns a message if the unit is unknown, or missing while legacy readings are refused.
+    ///
+    /// # Arguments
+    ///
+    /// * `metric` - Name of the metric (e.g. `temperature`)
+    /// * `legacy` - Whether values without a unit are accepted
+    fn to_stored(&self, metric: &str, legacy: bool) -> std::result::Result<f64, String> {
+        match self {
+            Measurement::WithUnit { value, unit } => units::to_stored(metric, *value, unit)
+                .ok_or_else(|| format!("Unknown {} unit '{}'", metric, unit)),
+            Measurement::Legacy(value) if legacy => Ok(*value),
+            Measurement::Legacy(_) => Err(format!(
+                "Missing {} unit, send it as {{\"value\": <value>, \"unit\": <unit>}}",
+                metric
+            )),
+        }
+    }
+}
+
+/// A single reading as sent by the weather station, every metric with its unit
+#[derive(Deserialize, Debug)]
+pub struct SentReading {
+    #[serde(default = "default_station")]
+    pub station: String,
+    pub temperature: Measurement,
+    pub pressure: Measurement,
+    pub humidity: Measurement,
+    pub timestamp: u64,
+}
+
+impl SentReading {
+    /// Converts the reading into the stored units, see `Measurement::to_stored`
+    ///
+    /// # Arguments
+    ///
+    /// * `legacy` - Whether values without a unit are accepted
+    fn into_reading(self, legacy: bool) -> std::result::Result<Reading, String> {
+        Ok(Reading {
+            temperature: self.temperature.to_stored("temperature", legacy)?,
+            pressure: self.pressure.to_stored("pressure", legacy)?,
+            humidity: self.humidity.to_stored("humidity", legacy)?,
+            station: self.station,
+            timestamp: self.timestamp,
+        })
+    }
+}
+
+/// Creates the 422 UnprocessableEntity response of a reading with an unknown or missing unit
+///
+/// # Arguments
+///
+/// * `message` - Message naming the metric
+fn invalid_unit(message: &str) -> HttpResponse {
+    response::error(StatusCode::UNPROCESSABLE_ENTITY, "invalid_unit", message)
+}
+
+/// Checks the API key sent by the weather station against `WEATHER_API_KEY`.
+/// Returns true if the key is present and matches.
+///
+/// # Arguments
+///
+/// * `req` - Request containing the API key header
+/// * `config` - Configuration containing the API key
+fn authorized(req: &HttpRequest, config: &Config) -> bool {
+    match req.headers().get(API_KEY_HEADER) {
+        Some(header) => match header.to_str() {
+            Ok(header) => auth::tokens_equal(header, &config.api_key),
+            Err(_) => false,
+        },
+        None => false,
+    }
+}
+
+/// Query data of the ingestion endpoints
+#[derive(Deserialize)]
+pub struct IngestQuery {
+    dry_run: Option<bool>,
+}
+
+/// Returns true if the request asks for a dry run, with `?dry_run=true` or `X-Dry-Run: true`
+///
+/// # Arguments
+///
+/// * `req` - Request containing the optional dry run header
+/// * `query` - Query containing the optional `dry_run`
+fn dry_run(req: &HttpRequest, query: &IngestQuery) -> bool {
+    query.dry_run.unwrap_or(false)
+        || req
+            .headers()
+            .get(DRY_RUN_HEADER)
+            .and_then(|header| header.to_str().ok())
+            .is_some_and(|header| header.eq_ignore_ascii_case("true"))
+}
+
+/// Value that would be written by a dry run
+#[derive(Serialize)]
+pub struct PlannedWrite {
+    key: String,
+    timestamp: u64,
+    value: f64,
+}
+
+/// Response of a dry run, the writes that were skipped
+#[derive(Serialize)]
+pub struct DryRunResult {
+    dry_run: bool,
+    writes: Vec<PlannedWrite>,
+}
+
+/// Lists the values the readings would be stored as, one per metric of every reading
+///
+/// # Arguments
+///
+/// * `readings` - Validated readings
+/// * `redis` - Connection to database, deciding the keys readings are stored in
+fn planned_writes(readings: &[Reading], redis: &Data<Pool>) -> DryRunResult {
+    let writes = readings
+        .iter()
+        .flat_map(|reading| {
+            vec![
+                ("temperature", reading.temperature),
+                ("pressure", reading.pressure),
+                ("humidity", reading.humidity),
+            ]
+            .into_iter()
+            .map(move |(metric, value)| PlannedWrite {
+                key: database::readings_key(&reading.station, metric, redis),
+                timestamp: reading.timestamp,
+                value,
+            })
+        })
+        .collect();
+
+    DryRunResult {
+        dry_run: true,
+        writes,
+    }
+}
+
+/// Handles HTTP POST requests to /ingest.
+/// Authenticates the weather station with the shared API key and stores the reading in the
+/// database under its station (`default` if the reading has no `station`), then checks the reading
+/// against the alert rules of the users. A reading at an already stored timestamp replaces the
+/// stored values. Sends 401 Unauthorized on a bad key, 400 BadRequest on a timestamp after the year
+/// 9999 and 422 UnprocessableEntity on a malformed body, invalid station or unknown unit.
+///
+/// Every metric declares its unit (`{"temperature": {"value": 21.3, "unit": "C"}, ...}`) and is
+/// converted into the stored unit, see `units::to_stored`. With `INGEST_LEGACY_FORMAT=true` bare
+/// values in the stored units are accepted as well, for firmware that doesn't send units.
+///
+/// A dry run (`?dry_run=true` or `X-Dry-Run: true`) validates the reading without storing it or
+/// checking alerts and returns 200 with the values that would have been written.
+///
+/// With `INGEST_MIN_INTERVAL_SECS` set, a reading arriving sooner after the last accepted reading
+/// of its station is rejected with 429 TooManyRequests and a `Retry-After` header holding the
+/// seconds until the next reading is accepted. /ingest/batch is not limited, it is meant for
+/// uploading buffered readings at once.
+///
+/// # Arguments
+///
+/// * `req` - Request containing the API key header
+/// * `query` - Query containing the optional `dry_run`
+/// * `body` - Raw JSON body containing the reading
+/// * `config` - Configuration containing the API key, legacy format flag and minimum interval
+/// * `redis` - RedisActor to access redis database
+/// * `mailer` - Queue of the mail worker, for alert emails
+///
+/// # Remarks
+///
+/// Should only be called from actix_web
+pub async fn ingest(
+    req: HttpRequest,
+    Query(query): Query<IngestQuery>,
+    body: Bytes,
+    config: Data<Config>,
+    redis: Data<Pool>,
+    mailer: Data<email::Mailer>,
+) -> Result<HttpResponse> {
+    if !authorized(&req, &config) {
+        return Ok(response::unauthorized());
+    }
+
+    let reading: SentReading = match serde_json::from_slice(&body) {
+        Ok(reading) => reading,
+        Err(e) => {
+            return Ok(response::error(
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "invalid_reading",
+                &format!("Invalid reading: {}", e),
+            ))
+        }
+    };
+
+    let reading = match reading.into_reading(config.ingest_legacy_format) {
+        Ok(reading) => reading,
+        Err(e) => return Ok(invalid_unit(&e)),
+    };
+
+    if !valid_station(&reading.station) {
+        return Ok(response::error(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "invalid_station",
+            "Invalid station",
+        ));
+    }
+
+    if !valid_timestamp(reading.timestamp) {
+        return Ok(invalid_timestamp());
+    }
+
+    if dry_run(&req, &query) {
+        let reading = [reading];
+        return Ok(HttpResponse::Ok().json(planned_writes(&reading, &redis)));
+    }
+
+    if let Some(interval) = config.ingest_min_interval_secs {
+        if let Some(wait) = database::ingest_accept(&reading.station, interval, &redis).await? {
+            let mut res = response::error(
+                StatusCode::TOO_MANY_REQUESTS,
+                "ingest_too_frequent",
+                "Readings of this station arrive too frequently",
+            );
+            res.headers_mut()
+                .insert(header::RETRY_AFTER, HeaderValue::from(wait));
+            return Ok(res);
+        }
+    }
+
+    database::readings_add(&reading, &redis).await?;
+    alerts::check(&reading, &redis, &mailer).await;
+
+    Ok(HttpResponse::Created().finish())
+}
+
+/// Response of /ingest/batch
+#[derive(Serialize)]
+pub struct BatchResult {
+    accepted: usize,
+}
+
+/// Handles HTTP POST requests to /ingest/batch.
+/// Authenticates the weather station like /ingest and stores an array of readings at once, e.g.
+/// the buffer of a station that was offline. Storing a reading again is a no-op, so a batch can
+/// safely be uploaded again. Returns the number of accepted readings (`{"accepted": 120}`).
+/// Sends 401 Unauthorized on a bad key, 413 PayloadTooLarge on more than `INGEST_BATCH_MAX`
+/// readings, 400 BadRequest on a timestamp after the year 9999 and 422 UnprocessableEntity on a
+/// malformed body, invalid station or unknown unit, in which case none of the readings are stored.
+/// Units are declared like on /ingest. Only the newest reading of every station is checked against
+/// the alert rules, so backfilled readings don't send alerts.
+///
+/// A dry run validates the readings like /ingest without storing them.
+///
+/// # Arguments
+///
+/// * `req` - Request containing the API key header
+/// * `query` - Query containing the optional `dry_run`
+/// * `body` - Raw JSON body containing the array of readings
+/// * `config` - Configuration containing the API key, batch limits and legacy format flag
+/// * `redis` - RedisActor to access redis database
+/// * `mailer` - Queue of the mail worker, for alert emails
+///
+/// # Remarks
+///
+/// Should only be called from actix_web
+pub async fn ingest_batch(
+    req: HttpRequest,
+    Query(query): Query<IngestQuery>,
+    body: Payload,
+    config: Data<Config>,
+    redis: Data<Pool>,
+    mailer: Data<email::Mailer>,
+) -> Result<HttpResponse> {
+    if !authorized(&req, &config) {
+        return Ok(response::unauthorized());
+    }
+
+    // Every reading may be as large as a single ingested reading
+    let limit = config.ingest_limit.saturating_mul(config.ingest_batch_max);
+    let body = limits::read(body, limit).await?;
+
+    let readings: Vec<SentReading> = match serde_json::from_slice(&body) {
+        Ok(readings) => readings,
+        Err(e) => {
+            return Ok(response::error(
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "invalid_reading",
+                &format!("Invalid readings: {}", e),
+            ))
+        }
+    };
+
+    if readings.len() > config.ingest_batch_max {
+        return Ok(response::error(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            "batch_too_large",
+            &format!(
+                "Too many readings, the limit is {}",
+                config.ingest_batch_max
+            ),
+        ));
+    }
+
+    let readings = match readings
+        .into_iter()
+        .map(|reading| reading.into_reading(config.ingest_legacy_format))
+        .collect::<std::result::Result<Vec<Reading>, String>>()
+    {
+        Ok(readings) => readings,
+        Err(e) => return Ok(invalid_unit(&e)),
+    };
+
+    if !readings
+        .iter()
+        .all(|reading| valid_station(&reading.station))
+    {
+        return Ok(response::error(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "invalid_station",
+            "Invalid station",
+        ));
+    }
+
+    if !readings
+        .iter()
+        .all(|reading| valid_timestamp(reading.timestamp))
+    {
+        return Ok(invalid_timestamp());
+    }
+
+    if dry_run(&req, &query) {
+        return Ok(HttpResponse::Ok().json(planned_writes(&readings, &redis)));
+    }
+
+    database::readings_add_batch(&readings, &redis).await?;
+
+    let mut newest: HashMap<&str, &Reading> = HashMap::new();
+    for reading in readings.iter() {
+        let entry = newest.entry(&reading.station).or_insert(reading);
+        if reading.timestamp > entry.timestamp {
+            *entry = reading;
+        }
+    }
+    for reading in newest.values() {
+        alerts::check(reading, &redis, &mailer).await;
+    }
+
+    Ok(HttpResponse::Created().json(BatchResult {
+        accepted: readings.len(),
+    }))
+}
+
+/// Altitude form data
+#[derive(Deserialize)]
+pub struct AltitudeData {
+    station: String,
+    altitude_meters: f64,
+}
+
+/// Handles HTTP POST requests to /admin/station_altitude
+/// Sets the altitude of a station, used to reduce its pressure to sea level (`pressure_msl`).
+/// Sends 401 Unauthorized if not logged in as admin and 422 UnprocessableEntity on an invalid
+/// station or an altitude outside -500 to 9000 meters.
+///
+/// # Arguments
+///
+/// * `form` - JSON data containing the station and its altitude in meters
+/// * `user` - Logged in user
+/// * `redis` - RedisActor to access redis database
+///
+/// # Remarks
+///
+/// Should only be called from actix_web
+pub async fn set_altitude(
+    form: Json<AltitudeData>,
+    AuthedUser(user): AuthedUser,
+    redis: Data<Pool>,
+) -> Result<HttpResponse> {
+    // If user is not admin -> Unauthorized
+    if !database::user_is_admin(&user, &redis).await? {
+        return Ok(response::unauthorized());
+    }
+
+    if !valid_station(&form.station) {
+        return Ok(response::error(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "invalid_station",
+            "Invalid station",
+        ));
+    }
+
+    if !(-500.0..=9000.0).contains(&form.altitude_meters) {
+        return Ok(response::error(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "invalid_altitude",
+            "Invalid altitude",
+        ));
+    }
+
+    database::station_altitude_set(&form.station, form.altitude_meters, &redis).await?;
+
+    Ok(HttpResponse::Ok().body("Altitude set"))
+}
+
+/// Ingestion status of a station on the stations page
+pub struct StationStatus {
+    id: String,
+    /// Time of the newest reading per metric, in the order of `graph::METRICS`
+    last: Vec<String>,
+    stale: bool,
+}
+
+#[derive(Template)]
+#[template(path = "admin/stations.html")]
+pub struct Stations<'a> {
+    metrics: &'a [&'a str],
+    stations: &'a [StationStatus],
+    stale_secs: u64,
+}
+
+/// Handles HTTP GET requests to /admin/stations
+/// Shows every station with the time of its newest reading per metric in the admin's timezone.
+/// Stations whose newest reading is older than `STATION_STALE_SECS` are flagged stale. Sends 401
+/// Unauthorized if not logged in as admin.
+///
+/// # Arguments
+///
+/// * `user` - Logged in user
+/// * `config` - Server configuration, containing the staleness threshold
+/// * `redis` - RedisActor to access redis database
+///
+/// # Remarks
+///
+/// Should only be called from actix_web
+pub async fn admin_stations(
+    AuthedUser(user): AuthedUser,
+    config: Data<Config>,
+    redis: Data<Pool>,
+) -> Result<HttpResponse> {
+    // If user is not admin -> Unauthorized
+    if !database::user_is_admin(&user, &redis).await? {
+        return Ok(response::unauthorized());
+    }
+
+    let sett = database::settings_get(&user, &redis).await?;
+    let tz: chrono_tz::Tz = sett.timezone.parse().unwrap_or(chrono_tz::UTC);
+    let stale_before = session::now().saturating_sub(config.station_stale_secs);
+
+    let mut stations = Vec::new();
+    for id in database::stations(&redis).await? {
+        let mut newest = None;
+        let mut last = Vec::new();
+
+        for metric in graph::METRICS.iter() {
+            match database::readings_latest(&id, metric, &redis).await? {
+                Some((ts, _)) => {
+                    newest = newest.max(Some(ts));
+                    // Readings stored before timestamps were checked may be out of range
+                    last.push(
+                        i64::try_from(ts)
+                            .ok()
+                            .and_then(|ts| Utc.timestamp_opt(ts, 0).single())
+                            .map(|time| {
+                                time.with_timezone(&tz)
+                                    .format("%Y-%m-%d %H:%M:%S")
+                                    .to_string()
+                            })
+                            .unwrap_or_else(|| ts.to_string()),
+                    );
+                }
+                None => last.push(String::from("Never")),
+            }
+        }
+
+        stations.push(StationStatus {
+            id,
+            last,
+            stale: newest.is_none_or(|ts| ts < stale_before),
+        });
+    }
+
+    let view = Stations {
+        metrics: &graph::METRICS,
+        stations: &stations,
+        stale_secs: config.station_stale_secs,
+    }
+    .render()
+    .unwrap();
+
+    Ok(HttpResponse::Ok().content_type("text/html").body(view))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::haak::testing;
+
+    #[test]
+    fn timestamps_up_to_year_9999() {
+        assert!(valid_timestamp(0));
+        assert!(valid_timestamp(1_600_000_000));
+        assert!(valid_timestamp(MAX_TIMESTAMP));
+        assert!(!valid_timestamp(MAX_TIMESTAMP + 1));
+        assert!(!valid_timestamp(u64::MAX));
+
+        let last = Utc.timestamp_opt(MAX_TIMESTAMP as i64, 0).single().unwrap();
+        assert_eq!(
+            last.format("%Y-%m-%d %H:%M:%S").to_string(),
+            "9999-12-31 23:59:59"
+        );
+    }
+
+    #[actix_rt::test]
+    async fn dry_run_lists_the_keys_readings_are_stored_in() {
+        let reading = Reading {
+            station: String::from("garden"),
+            temperature: 21.5,
+            pressure: 1.013,
+            humidity: 50.0,
+            timestamp: 1_600_000_000,
+        };
+
+        for (compression, key) in [
+            (None, "weather_test:readings:garden:temperature"),
+            (
+                Some(3600),
+                "weather_test:readings_windows:garden:temperature",
+            ),
+        ]
+        .iter()
+        {
+            let redis = testing::pool_compressed(*compression);
+            let planned = planned_writes(std::slice::from_ref(&reading), &redis);
+
+            assert_eq!(planned.writes.len(), 3);
+            assert_eq!(planned.writes[0].key, *key);
+        }
+    }
+}
@@ -0,0 +1,253 @@
+//! Documentation for session module
+//! Includes session lifetime handling.
+//!
+//! A session expires after `SESSION_TTL_SECS` (default 7200, at most 65535) seconds since login,
+//! or after `SESSION_IDLE_SECS` (default 1800) seconds without requests. Expired sessions are
+//! purged before the request is handled, so handlers redirect the user to `/login`.
+//!
+//! Every login gets a session ID which is tracked in the `sessions:<email>` sorted set, scored by
+//! login time. Sessions whose ID is no longer in the set (e.g. after `/logout_all`) are purged as
+//! well. With `MAX_SESSIONS_PER_USER` set, a login beyond the maximum evicts the oldest session of
+//! the user.
+//!
+//! The session cookie (`SESSION_COOKIE_NAME`, default `actix-session`) is always `HttpOnly`, and
+//! `Secure` and `SameSite=Lax` unless `SESSION_COOKIE_SECURE=false` or `SESSION_COOKIE_SAMESITE`
+//! (`Strict` or `Lax`) say otherwise.
+use crate::haak::database::{self, Pool};
+use crate::haak::response;
+
+use actix_session::{Session, UserSession};
+use actix_web::cookie::SameSite;
+use actix_web::dev::{Payload, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::error::InternalError;
+use actix_web::http::header;
+use actix_web::web::Data;
+use actix_web::{Error, FromRequest, HttpRequest, HttpResponse};
+
+use futures::future::{err, ok, Ready};
+use rand::rngs::OsRng;
+use rand::RngCore;
+
+use std::cell::RefCell;
+use std::future::Future;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Session lifetime and cookie configuration, read by `Config::load`
+#[derive(Clone, Debug)]
+pub struct SessionConfig {
+    /// Absolute lifetime of a session in seconds, also used as TTL of the redis session key
+    pub ttl: u16,
+    /// Maximum time between two requests in seconds
+    pub idle: u64,
+    /// Maximum number of active sessions per user, unlimited if `None`
+    pub max: Option<usize>,
+    /// Name of the session cookie
+    pub cookie_name: String,
+    /// Whether the session cookie is only sent over HTTPS
+    pub cookie_secure: bool,
+    /// `SameSite` attribute of the session cookie
+    pub cookie_same_site: SameSite,
+}
+
+/// Current unix timestamp in seconds
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+/// Creates a new 16 byte session ID
+fn generate_sid() -> String {
+    let mut sid = vec![0u8; 16];
+    OsRng.fill_bytes(&mut sid);
+    base64::encode_config(&sid, base64::URL_SAFE)
+}
+
+/// Logs the user in on the session, records the login time and adds the session to the active
+/// sessions of the user, evicting the oldest sessions beyond `MAX_SESSIONS_PER_USER`
+///
+/// # Arguments
+///
+/// * `session` - Session containing all CookieSession data
+/// * `email` - Email address of the user
+/// * `config` - Session lifetime configuration
+/// * `redis` - RedisActor to access redis database
+pub async fn start(
+    session: &Session,
+    email: String,
+    config: &SessionConfig,
+    redis: &Data<Pool>,
+) -> Result<(), database::DbError> {
+    let sid = generate_sid();
+    database::session_add(&email, &sid, now(), config.ttl, config.max, redis).await?;
+
+    let _ = session.set("email", email);
+    let _ = session.set("sid", sid);
+    let _ = session.set("login_at", now());
+    let _ = session.set("last_seen", now());
+    session.remove("pending_login");
+
+    Ok(())
+}
+
+/// Purges the session of the request if it expired or was revoked, otherwise records the request
+/// time. Database errors are logged and purge the session.
+///
+/// # Arguments
+///
+/// * `req` - Request containing the session
+/// * `config` - Session lifetime configuration
+///
+/// # Remarks
+///
+/// Should be called from middleware wrapped inside the session middleware
+async fn check(req: &ServiceRequest, config: &SessionConfig) {
+    let session = req.get_session();
+
+    let email = match session.get::<String>("email").unwrap_or(None) {
+        Some(email) => email,
+        None => return,
+    };
+
+    let now = now();
+    let login_at = session.get::<u64>("login_at").unwrap_or(None).unwrap_or(0);
+    let last_seen = session.get::<u64>("last_seen").unwrap_or(None).unwrap_or(0);
+
+    if now.saturating_sub(login_at) > u64::from(config.ttl)
+        || now.saturating_sub(last_seen) > config.idle
+    {
+        session.purge();
+        return;
+    }
+
+    let sid = session
+        .get::<String>("sid")
+        .unwrap_or(None)
+        .unwrap_or_default();
+    let active = match req.app_data::<Pool>() {
+        Some(redis) => match database::session_exists(&email, &sid, &redis).await {
+            Ok(active) => active,
+            Err(e) => {
+                error!("{}", e);
+                false
+            }
+        },
+        None => false,
+    };
+
+    if active {
+        let _ = session.set("last_seen", now);
+    } else {
+        session.purge();
+    }
+}
+
+/// Middleware expiring and revoking sessions, must be wrapped inside the session middleware
+pub struct SessionCheck {
+    config: SessionConfig,
+}
+
+impl SessionCheck {
+    /// Creates the middleware
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - Session lifetime configuration
+    pub fn new(config: SessionConfig) -> SessionCheck {
+        SessionCheck { config }
+    }
+}
+
+impl<S, B> Transform<S> for SessionCheck
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = SessionCheckMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(SessionCheckMiddleware {
+            service: Rc::new(RefCell::new(service)),
+            config: self.config.clone(),
+        })
+    }
+}
+
+/// Service created by `SessionCheck`
+pub struct SessionCheckMiddleware<S> {
+    service: Rc<RefCell<S>>,
+    config: SessionConfig,
+}
+
+impl<S, B> Service for SessionCheckMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    #[allow(clippy::type_complexity)]
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.borrow_mut().poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        let config = self.config.clone();
+
+        Box::pin(async move {
+            check(&req, &config).await;
+
+            let fut = service.borrow_mut().call(req);
+            fut.await
+        })
+    }
+}
+
+/// Email address of the logged in user, extracted from the session.
+///
+/// Handlers taking an `AuthedUser` are only called for logged in users. Other requests are
+/// redirected to /login when the client accepts HTML (page loads and form posts), or get 401
+/// Unauthorized otherwise (API calls).
+pub struct AuthedUser(pub String);
+
+impl FromRequest for AuthedUser {
+    type Error = Error;
+    type Future = Ready<Result<AuthedUser, Error>>;
+    type Config = ();
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        if let Some(email) = req.get_session().get::<String>("email").unwrap_or(None) {
+            return ok(AuthedUser(email));
+        }
+
+        let html = req
+            .headers()
+            .get(header::ACCEPT)
+            .and_then(|accept| accept.to_str().ok())
+            .is_some_and(|accept| accept.contains("text/html"));
+
+        let res = match html {
+            true => HttpResponse::SeeOther()
+                .header(header::LOCATION, "/login")
+                .finish(),
+            false => response::unauthorized(),
+        };
+
+        err(InternalError::from_response("Not logged in", res).into())
+    }
+}
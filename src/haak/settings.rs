@@ -1,15 +1,21 @@
 //! Documentation for settings module
 //!
 //! Most functions are called from the `actix-web` framework
-use crate::haak::database;
+use crate::haak::alerts::Rule;
+use crate::haak::csrf;
+use crate::haak::database::{self, Pool};
+use crate::haak::i18n;
+use crate::haak::response;
+use crate::haak::session::AuthedUser;
 
-use actix::Addr;
-use actix_redis::RedisActor;
 use actix_session::Session;
-use actix_web::web::{Data, Form};
-use actix_web::{HttpResponse, Result};
+use actix_web::http::{header, StatusCode};
+use actix_web::web::{Data, Form, Json};
+use actix_web::{HttpRequest, HttpResponse, Result};
 
-use serde::Deserialize;
+use serde::{de, Deserialize, Deserializer, Serialize};
+
+use std::env;
 
 use askama::Template;
 
@@ -18,82 +24,383 @@ use askama::Template;
 pub struct Settings<'a> {
     temperature: &'a str,
     pressure: &'a str,
+    humidity: &'a str,
     theme: &'a str,
+    theme_class: &'static str,
     timeframe: &'a str,
+    timezone: &'a str,
+    locale: &'a str,
+    chart_type: &'a str,
+    smoothing: bool,
+    show_temperature: bool,
+    show_pressure: bool,
+    show_humidity: bool,
+    t: i18n::Messages<'a>,
     admin: bool,
+    alerts: &'a [Rule],
+    csrf_token: &'a str,
+    error: &'a str,
+}
+
+/// Renders the settings page with the stored settings and alert rules of the user
+///
+/// # Arguments
+///
+/// * `user` - Email address of the logged in user
+/// * `error` - Error message shown above the form, empty for none
+/// * `session` - Session containing all CookieSession data
+/// * `redis` - RedisActor to access redis database
+pub async fn render(
+    user: &str,
+    error: &str,
+    session: &Session,
+    redis: &Data<Pool>,
+) -> Result<String> {
+    let sett = database::settings_get(user, redis).await?;
+
+    Ok(Settings {
+        temperature: &sett.temperature,
+        pressure: &sett.pressure,
+        humidity: &sett.humidity,
+        theme: &sett.theme,
+        theme_class: theme_class(&sett.theme),
+        timeframe: &sett.timeframe,
+        timezone: &sett.timezone,
+        locale: &sett.locale,
+        chart_type: &sett.chart_type,
+        smoothing: sett.smoothing,
+        show_temperature: sett.show_temperature,
+        show_pressure: sett.show_pressure,
+        show_humidity: sett.show_humidity,
+        t: i18n::Messages::new(&sett.locale),
+        admin: database::user_is_admin(user, redis).await?,
+        alerts: &database::alerts_get(user, redis).await?,
+        csrf_token: &csrf::token(session),
+        error,
+    }
+    .render()
+    .unwrap())
+}
+
+/// Returns true if the client accepts JSON, as requested with `Accept: application/json`
+///
+/// # Arguments
+///
+/// * `req` - HTTP request
+fn accepts_json(req: &HttpRequest) -> bool {
+    req.headers()
+        .get(header::ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .is_some_and(|accept| accept.contains("application/json"))
 }
 
 /// Shows settings index. If the user is an admin it also shows the registration form. Redirects to
 /// /login if not logged in.
+/// Clients accepting `application/json` receive the `UserSettings` as JSON instead, or 401
+/// Unauthorized if not logged in.
+///
+/// # Arguments
+///
+/// * `req` - HTTP request
+/// * `user` - Logged in user
+/// * `session` - Session containing all CookieSession data
+/// * `redis` - RedisActor to access redis database
 ///
 /// # Remarks
 ///
 /// Should only be called from actix_web
 pub async fn settings_index(
+    req: HttpRequest,
+    AuthedUser(user): AuthedUser,
     session: Session,
-    redis: Data<Addr<RedisActor>>,
+    redis: Data<Pool>,
 ) -> Result<HttpResponse> {
-    let user = session.get::<String>("email").unwrap();
-
-    // If not logged in -> redirect to /login
-    if user.is_none() {
-        return Ok(HttpResponse::SeeOther()
-            .header(actix_web::http::header::LOCATION, "/login")
-            .body(""));
+    if accepts_json(&req) {
+        return Ok(HttpResponse::Ok().json(database::settings_get(&user, &redis).await?));
     }
 
-    let sett =
-        database::settings_get(&session.get::<String>("email").unwrap().unwrap(), &redis).await;
-
-    let view = Settings {
-        temperature: &sett[0],
-        pressure: &sett[1],
-        theme: &sett[2],
-        timeframe: &sett[3],
-        admin: database::user_is_admin(&user.unwrap(), &redis).await,
-    }
-    .render()
-    .unwrap();
+    let view = render(&user, "", &session, &redis).await?;
 
     Ok(HttpResponse::Ok().content_type("text/html").body(view))
 }
 
-/// Form data returned from settings-save
-#[derive(Deserialize, Debug)]
-pub struct SettingsData {
+/// Settings of a user, as stored in the database and returned from settings-save
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UserSettings {
     pub temperature: String,
     pub pressure: String,
+    pub humidity: String,
     pub theme: String,
     pub timeframe: String,
+    pub timezone: String,
+    /// Language of emails, missing in settings exported before it was added
+    #[serde(default = "default_locale")]
+    pub locale: String,
+    /// How the graph draws readings, missing in settings exported before it was added
+    #[serde(default = "default_chart_type")]
+    pub chart_type: String,
+    /// Whether the graph smooths its lines, missing in settings exported before it was added and
+    /// in a settings form with the checkbox unchecked
+    #[serde(default, deserialize_with = "form_bool")]
+    pub smoothing: bool,
+    /// Whether the graph shows temperatures, missing in settings exported before it was added
+    #[serde(default = "visible", deserialize_with = "form_bool")]
+    pub show_temperature: bool,
+    /// Whether the graph shows pressures, missing in settings exported before it was added
+    #[serde(default = "visible", deserialize_with = "form_bool")]
+    pub show_pressure: bool,
+    /// Whether the graph shows humidities, missing in settings exported before it was added
+    #[serde(default = "visible", deserialize_with = "form_bool")]
+    pub show_humidity: bool,
+}
+
+/// Locale of settings without one
+fn default_locale() -> String {
+    String::from(i18n::LOCALES[0])
+}
+
+/// Chart type of settings without one
+fn default_chart_type() -> String {
+    String::from("line")
+}
+
+/// Visibility of metrics in settings without one
+fn visible() -> bool {
+    true
+}
+
+/// Deserializes a boolean from JSON or from the `"true"` or `"false"` of the settings form, as
+/// flattened form fields are always strings
+fn form_bool<'de, D: Deserializer<'de>>(deserializer: D) -> std::result::Result<bool, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Checkbox {
+        Bool(bool),
+        Str(String),
+    }
+
+    match Checkbox::deserialize(deserializer)? {
+        Checkbox::Bool(checked) => Ok(checked),
+        Checkbox::Str(value) => value.parse().map_err(de::Error::custom),
+    }
+}
+
+/// Built-in default settings, used when no `DEFAULT_*` variable overrides them
+impl Default for UserSettings {
+    fn default() -> Self {
+        UserSettings {
+            temperature: String::from("Celsius"),
+            pressure: String::from("Bar"),
+            humidity: String::from("Percent"),
+            theme: String::from("Light"),
+            timeframe: String::from("Week"),
+            timezone: String::from("UTC"),
+            locale: default_locale(),
+            chart_type: default_chart_type(),
+            smoothing: false,
+            show_temperature: visible(),
+            show_pressure: visible(),
+            show_humidity: visible(),
+        }
+    }
+}
+
+impl UserSettings {
+    /// Returns true if the graph shows the metric, metrics without a visibility setting are shown
+    ///
+    /// # Arguments
+    ///
+    /// * `metric` - Name of the metric (e.g. `temperature`)
+    pub fn shows(&self, metric: &str) -> bool {
+        match metric {
+            "temperature" => self.show_temperature,
+            "pressure" => self.show_pressure,
+            "humidity" => self.show_humidity,
+            _ => true,
+        }
+    }
+
+    /// Default settings for new users and for settings missing from the database, read from
+    /// `DEFAULT_TEMPERATURE`, `DEFAULT_PRESSURE`, `DEFAULT_THEME`, `DEFAULT_TIMEFRAME`,
+    /// `DEFAULT_TIMEZONE` and `DEFAULT_LOCALE`. Unset variables fall back to
+    /// `UserSettings::default()`.
+    pub fn from_env() -> UserSettings {
+        let defaults = UserSettings::default();
+        let var = |name: &str, default: String| env::var(name).unwrap_or(default);
+
+        UserSettings {
+            temperature: var("DEFAULT_TEMPERATURE", defaults.temperature),
+            pressure: var("DEFAULT_PRESSURE", defaults.pressure),
+            humidity: defaults.humidity,
+            theme: var("DEFAULT_THEME", defaults.theme),
+            timeframe: var("DEFAULT_TIMEFRAME", defaults.timeframe),
+            timezone: var("DEFAULT_TIMEZONE", defaults.timezone),
+            locale: var("DEFAULT_LOCALE", defaults.locale),
+            chart_type: defaults.chart_type,
+            smoothing: defaults.smoothing,
+            show_temperature: defaults.show_temperature,
+            show_pressure: defaults.show_pressure,
+            show_humidity: defaults.show_humidity,
+        }
+    }
+}
+
+/// Settings form data, the user settings and the CSRF token
+#[derive(Deserialize)]
+pub struct SettingsForm {
+    #[serde(flatten)]
+    settings: UserSettings,
+    csrf_token: Option<String>,
+}
+
+/// Returns the class of the `<body>` of a page in the theme, so the theme applies on first paint.
+/// Unknown themes fall back to `Light`.
+///
+/// # Arguments
+///
+/// * `theme` - Theme of the user settings
+pub fn theme_class(theme: &str) -> &'static str {
+    match theme {
+        "Dark" => "theme-dark",
+        _ => "theme-light",
+    }
+}
+
+/// Valid temperature units
+pub const TEMPERATURES: [&str; 3] = ["Celsius", "Kelvin", "Fahrenheit"];
+
+/// Valid pressure units
+pub const PRESSURES: [&str; 5] = ["Atmosphere", "Millibar", "Bar", "PSI", "Mercury"];
+
+/// Valid humidity units
+pub const HUMIDITIES: [&str; 1] = ["Percent"];
+
+/// Valid themes
+pub const THEMES: [&str; 2] = ["Light", "Dark"];
+
+/// Valid timeframes
+pub const TIMEFRAMES: [&str; 3] = ["Week", "Month", "QuarterYear"];
+
+/// Chart types the graph can draw
+pub const CHART_TYPES: [&str; 3] = ["line", "area", "bar"];
+
+/// Returns true if the temperature unit is valid
+fn valid_temperature(temperature: &str) -> bool {
+    TEMPERATURES.contains(&temperature)
+}
+
+/// Returns true if the pressure unit is valid
+fn valid_pressure(pressure: &str) -> bool {
+    PRESSURES.contains(&pressure)
+}
+
+/// Returns true if the humidity unit is valid
+fn valid_humidity(humidity: &str) -> bool {
+    HUMIDITIES.contains(&humidity)
+}
+
+/// Returns true if the theme is valid
+fn valid_theme(theme: &str) -> bool {
+    THEMES.contains(&theme)
+}
+
+/// Returns true if the timeframe is valid
+fn valid_timeframe(timeframe: &str) -> bool {
+    TIMEFRAMES.contains(&timeframe)
+}
+
+/// Returns true if the graph can draw the chart type
+fn valid_chart_type(chart_type: &str) -> bool {
+    CHART_TYPES.contains(&chart_type)
+}
+
+/// Returns true if the pages and emails are available in the locale
+fn valid_locale(locale: &str) -> bool {
+    i18n::LOCALES.contains(&locale)
+}
+
+/// Returns true if the timezone is in the tz database
+fn valid_timezone(timezone: &str) -> bool {
+    timezone.parse::<chrono_tz::Tz>().is_ok()
+}
+
+/// Returns the names of all invalid fields of the settings, empty if the settings are valid
+///
+/// # Arguments
+///
+/// * `data` - UserSettings containing all settings
+fn invalid_fields(data: &UserSettings) -> Vec<&'static str> {
+    let checks: [(&str, bool); 8] = [
+        ("temperature", valid_temperature(&data.temperature)),
+        ("pressure", valid_pressure(&data.pressure)),
+        ("humidity", valid_humidity(&data.humidity)),
+        ("theme", valid_theme(&data.theme)),
+        ("timeframe", valid_timeframe(&data.timeframe)),
+        ("timezone", valid_timezone(&data.timezone)),
+        ("locale", valid_locale(&data.locale)),
+        ("chart_type", valid_chart_type(&data.chart_type)),
+    ];
+
+    checks
+        .iter()
+        .filter(|(_, valid)| !valid)
+        .map(|(field, _)| *field)
+        .collect()
 }
 
 /// Settings validator.
-/// Returns true if settings are valid.
+/// Returns the name of the first invalid field if the settings are invalid.
 ///
 /// # Arguments
 ///
-/// * `data` - SettingsData containing all settings
-fn validate_settings(data: &SettingsData) -> bool {
-    return (data.temperature == "Celsius"
-        || data.temperature == "Kelvin"
-        || data.temperature == "Fahrenheit")
-        && (data.pressure == "Atmosphere"
-            || data.pressure == "Millibar"
-            || data.pressure == "Bar"
-            || data.pressure == "PSI"
-            || data.pressure == "Mercury")
-        && (data.theme == "Light" || data.theme == "Dark")
-        && (data.timeframe == "Week"
-            || data.timeframe == "Month"
-            || data.timeframe == "QuarterYear");
+/// * `data` - UserSettings containing all settings
+pub fn validate_settings(data: &UserSettings) -> std::result::Result<(), &'static str> {
+    match invalid_fields(data).first() {
+        Some(field) => Err(field),
+        None => Ok(()),
+    }
+}
+
+/// Valid values of every setting with a fixed set of options, as returned from /api/units
+#[derive(Serialize)]
+pub struct Options {
+    temperature: &'static [&'static str],
+    pressure: &'static [&'static str],
+    humidity: &'static [&'static str],
+    theme: &'static [&'static str],
+    timeframe: &'static [&'static str],
+    chart_type: &'static [&'static str],
+    locale: &'static [&'static str],
+}
+
+/// Handles HTTP GET requests to /api/units.
+/// Sends the valid values of the settings with a fixed set of options, the same values the
+/// settings are validated against, so frontends don't have to hard-code them.
+///
+/// # Remarks
+///
+/// Should only be called from actix_web
+pub async fn units() -> HttpResponse {
+    HttpResponse::Ok().json(Options {
+        temperature: &TEMPERATURES,
+        pressure: &PRESSURES,
+        humidity: &HUMIDITIES,
+        theme: &THEMES,
+        timeframe: &TIMEFRAMES,
+        chart_type: &CHART_TYPES,
+        locale: &i18n::LOCALES,
+    })
 }
 
 /// Handles POST requests to /settings. Saves the settings in the database.
-/// Redirects to /login if not logged in.
+/// Redirects to /login if not logged in and sends 403 Forbidden if the CSRF token is invalid.
+/// Invalid settings are not saved, the settings page is shown again with 422 UnprocessableEntity
+/// and a message naming the invalid field.
 ///
 /// # Arguments
 ///
-/// * `form` - JSON data of the settings form
+/// * `form` - Data of the settings form
+/// * `user` - Logged in user
 /// * `session` - Session containing all CookieSession data
 /// * `redis` - RedisActor to access redis database
 ///
@@ -101,34 +408,88 @@ fn validate_settings(data: &SettingsData) -> bool {
 ///
 /// Should only be called from actix_web
 pub async fn settings_save(
-    form: Form<SettingsData>,
+    form: Form<SettingsForm>,
+    AuthedUser(user): AuthedUser,
     session: Session,
-    redis: Data<Addr<RedisActor>>,
-) -> HttpResponse {
-    // If not logged in -> redirect to /login
-    if session.get::<String>("email").unwrap().is_none() {
-        return HttpResponse::SeeOther()
-            .header(actix_web::http::header::LOCATION, "/login")
-            .finish();
+    redis: Data<Pool>,
+) -> Result<HttpResponse> {
+    let form = form.into_inner();
+
+    if !csrf::verify(&session, form.csrf_token.as_deref()) {
+        return Ok(response::invalid_csrf());
     }
 
-    let data = SettingsData {
-        temperature: form.temperature.clone(),
-        pressure: form.pressure.clone(),
-        theme: form.theme.clone(),
-        timeframe: form.timeframe.clone(),
-    };
-
-    if validate_settings(&data) {
-        database::settings_set(
-            &session.get::<String>("email").unwrap().unwrap(),
-            &data,
-            &redis,
-        )
-        .await;
+    let data = form.settings;
+
+    if let Err(field) = validate_settings(&data) {
+        let error = format!("Invalid {}, settings were not saved", field);
+        let view = render(&user, &error, &session, &redis).await?;
+
+        return Ok(HttpResponse::UnprocessableEntity()
+            .content_type("text/html")
+            .body(view));
     }
 
-    return HttpResponse::SeeOther()
+    database::settings_set(&user, &data, &redis).await?;
+
+    Ok(HttpResponse::SeeOther()
         .header(actix_web::http::header::LOCATION, "/settings")
-        .finish();
+        .finish())
+}
+
+/// Handles HTTP GET requests to /settings/export.
+/// Sends the `UserSettings` of the logged in user as downloadable `settings.json`, to be imported
+/// with /settings/import. Sends 401 Unauthorized if not logged in.
+///
+/// # Arguments
+///
+/// * `user` - Logged in user
+/// * `redis` - RedisActor to access redis database
+///
+/// # Remarks
+///
+/// Should only be called from actix_web
+pub async fn settings_export(
+    AuthedUser(user): AuthedUser,
+    redis: Data<Pool>,
+) -> Result<HttpResponse> {
+    Ok(HttpResponse::Ok()
+        .header(
+            header::CONTENT_DISPOSITION,
+            "attachment; filename=\"settings.json\"",
+        )
+        .json(database::settings_get(&user, &redis).await?))
+}
+
+/// Handles HTTP POST requests to /settings/import.
+/// Saves settings exported with /settings/export for the logged in user. Sends 401 Unauthorized if
+/// not logged in and 422 UnprocessableEntity naming every invalid field if the settings are
+/// invalid, nothing is saved then.
+///
+/// # Arguments
+///
+/// * `form` - JSON data containing all settings
+/// * `user` - Logged in user
+/// * `redis` - RedisActor to access redis database
+///
+/// # Remarks
+///
+/// Should only be called from actix_web
+pub async fn settings_import(
+    form: Json<UserSettings>,
+    AuthedUser(user): AuthedUser,
+    redis: Data<Pool>,
+) -> Result<HttpResponse> {
+    let invalid = invalid_fields(&form);
+    if !invalid.is_empty() {
+        return Ok(response::error(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "invalid_settings",
+            &format!("Invalid {}", invalid.join(", ")),
+        ));
+    }
+
+    database::settings_set(&user, &form, &redis).await?;
+
+    Ok(HttpResponse::Ok().json(form.into_inner()))
 }
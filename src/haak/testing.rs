@@ -0,0 +1,106 @@
+//! Documentation for testing module
+//! Includes helpers of the tests that talk to redis.
+//!
+//! These tests are ignored by default, as they need a redis server. Run them against the redis at
+//! `REDIS_URL` (default `127.0.0.1:6379`) with `cargo test -- --ignored`. Their keys start with
+//! `weather_test:`, so they don't touch the data of a server sharing the redis.
+use crate::haak::config::Config;
+use crate::haak::database::Pool;
+use crate::haak::keys;
+use crate::haak::session::SessionConfig;
+use crate::haak::tls;
+
+use actix_web::cookie::SameSite;
+use actix_web::web::Data;
+
+use rand::rngs::OsRng;
+use rand::RngCore;
+
+use std::env;
+
+/// Prefix of every key written by tests
+const PREFIX: &str = "weather_test:";
+
+/// Connects to the redis of the tests, readings are stored in sorted sets
+pub fn pool() -> Data<Pool> {
+    pool_compressed(None)
+}
+
+/// Connects to the redis of the tests
+///
+/// # Arguments
+///
+/// * `compression` - Length of the windows readings are compressed in (seconds)
+pub fn pool_compressed(compression: Option<u64>) -> Data<Pool> {
+    keys::init(PREFIX);
+
+    let address = env::var("REDIS_URL").unwrap_or_else(|_| String::from("127.0.0.1:6379"));
+    Data::new(Pool::start(
+        address.trim_start_matches("redis://").trim_end_matches('/'),
+        1,
+        compression,
+    ))
+}
+
+/// Returns the configuration of a server using the defaults, for tests driving handlers
+pub fn config() -> Config {
+    Config {
+        cookie_secret: vec![0; 32],
+        ips: Vec::new(),
+        port: 0,
+        tls: tls::Identity::Pem {
+            cert: String::new(),
+            key: String::new(),
+        },
+        url: String::from("localhost"),
+        webauthn_origin: String::from("https://localhost"),
+        api_key: String::new(),
+        redis_address: String::new(),
+        redis_pool_size: 1,
+        redis_key_prefix: String::from(PREFIX),
+        templates_dir: String::from("./templates"),
+        http_port: None,
+        metrics_port: None,
+        metrics_ip: String::from("127.0.0.1"),
+        retention_days: None,
+        compression_secs: None,
+        json_limit: 8 * 1024,
+        form_limit: 8 * 1024,
+        ingest_limit: 8 * 1024,
+        restore_limit: 64 * 1024 * 1024,
+        ingest_batch_max: 1000,
+        ingest_min_interval_secs: None,
+        remember_device_days: 30,
+        ingest_legacy_format: false,
+        cors_origins: Vec::new(),
+        require_redis: true,
+        station_stale_secs: 900,
+        register_ttl_secs: 3600,
+        email_change_ttl_secs: 3600,
+        login_ttl_secs: 600,
+        login_code_length: None,
+        validate_mx: false,
+        session: SessionConfig {
+            ttl: 3600,
+            idle: 3600,
+            max: None,
+            cookie_name: String::from("session"),
+            cookie_secure: false,
+            cookie_same_site: SameSite::Lax,
+        },
+    }
+}
+
+/// Returns a name no other test run uses, so tests can run concurrently
+///
+/// # Arguments
+///
+/// * `name` - Start of the name (e.g. `station`)
+pub fn unique(name: &str) -> String {
+    format!("{}-{:016x}", name, OsRng.next_u64())
+}
+
+/// Returns an email address no other test run uses
+pub fn email() -> String {
+    format!("{}@example.com", unique("user"))
+}
@@ -0,0 +1,170 @@
+//! Documentation for tls module
+//! Includes the TLS setup of the HTTPS listener.
+//!
+//! The certificate chain and private key are read from PEM files (`TLS_CERT_PATH`, default
+//! `cert.pem`, and `TLS_KEY_PATH`, default `key.pem`), or from a PKCS#12 bundle when
+//! `TLS_PKCS12_PATH` is set, decrypted with `TLS_PKCS12_PASSWORD`.
+//!
+//! On SIGHUP the files are loaded again and new connections use the new certificate, e.g. after a
+//! renewal. The listeners stay open, established connections keep the old certificate.
+use openssl::pkcs12::Pkcs12;
+use openssl::ssl::{
+    AlpnError, ClientHelloResponse, SslAcceptor, SslAcceptorBuilder, SslContext, SslFiletype,
+    SslMethod,
+};
+
+use tokio::signal::unix::{signal, SignalKind};
+
+use std::fs;
+use std::sync::{Arc, RwLock};
+
+/// Where the certificate and private key are read from
+#[derive(Clone, Debug)]
+pub enum Identity {
+    /// Separate PEM files of the certificate chain and the private key
+    Pem { cert: String, key: String },
+    /// PKCS#12 bundle of the certificate, its chain and the private key
+    Pkcs12 { path: String, password: String },
+}
+
+/// Creates the TLS acceptor of the HTTPS listener, a new one is needed for every bound address.
+/// Returns a message naming the file if the certificate or key can't be loaded.
+///
+/// # Arguments
+///
+/// * `identity` - Location of the certificate and private key
+pub fn acceptor(identity: &Identity) -> Result<SslAcceptorBuilder, String> {
+    let mut builder = SslAcceptor::mozilla_intermediate(SslMethod::tls())
+        .map_err(|e| format!("Could not set up TLS: {}", e))?;
+
+    match identity {
+        Identity::Pem { cert, key } => {
+            builder
+                .set_private_key_file(key, SslFiletype::PEM)
+                .map_err(|e| format!("Could not load TLS_KEY_PATH '{}': {}", key, e))?;
+            builder
+                .set_certificate_chain_file(cert)
+                .map_err(|e| format!("Could not load TLS_CERT_PATH '{}': {}", cert, e))?;
+        }
+        Identity::Pkcs12 { path, password } => {
+            let error = |e: &dyn std::fmt::Display| {
+                format!("Could not load TLS_PKCS12_PATH '{}': {}", path, e)
+            };
+
+            let der = fs::read(path).map_err(|e| error(&e))?;
+            let bundle = Pkcs12::from_der(&der)
+                .and_then(|bundle| bundle.parse2(password))
+                .map_err(|e| error(&e))?;
+
+            let key = bundle
+                .pkey
+                .ok_or_else(|| error(&"the bundle has no private key"))?;
+            let cert = bundle
+                .cert
+                .ok_or_else(|| error(&"the bundle has no certificate"))?;
+
+            builder.set_private_key(&key).map_err(|e| error(&e))?;
+            builder.set_certificate(&cert).map_err(|e| error(&e))?;
+            for ca in bundle.ca.into_iter().flatten() {
+                builder.add_extra_chain_cert(ca).map_err(|e| error(&e))?;
+            }
+        }
+    }
+
+    builder
+        .check_private_key()
+        .map_err(|e| format!("TLS private key doesn't match the certificate: {}", e))?;
+
+    Ok(builder)
+}
+
+/// Creates a TLS context to switch new connections to, with the ALPN protocols actix_web sets on
+/// the listeners as the context replaces theirs
+///
+/// # Arguments
+///
+/// * `identity` - Location of the certificate and private key
+fn context(identity: &Identity) -> Result<SslContext, String> {
+    let mut builder = acceptor(identity)?;
+
+    builder.set_alpn_select_callback(|_, protos| {
+        const H2: &[u8] = b"\x02h2";
+        if protos.windows(3).any(|window| window == H2) {
+            Ok(b"h2")
+        } else {
+            Err(AlpnError::NOACK)
+        }
+    });
+    builder
+        .set_alpn_protos(b"\x08http/1.1\x02h2")
+        .map_err(|e| format!("Could not set up TLS: {}", e))?;
+
+    Ok(builder.build().into_context())
+}
+
+/// Certificate and key of the HTTPS listeners, reloadable while the server runs
+#[derive(Clone)]
+pub struct Reloadable {
+    identity: Identity,
+    /// Context every new connection is switched to
+    current: Arc<RwLock<SslContext>>,
+}
+
+impl Reloadable {
+    /// Loads the certificate and key, returns a message naming the file if they can't be loaded
+    ///
+    /// # Arguments
+    ///
+    /// * `identity` - Location of the certificate and private key
+    pub fn load(identity: Identity) -> Result<Reloadable, String> {
+        let current = context(&identity)?;
+
+        Ok(Reloadable {
+            identity,
+            current: Arc::new(RwLock::new(current)),
+        })
+    }
+
+    /// Creates the TLS acceptor of a listener, switching every new connection to the most
+    /// recently loaded certificate
+    pub fn acceptor(&self) -> Result<SslAcceptorBuilder, String> {
+        let mut builder = acceptor(&self.identity)?;
+        let current = self.current.clone();
+
+        builder.set_client_hello_callback(move |ssl, _| {
+            let context = current.read().unwrap().clone();
+            ssl.set_ssl_context(&context)?;
+            Ok(ClientHelloResponse::SUCCESS)
+        });
+
+        Ok(builder)
+    }
+
+    /// Loads the certificate and key again. If they can't be loaded the old ones stay in use.
+    pub fn reload(&self) -> Result<(), String> {
+        let context = context(&self.identity)?;
+        *self.current.write().unwrap() = context;
+
+        Ok(())
+    }
+
+    /// Starts the task reloading the certificate and key on SIGHUP on the current arbiter
+    pub fn reload_on_hangup(self) {
+        actix_rt::spawn(async move {
+            let mut hangups = match signal(SignalKind::hangup()) {
+                Ok(hangups) => hangups,
+                Err(e) => {
+                    error!("Could not listen for SIGHUP, TLS can't be reloaded: {}", e);
+                    return;
+                }
+            };
+
+            while hangups.recv().await.is_some() {
+                match self.reload() {
+                    Ok(()) => info!("Reloaded the TLS certificate"),
+                    Err(e) => error!("{}, keeping the old TLS certificate", e),
+                }
+            }
+        });
+    }
+}
@@ -0,0 +1,66 @@
+//! Documentation for TOTP module
+//! Includes generation and verification of time-based one-time passwords (RFC 6238), used as
+//! optional second factor after the first login step.
+use crate::haak::auth;
+
+use rand::rngs::OsRng;
+use rand::RngCore;
+use totp_lite::{totp_custom, Sha1};
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Length of a time step in seconds
+const STEP: u64 = 30;
+
+/// Number of digits in a code
+const DIGITS: u32 = 6;
+
+/// Issuer shown in authenticator apps
+const ISSUER: &str = "HAAK Weather Station";
+
+/// Base32 alphabet used by authenticator apps
+const ALPHABET: base32::Alphabet = base32::Alphabet::RFC4648 { padding: false };
+
+/// Generates a new 20 byte secret, base32 encoded
+pub fn generate_secret() -> String {
+    let mut secret = [0u8; 20];
+    OsRng.fill_bytes(&mut secret);
+    base32::encode(ALPHABET, &secret)
+}
+
+/// Creates an `otpauth://` URI to enroll the secret in an authenticator app (e.g. as QR code)
+///
+/// # Arguments
+///
+/// * `email` - Email address of the user, used as account name
+/// * `secret` - Base32 encoded secret
+pub fn uri(email: &str, secret: &str) -> String {
+    let issuer = ISSUER.replace(' ', "%20");
+    format!(
+        "otpauth://totp/{}:{}?secret={}&issuer={}&digits={}&period={}",
+        issuer, email, secret, issuer, DIGITS, STEP
+    )
+}
+
+/// Verifies a code against the secret, allowing one time step of clock drift in both directions.
+/// Returns false if the secret can't be decoded.
+///
+/// # Arguments
+///
+/// * `secret` - Base32 encoded secret
+/// * `code` - Code submitted by the user
+pub fn verify(secret: &str, code: &str) -> bool {
+    let secret = match base32::decode(ALPHABET, secret) {
+        Some(secret) => secret,
+        None => return false,
+    };
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+
+    [now.saturating_sub(STEP), now, now + STEP]
+        .iter()
+        .any(|time| auth::tokens_equal(&totp_custom::<Sha1>(STEP, DIGITS, &secret, *time), code))
+}
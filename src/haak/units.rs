@@ -0,0 +1,246 @@
+//! Documentation for units module
+//! Includes conversion of readings into the units chosen in the user's settings.
+//!
+//! Readings are stored with temperature in Celsius, pressure in Bar and humidity in percent.
+use crate::haak::settings::UserSettings;
+
+/// Converts a temperature from Celsius
+///
+/// # Arguments
+///
+/// * `value` - Temperature in Celsius
+/// * `to` - Temperature setting (`Celsius`, `Kelvin` or `Fahrenheit`)
+pub fn convert_temperature(value: f64, to: &str) -> f64 {
+    match to {
+        "Kelvin" => value + 273.15,
+        "Fahrenheit" => value * 9.0 / 5.0 + 32.0,
+        _ => value,
+    }
+}
+
+/// Converts a pressure from Bar
+///
+/// # Arguments
+///
+/// * `value` - Pressure in Bar
+/// * `to` - Pressure setting (`Atmosphere`, `Millibar`, `Bar`, `PSI` or `Mercury`)
+pub fn convert_pressure(value: f64, to: &str) -> f64 {
+    match to {
+        "Atmosphere" => value / 1.013_25,
+        "Millibar" => value * 1000.0,
+        "PSI" => value * 14.503_773_8,
+        "Mercury" => value * 750.061_683,
+        _ => value,
+    }
+}
+
+/// Converts a temperature sent by a weather station into Celsius, `None` for an unknown unit
+///
+/// # Arguments
+///
+/// * `value` - Temperature in the unit
+/// * `unit` - Unit of the temperature (`C`, `K` or `F`)
+pub fn temperature_from(value: f64, unit: &str) -> Option<f64> {
+    match unit {
+        "C" => Some(value),
+        "K" => Some(value - 273.15),
+        "F" => Some((value - 32.0) * 5.0 / 9.0),
+        _ => None,
+    }
+}
+
+/// Converts a pressure sent by a weather station into Bar, `None` for an unknown unit
+///
+/// # Arguments
+///
+/// * `value` - Pressure in the unit
+/// * `unit` - Unit of the pressure (`bar`, `mbar`, `hPa`, `kPa`, `Pa`, `atm`, `psi` or `mmHg`)
+pub fn pressure_from(value: f64, unit: &str) -> Option<f64> {
+    match unit {
+        "bar" => Some(value),
+        "mbar" | "hPa" => Some(value / 1000.0),
+        "kPa" => Some(value / 100.0),
+        "Pa" => Some(value / 100_000.0),
+        "atm" => Some(value * 1.013_25),
+        "psi" => Some(value / 14.503_773_8),
+        "mmHg" => Some(value / 750.061_683),
+        _ => None,
+    }
+}
+
+/// Converts a reading of a metric sent by a weather station into the unit it is stored in,
+/// `None` for an unknown unit
+///
+/// # Arguments
+///
+/// * `metric` - Name of the metric (e.g. `temperature`)
+/// * `value` - Value in the unit
+/// * `unit` - Unit of the value (e.g. `hPa`)
+pub fn to_stored(metric: &str, value: f64, unit: &str) -> Option<f64> {
+    match metric {
+        "temperature" => temperature_from(value, unit),
+        "pressure" => pressure_from(value, unit),
+        _ if unit == "%" => Some(value),
+        _ => None,
+    }
+}
+
+/// Reduces a station pressure to sea level with the barometric formula of the International
+/// Standard Atmosphere, `p0 = p * (1 - 0.0065 * h / 288.15) ^ -5.255`. It assumes the standard
+/// temperature lapse rate of 6.5 K/km, which is accurate to about 1 hPa below 1000 m.
+///
+/// # Arguments
+///
+/// * `value` - Station pressure, in any unit
+/// * `altitude` - Altitude of the station in meters
+pub fn sea_level_pressure(value: f64, altitude: f64) -> f64 {
+    value * (1.0 - 0.0065 * altitude / 288.15).powf(-5.255)
+}
+
+/// Unit of a metric in the user's settings (e.g. `Celsius`)
+///
+/// # Arguments
+///
+/// * `metric` - Name of the metric (e.g. `temperature`)
+/// * `settings` - Settings of the user
+pub fn unit<'a>(metric: &str, settings: &'a UserSettings) -> &'a str {
+    match metric {
+        "temperature" => &settings.temperature,
+        "pressure" | "pressure_msl" => &settings.pressure,
+        _ => &settings.humidity,
+    }
+}
+
+/// Converts a stored reading of a metric into the user's units
+///
+/// # Arguments
+///
+/// * `metric` - Name of the metric (e.g. `temperature`)
+/// * `value` - Stored value
+/// * `settings` - Settings of the user
+pub fn convert(metric: &str, value: f64, settings: &UserSettings) -> f64 {
+    match metric {
+        "temperature" => convert_temperature(value, &settings.temperature),
+        "pressure" | "pressure_msl" => convert_pressure(value, &settings.pressure),
+        _ => value,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Asserts that two values are equal within a tolerance
+    fn assert_close(actual: f64, expected: f64, tolerance: f64) {
+        assert!(
+            (actual - expected).abs() <= tolerance,
+            "{} is not within {} of {}",
+            actual,
+            tolerance,
+            expected
+        );
+    }
+
+    /// Temperature settings and the units stations send them in
+    const TEMPERATURES: [(&str, &str); 3] =
+        [("Celsius", "C"), ("Kelvin", "K"), ("Fahrenheit", "F")];
+
+    /// Pressure settings and the units stations send them in
+    const PRESSURES: [(&str, &str); 5] = [
+        ("Bar", "bar"),
+        ("Millibar", "mbar"),
+        ("Atmosphere", "atm"),
+        ("PSI", "psi"),
+        ("Mercury", "mmHg"),
+    ];
+
+    #[test]
+    fn temperature_reference_values() {
+        for (celsius, kelvin, fahrenheit) in [
+            (0.0, 273.15, 32.0),
+            (100.0, 373.15, 212.0),
+            (-40.0, 233.15, -40.0),
+            (37.0, 310.15, 98.6),
+        ]
+        .iter()
+        {
+            assert_close(convert_temperature(*celsius, "Celsius"), *celsius, 1e-9);
+            assert_close(convert_temperature(*celsius, "Kelvin"), *kelvin, 1e-9);
+            assert_close(
+                convert_temperature(*celsius, "Fahrenheit"),
+                *fahrenheit,
+                1e-9,
+            );
+            assert_close(temperature_from(*kelvin, "K").unwrap(), *celsius, 1e-9);
+            assert_close(temperature_from(*fahrenheit, "F").unwrap(), *celsius, 1e-9);
+        }
+    }
+
+    #[test]
+    fn pressure_reference_values() {
+        // One standard atmosphere
+        let bar = 1.013_25;
+        assert_close(convert_pressure(bar, "Atmosphere"), 1.0, 1e-9);
+        assert_close(convert_pressure(bar, "Millibar"), 1013.25, 1e-9);
+        assert_close(convert_pressure(bar, "PSI"), 14.695_95, 1e-4);
+        assert_close(convert_pressure(bar, "Mercury"), 760.0, 1e-3);
+
+        for (value, unit) in [
+            (1.013_25, "bar"),
+            (1013.25, "mbar"),
+            (1013.25, "hPa"),
+            (101.325, "kPa"),
+            (101_325.0, "Pa"),
+            (1.0, "atm"),
+            (14.695_95, "psi"),
+            (760.0, "mmHg"),
+        ]
+        .iter()
+        {
+            assert_close(pressure_from(*value, unit).unwrap(), bar, 1e-5);
+        }
+    }
+
+    #[test]
+    fn conversions_round_trip() {
+        for value in [-30.0, 0.0, 21.5, 45.0].iter() {
+            for (setting, unit) in TEMPERATURES.iter() {
+                let converted = convert_temperature(*value, setting);
+                assert_close(temperature_from(converted, unit).unwrap(), *value, 1e-9);
+            }
+        }
+
+        for value in [0.87, 1.0, 1.013_25, 1.05].iter() {
+            for (setting, unit) in PRESSURES.iter() {
+                let converted = convert_pressure(*value, setting);
+                assert_close(pressure_from(converted, unit).unwrap(), *value, 1e-9);
+            }
+        }
+    }
+
+    #[test]
+    fn sea_level_pressure_of_standard_atmosphere() {
+        // Pressure of the International Standard Atmosphere at altitude, in hPa (ISO 2533)
+        for (pressure, altitude) in [
+            (1013.25, 0.0),
+            (954.61, 500.0),
+            (898.76, 1000.0),
+            (794.95, 2000.0),
+        ]
+        .iter()
+        {
+            assert_close(sea_level_pressure(*pressure, *altitude), 1013.25, 0.1);
+        }
+
+        // The unit of the pressure doesn't matter
+        assert_close(sea_level_pressure(0.898_76, 1000.0), 1.013_25, 1e-4);
+    }
+
+    #[test]
+    fn unknown_units_are_rejected() {
+        assert_eq!(temperature_from(20.0, "celsius"), None);
+        assert_eq!(pressure_from(1.0, "inHg"), None);
+        assert_eq!(to_stored("humidity", 50.0, "%"), Some(50.0));
+        assert_eq!(to_stored("humidity", 50.0, "ratio"), None);
+    }
+}
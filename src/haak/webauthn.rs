@@ -0,0 +1,764 @@
+//! Documentation for WebAuthn module
+//! Includes the registration and authentication ceremonies of security keys and passkeys, used as
+//! alternative first login factor next to the email challenge.
+//!
+//! Only the parts of WebAuthn the server needs are implemented: attestation is not requested, so
+//! the authenticator model isn't verified, and ES256 and RS256 keys are supported. Credentials are
+//! stored in the `webauthn:<email>` hash, keyed by credential ID.
+//!
+//! The relying party ID is `WEATHER_URL`, so credentials are bound to that host name. The origin
+//! is `WEBAUTHN_ORIGIN`, as the port the server binds to isn't the public one behind a proxy.
+use crate::haak::auth;
+use crate::haak::config::Config;
+
+use openssl::bn::BigNum;
+use openssl::ec::{EcGroup, EcKey};
+use openssl::hash::MessageDigest;
+use openssl::nid::Nid;
+use openssl::pkey::PKey;
+use openssl::rsa::Rsa;
+use openssl::sha::sha256;
+use openssl::sign::Verifier;
+use rand::rngs::OsRng;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use std::convert::TryFrom;
+
+/// Time a ceremony may take in seconds
+pub const TIMEOUT_SECS: u64 = 300;
+
+/// COSE algorithm of ECDSA with P-256 and SHA-256
+const ES256: i64 = -7;
+
+/// COSE algorithm of RSASSA-PKCS1-v1_5 with SHA-256
+const RS256: i64 = -257;
+
+/// Authenticator data flag set if the user was present
+const USER_PRESENT: u8 = 0x01;
+
+/// Authenticator data flag set if attested credential data is included
+const ATTESTED_DATA: u8 = 0x40;
+
+/// Maximum nesting of CBOR items, authenticators nest at most a few levels
+const MAX_DEPTH: usize = 8;
+
+/// Credential of a user, as stored in the database
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Credential {
+    /// Credential ID, base64url encoded
+    pub id: String,
+    /// COSE algorithm of the key
+    pub alg: i64,
+    /// Public key as DER encoded SubjectPublicKeyInfo, base64 encoded
+    pub public_key: String,
+    /// Signature counter of the authenticator, 0 if it doesn't count
+    pub sign_count: u32,
+}
+
+/// Ceremony waiting for the response of the authenticator, stored in the session
+#[derive(Serialize, Deserialize)]
+pub struct Pending {
+    /// User of the ceremony
+    pub email: String,
+    /// Challenge the authenticator signs, base64url encoded
+    pub challenge: String,
+    /// Unix timestamp after which the ceremony fails
+    pub expires: u64,
+}
+
+/// Response of the authenticator to a registration ceremony, fields base64url encoded
+#[derive(Deserialize)]
+pub struct Attestation {
+    pub id: String,
+    #[serde(rename = "clientDataJSON")]
+    pub client_data_json: String,
+    #[serde(rename = "attestationObject")]
+    pub attestation_object: String,
+}
+
+/// Response of the authenticator to an authentication ceremony, fields base64url encoded
+#[derive(Deserialize)]
+pub struct Assertion {
+    pub id: String,
+    #[serde(rename = "clientDataJSON")]
+    pub client_data_json: String,
+    #[serde(rename = "authenticatorData")]
+    pub authenticator_data: String,
+    pub signature: String,
+}
+
+/// Client data collected by the browser
+#[derive(Deserialize)]
+struct ClientData {
+    #[serde(rename = "type")]
+    kind: String,
+    challenge: String,
+    origin: String,
+}
+
+/// Relying party the credentials are bound to
+pub struct RelyingParty {
+    /// Host name of the server
+    pub id: String,
+    /// Origin the browser reports, e.g. `https://weather.example.com`
+    pub origin: String,
+}
+
+impl RelyingParty {
+    /// Creates the relying party of the server from `WEATHER_URL` and `WEBAUTHN_ORIGIN`
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - Server configuration
+    pub fn new(config: &Config) -> RelyingParty {
+        RelyingParty {
+            id: config.url.clone(),
+            origin: config.webauthn_origin.clone(),
+        }
+    }
+}
+
+/// Encodes bytes as base64url without padding, as used by WebAuthn
+fn encode(data: &[u8]) -> String {
+    base64::encode_config(data, base64::URL_SAFE_NO_PAD)
+}
+
+/// Decodes base64url, with or without padding
+///
+/// # Arguments
+///
+/// * `what` - Name of the decoded field, used in the error
+/// * `data` - Base64url encoded data
+fn decode(what: &str, data: &str) -> Result<Vec<u8>, String> {
+    base64::decode_config(data.trim_end_matches('='), base64::URL_SAFE_NO_PAD)
+        .map_err(|e| format!("Invalid {}: {}", what, e))
+}
+
+/// Generates a new 32 byte challenge, base64url encoded
+pub fn generate_challenge() -> String {
+    let mut challenge = [0u8; 32];
+    OsRng.fill_bytes(&mut challenge);
+    encode(&challenge)
+}
+
+/// Creates a credential ID for users without credentials, so the options of /webauthn/login/start
+/// don't reveal whether the user exists or has a security key. The ID is the same on every
+/// attempt, like a real one.
+///
+/// # Arguments
+///
+/// * `email` - Email address of the user
+/// * `secret` - Server secret, so the ID can't be computed by others
+pub fn decoy_id(email: &str, secret: &[u8]) -> String {
+    let mut data = secret.to_vec();
+    data.extend_from_slice(email.as_bytes());
+    encode(&sha256(&data))
+}
+
+/// Creates the `PublicKeyCredentialCreationOptions` of a registration ceremony, passed to
+/// `navigator.credentials.create` after decoding the base64url fields
+///
+/// # Arguments
+///
+/// * `rp` - Relying party
+/// * `email` - Email address of the user registering a credential
+/// * `challenge` - Challenge of the ceremony
+/// * `existing` - Credentials of the user, which the authenticator must not register again
+pub fn creation_options(
+    rp: &RelyingParty,
+    email: &str,
+    challenge: &str,
+    existing: &[Credential],
+) -> serde_json::Value {
+    let exclude: Vec<_> = existing
+        .iter()
+        .map(|credential| json!({"type": "public-key", "id": credential.id}))
+        .collect();
+
+    json!({
+        "challenge": challenge,
+        "rp": {"id": rp.id, "name": rp.id},
+        "user": {"id": encode(&sha256(email.as_bytes())), "name": email, "displayName": email},
+        "pubKeyCredParams": [
+            {"type": "public-key", "alg": ES256},
+            {"type": "public-key", "alg": RS256},
+        ],
+        "excludeCredentials": exclude,
+        "attestation": "none",
+        "timeout": TIMEOUT_SECS * 1000,
+    })
+}
+
+/// Creates the `PublicKeyCredentialRequestOptions` of an authentication ceremony, passed to
+/// `navigator.credentials.get` after decoding the base64url fields
+///
+/// # Arguments
+///
+/// * `rp` - Relying party
+/// * `challenge` - Challenge of the ceremony
+/// * `ids` - Credential IDs the user may sign with
+pub fn request_options(rp: &RelyingParty, challenge: &str, ids: &[String]) -> serde_json::Value {
+    let allow: Vec<_> = ids
+        .iter()
+        .map(|id| json!({"type": "public-key", "id": id}))
+        .collect();
+
+    json!({
+        "challenge": challenge,
+        "rpId": rp.id,
+        "allowCredentials": allow,
+        "userVerification": "preferred",
+        "timeout": TIMEOUT_SECS * 1000,
+    })
+}
+
+/// Checks the client data of a ceremony: its type, challenge and origin
+///
+/// # Arguments
+///
+/// * `rp` - Relying party
+/// * `client_data` - Raw client data JSON
+/// * `kind` - Expected type, `webauthn.create` or `webauthn.get`
+/// * `challenge` - Challenge of the ceremony
+fn check_client_data(
+    rp: &RelyingParty,
+    client_data: &[u8],
+    kind: &str,
+    challenge: &str,
+) -> Result<(), String> {
+    let data: ClientData =
+        serde_json::from_slice(client_data).map_err(|e| format!("Invalid client data: {}", e))?;
+
+    if data.kind != kind {
+        return Err(format!("Unexpected client data type '{}'", data.kind));
+    }
+    if !auth::tokens_equal(data.challenge.trim_end_matches('='), challenge) {
+        return Err(String::from("Challenge mismatch"));
+    }
+    if data.origin != rp.origin {
+        return Err(format!("Unexpected origin '{}'", data.origin));
+    }
+
+    Ok(())
+}
+
+/// Checks the relying party hash and user presence flag of authenticator data.
+/// Returns the flags and signature counter.
+///
+/// # Arguments
+///
+/// * `rp` - Relying party
+/// * `data` - Raw authenticator data
+fn check_authenticator_data(rp: &RelyingParty, data: &[u8]) -> Result<(u8, u32), String> {
+    if data.len() < 37 {
+        return Err(String::from("Authenticator data too short"));
+    }
+    if data[..32] != sha256(rp.id.as_bytes()) {
+        return Err(String::from("Credential of another relying party"));
+    }
+
+    let flags = data[32];
+    if flags & USER_PRESENT == 0 {
+        return Err(String::from("User not present"));
+    }
+
+    let mut count = [0u8; 4];
+    count.copy_from_slice(&data[33..37]);
+
+    Ok((flags, u32::from_be_bytes(count)))
+}
+
+/// Verifies the response of a registration ceremony. Returns the new credential.
+///
+/// # Arguments
+///
+/// * `rp` - Relying party
+/// * `challenge` - Challenge of the ceremony
+/// * `attestation` - Response of the authenticator
+pub fn register(
+    rp: &RelyingParty,
+    challenge: &str,
+    attestation: &Attestation,
+) -> Result<Credential, String> {
+    let client_data = decode("client data", &attestation.client_data_json)?;
+    check_client_data(rp, &client_data, "webauthn.create", challenge)?;
+
+    let object = decode("attestation object", &attestation.attestation_object)?;
+    let data = match Cbor::parse(&object, &mut 0, 0)?.get_text("authData") {
+        Some(Cbor::Bytes(data)) => data.clone(),
+        _ => {
+            return Err(String::from(
+                "Attestation object without authenticator data",
+            ))
+        }
+    };
+
+    let (flags, sign_count) = check_authenticator_data(rp, &data)?;
+    if flags & ATTESTED_DATA == 0 || data.len() < 55 {
+        return Err(String::from("Authenticator data without credential"));
+    }
+
+    // AAGUID (16 bytes), credential ID length (2 bytes), credential ID, COSE key
+    let id_len = usize::from(u16::from_be_bytes([data[53], data[54]]));
+    let id = data
+        .get(55..55 + id_len)
+        .ok_or_else(|| String::from("Authenticator data too short"))?;
+    if encode(id) != attestation.id.trim_end_matches('=') {
+        return Err(String::from("Credential ID mismatch"));
+    }
+
+    let key = Cbor::parse(&data, &mut (55 + id_len), 0)?;
+    let (alg, public_key) = public_key(&key)?;
+
+    Ok(Credential {
+        id: encode(id),
+        alg,
+        public_key: base64::encode(&public_key),
+        sign_count,
+    })
+}
+
+/// Verifies the response of an authentication ceremony. Returns the new signature counter.
+/// Fails if the counter didn't increase while the authenticator counts, as the credential may be
+/// cloned then.
+///
+/// # Arguments
+///
+/// * `rp` - Relying party
+/// * `challenge` - Challenge of the ceremony
+/// * `credential` - Stored credential the response claims to be signed with
+/// * `assertion` - Response of the authenticator
+pub fn authenticate(
+    rp: &RelyingParty,
+    challenge: &str,
+    credential: &Credential,
+    assertion: &Assertion,
+) -> Result<u32, String> {
+    let client_data = decode("client data", &assertion.client_data_json)?;
+    check_client_data(rp, &client_data, "webauthn.get", challenge)?;
+
+    let data = decode("authenticator data", &assertion.authenticator_data)?;
+    let (_, sign_count) = check_authenticator_data(rp, &data)?;
+
+    let signature = decode("signature", &assertion.signature)?;
+    let public_key = base64::decode(&credential.public_key)
+        .map_err(|e| format!("Invalid stored public key: {}", e))?;
+
+    let valid = (|| {
+        let key = PKey::public_key_from_der(&public_key)?;
+        let mut verifier = Verifier::new(MessageDigest::sha256(), &key)?;
+        verifier.update(&data)?;
+        verifier.update(&sha256(&client_data))?;
+        verifier.verify(&signature)
+    })()
+    .map_err(|e| format!("Could not verify signature: {}", e))?;
+
+    if !valid {
+        return Err(String::from("Invalid signature"));
+    }
+
+    if (sign_count != 0 || credential.sign_count != 0) && sign_count <= credential.sign_count {
+        return Err(String::from("Signature counter did not increase"));
+    }
+
+    Ok(sign_count)
+}
+
+/// Converts a COSE key into its algorithm and DER encoded SubjectPublicKeyInfo
+///
+/// # Arguments
+///
+/// * `key` - Decoded COSE key
+fn public_key(key: &Cbor) -> Result<(i64, Vec<u8>), String> {
+    let bytes = |label: i64| match key.get_int(label) {
+        Some(Cbor::Bytes(bytes)) => Ok(bytes.as_slice()),
+        _ => Err(format!("COSE key without parameter {}", label)),
+    };
+    let to_der = |e: openssl::error::ErrorStack| format!("Invalid public key: {}", e);
+
+    match key.get_int(3) {
+        // EC2 key on P-256: x (-2) and y (-3)
+        Some(Cbor::Int(ES256)) => {
+            let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).map_err(to_der)?;
+            let x = BigNum::from_slice(bytes(-2)?).map_err(to_der)?;
+            let y = BigNum::from_slice(bytes(-3)?).map_err(to_der)?;
+            let key = EcKey::from_public_key_affine_coordinates(&group, &x, &y).map_err(to_der)?;
+            key.check_key().map_err(to_der)?;
+            let der = PKey::from_ec_key(key)
+                .and_then(|key| key.public_key_to_der())
+                .map_err(to_der)?;
+            Ok((ES256, der))
+        }
+        // RSA key: n (-1) and e (-2)
+        Some(Cbor::Int(RS256)) => {
+            let n = BigNum::from_slice(bytes(-1)?).map_err(to_der)?;
+            let e = BigNum::from_slice(bytes(-2)?).map_err(to_der)?;
+            let der = Rsa::from_public_components(n, e)
+                .and_then(PKey::from_rsa)
+                .and_then(|key| key.public_key_to_der())
+                .map_err(to_der)?;
+            Ok((RS256, der))
+        }
+        _ => Err(String::from("Unsupported key algorithm")),
+    }
+}
+
+/// Decoded CBOR item, as far as authenticators use them
+#[derive(Debug, PartialEq)]
+enum Cbor {
+    Int(i64),
+    Bytes(Vec<u8>),
+    Text(String),
+    Array(Vec<Cbor>),
+    Map(Vec<(Cbor, Cbor)>),
+    /// Booleans, null, undefined and floats, which aren't needed
+    Simple,
+}
+
+impl Cbor {
+    /// Decodes the item at `pos` and moves `pos` past it. Indefinite lengths are rejected, as
+    /// authenticators encode canonically.
+    ///
+    /// # Arguments
+    ///
+    /// * `data` - Encoded data
+    /// * `pos` - Position of the item
+    /// * `depth` - Nesting of the item
+    fn parse(data: &[u8], pos: &mut usize, depth: usize) -> Result<Cbor, String> {
+        if depth > MAX_DEPTH {
+            return Err(String::from("CBOR nested too deeply"));
+        }
+
+        let mut take = |len: usize| -> Result<&[u8], String> {
+            let bytes = data
+                .get(*pos..pos.saturating_add(len))
+                .ok_or_else(|| String::from("Truncated CBOR"))?;
+            *pos += len;
+            Ok(bytes)
+        };
+
+        let initial = take(1)?[0];
+        let (major, info) = (initial >> 5, initial & 0x1f);
+        let arg = match info {
+            0..=23 => u64::from(info),
+            24..=27 => take(1 << (info - 24))?
+                .iter()
+                .fold(0, |arg, byte| arg << 8 | u64::from(*byte)),
+            _ => return Err(String::from("Unsupported CBOR length")),
+        };
+        let len = usize::try_from(arg).map_err(|_| String::from("CBOR item too large"))?;
+
+        match major {
+            0 => i64::try_from(arg)
+                .map(Cbor::Int)
+                .map_err(|_| String::from("CBOR integer too large")),
+            1 => i64::try_from(arg)
+                .map(|arg| Cbor::Int(-1 - arg))
+                .map_err(|_| String::from("CBOR integer too large")),
+            2 => Ok(Cbor::Bytes(take(len)?.to_vec())),
+            3 => String::from_utf8(take(len)?.to_vec())
+                .map(Cbor::Text)
+                .map_err(|_| String::from("Invalid CBOR text")),
+            4 => (0..len)
+                .map(|_| Cbor::parse(data, pos, depth + 1))
+                .collect::<Result<_, _>>()
+                .map(Cbor::Array),
+            5 => (0..len)
+                .map(|_| {
+                    let key = Cbor::parse(data, pos, depth + 1)?;
+                    Ok((key, Cbor::parse(data, pos, depth + 1)?))
+                })
+                .collect::<Result<_, String>>()
+                .map(Cbor::Map),
+            // Tagged item, the tag isn't needed
+            6 => Cbor::parse(data, pos, depth + 1),
+            _ => Ok(Cbor::Simple),
+        }
+    }
+
+    /// Returns the value of a map entry with the key
+    fn get(&self, key: &Cbor) -> Option<&Cbor> {
+        match self {
+            Cbor::Map(entries) => entries.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    /// Returns the value of a map entry with the text key
+    fn get_text(&self, key: &str) -> Option<&Cbor> {
+        self.get(&Cbor::Text(String::from(key)))
+    }
+
+    /// Returns the value of a map entry with the integer key
+    fn get_int(&self, key: i64) -> Option<&Cbor> {
+        self.get(&Cbor::Int(key))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    //! The vectors are made by a software authenticator with keys generated by openssl, laid out
+    //! as hardware authenticators do: `none` attestation, canonical CBOR and DER signatures.
+    use super::*;
+
+    use openssl::ec::EcKey;
+    use openssl::pkey::Private;
+    use openssl::sign::Signer;
+
+    const CHALLENGE: &str = "c2VydmVyIGNoYWxsZW5nZSBvZiB0aGUgdGVzdCBjZXJlbW9ueQ";
+
+    fn rp() -> RelyingParty {
+        RelyingParty {
+            id: String::from("weather.example.com"),
+            origin: String::from("https://weather.example.com"),
+        }
+    }
+
+    fn head(major: u8, arg: usize) -> Vec<u8> {
+        match arg {
+            0..=23 => vec![major << 5 | arg as u8],
+            24..=0xff => vec![major << 5 | 24, arg as u8],
+            _ => vec![major << 5 | 25, (arg >> 8) as u8, arg as u8],
+        }
+    }
+
+    fn int(value: i64) -> Vec<u8> {
+        match value {
+            0..=i64::MAX => head(0, value as usize),
+            _ => head(1, (-1 - value) as usize),
+        }
+    }
+
+    fn bytes(data: &[u8]) -> Vec<u8> {
+        [head(2, data.len()), data.to_vec()].concat()
+    }
+
+    fn text(data: &str) -> Vec<u8> {
+        [head(3, data.len()), data.as_bytes().to_vec()].concat()
+    }
+
+    fn map(entries: Vec<(Vec<u8>, Vec<u8>)>) -> Vec<u8> {
+        let mut out = head(5, entries.len());
+        for (key, value) in entries {
+            out.extend(key);
+            out.extend(value);
+        }
+        out
+    }
+
+    /// Software authenticator holding a single credential
+    struct Authenticator {
+        id: Vec<u8>,
+        alg: i64,
+        key: PKey<Private>,
+    }
+
+    impl Authenticator {
+        fn es256() -> Authenticator {
+            let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+            Authenticator {
+                id: vec![0xa5; 32],
+                alg: ES256,
+                key: PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap(),
+            }
+        }
+
+        fn rs256() -> Authenticator {
+            Authenticator {
+                id: vec![0x5a; 64],
+                alg: RS256,
+                key: PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap(),
+            }
+        }
+
+        fn cose_key(&self) -> Vec<u8> {
+            match self.alg {
+                ES256 => {
+                    let key = self.key.ec_key().unwrap();
+                    let mut ctx = openssl::bn::BigNumContext::new().unwrap();
+                    let (mut x, mut y) = (BigNum::new().unwrap(), BigNum::new().unwrap());
+                    key.public_key()
+                        .affine_coordinates_gfp(key.group(), &mut x, &mut y, &mut ctx)
+                        .unwrap();
+                    map(vec![
+                        (int(1), int(2)),
+                        (int(3), int(ES256)),
+                        (int(-1), int(1)),
+                        (int(-2), bytes(&x.to_vec_padded(32).unwrap())),
+                        (int(-3), bytes(&y.to_vec_padded(32).unwrap())),
+                    ])
+                }
+                _ => {
+                    let key = self.key.rsa().unwrap();
+                    map(vec![
+                        (int(1), int(3)),
+                        (int(3), int(RS256)),
+                        (int(-1), bytes(&key.n().to_vec())),
+                        (int(-2), bytes(&key.e().to_vec())),
+                    ])
+                }
+            }
+        }
+
+        fn authenticator_data(&self, rp_id: &str, flags: u8, count: u32) -> Vec<u8> {
+            let mut data = sha256(rp_id.as_bytes()).to_vec();
+            data.push(flags);
+            data.extend_from_slice(&count.to_be_bytes());
+            data
+        }
+
+        fn client_data(kind: &str, challenge: &str, origin: &str) -> Vec<u8> {
+            json!({"type": kind, "challenge": challenge, "origin": origin, "crossOrigin": false})
+                .to_string()
+                .into_bytes()
+        }
+
+        fn attest(&self, rp: &RelyingParty, challenge: &str) -> Attestation {
+            let mut data = self.authenticator_data(&rp.id, USER_PRESENT | ATTESTED_DATA, 0);
+            data.extend_from_slice(&[0; 16]);
+            data.extend_from_slice(&(self.id.len() as u16).to_be_bytes());
+            data.extend_from_slice(&self.id);
+            data.extend(self.cose_key());
+
+            let object = map(vec![
+                (text("fmt"), text("none")),
+                (text("attStmt"), map(vec![])),
+                (text("authData"), bytes(&data)),
+            ]);
+
+            Attestation {
+                id: encode(&self.id),
+                client_data_json: encode(&Self::client_data(
+                    "webauthn.create",
+                    challenge,
+                    &rp.origin,
+                )),
+                attestation_object: encode(&object),
+            }
+        }
+
+        fn assert(&self, rp: &RelyingParty, challenge: &str, count: u32) -> Assertion {
+            let data = self.authenticator_data(&rp.id, USER_PRESENT, count);
+            let client_data = Self::client_data("webauthn.get", challenge, &rp.origin);
+
+            let mut signer = Signer::new(MessageDigest::sha256(), &self.key).unwrap();
+            signer.update(&data).unwrap();
+            signer.update(&sha256(&client_data)).unwrap();
+
+            Assertion {
+                id: encode(&self.id),
+                client_data_json: encode(&client_data),
+                authenticator_data: encode(&data),
+                signature: encode(&signer.sign_to_vec().unwrap()),
+            }
+        }
+    }
+
+    #[test]
+    fn es256_ceremonies() {
+        let authenticator = Authenticator::es256();
+        let credential = register(&rp(), CHALLENGE, &authenticator.attest(&rp(), CHALLENGE))
+            .expect("registration");
+
+        assert_eq!(credential.id, encode(&authenticator.id));
+        assert_eq!(credential.alg, ES256);
+        assert_eq!(
+            base64::decode(&credential.public_key).unwrap(),
+            authenticator.key.public_key_to_der().unwrap()
+        );
+
+        let assertion = authenticator.assert(&rp(), CHALLENGE, 1);
+        assert_eq!(
+            authenticate(&rp(), CHALLENGE, &credential, &assertion),
+            Ok(1)
+        );
+    }
+
+    #[test]
+    fn rs256_ceremonies() {
+        let authenticator = Authenticator::rs256();
+        let credential = register(&rp(), CHALLENGE, &authenticator.attest(&rp(), CHALLENGE))
+            .expect("registration");
+
+        assert_eq!(credential.alg, RS256);
+
+        let assertion = authenticator.assert(&rp(), CHALLENGE, 0);
+        assert_eq!(
+            authenticate(&rp(), CHALLENGE, &credential, &assertion),
+            Ok(0)
+        );
+    }
+
+    #[test]
+    fn registration_rejects_other_ceremonies() {
+        let authenticator = Authenticator::es256();
+        let other = RelyingParty {
+            id: String::from("evil.example.com"),
+            origin: String::from("https://weather.example.com:8443"),
+        };
+
+        let attestation = authenticator.attest(&rp(), CHALLENGE);
+        assert!(register(&rp(), &generate_challenge(), &attestation).is_err());
+
+        let attestation = authenticator.attest(&other, CHALLENGE);
+        assert!(register(&rp(), CHALLENGE, &attestation).is_err());
+
+        let mut attestation = authenticator.attest(&rp(), CHALLENGE);
+        attestation.id = encode(&[0; 32]);
+        assert!(register(&rp(), CHALLENGE, &attestation).is_err());
+
+        let mut attestation = authenticator.attest(&rp(), CHALLENGE);
+        attestation.client_data_json = encode(&Authenticator::client_data(
+            "webauthn.get",
+            CHALLENGE,
+            &rp().origin,
+        ));
+        assert!(register(&rp(), CHALLENGE, &attestation).is_err());
+    }
+
+    #[test]
+    fn authentication_rejects_forgeries() {
+        let authenticator = Authenticator::es256();
+        let mut credential =
+            register(&rp(), CHALLENGE, &authenticator.attest(&rp(), CHALLENGE)).unwrap();
+        credential.sign_count = 5;
+
+        let assertion = authenticator.assert(&rp(), CHALLENGE, 6);
+        assert!(authenticate(&rp(), &generate_challenge(), &credential, &assertion).is_err());
+
+        let mut assertion = authenticator.assert(&rp(), CHALLENGE, 6);
+        assertion.signature = authenticator.assert(&rp(), CHALLENGE, 7).signature;
+        assert_eq!(
+            authenticate(&rp(), CHALLENGE, &credential, &assertion),
+            Err(String::from("Invalid signature"))
+        );
+
+        let other = Authenticator::es256().assert(&rp(), CHALLENGE, 6);
+        assert!(authenticate(&rp(), CHALLENGE, &credential, &other).is_err());
+
+        let replayed = authenticator.assert(&rp(), CHALLENGE, 5);
+        assert_eq!(
+            authenticate(&rp(), CHALLENGE, &credential, &replayed),
+            Err(String::from("Signature counter did not increase"))
+        );
+    }
+
+    #[test]
+    fn cbor_rejects_malformed_items() {
+        assert_eq!(
+            Cbor::parse(&map(vec![(int(1), int(-7))]), &mut 0, 0),
+            Ok(Cbor::Map(vec![(Cbor::Int(1), Cbor::Int(-7))]))
+        );
+        assert!(Cbor::parse(&[0x58, 0x20, 0x00], &mut 0, 0).is_err());
+        assert!(Cbor::parse(&[0x5f, 0x41, 0x00, 0xff], &mut 0, 0).is_err());
+        assert!(Cbor::parse(&[0x81; 16], &mut 0, 0).is_err());
+        assert!(Cbor::parse(
+            &[0x1b, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff],
+            &mut 0,
+            0
+        )
+        .is_err());
+    }
+}
@@ -1,98 +1,351 @@
 //! Main file
 mod haak;
 
+#[macro_use]
+extern crate log;
 #[macro_use]
 extern crate redis_async;
 
-use std::env;
-
 use actix_files::{Files, NamedFile};
-use actix_redis::{RedisActor, RedisSession};
-use actix_web::{middleware, web, App, HttpRequest, HttpServer, Result};
+use actix_redis::RedisSession;
+use actix_web::http::ContentEncoding;
+use actix_web::web::Data;
+use actix_web::{middleware, web, App, HttpServer, Result};
 
-use openssl::ssl::{SslAcceptor, SslFiletype, SslMethod};
+use std::net::SocketAddr;
 
 /// Favicon handler
-/// Loads the favicon in ./templates/favicon.ico
+/// Loads the favicon in `<TEMPLATES_DIR>/favicon.ico`, sent uncompressed as it is tiny
 ///
 /// # Remarks
 ///
 /// Should be called from actix_web
-async fn favicon(_req: HttpRequest) -> Result<NamedFile> {
-    Ok(NamedFile::open("./templates/favicon.ico")?)
+async fn favicon(config: Data<haak::config::Config>) -> Result<NamedFile> {
+    Ok(
+        NamedFile::open(format!("{}/favicon.ico", config.templates_dir))?
+            .set_content_encoding(ContentEncoding::Identity),
+    )
 }
 
 /// Main function.
 ///
-/// Gets cookie secret from environment, setups redis, the logger and routes.
+/// Loads the configuration (see `haak::config`), setups redis, the logger and routes.
+/// The HTTPS listener binds to every address in `WEATHER_IP`, SIGHUP reloads its certificate.
+/// When `WEATHER_HTTP_PORT` is set, also listens on that port for plain HTTP and redirects all
+/// requests to HTTPS. When `METRICS_PORT` is set, /metrics is served on that port (bound to
+/// `METRICS_IP`, default `127.0.0.1`) instead of on the public listener.
+/// With `--check` only the dependencies are checked (see `haak::health::diagnose`), the process
+/// exits with 1 if any check failed.
 #[actix_rt::main]
 async fn main() -> std::io::Result<()> {
-    std::env::set_var("RUST_LOG", "actix_web=info,actix_redis=info");
-    env_logger::init();
+    let check_only = std::env::args().skip(1).any(|arg| arg == "--check");
+    let config = haak::config::Config::load().unwrap_or_else(|e| panic!("{}", e));
+
+    // Default log levels, unless overridden with RUST_LOG
+    if std::env::var_os("RUST_LOG").is_none() {
+        std::env::set_var(
+            "RUST_LOG",
+            "actix_web=info,actix_redis=info,server=info,access=info",
+        );
+    }
+    let json_log = haak::logging::json_enabled();
+    haak::logging::init(json_log);
 
     // Cookie secret is used to encrypt the session token
-    let cookie_secret = base64::decode(
-        &env::var("COOKIE_SECRET_KEY")
-        .expect("Cookie secret key not set, generate a new one with export COOKIE_SECRET_KEY=`cat /dev/urandom | head -c 32 | base64`")
-    ).unwrap();
+    let cookie_secret = config.cookie_secret.clone();
+
+    let default_settings = haak::settings::UserSettings::from_env();
+    if let Err(field) = haak::settings::validate_settings(&default_settings) {
+        panic!(
+            "Invalid default {} in {:?}, check DEFAULT_TEMPERATURE, DEFAULT_PRESSURE, DEFAULT_THEME, DEFAULT_TIMEFRAME, DEFAULT_TIMEZONE and DEFAULT_LOCALE",
+            field, default_settings
+        );
+    }
+
+    let redis_address = config.redis_address.clone();
+    haak::keys::init(&config.redis_key_prefix);
+    let compression = config.compression_secs;
+
+    // Redis is required unless REQUIRE_REDIS=false, without it every request fails
+    let redis = Data::new(haak::database::Pool::start(&redis_address, 1, compression));
+    let diagnostics = haak::health::diagnose(&config, &redis).await;
+    if check_only {
+        std::process::exit(if diagnostics.passed() { 0 } else { 1 });
+    }
+
+    if let Err(e) = diagnostics.redis {
+        if config.require_redis {
+            panic!(
+                "Redis at {} is unreachable ({}), set REQUIRE_REDIS=false to start anyway",
+                redis_address, e
+            );
+        }
+        warn!(
+            "Redis at {} is unreachable ({}), starting anyway as REQUIRE_REDIS=false. \
+             Requests fail until it is back.",
+            redis_address, e
+        );
+    }
 
-    let port =
-        &env::var("WEATHER_PORT").expect("Port not set, set it with export WEATHER_PORT=443");
+    let mailer = haak::email::Mailer::start(config.url.clone());
+    let mx_check = haak::mx::MxCheck::start(config.validate_mx);
+    let templates_dir = config.templates_dir.clone();
+    let session_config = config.session.clone();
 
-    &env::var("WEATHER_URL").expect("URL not set, set it with export WEATHER_URL=<url>");
+    // Optional plain HTTP listener redirecting to HTTPS
+    let http_port = config.http_port;
 
-    let ip = &env::var("WEATHER_IP").expect("IP not set, set it with export WEATHER_IP=<ip>");
+    // Optional internal listener for the Prometheus metrics
+    let metrics = haak::metrics::Metrics::default();
+    let metrics_port = config.metrics_port;
+    let metrics_public = metrics_port.is_none();
+    let metrics_redis_address = redis_address.clone();
+    let redis_pool_size = config.redis_pool_size;
+    let metrics_data = metrics.clone();
 
-    let mut builder = SslAcceptor::mozilla_intermediate(SslMethod::tls()).unwrap();
-    builder
-        .set_private_key_file("key.pem", SslFiletype::PEM)
-        .unwrap();
-    builder.set_certificate_chain_file("cert.pem").unwrap();
+    // Optional trimming of readings older than the retention window
+    if let Some(days) = config.retention_days {
+        haak::retention::start(days, redis.clone());
+    }
 
-    HttpServer::new(move || {
+    let tls = haak::tls::Reloadable::load(config.tls.clone()).unwrap_or_else(|e| panic!("{}", e));
+    tls.clone().reload_on_hangup();
+
+    let app_config = config.clone();
+
+    let mut https = HttpServer::new(move || {
         App::new()
-            // redis session middleware
-            .data(RedisActor::start("127.0.0.1:6379"))
-            .wrap(RedisSession::new("127.0.0.1:6379", &cookie_secret[..]))
-            // enable logger
-            .wrap(middleware::Logger::default())
+            // redis connections of this worker
+            .data(haak::database::Pool::start(&redis_address, redis_pool_size, compression))
+            .data(app_config.clone())
+            .data(metrics.clone())
+            .data(mailer.clone())
+            .data(mx_check.clone())
+            // request body limits, per extractor as resource data would replace the app data
+            .app_data(haak::limits::json(app_config.json_limit))
+            .app_data(haak::limits::form(app_config.form_limit))
+            .app_data(haak::limits::payload(app_config.ingest_limit))
+            // expire idle and old sessions, must be wrapped inside the session middleware
+            .wrap(haak::session::SessionCheck::new(session_config.clone()))
+            .wrap(
+                RedisSession::new(redis_address.as_str(), &cookie_secret[..])
+                    .ttl(session_config.ttl)
+                    .cache_keygen(Box::new(|key: &str| {
+                        haak::keys::key(&format!("session:{}", key))
+                    }))
+                    .cookie_name(&session_config.cookie_name)
+                    .cookie_secure(session_config.cookie_secure)
+                    .cookie_same_site(session_config.cookie_same_site),
+            )
+            // enable logger, JSON access log with LOG_FORMAT=json
+            .wrap(middleware::Logger::new(haak::logging::ACCESS_FORMAT))
+            .wrap(middleware::Condition::new(
+                json_log,
+                haak::logging::JsonLogger,
+            ))
+            // compress responses based on Accept-Encoding
+            .wrap(middleware::Compress::default())
+            .wrap(haak::metrics::RequestMetrics(metrics.clone()))
+            // outermost, so the request ID is known to the loggers and handlers
+            .wrap(haak::request_id::RequestIds)
             // Resources
             .service(Files::new(
                 "/resources/images",
-                "./templates/resources/images/",
+                format!("{}/resources/images/", templates_dir),
             ))
             .service(Files::new(
                 "/resources/scripts",
-                "./templates/resources/scripts/",
+                format!("{}/resources/scripts/", templates_dir),
             ))
             .service(Files::new(
                 "/resources/styles",
-                "./templates/resources/styles/",
+                format!("{}/resources/styles/", templates_dir),
             ))
             .route("/favicon.ico", web::get().to(favicon))
+            // Health
+            .route("/healthz", web::get().to(haak::health::healthz))
+            .route("/version", web::get().to(haak::health::version))
+            .configure(|cfg| {
+                if metrics_public {
+                    cfg.route("/metrics", web::get().to(haak::metrics::metrics));
+                }
+            })
             // Debug
             //.service(web::resource("/test").route(web::get().to(test)))
             // Authentication
             .service(
                 web::resource("/login")
+                    .app_data(haak::limits::json_expecting(
+                        app_config.json_limit,
+                        r#"{"email": ..., "csrf_token": ..., "password"/"remember": ... (optional)}"#,
+                    ))
                     .route(web::get().to(haak::auth::login_get))
                     .route(web::post().to(haak::auth::login_submit)),
             )
             .service(web::resource("/poll_login").to(haak::auth::poll_login))
             .service(web::resource("/verify_login").to(haak::auth::verify_login))
+            .service(
+                web::resource("/verify_login_code")
+                    .route(web::post().to(haak::auth::verify_login_code)),
+            )
             .service(web::resource("/logout").to(haak::auth::logout))
+            .service(web::resource("/logout_all").route(web::post().to(haak::auth::logout_all)))
             .service(web::resource("/register").to(haak::auth::register))
             .service(web::resource("/verify_register").to(haak::auth::verify_register))
+            .service(
+                web::resource("/verify_totp")
+                    .route(web::get().to(haak::auth::totp_get))
+                    .route(web::post().to(haak::auth::totp_submit)),
+            )
+            .service(web::resource("/totp/enroll").route(web::post().to(haak::auth::totp_enroll)))
+            .service(web::resource("/totp/enable").route(web::post().to(haak::auth::totp_enable)))
+            .service(
+                web::resource("/webauthn/register/start")
+                    .route(web::post().to(haak::auth::webauthn_register_start)),
+            )
+            .service(
+                web::resource("/webauthn/register/finish")
+                    .route(web::post().to(haak::auth::webauthn_register_finish)),
+            )
+            .service(
+                web::resource("/webauthn/login/start")
+                    .route(web::post().to(haak::auth::webauthn_login_start)),
+            )
+            .service(
+                web::resource("/webauthn/login/finish")
+                    .route(web::post().to(haak::auth::webauthn_login_finish)),
+            )
+            // Administration
+            .service(web::resource("/admin/users").route(web::get().to(haak::auth::list_users)))
+            .service(web::resource("/admin/audit").route(web::get().to(haak::audit::audit_log)))
+            .service(web::resource("/admin/backup").route(web::get().to(haak::backup::backup)))
+            .service(web::resource("/admin/restore").route(web::post().to(haak::backup::restore)))
+            .service(
+                web::resource("/admin/delete_user").route(web::post().to(haak::auth::delete_user)),
+            )
+            .service(
+                web::resource("/admin/resend_register")
+                    .route(web::post().to(haak::auth::resend_register)),
+            )
+            .service(
+                web::resource("/admin/test_email").route(web::post().to(haak::auth::test_email)),
+            )
+            .service(web::resource("/admin/set_role").route(web::post().to(haak::auth::set_role)))
+            .service(
+                web::resource("/admin/stations").route(web::get().to(haak::sensor::admin_stations)),
+            )
+            .service(
+                web::resource("/admin/station_altitude")
+                    .route(web::post().to(haak::sensor::set_altitude)),
+            )
             // Settings
             .service(
                 web::resource("/settings")
+                    .app_data(haak::limits::form_expecting(
+                        app_config.form_limit,
+                        "temperature, pressure, humidity, theme, timeframe, timezone, locale, \
+                         chart_type, show_temperature, show_pressure, show_humidity, smoothing \
+                         (optional) and csrf_token",
+                    ))
                     .route(web::get().to(haak::settings::settings_index))
                     .route(web::post().to(haak::settings::settings_save)),
             )
-            // Graphs
+            .service(
+                web::resource("/settings/export")
+                    .route(web::get().to(haak::settings::settings_export)),
+            )
+            .service(
+                web::resource("/settings/import")
+                    .route(web::post().to(haak::settings::settings_import)),
+            )
+            .service(
+                web::resource("/settings/alerts").route(web::post().to(haak::alerts::alert_add)),
+            )
+            .service(
+                web::resource("/settings/alerts/delete")
+                    .route(web::post().to(haak::alerts::alert_delete)),
+            )
+            .service(
+                web::resource("/settings/password").route(web::post().to(haak::auth::set_password)),
+            )
+            .service(
+                web::resource("/settings/change_email")
+                    .route(web::post().to(haak::auth::change_email)),
+            )
+            .service(web::resource("/verify_email_change").to(haak::auth::verify_email_change))
+            // Sensor
+            .service(web::resource("/ingest").route(web::post().to(haak::sensor::ingest)))
+            .service(
+                web::resource("/ingest/batch").route(web::post().to(haak::sensor::ingest_batch)),
+            )
+            // Graphs, callable cross-origin from CORS_ALLOWED_ORIGINS
+            .service(
+                web::scope("/api")
+                    .wrap(middleware::Condition::new(
+                        !app_config.cors_origins.is_empty(),
+                        haak::cors::cors(&app_config.cors_origins),
+                    ))
+                    .service(web::resource("/readings").route(web::get().to(haak::graph::readings)))
+                    .service(web::resource("/latest").route(web::get().to(haak::graph::latest)))
+                    .service(web::resource("/derived").route(web::get().to(haak::derived::derived)))
+                    .service(web::resource("/stations").route(web::get().to(haak::graph::stations)))
+                    .service(web::resource("/summary").route(web::get().to(haak::graph::summary)))
+                    .service(web::resource("/gaps").route(web::get().to(haak::graph::gaps)))
+                    .service(web::resource("/me").route(web::get().to(haak::auth::me)))
+                    .service(web::resource("/units").route(web::get().to(haak::settings::units)))
+                    .service(
+                        web::resource("/annotations")
+                            .route(web::post().to(haak::annotations::annotation_add)),
+                    )
+                    .service(
+                        web::resource("/annotations/{ts}")
+                            .route(web::delete().to(haak::annotations::annotation_delete)),
+                    )
+                    .service(
+                        web::resource("/readings.csv")
+                            .route(web::get().to(haak::graph::readings_csv)),
+                    ),
+            )
             .service(web::resource("/").to(haak::graph::graph_index))
-    })
-    .bind_openssl(format!("{}:{}", ip, port), builder)?
-    .run()
-    .await
+    });
+    for ip in config.ips.iter() {
+        let acceptor = tls.acceptor().unwrap_or_else(|e| panic!("{}", e));
+        https = https.bind_openssl(SocketAddr::new(*ip, config.port), acceptor)?;
+    }
+
+    let mut servers = vec![https.run()];
+
+    if let Some(http_port) = http_port {
+        let redirect_config = config.clone();
+        let mut http = HttpServer::new(move || {
+            App::new()
+                .data(redirect_config.clone())
+                .wrap(middleware::Logger::default())
+                .default_service(web::route().to(haak::redirect::https_redirect))
+        });
+        for ip in config.ips.iter() {
+            http = http.bind(SocketAddr::new(*ip, http_port))?;
+        }
+
+        servers.push(http.run());
+    }
+
+    if let Some(metrics_port) = metrics_port {
+        let internal = HttpServer::new(move || {
+            App::new()
+                .data(haak::database::Pool::start(
+                    &metrics_redis_address,
+                    1,
+                    compression,
+                ))
+                .data(metrics_data.clone())
+                .route("/metrics", web::get().to(haak::metrics::metrics))
+        })
+        .bind(format!("{}:{}", config.metrics_ip, metrics_port))?
+        .run();
+
+        servers.push(internal);
+    }
+
+    futures::future::try_join_all(servers).await.map(|_| ())
 }
@@ -44,6 +44,13 @@ unit_dictionary = {
     }
 }
 
+//hide the panels of the metrics hidden in the settings
+for (const metric in options.show) {
+  if (!options.show[metric]) {
+    document.getElementById(metric + 'Value').parentElement.style.display = 'none';
+  }
+}
+
 //the first thing the website does is fetching the data from the api
 //and set it in the graph with the default timestamp
 const loadData = () => {
@@ -70,7 +77,8 @@ const loadData = () => {
       graph = new Rickshaw.Graph({ //making the chart
         element: document.querySelector('#chart'), //the chart id for in the html file
         stack: false,
-        renderer: 'line', //this is the type of graph
+        renderer: options.chart_type, //this is the type of graph
+        interpolation: options.smoothing ? 'cardinal' : 'linear',
         series: [
           { //data of humidity
             name: 'Humidity',
@@ -92,7 +100,7 @@ const loadData = () => {
             data: unpackData(parsedResponse, 'pressure'),
             color: palette.color()
           }
-        ]
+        ].filter(series => options.show[series.name.toLowerCase()] !== false) //hidden in the settings
       });
 
       addContents();
@@ -0,0 +1,59 @@
+// Security key ceremonies, the server sends and expects binary fields base64url encoded
+
+function fromBase64url(value) {
+  const base64 = value.replace(/-/g, '+').replace(/_/g, '/');
+  return Uint8Array.from(atob(base64), c => c.charCodeAt(0));
+}
+
+function toBase64url(buffer) {
+  return btoa(String.fromCharCode(...new Uint8Array(buffer)))
+    .replace(/\+/g, '-').replace(/\//g, '_').replace(/=+$/, '');
+}
+
+async function postJson(url, body) {
+  return fetch(url, {
+    method: 'POST',
+    credentials: 'include',
+    headers: {
+      'Content-Type': 'application/json;charset=utf-8'
+    },
+    body: JSON.stringify(body)
+  });
+}
+
+// Registers a security key for the logged in user, returns the response of the server
+async function registerSecurityKey() {
+  const options = await (await postJson('/webauthn/register/start', {})).json();
+  options.challenge = fromBase64url(options.challenge);
+  options.user.id = fromBase64url(options.user.id);
+  options.excludeCredentials.forEach(credential => credential.id = fromBase64url(credential.id));
+
+  const credential = await navigator.credentials.create({publicKey: options});
+
+  return postJson('/webauthn/register/finish', {
+    id: credential.id,
+    clientDataJSON: toBase64url(credential.response.clientDataJSON),
+    attestationObject: toBase64url(credential.response.attestationObject)
+  });
+}
+
+// Logs in with a security key, returns the response of the server
+async function loginSecurityKey(email, csrf_token) {
+  const start = await postJson('/webauthn/login/start', {email: email, csrf_token: csrf_token});
+  if(start.status != 200) {
+    return start;
+  }
+
+  const options = await start.json();
+  options.challenge = fromBase64url(options.challenge);
+  options.allowCredentials.forEach(credential => credential.id = fromBase64url(credential.id));
+
+  const credential = await navigator.credentials.get({publicKey: options});
+
+  return postJson('/webauthn/login/finish', {
+    id: credential.id,
+    clientDataJSON: toBase64url(credential.response.clientDataJSON),
+    authenticatorData: toBase64url(credential.response.authenticatorData),
+    signature: toBase64url(credential.response.signature)
+  });
+}